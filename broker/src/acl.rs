@@ -0,0 +1,543 @@
+//! Topic level access control.
+//!
+//! Rules grant or deny publishing and subscribing to the topics matched by a topic filter, for
+//! every client, for a single user name or client identifier, or for the clients connecting from
+//! an address range. The address is the one of the client, as told by a PROXY protocol header
+//! when the listener reads it. `%u` and `%c` in the topic
+//! filter stand for the user name and the client identifier of the client being checked.
+//!
+//! A deny rule wins over any allow rule. A topic no rule applies to is denied. An allow rule
+//! applies to the topic filter of a SUBSCRIBE when it matches every topic the filter does, so
+//! `sensors/#` permits subscribing to `sensors/+/temperature` while `sensors/+` does not permit
+//! subscribing to `sensors/#`. A deny rule applies when it matches any of them, so
+//! `deny subscribe any sensors/secret` refuses `sensors/#`.
+
+use crate::topic;
+use std::fmt;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use thiserror::Error;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+#[derive(Error, Debug)]
+pub enum AclError {
+    #[error("Reading ACL file {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Line {0} of the ACL file is not of the form <allow|deny> <publish|subscribe|both> <any|user NAME|client ID|address RANGE> <topic filter>")]
+    MalformedLine(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    Allow,
+    Deny,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Publish,
+    Subscribe,
+    Both,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Principal {
+    Anyone,
+    User(String),
+    ClientId(String),
+    Address(AddressRange),
+}
+
+/// Addresses sharing their first bits with a network address, written `192.0.2.0/24` or, for a
+/// single address, `192.0.2.7`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AddressRange {
+    pub network: IpAddr,
+    pub prefix_length: u8,
+}
+
+impl AddressRange {
+    pub fn contains(&self, address: IpAddr) -> bool {
+        match (
+            bits(self.network.to_canonical()),
+            bits(address.to_canonical()),
+        ) {
+            ((network, width), (address, other_width)) if width == other_width => {
+                let mask = match self.prefix_length {
+                    0 => 0,
+                    length => u128::MAX << (width - length as u32),
+                };
+                network & mask == address & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Address as a number, with the number of bits it has.
+fn bits(address: IpAddr) -> (u128, u32) {
+    match address {
+        IpAddr::V4(v4) => (u32::from(v4) as u128, 32),
+        IpAddr::V6(v6) => (u128::from(v6), 128),
+    }
+}
+
+impl FromStr for AddressRange {
+    type Err = ();
+
+    fn from_str(range: &str) -> Result<Self, Self::Err> {
+        let (network, prefix_length) = match range.split_once('/') {
+            Some((network, length)) => (network, Some(length)),
+            None => (range, None),
+        };
+        let network: IpAddr = network.parse().map_err(|_| ())?;
+        let width = bits(network).1 as u8;
+        let prefix_length = match prefix_length {
+            Some(length) => length.parse().map_err(|_| ())?,
+            None => width,
+        };
+        match prefix_length <= width {
+            true => Ok(AddressRange {
+                network,
+                prefix_length,
+            }),
+            false => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for AddressRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_length)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AclRule {
+    pub permission: Permission,
+    pub access: Access,
+    pub principal: Principal,
+    pub topic_filter: String,
+}
+
+impl AclRule {
+    pub fn allow(principal: Principal, access: Access, topic_filter: &str) -> AclRule {
+        AclRule {
+            permission: Permission::Allow,
+            access,
+            principal,
+            topic_filter: topic_filter.to_string(),
+        }
+    }
+
+    pub fn deny(principal: Principal, access: Access, topic_filter: &str) -> AclRule {
+        AclRule {
+            permission: Permission::Deny,
+            access,
+            principal,
+            topic_filter: topic_filter.to_string(),
+        }
+    }
+
+    fn applies(
+        &self,
+        client_id: &str,
+        username: Option<&str>,
+        address: Option<IpAddr>,
+        access: Access,
+        topic: &str,
+    ) -> bool {
+        if self.access != Access::Both && self.access != access {
+            return false;
+        }
+
+        let principal = match &self.principal {
+            Principal::Anyone => true,
+            Principal::User(user) => username == Some(user.as_str()),
+            Principal::ClientId(id) => client_id == id,
+            Principal::Address(range) => address.is_some_and(|address| range.contains(address)),
+        };
+
+        principal
+            && substitute(&self.topic_filter, client_id, username)
+                .map(|filter| match (access, &self.permission) {
+                    (Access::Subscribe, Permission::Allow) => topic::covers(&filter, topic),
+                    (Access::Subscribe, Permission::Deny) => topic::intersects(&filter, topic),
+                    _ => topic::matches(&filter, topic),
+                })
+                .unwrap_or(false)
+    }
+}
+
+/// Line of the ACL file holding the rule.
+impl fmt::Display for AclRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let permission = match self.permission {
+            Permission::Allow => "allow",
+            Permission::Deny => "deny",
+        };
+        let access = match self.access {
+            Access::Publish => "publish",
+            Access::Subscribe => "subscribe",
+            Access::Both => "both",
+        };
+        match &self.principal {
+            Principal::Anyone => write!(f, "{permission} {access} any {}", self.topic_filter),
+            Principal::User(user) => {
+                write!(f, "{permission} {access} user {user} {}", self.topic_filter)
+            }
+            Principal::ClientId(id) => {
+                write!(f, "{permission} {access} client {id} {}", self.topic_filter)
+            }
+            Principal::Address(range) => {
+                write!(
+                    f,
+                    "{permission} {access} address {range} {}",
+                    self.topic_filter
+                )
+            }
+        }
+    }
+}
+
+/// Replaces `%u` and `%c`. Returns None when the rule cannot apply to the client: it has no user
+/// name, or its user name or client identifier contains a wildcard.
+fn substitute(topic_filter: &str, client_id: &str, username: Option<&str>) -> Option<String> {
+    let mut filter = topic_filter.to_string();
+
+    if filter.contains("%u") {
+        let username = username.filter(|u| !u.contains(['+', '#']))?;
+        filter = filter.replace("%u", username);
+    }
+    if filter.contains("%c") {
+        if client_id.contains(['+', '#']) {
+            return None;
+        }
+        filter = filter.replace("%c", client_id);
+    }
+
+    Some(filter)
+}
+
+/// Set of ACL rules, optionally loaded from a file that can be reloaded while the broker runs.
+///
+/// The file holds one rule per line, blank lines and lines starting with `#` are ignored:
+///
+/// ```text
+/// allow both any devices/%c/#
+/// allow subscribe user alice sensors/#
+/// deny publish client legacy-device sensors/#
+/// allow subscribe address 10.0.0.0/8 metrics/#
+/// ```
+#[derive(Debug, Default)]
+pub struct Acl {
+    path: Option<PathBuf>,
+    rules: RwLock<Vec<AclRule>>,
+}
+
+impl Acl {
+    pub fn new(rules: Vec<AclRule>) -> Acl {
+        Acl {
+            path: None,
+            rules: RwLock::new(rules),
+        }
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Acl, AclError> {
+        let path = path.as_ref().to_path_buf();
+        let rules = Acl::parse(&std::fs::read_to_string(&path)?)?;
+
+        Ok(Acl {
+            path: Some(path),
+            rules: RwLock::new(rules),
+        })
+    }
+
+    pub fn parse(contents: &str) -> Result<Vec<AclRule>, AclError> {
+        let mut rules = vec![];
+
+        for (index, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let malformed = AclError::MalformedLine(index + 1);
+            let fields: Vec<&str> = line.split_whitespace().collect();
+
+            let permission = match fields[0] {
+                "allow" => Permission::Allow,
+                "deny" => Permission::Deny,
+                _ => return Err(malformed),
+            };
+            let access = match fields.get(1) {
+                Some(&"publish") => Access::Publish,
+                Some(&"subscribe") => Access::Subscribe,
+                Some(&"both") => Access::Both,
+                _ => return Err(malformed),
+            };
+            let (principal, topic_filter) = match fields[2..] {
+                ["any", topic_filter] => (Principal::Anyone, topic_filter),
+                ["user", user, topic_filter] => (Principal::User(user.to_string()), topic_filter),
+                ["client", id, topic_filter] => (Principal::ClientId(id.to_string()), topic_filter),
+                ["address", range, topic_filter] => match range.parse() {
+                    Ok(range) => (Principal::Address(range), topic_filter),
+                    Err(()) => return Err(malformed),
+                },
+                _ => return Err(malformed),
+            };
+            if !topic::is_valid_topic_filter(topic_filter) {
+                return Err(malformed);
+            }
+
+            rules.push(AclRule {
+                permission,
+                access,
+                principal,
+                topic_filter: topic_filter.to_string(),
+            });
+        }
+
+        Ok(rules)
+    }
+
+    /// Reads the file again. The current rules are kept when the file cannot be parsed.
+    pub fn reload(&self) -> Result<(), AclError> {
+        if let Some(path) = &self.path {
+            let rules = Acl::parse(&std::fs::read_to_string(path)?)?;
+            self.set_rules(rules);
+        }
+        Ok(())
+    }
+
+    /// Reloads the file whenever its modification time changes, checking every period.
+    pub fn watch(self: Arc<Self>, period: Duration) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut modified = self.modified();
+            let mut interval = tokio::time::interval(period);
+
+            loop {
+                interval.tick().await;
+                let current = self.modified();
+                if current == modified {
+                    continue;
+                }
+                modified = current;
+
+                match self.reload() {
+                    Ok(()) => info!("reloaded ACL file {:?}", self.path),
+                    Err(e) => warn!("keeping previous ACL rules: {e}"),
+                }
+            }
+        })
+    }
+
+    fn modified(&self) -> Option<SystemTime> {
+        let path = self.path.as_ref()?;
+        std::fs::metadata(path).and_then(|m| m.modified()).ok()
+    }
+
+    pub fn rules(&self) -> Vec<AclRule> {
+        self.rules.read().unwrap().clone()
+    }
+
+    pub fn set_rules(&self, rules: Vec<AclRule>) {
+        *self.rules.write().unwrap() = rules;
+    }
+
+    /// Appends the rules. Reloading the file drops them.
+    pub fn add_rules(&self, rules: Vec<AclRule>) {
+        self.rules.write().unwrap().extend(rules);
+    }
+
+    /// Removes the rule at the index, in the order of `rules`. Reloading the file restores it.
+    pub fn remove_rule(&self, index: usize) -> Option<AclRule> {
+        let mut rules = self.rules.write().unwrap();
+        (index < rules.len()).then(|| rules.remove(index))
+    }
+
+    /// Whether the client may publish to the topic name, or subscribe to the topic filter.
+    /// Address rules do not apply to a client without an address, e.g. on a Unix socket.
+    pub fn check(
+        &self,
+        client_id: &str,
+        username: Option<&str>,
+        address: Option<IpAddr>,
+        access: Access,
+        topic: &str,
+    ) -> bool {
+        let rules = self.rules.read().unwrap();
+        let mut applying = rules
+            .iter()
+            .filter(|rule| rule.applies(client_id, username, address, access, topic))
+            .peekable();
+
+        applying.peek().is_some() && applying.all(|rule| rule.permission == Permission::Allow)
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use crate::acl::{Access, Acl, AclRule, Principal};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    pub fn should_let_deny_override_allow() {
+        let acl = Acl::new(vec![
+            AclRule::allow(Principal::Anyone, Access::Both, "sensors/#"),
+            AclRule::deny(
+                Principal::User(String::from("guest")),
+                Access::Publish,
+                "sensors/#",
+            ),
+        ]);
+
+        assert!(acl.check("ID", Some("alice"), None, Access::Publish, "sensors/1"));
+        assert!(acl.check("ID", Some("guest"), None, Access::Subscribe, "sensors/1"));
+        assert!(!acl.check("ID", Some("guest"), None, Access::Publish, "sensors/1"));
+        assert!(!acl.check("ID", Some("alice"), None, Access::Publish, "actuators/1"));
+    }
+
+    #[test]
+    pub fn should_substitute_user_name_and_client_id() {
+        let acl = Acl::new(vec![
+            AclRule::allow(Principal::Anyone, Access::Both, "users/%u/#"),
+            AclRule::allow(Principal::Anyone, Access::Publish, "devices/%c"),
+        ]);
+
+        assert!(acl.check(
+            "ID",
+            Some("alice"),
+            None,
+            Access::Subscribe,
+            "users/alice/inbox"
+        ));
+        assert!(!acl.check(
+            "ID",
+            Some("alice"),
+            None,
+            Access::Subscribe,
+            "users/bob/inbox"
+        ));
+        assert!(!acl.check("ID", None, None, Access::Subscribe, "users//inbox"));
+        assert!(acl.check("dev-1", None, None, Access::Publish, "devices/dev-1"));
+        assert!(!acl.check("dev-+", None, None, Access::Publish, "devices/dev-+"));
+    }
+
+    #[test]
+    pub fn should_check_subscriptions_with_wildcards() {
+        let acl = Acl::new(vec![AclRule::allow(
+            Principal::Anyone,
+            Access::Subscribe,
+            "sensors/+/temperature",
+        )]);
+
+        assert!(acl.check("ID", None, None, Access::Subscribe, "sensors/+/temperature"));
+        assert!(!acl.check("ID", None, None, Access::Subscribe, "sensors/#"));
+        assert!(!acl.check("ID", None, None, Access::Subscribe, "#"));
+    }
+
+    #[test]
+    pub fn should_not_widen_subscriptions_beyond_single_level_wildcards() {
+        let acl = Acl::new(vec![
+            AclRule::allow(Principal::Anyone, Access::Subscribe, "sensors/+"),
+            AclRule::allow(Principal::Anyone, Access::Subscribe, "actuators/#"),
+            AclRule::deny(Principal::Anyone, Access::Subscribe, "actuators/secret"),
+        ]);
+
+        assert!(acl.check("ID", None, None, Access::Subscribe, "sensors/+"));
+        assert!(acl.check("ID", None, None, Access::Subscribe, "sensors/kitchen"));
+        assert!(!acl.check("ID", None, None, Access::Subscribe, "sensors/#"));
+        assert!(!acl.check("ID", None, None, Access::Subscribe, "sensors/+/#"));
+        assert!(acl.check("ID", None, None, Access::Subscribe, "actuators/+/state"));
+        assert!(!acl.check("ID", None, None, Access::Subscribe, "actuators/#"));
+        assert!(!acl.check("ID", None, None, Access::Subscribe, "actuators/+"));
+    }
+
+    #[test]
+    pub fn should_parse_rules() {
+        let rules = Acl::parse(
+            "# rules\nallow both any devices/%c/#\n\ndeny publish client legacy sensors/#\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            vec![
+                AclRule::allow(Principal::Anyone, Access::Both, "devices/%c/#"),
+                AclRule::deny(
+                    Principal::ClientId(String::from("legacy")),
+                    Access::Publish,
+                    "sensors/#"
+                ),
+            ],
+            rules
+        );
+        assert_eq!("deny publish client legacy sensors/#", rules[1].to_string());
+        assert!(Acl::parse("allow both anyone #").is_err());
+        assert!(Acl::parse("allow read any #").is_err());
+        assert!(Acl::parse("allow both any a/#/b").is_err());
+    }
+
+    #[test]
+    pub fn should_match_client_address_ranges() {
+        let acl = Acl::new(Acl::parse("allow publish address 10.0.0.0/8 metrics/#\nallow subscribe address 2001:db8::1 #\n").unwrap());
+        let address = |address: &str| Some(address.parse().unwrap());
+
+        assert!(acl.check(
+            "ID",
+            None,
+            address("10.1.2.3"),
+            Access::Publish,
+            "metrics/cpu"
+        ));
+        assert!(acl.check(
+            "ID",
+            None,
+            address("::ffff:10.1.2.3"),
+            Access::Publish,
+            "metrics/cpu"
+        ));
+        assert!(!acl.check(
+            "ID",
+            None,
+            address("11.1.2.3"),
+            Access::Publish,
+            "metrics/cpu"
+        ));
+        assert!(!acl.check("ID", None, None, Access::Publish, "metrics/cpu"));
+        assert!(acl.check("ID", None, address("2001:db8::1"), Access::Subscribe, "a"));
+        assert!(!acl.check("ID", None, address("2001:db8::2"), Access::Subscribe, "a"));
+        assert_eq!(
+            "allow publish address 10.0.0.0/8 metrics/#",
+            acl.rules()[0].to_string()
+        );
+        assert!(Acl::parse("allow both address 10.0.0.0/33 #").is_err());
+        assert!(Acl::parse("allow both address localhost #").is_err());
+    }
+
+    #[tokio::test]
+    async fn should_reload_changed_file() {
+        let path = std::env::temp_dir().join(format!("acl-{}", std::process::id()));
+        std::fs::write(&path, "allow both any a\n").unwrap();
+
+        let acl = Arc::new(Acl::load(&path).unwrap());
+        let watcher = acl.clone().watch(Duration::from_millis(10));
+        assert!(acl.check("ID", None, None, Access::Publish, "a"));
+
+        // make sure the modification time changes on file systems with coarse timestamps
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        std::fs::write(&path, "allow both any b\n").unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        assert!(!acl.check("ID", None, None, Access::Publish, "a"));
+        assert!(acl.check("ID", None, None, Access::Publish, "b"));
+
+        watcher.abort();
+        std::fs::remove_file(path).unwrap();
+    }
+}
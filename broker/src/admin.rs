@@ -0,0 +1,766 @@
+//! Administration API, served over HTTP to manage the broker while it runs.
+//!
+//! Every request must carry `Authorization: Bearer <token>`. The API listens on
+//! [`DEFAULT_ADDRESS`] unless configured otherwise; binding it to another interface exposes it to
+//! the network. Path segments and query values are percent-decoded, so `#` in a topic filter is
+//! written `%23`.
+//!
+//! | Request                        | Effect                                                   |
+//! |--------------------------------|----------------------------------------------------------|
+//! | `GET /clients`                 | Connected clients                                        |
+//! | `DELETE /clients/{id}`         | Disconnects the client with 0x98 Administrative action   |
+//! | `GET /sessions[/{id}]`         | Sessions, connected or not                               |
+//! | `DELETE /sessions/{id}`        | Removes the session, disconnecting its client            |
+//! | `GET /retained`                | Retained messages                                        |
+//! | `DELETE /retained?topic=F`     | Clears the retained messages matching F, all without F   |
+//! | `GET /acl`, `POST /acl`        | ACL rules, adds the lines of the body in the file format |
+//! | `DELETE /acl/{index}`          | Removes a rule                                           |
+//! | `GET /users`, `POST /users`    | Users, adds `{"username": .., "password": ..}`           |
+//! | `DELETE /users/{name}`         | Removes a user                                           |
+//! | `POST /reload`                 | Reloads the ACL, rule, password and certificate files    |
+//! | `GET /maintenance`             | Redirection of the maintenance, null outside of it       |
+//! | `PUT /maintenance`             | Redirects `{"server_reference": .., "permanent": ..}`    |
+//! | `DELETE /maintenance`          | Accepts clients again                                    |
+//!
+//! Rules and users changed through the API are kept in memory: reloading the files replaces
+//! them.
+
+use crate::acl::Acl;
+use crate::auth::password::PasswordFile;
+use crate::auth::scram::constant_time_eq;
+use crate::broker::{Broker, Redirect};
+use crate::http;
+use crate::http::{Request, Response};
+use crate::session::SessionError;
+use crate::tls::TlsAcceptor;
+use deser::packets::publish::Publish;
+use deser::packets::subscribe::TopicFilterAndSubscriptionOptions;
+use nu_pretty_hex::simple_hex;
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tracing::info;
+
+pub const DEFAULT_ADDRESS: &str = "127.0.0.1:18083";
+
+pub struct AdminConfig {
+    address: SocketAddr,
+    token: String,
+    users: Option<Arc<PasswordFile>>,
+    tls: Vec<Arc<TlsAcceptor>>,
+}
+
+impl AdminConfig {
+    /// Requests must present the token. An empty token refuses every request.
+    pub fn new(token: &str) -> AdminConfig {
+        AdminConfig {
+            address: DEFAULT_ADDRESS.parse().unwrap(),
+            token: token.to_string(),
+            users: None,
+            tls: vec![],
+        }
+    }
+
+    pub fn address(mut self, address: SocketAddr) -> Self {
+        self.address = address;
+        self
+    }
+
+    /// Password file the users endpoints manage, also registered as a password backend of the
+    /// broker.
+    pub fn users(mut self, users: Arc<PasswordFile>) -> Self {
+        self.users = Some(users);
+        self
+    }
+
+    /// Certificates reloaded with the configuration, repeatable.
+    pub fn tls(mut self, tls: Arc<TlsAcceptor>) -> Self {
+        self.tls.push(tls);
+        self
+    }
+
+    pub async fn bind(&self) -> std::io::Result<TcpListener> {
+        TcpListener::bind(self.address).await
+    }
+
+    fn is_authorized(&self, request: &Request) -> bool {
+        let token = request
+            .header("Authorization")
+            .and_then(|value| value.strip_prefix("Bearer "));
+        match token {
+            Some(token) if !self.token.is_empty() => {
+                constant_time_eq(token.trim().as_bytes(), self.token.as_bytes())
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Connected client, registered by its session once the CONNACK is decided.
+#[derive(Debug)]
+pub(crate) struct ConnectedClient {
+    pub(crate) client_id: String,
+    pub(crate) connection_id: u64,
+    pub(crate) peer_addr: Option<SocketAddr>,
+    pub(crate) listener: &'static str,
+    pub(crate) username: Option<String>,
+    pub(crate) protocol_version: u8,
+    pub(crate) keep_alive: u16,
+    /// Tells the session to disconnect the client with the error.
+    pub(crate) disconnect: mpsc::Sender<SessionError>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientInfo {
+    pub client_id: String,
+    pub peer_addr: Option<SocketAddr>,
+    pub listener: &'static str,
+    pub username: Option<String>,
+    pub protocol_version: u8,
+    pub keep_alive: u16,
+    pub subscriptions: Vec<String>,
+    pub queued: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionInfo {
+    pub client_id: String,
+    pub connected: bool,
+    pub expiry_interval: u32,
+    /// Seconds since the Unix epoch.
+    pub disconnected_at: Option<u64>,
+    pub subscriptions: Vec<TopicFilterAndSubscriptionOptions>,
+    pub queued: usize,
+    pub queued_bytes: usize,
+    pub in_flight: usize,
+    pub dropped: u64,
+}
+
+impl Broker {
+    pub(crate) fn register_client(&self, client: ConnectedClient) {
+        self.clients
+            .lock()
+            .unwrap()
+            .insert(client.client_id.clone(), client);
+    }
+
+    /// Unless another connection registered the client identifier since.
+    pub(crate) fn unregister_client(&self, client_id: &str, connection_id: u64) {
+        let mut clients = self.clients.lock().unwrap();
+        if clients
+            .get(client_id)
+            .is_some_and(|client| client.connection_id == connection_id)
+        {
+            clients.remove(client_id);
+        }
+    }
+
+    /// Connected clients, by client identifier.
+    pub fn clients(&self) -> Vec<ClientInfo> {
+        let connected: Vec<ClientInfo> = self
+            .clients
+            .lock()
+            .unwrap()
+            .values()
+            .map(|client| ClientInfo {
+                client_id: client.client_id.clone(),
+                peer_addr: client.peer_addr,
+                listener: client.listener,
+                username: client.username.clone(),
+                protocol_version: client.protocol_version,
+                keep_alive: client.keep_alive,
+                subscriptions: vec![],
+                queued: 0,
+            })
+            .collect();
+
+        let router = self.router.lock().unwrap();
+        let mut clients: Vec<ClientInfo> = connected
+            .into_iter()
+            .map(|mut client| {
+                if let Some(session) = router.session(&client.client_id) {
+                    client.subscriptions = session
+                        .subscriptions
+                        .into_iter()
+                        .map(|s| s.topic_filter)
+                        .collect();
+                    client.queued = session.queued;
+                }
+                client
+            })
+            .collect();
+        clients.sort_by(|a, b| a.client_id.cmp(&b.client_id));
+        clients
+    }
+
+    /// Disconnects the client with DISCONNECT 0x98 Administrative action. Its session is kept
+    /// as if it had disconnected itself. Returns whether the client was connected.
+    pub fn kick(&self, client_id: &str) -> bool {
+        match self.clients.lock().unwrap().get(client_id) {
+            Some(client) => {
+                // a full channel already holds a request
+                let _ = client
+                    .disconnect
+                    .try_send(SessionError::AdministrativeAction);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn sessions(&self) -> Vec<SessionInfo> {
+        self.router.lock().unwrap().sessions()
+    }
+
+    pub fn session(&self, client_id: &str) -> Option<SessionInfo> {
+        self.router.lock().unwrap().session(client_id)
+    }
+
+    /// Removes the session with its subscriptions and messages, disconnecting its client.
+    /// Returns whether the session existed.
+    pub fn remove_session(&self, client_id: &str) -> bool {
+        // removed before the client disconnects, which would remove a session without expiry
+        let existed = self.router.lock().unwrap().remove_session(client_id);
+        self.kick(client_id);
+        existed
+    }
+
+    pub fn retained_messages(&self) -> Vec<Publish> {
+        self.router.lock().unwrap().retained()
+    }
+
+    /// Removes the retained messages of the topics matching the filter and returns how many.
+    pub fn clear_retained(&self, topic_filter: &str) -> usize {
+        self.router.lock().unwrap().clear_retained(topic_filter)
+    }
+
+    /// Answers the administration API until the listener fails.
+    pub async fn serve_admin(
+        self: Arc<Self>,
+        listener: TcpListener,
+        config: AdminConfig,
+    ) -> std::io::Result<()> {
+        let handler = move |request: Request| match config.is_authorized(&request) {
+            true => handle(&self, &config, &request),
+            false => Response::text(401, "unauthorized\n"),
+        };
+        http::serve(listener, Arc::new(handler)).await
+    }
+}
+
+fn handle(broker: &Broker, config: &AdminConfig, request: &Request) -> Response {
+    let segments: Option<Vec<String>> = request
+        .path
+        .trim_matches('/')
+        .split('/')
+        .map(percent_decode)
+        .collect();
+    let segments = match segments {
+        Some(segments) => segments,
+        None => return Response::text(400, "malformed path\n"),
+    };
+    let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+
+    match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["clients"]) => json_response(
+            200,
+            broker.clients().iter().map(client_json).collect::<Value>(),
+        ),
+        ("DELETE", ["clients", client_id]) => match broker.kick(client_id) {
+            true => {
+                info!("administrator disconnected client {client_id}");
+                Response::text(204, "")
+            }
+            false => Response::text(404, "client not connected\n"),
+        },
+        ("GET", ["sessions"]) => json_response(
+            200,
+            broker
+                .sessions()
+                .iter()
+                .map(session_json)
+                .collect::<Value>(),
+        ),
+        ("GET", ["sessions", client_id]) => match broker.session(client_id) {
+            Some(session) => json_response(200, session_json(&session)),
+            None => Response::text(404, "no such session\n"),
+        },
+        ("DELETE", ["sessions", client_id]) => match broker.remove_session(client_id) {
+            true => {
+                info!("administrator removed the session of client {client_id}");
+                Response::text(204, "")
+            }
+            false => Response::text(404, "no such session\n"),
+        },
+        ("GET", ["retained"]) => json_response(
+            200,
+            broker
+                .retained_messages()
+                .iter()
+                .map(retained_json)
+                .collect::<Value>(),
+        ),
+        ("DELETE", ["retained"]) => {
+            let topic_filter = match query_value(request, "topic") {
+                Some(Some(topic_filter)) => topic_filter,
+                Some(None) => return Response::text(400, "malformed query\n"),
+                None => String::from("#"),
+            };
+            let removed = broker.clear_retained(&topic_filter);
+            info!("administrator cleared {removed} retained messages matching {topic_filter}");
+            json_response(200, json!({ "removed": removed }))
+        }
+        (_, ["acl", ..]) => match &broker.acl {
+            Some(acl) => handle_acl(acl, request, &segments[1..]),
+            None => Response::text(409, "no ACL configured\n"),
+        },
+        (_, ["users", ..]) => match &config.users {
+            Some(users) => handle_users(users, request, &segments[1..]),
+            None => Response::text(409, "no password file configured\n"),
+        },
+        ("POST", ["reload"]) => reload(broker, config),
+        ("GET", ["maintenance"]) => json_response(
+            200,
+            match broker.maintenance() {
+                Some(redirect) => redirect_json(&redirect),
+                None => Value::Null,
+            },
+        ),
+        ("PUT", ["maintenance"]) => {
+            let body: Value = match serde_json::from_slice(&request.body) {
+                Ok(body) => body,
+                Err(e) => return Response::text(400, &format!("{e}\n")),
+            };
+            let server_reference = match body["server_reference"].as_str() {
+                Some(server_reference) => server_reference.to_string(),
+                None => return Response::text(400, "server_reference is required\n"),
+            };
+            let redirect = Redirect {
+                server_reference,
+                permanent: body["permanent"].as_bool().unwrap_or(false),
+            };
+            info!(
+                "administrator redirected clients to {}",
+                redirect.server_reference
+            );
+            broker.enter_maintenance(redirect);
+            Response::text(204, "")
+        }
+        ("DELETE", ["maintenance"]) => {
+            info!("administrator ended the maintenance");
+            broker.leave_maintenance();
+            Response::text(204, "")
+        }
+        (_, ["clients" | "sessions" | "retained" | "reload" | "maintenance", ..]) => {
+            Response::text(405, "method not allowed\n")
+        }
+        _ => Response::text(404, "not found\n"),
+    }
+}
+
+fn handle_acl(acl: &Acl, request: &Request, segments: &[&str]) -> Response {
+    match (request.method.as_str(), segments) {
+        ("GET", []) => json_response(
+            200,
+            acl.rules()
+                .iter()
+                .map(|rule| rule.to_string())
+                .collect::<Value>(),
+        ),
+        ("POST", []) => {
+            let rules = match std::str::from_utf8(&request.body)
+                .map_err(|e| e.to_string())
+                .and_then(|body| Acl::parse(body).map_err(|e| e.to_string()))
+            {
+                Ok(rules) => rules,
+                Err(e) => return Response::text(400, &format!("{e}\n")),
+            };
+            for rule in &rules {
+                info!("administrator added ACL rule {rule}");
+            }
+            acl.add_rules(rules);
+            Response::text(201, "")
+        }
+        ("DELETE", [index]) => match index.parse().ok().and_then(|i| acl.remove_rule(i)) {
+            Some(rule) => {
+                info!("administrator removed ACL rule {rule}");
+                Response::text(204, "")
+            }
+            None => Response::text(404, "no such rule\n"),
+        },
+        _ => Response::text(405, "method not allowed\n"),
+    }
+}
+
+fn handle_users(users: &PasswordFile, request: &Request, segments: &[&str]) -> Response {
+    match (request.method.as_str(), segments) {
+        ("GET", []) => json_response(200, json!(users.users())),
+        ("POST", []) => {
+            let body: Value = match serde_json::from_slice(&request.body) {
+                Ok(body) => body,
+                Err(e) => return Response::text(400, &format!("{e}\n")),
+            };
+            let (username, password) = match (body["username"].as_str(), body["password"].as_str())
+            {
+                (Some(username), Some(password)) => (username, password),
+                _ => return Response::text(400, "username and password are required\n"),
+            };
+            match users.set_password(username, password.as_bytes()) {
+                Ok(()) => {
+                    info!("administrator set the password of user {username}");
+                    Response::text(201, "")
+                }
+                Err(e) => Response::text(400, &format!("{e}\n")),
+            }
+        }
+        ("DELETE", [username]) => match users.remove_user(username) {
+            true => {
+                info!("administrator removed user {username}");
+                Response::text(204, "")
+            }
+            false => Response::text(404, "no such user\n"),
+        },
+        _ => Response::text(405, "method not allowed\n"),
+    }
+}
+
+/// Reloads every file, keeping the previous configuration of the ones that fail.
+fn reload(broker: &Broker, config: &AdminConfig) -> Response {
+    let mut errors = vec![];
+    if let Some(Err(e)) = broker.acl.as_ref().map(|acl| acl.reload()) {
+        errors.push(e.to_string());
+    }
+    if let Some(Err(e)) = broker.rules.as_ref().map(|rules| rules.reload()) {
+        errors.push(e.to_string());
+    }
+    if let Some(Err(e)) = config.users.as_ref().map(|users| users.reload()) {
+        errors.push(e.to_string());
+    }
+    for tls in &config.tls {
+        if let Err(e) = tls.reload() {
+            errors.push(e.to_string());
+        }
+    }
+
+    match errors.is_empty() {
+        true => {
+            info!("administrator reloaded the configuration");
+            Response::text(204, "")
+        }
+        false => json_response(500, json!({ "errors": errors })),
+    }
+}
+
+fn json_response(status: u16, value: Value) -> Response {
+    Response::new(status, "application/json", value.to_string())
+}
+
+fn client_json(client: &ClientInfo) -> Value {
+    json!({
+        "client_id": client.client_id,
+        "address": client.peer_addr.map(|addr| addr.to_string()),
+        "listener": client.listener,
+        "username": client.username,
+        "protocol_version": client.protocol_version,
+        "keep_alive": client.keep_alive,
+        "subscriptions": client.subscriptions,
+        "queued": client.queued,
+    })
+}
+
+fn session_json(session: &SessionInfo) -> Value {
+    let subscriptions: Vec<Value> = session
+        .subscriptions
+        .iter()
+        .map(|s| json!({ "topic_filter": s.topic_filter, "qos": s.qos() }))
+        .collect();
+    json!({
+        "client_id": session.client_id,
+        "connected": session.connected,
+        "expiry_interval": session.expiry_interval,
+        "disconnected_at": session.disconnected_at,
+        "subscriptions": subscriptions,
+        "queued": session.queued,
+        "queued_bytes": session.queued_bytes,
+        "in_flight": session.in_flight,
+        "dropped": session.dropped,
+    })
+}
+
+fn redirect_json(redirect: &Redirect) -> Value {
+    json!({
+        "server_reference": redirect.server_reference,
+        "permanent": redirect.permanent,
+    })
+}
+
+fn retained_json(publish: &Publish) -> Value {
+    let payload = publish.application_message.as_deref().unwrap_or_default();
+    let mut object = json!({ "topic": publish.topic_name, "qos": publish.qos_number() });
+    match std::str::from_utf8(payload) {
+        Ok(payload) => object["payload"] = json!(payload),
+        Err(_) => object["payload_hex"] = json!(simple_hex(&payload)),
+    }
+    object
+}
+
+/// None when the query does not hold the parameter, Some(None) when its value is malformed.
+fn query_value(request: &Request, name: &str) -> Option<Option<String>> {
+    request
+        .query
+        .as_deref()?
+        .split('&')
+        .find_map(|pair| match pair.split_once('=') {
+            Some((key, value)) if key == name => Some(percent_decode(value)),
+            _ => None,
+        })
+}
+
+/// Decodes `%XX` escapes, None when one is malformed or the result is not UTF-8.
+fn percent_decode(text: &str) -> Option<String> {
+    let mut decoded = Vec::with_capacity(text.len());
+    let mut bytes = text.bytes();
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'%' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                decoded.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+            }
+            byte => decoded.push(byte),
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+#[cfg(test)]
+pub mod test {
+    use crate::acl::{Access, Acl, AclRule, Principal};
+    use crate::admin::{percent_decode, AdminConfig};
+    use crate::auth::password::PasswordFile;
+    use crate::broker::Broker;
+    use crate::connection::Connection;
+    use crate::proxy::ProxyProtocol;
+    use deser::packets::connect::Connect;
+    use deser::packets::publish::Publish;
+    use deser::packets::reason_codes::DISCONNECT;
+    use deser::packets::subscribe::{
+        Subscribe, SubscriptionOptions, TopicFilterAndSubscriptionOptions,
+    };
+    use deser::primitive_types::FourByteInteger;
+    use deser::properties::Property;
+    use deser::ControlPacket;
+    use serde_json::{json, Value};
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[test]
+    pub fn should_percent_decode() {
+        assert_eq!(Some(String::from("a/#")), percent_decode("a%2F%23"));
+        assert_eq!(None, percent_decode("a%2"));
+        assert_eq!(None, percent_decode("%zz"));
+    }
+
+    async fn connect(addr: SocketAddr, client_id: &str) -> Connection<TcpStream> {
+        let mut client = Connection::new(TcpStream::connect(addr).await.unwrap());
+        let connect = Connect {
+            client_id: String::from(client_id),
+            keep_alive: 30,
+            ..Default::default()
+        };
+        client
+            .write_packet(&ControlPacket::Connect(connect))
+            .await
+            .unwrap();
+        client.read_packet().await.unwrap();
+        client
+    }
+
+    /// Status code and body of the response.
+    async fn request(addr: SocketAddr, method: &str, path: &str, body: &str) -> (u16, String) {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!(
+            "{method} {path} HTTP/1.1\r\nAuthorization: Bearer secret\r\n\
+             Content-Length: {}\r\n\r\n{body}",
+            body.len()
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+
+        let status = response[9..12].parse().unwrap();
+        let (_, body) = response.split_once("\r\n\r\n").unwrap();
+        (status, body.to_string())
+    }
+
+    async fn get(addr: SocketAddr, path: &str) -> Value {
+        let (status, body) = request(addr, "GET", path, "").await;
+        assert_eq!(200, status, "{body}");
+        serde_json::from_str(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn should_manage_broker_over_http() {
+        let acl = Arc::new(Acl::new(vec![AclRule::allow(
+            Principal::Anyone,
+            Access::Both,
+            "#",
+        )]));
+        let users = Arc::new(PasswordFile::default());
+        let broker = Broker::builder().acl(acl.clone()).build();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mqtt_addr = listener.local_addr().unwrap();
+        tokio::spawn(broker.clone().serve(listener, ProxyProtocol::Reject));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let admin_addr = listener.local_addr().unwrap();
+        let config = AdminConfig::new("secret").users(users.clone());
+        tokio::spawn(broker.clone().serve_admin(listener, config));
+
+        let mut stream = TcpStream::connect(admin_addr).await.unwrap();
+        stream
+            .write_all(b"GET /clients HTTP/1.1\r\nAuthorization: Bearer guess\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+
+        let mut subscriber = connect(mqtt_addr, "sub").await;
+        let subscribe = Subscribe {
+            packet_id: 1,
+            topic_filters: vec![TopicFilterAndSubscriptionOptions::new(
+                String::from("a/#"),
+                SubscriptionOptions { raw_value: 1 },
+            )],
+            ..Default::default()
+        };
+        subscriber
+            .write_packet(&ControlPacket::Subscribe(subscribe))
+            .await
+            .unwrap();
+        subscriber.read_packet().await.unwrap();
+
+        let clients = get(admin_addr, "/clients").await;
+        assert_eq!("sub", clients[0]["client_id"]);
+        assert_eq!("tcp", clients[0]["listener"]);
+        assert_eq!(5, clients[0]["protocol_version"]);
+        assert_eq!(30, clients[0]["keep_alive"]);
+        assert_eq!(json!(["a/#"]), clients[0]["subscriptions"]);
+
+        let mut publisher = connect(mqtt_addr, "pub").await;
+        publisher
+            .write_packet(&ControlPacket::Publish(Publish {
+                packet_type_low_nibble: 0b0001,
+                topic_name: String::from("a/b"),
+                application_message: Some(b"on".to_vec()),
+                ..Default::default()
+            }))
+            .await
+            .unwrap();
+        subscriber.read_packet().await.unwrap();
+        assert_eq!(
+            json!([{ "topic": "a/b", "qos": 0, "payload": "on" }]),
+            get(admin_addr, "/retained").await
+        );
+        assert_eq!(
+            (200, String::from("{\"removed\":1}")),
+            request(admin_addr, "DELETE", "/retained?topic=a%2F%23", "").await
+        );
+
+        assert_eq!(
+            204,
+            request(admin_addr, "DELETE", "/clients/sub", "").await.0
+        );
+        match subscriber.read_packet().await.unwrap() {
+            Some(ControlPacket::Disconnect(disconnect)) => {
+                assert_eq!(DISCONNECT::AdministrativeAction, disconnect.reason_code)
+            }
+            packet => panic!("expected DISCONNECT, received {packet:?}"),
+        }
+        assert_eq!(404, request(admin_addr, "GET", "/sessions/sub", "").await.0);
+        assert_eq!(
+            1,
+            get(admin_addr, "/sessions").await.as_array().unwrap().len()
+        );
+        assert_eq!(
+            204,
+            request(admin_addr, "DELETE", "/sessions/pub", "").await.0
+        );
+        assert!(matches!(
+            publisher.read_packet().await.unwrap(),
+            Some(ControlPacket::Disconnect(_))
+        ));
+
+        assert_eq!(
+            201,
+            request(admin_addr, "POST", "/acl", "deny publish user bob a/#\n")
+                .await
+                .0
+        );
+        assert_eq!(
+            json!(["allow both any #", "deny publish user bob a/#"]),
+            get(admin_addr, "/acl").await
+        );
+        assert!(!acl.check("ID", Some("bob"), None, Access::Publish, "a/b"));
+        assert_eq!(204, request(admin_addr, "DELETE", "/acl/1", "").await.0);
+        assert_eq!(404, request(admin_addr, "DELETE", "/acl/1", "").await.0);
+        assert_eq!(
+            400,
+            request(admin_addr, "POST", "/acl", "allow everything")
+                .await
+                .0
+        );
+
+        let user = r#"{"username": "bob", "password": "hunter2"}"#;
+        assert_eq!(201, request(admin_addr, "POST", "/users", user).await.0);
+        assert_eq!(json!(["bob"]), get(admin_addr, "/users").await);
+        assert_eq!(204, request(admin_addr, "DELETE", "/users/bob", "").await.0);
+        assert!(users.users().is_empty());
+
+        assert_eq!(204, request(admin_addr, "POST", "/reload", "").await.0);
+
+        let redirect = r#"{"server_reference": "standby:1883"}"#;
+        assert_eq!(
+            204,
+            request(admin_addr, "PUT", "/maintenance", redirect).await.0
+        );
+        assert_eq!(
+            json!({ "server_reference": "standby:1883", "permanent": false }),
+            get(admin_addr, "/maintenance").await
+        );
+        assert_eq!(
+            204,
+            request(admin_addr, "DELETE", "/maintenance", "").await.0
+        );
+        assert!(broker.maintenance().is_none());
+        assert_eq!(405, request(admin_addr, "PUT", "/clients", "").await.0);
+    }
+
+    #[tokio::test]
+    async fn should_unregister_client_when_connack_cannot_be_sent() {
+        let broker = Broker::builder().build();
+        let (client, server) = tokio::io::duplex(4096);
+        let mut client = Connection::new(client);
+        client
+            .write_packet(&ControlPacket::Connect(Connect {
+                client_id: String::from("ID"),
+                variable_header_properties: Some(vec![Property::SessionExpiryInterval(
+                    FourByteInteger(60),
+                )]),
+                ..Default::default()
+            }))
+            .await
+            .unwrap();
+        // gone before the CONNACK
+        drop(client);
+
+        assert!(broker.clone().handle(server).await.is_err());
+        assert!(broker.clients().is_empty());
+        let sessions = broker.sessions();
+        assert_eq!(1, sessions.len());
+        assert!(!sessions[0].connected);
+    }
+}
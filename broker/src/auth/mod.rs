@@ -0,0 +1,103 @@
+pub mod password;
+pub mod scram;
+
+use deser::primitive_types::{BinaryData, Utf8EncodedString};
+use deser::properties::Property;
+use std::collections::HashMap;
+use std::sync::Arc;
+use thiserror::Error;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum AuthError {
+    #[error("Authentication method {0} is not supported")]
+    UnsupportedMethod(String),
+    #[error("Malformed authentication data: {0}")]
+    MalformedData(String),
+    #[error("Unknown user {0}")]
+    UnknownUser(String),
+    #[error("Authentication failed for user {0}")]
+    Failed(String),
+    #[error("Client {0} is not authorized to connect")]
+    NotAuthorized(String),
+}
+
+/// Result of feeding the client's Authentication Data to an exchange.
+#[derive(Debug, PartialEq, Eq)]
+pub enum AuthStep {
+    /// Another round trip is needed. The data is sent to the client in an AUTH packet with
+    /// reason code 0x18 Continue authentication.
+    Continue(Vec<u8>),
+    /// The client is authenticated. The data, if any, is sent in the CONNACK, or in the AUTH
+    /// Success packet when re-authenticating.
+    Success(Option<Vec<u8>>),
+}
+
+/// A SASL style authentication mechanism, selected by the client with the Authentication Method
+/// property of the CONNECT packet.
+pub trait Authenticator: Send + Sync {
+    /// Authentication Method name, e.g. SCRAM-SHA-256
+    fn method(&self) -> &str;
+
+    /// Starts a new exchange. Called for every CONNECT and every re-authentication.
+    fn start(&self) -> Box<dyn AuthExchange>;
+}
+
+/// State of a single, possibly multi step, authentication exchange. A re-authentication is
+/// kept by its session between the packets of the client.
+pub trait AuthExchange: Send + Sync {
+    fn step(&mut self, data: Option<&[u8]>) -> Result<AuthStep, AuthError>;
+
+    /// Identity established by a successful exchange. Without one, the client has no user name
+    /// unless its TLS certificate gives one.
+    fn identity(&self) -> Option<String> {
+        None
+    }
+}
+
+/// Authenticators known to the broker, keyed by Authentication Method.
+#[derive(Default, Clone)]
+pub struct Authenticators {
+    methods: HashMap<String, Arc<dyn Authenticator>>,
+}
+
+impl Authenticators {
+    pub fn register(&mut self, authenticator: Arc<dyn Authenticator>) {
+        self.methods
+            .insert(authenticator.method().to_string(), authenticator);
+    }
+
+    pub fn get(&self, method: &str) -> Option<Arc<dyn Authenticator>> {
+        self.methods.get(method).cloned()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.methods.is_empty()
+    }
+}
+
+pub fn authentication_method(properties: &Option<Vec<Property>>) -> Option<String> {
+    properties.iter().flatten().find_map(|p| match p {
+        Property::AuthenticationMethod(Utf8EncodedString(method)) => Some(method.clone()),
+        _ => None,
+    })
+}
+
+pub fn authentication_data(properties: &Option<Vec<Property>>) -> Option<Vec<u8>> {
+    properties.iter().flatten().find_map(|p| match p {
+        Property::AuthenticationData(BinaryData(data)) => Some(data.clone()),
+        _ => None,
+    })
+}
+
+/// Properties carrying the method and data of an exchange back to the client.
+pub fn authentication_properties(method: &str, data: Option<Vec<u8>>) -> Vec<Property> {
+    let mut properties = vec![Property::AuthenticationMethod(Utf8EncodedString(
+        method.to_string(),
+    ))];
+
+    if let Some(data) = data {
+        properties.push(Property::AuthenticationData(BinaryData(data)));
+    }
+
+    properties
+}
@@ -0,0 +1,449 @@
+//! User name and password authentication of the CONNECT packet.
+//!
+//! Backends are consulted in the order they were registered with the broker. The first backend
+//! that does not defer decides the outcome. A client that every backend defers on is refused with
+//! CONNACK 0x87 Not authorized.
+
+use crate::connection::PeerCredentials;
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::SaltString;
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, OnceLock, RwLock};
+use thiserror::Error;
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+#[derive(Error, Debug)]
+pub enum PasswordFileError {
+    #[error("Reading password file {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Line {0} of the password file is not of the form username:hash")]
+    MalformedLine(usize),
+    #[error("Line {0} of the password file does not hold an argon2 or bcrypt hash")]
+    UnsupportedHash(usize),
+    #[error("User name {0:?} contains a colon")]
+    InvalidUserName(String),
+}
+
+/// User name and password presented in the CONNECT packet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Credentials {
+    pub client_id: String,
+    pub username: Option<String>,
+    /// Binary Data, not necessarily UTF-8.
+    pub password: Option<Vec<u8>>,
+    /// Address of the client, unset for Unix domain sockets.
+    pub peer_addr: Option<SocketAddr>,
+    /// Set for clients connected over a Unix domain socket.
+    pub peer_credentials: Option<PeerCredentials>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PasswordCheck {
+    Allow,
+    /// Refused with CONNACK 0x86 Bad User Name or Password.
+    BadUserNameOrPassword,
+    /// Refused with CONNACK 0x87 Not authorized.
+    NotAuthorized,
+    /// Leave the decision to the next backend.
+    Defer,
+}
+
+pub trait PasswordBackend: Send + Sync {
+    fn check<'a>(&'a self, credentials: &'a Credentials) -> BoxFuture<'a, PasswordCheck>;
+}
+
+/// Static password file in the spirit of mosquitto_passwd. Each line holds `username:hash`
+/// where the hash is an argon2 PHC string or a bcrypt hash. Blank lines and lines starting with
+/// `#` are ignored.
+///
+/// Users added or removed while the broker runs are kept in memory, reloading the file replaces
+/// them.
+#[derive(Debug, Default)]
+pub struct PasswordFile {
+    path: Option<PathBuf>,
+    hashes: RwLock<HashMap<String, String>>,
+}
+
+impl PasswordFile {
+    pub fn load(path: impl AsRef<Path>) -> Result<PasswordFile, PasswordFileError> {
+        let path = path.as_ref().to_path_buf();
+        let hashes = PasswordFile::parse_hashes(&std::fs::read_to_string(&path)?)?;
+
+        Ok(PasswordFile {
+            path: Some(path),
+            hashes: RwLock::new(hashes),
+        })
+    }
+
+    pub fn parse(contents: &str) -> Result<PasswordFile, PasswordFileError> {
+        Ok(PasswordFile {
+            path: None,
+            hashes: RwLock::new(PasswordFile::parse_hashes(contents)?),
+        })
+    }
+
+    fn parse_hashes(contents: &str) -> Result<HashMap<String, String>, PasswordFileError> {
+        let mut hashes = HashMap::new();
+
+        for (index, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (username, hash) = line
+                .split_once(':')
+                .ok_or(PasswordFileError::MalformedLine(index + 1))?;
+            if !(is_argon2(hash) || is_bcrypt(hash)) {
+                return Err(PasswordFileError::UnsupportedHash(index + 1));
+            }
+
+            hashes.insert(username.to_string(), hash.to_string());
+        }
+
+        Ok(hashes)
+    }
+
+    /// Reads the file again. The current users are kept when the file cannot be parsed.
+    pub fn reload(&self) -> Result<(), PasswordFileError> {
+        if let Some(path) = &self.path {
+            let hashes = PasswordFile::parse_hashes(&std::fs::read_to_string(path)?)?;
+            *self.hashes.write().unwrap() = hashes;
+        }
+        Ok(())
+    }
+
+    /// User names, sorted.
+    pub fn users(&self) -> Vec<String> {
+        let mut users: Vec<String> = self.hashes.read().unwrap().keys().cloned().collect();
+        users.sort();
+        users
+    }
+
+    /// Adds the user, or changes its password, hashing the password with argon2id.
+    pub fn set_password(&self, username: &str, password: &[u8]) -> Result<(), PasswordFileError> {
+        if username.contains(':') {
+            return Err(PasswordFileError::InvalidUserName(username.to_string()));
+        }
+        let entry = PasswordFile::entry(username, password);
+        let (_, hash) = entry.split_at(username.len() + 1);
+        self.hashes
+            .write()
+            .unwrap()
+            .insert(username.to_string(), hash.to_string());
+        Ok(())
+    }
+
+    /// Returns whether the user existed.
+    pub fn remove_user(&self, username: &str) -> bool {
+        self.hashes.write().unwrap().remove(username).is_some()
+    }
+
+    /// Line for the password file, hashing the password with argon2id.
+    pub fn entry(username: &str, password: &[u8]) -> String {
+        let salt = SaltString::generate(&mut OsRng);
+        let hash = Argon2::default()
+            .hash_password(password, &salt)
+            .expect("argon2 accepts passwords of any length");
+        format!("{username}:{hash}")
+    }
+
+    /// Unknown users are checked against a dummy hash, so that refusing them takes as long as
+    /// refusing a wrong password and does not tell which users exist.
+    async fn verify(&self, username: &str, password: &[u8]) -> bool {
+        let hash = self.hashes.read().unwrap().get(username).cloned();
+        // hashing is slow, it runs on the blocking thread pool without holding the lock
+        let password = password.to_vec();
+        tokio::task::spawn_blocking(move || match hash {
+            Some(hash) => verify_hash(&hash, &password),
+            None => {
+                verify_hash(dummy_hash(), &password);
+                false
+            }
+        })
+        .await
+        .unwrap_or(false)
+    }
+}
+
+/// Argon2 hash of the password `dummy`, computed once.
+fn dummy_hash() -> &'static str {
+    static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+    DUMMY_HASH.get_or_init(|| PasswordFile::entry("", b"dummy")[1..].to_string())
+}
+
+fn verify_hash(hash: &str, password: &[u8]) -> bool {
+    if is_argon2(hash) {
+        PasswordHash::new(hash)
+            .map(|hash| Argon2::default().verify_password(password, &hash).is_ok())
+            .unwrap_or(false)
+    } else {
+        bcrypt::verify(password, hash).unwrap_or(false)
+    }
+}
+
+impl PasswordBackend for PasswordFile {
+    fn check<'a>(&'a self, credentials: &'a Credentials) -> BoxFuture<'a, PasswordCheck> {
+        Box::pin(async move {
+            let username = match &credentials.username {
+                Some(username) => username,
+                None => return PasswordCheck::Defer,
+            };
+            let password = credentials.password.as_deref().unwrap_or_default();
+
+            if self.verify(username, password).await {
+                PasswordCheck::Allow
+            } else {
+                PasswordCheck::BadUserNameOrPassword
+            }
+        })
+    }
+}
+
+fn is_argon2(hash: &str) -> bool {
+    hash.starts_with("$argon2")
+}
+
+fn is_bcrypt(hash: &str) -> bool {
+    ["$2a$", "$2b$", "$2x$", "$2y$"]
+        .iter()
+        .any(|prefix| hash.starts_with(prefix))
+}
+
+/// Decides on clients that connect without a user name, deferring on all others.
+#[derive(Debug, Clone)]
+pub struct AllowAnonymous(pub bool);
+
+impl PasswordBackend for AllowAnonymous {
+    fn check<'a>(&'a self, credentials: &'a Credentials) -> BoxFuture<'a, PasswordCheck> {
+        Box::pin(async move {
+            match (&credentials.username, self.0) {
+                (Some(_), _) => PasswordCheck::Defer,
+                (None, true) => PasswordCheck::Allow,
+                (None, false) => PasswordCheck::NotAuthorized,
+            }
+        })
+    }
+}
+
+/// Allows local processes running as one of the users, whatever the password, when they connect
+/// without a user name or with the name of their user in /etc/passwd. Defers on all other
+/// clients, so that a process claiming another user name has to prove it to the next backends.
+#[derive(Debug, Clone)]
+pub struct AllowPeerUids(pub Vec<u32>);
+
+impl PasswordBackend for AllowPeerUids {
+    fn check<'a>(&'a self, credentials: &'a Credentials) -> BoxFuture<'a, PasswordCheck> {
+        Box::pin(async move {
+            let Some(peer) = credentials.peer_credentials else {
+                return PasswordCheck::Defer;
+            };
+            if !self.0.contains(&peer.uid) {
+                return PasswordCheck::Defer;
+            }
+            match &credentials.username {
+                None => PasswordCheck::Allow,
+                Some(username) if passwd_name(peer.uid).await.as_ref() == Some(username) => {
+                    PasswordCheck::Allow
+                }
+                Some(_) => PasswordCheck::Defer,
+            }
+        })
+    }
+}
+
+/// Name of the user in /etc/passwd.
+pub(crate) async fn passwd_name(uid: u32) -> Option<String> {
+    let passwd = tokio::fs::read_to_string("/etc/passwd").await.ok()?;
+    let uid = uid.to_string();
+    passwd.lines().find_map(|line| {
+        let mut fields = line.split(':');
+        let name = fields.next()?;
+        (fields.nth(1)? == uid).then(|| name.to_string())
+    })
+}
+
+/// Hands the credentials to a user supplied async closure.
+pub struct AuthFn<F> {
+    check: F,
+}
+
+impl<F, Fut> AuthFn<F>
+where
+    F: Fn(Credentials) -> Fut + Send + Sync,
+    Fut: Future<Output = PasswordCheck> + Send + 'static,
+{
+    pub fn new(check: F) -> Arc<AuthFn<F>> {
+        Arc::new(AuthFn { check })
+    }
+}
+
+impl<F, Fut> PasswordBackend for AuthFn<F>
+where
+    F: Fn(Credentials) -> Fut + Send + Sync,
+    Fut: Future<Output = PasswordCheck> + Send + 'static,
+{
+    fn check<'a>(&'a self, credentials: &'a Credentials) -> BoxFuture<'a, PasswordCheck> {
+        Box::pin((self.check)(credentials.clone()))
+    }
+}
+
+/// Runs the backends in order, returning the first decision that is not a deferral.
+pub async fn check_credentials(
+    backends: &[Arc<dyn PasswordBackend>],
+    credentials: &Credentials,
+) -> PasswordCheck {
+    for backend in backends {
+        match backend.check(credentials).await {
+            PasswordCheck::Defer => continue,
+            decision => return decision,
+        }
+    }
+
+    PasswordCheck::NotAuthorized
+}
+
+#[cfg(test)]
+pub mod test {
+    use crate::auth::password::{
+        check_credentials, AllowAnonymous, AuthFn, Credentials, PasswordBackend, PasswordCheck,
+        PasswordFile,
+    };
+    use argon2::password_hash::SaltString;
+    use argon2::{Algorithm, Argon2, Params, PasswordHasher, Version};
+    use std::sync::Arc;
+
+    fn credentials(username: Option<&str>, password: Option<&str>) -> Credentials {
+        Credentials {
+            client_id: String::from("ID"),
+            username: username.map(String::from),
+            password: password.map(|p| p.as_bytes().to_vec()),
+            peer_addr: None,
+            peer_credentials: None,
+        }
+    }
+
+    fn password_file() -> PasswordFile {
+        let argon2 = Argon2::new(
+            Algorithm::Argon2id,
+            Version::V0x13,
+            Params::new(1024, 1, 1, None).unwrap(),
+        );
+        let salt = SaltString::from_b64("c29tZXNhbHQ").unwrap();
+        let alice = argon2.hash_password(b"secret", &salt).unwrap();
+        let bob = bcrypt::hash("hunter2", 4).unwrap();
+
+        PasswordFile::parse(&format!("# users\nalice:{alice}\n\nbob:{bob}\n")).unwrap()
+    }
+
+    #[tokio::test]
+    async fn should_check_argon2_and_bcrypt_hashes() {
+        let file = password_file();
+
+        assert_eq!(
+            PasswordCheck::Allow,
+            file.check(&credentials(Some("alice"), Some("secret")))
+                .await
+        );
+        assert_eq!(
+            PasswordCheck::Allow,
+            file.check(&credentials(Some("bob"), Some("hunter2"))).await
+        );
+        assert_eq!(
+            PasswordCheck::BadUserNameOrPassword,
+            file.check(&credentials(Some("bob"), Some("secret"))).await
+        );
+        assert_eq!(
+            PasswordCheck::BadUserNameOrPassword,
+            file.check(&credentials(Some("carol"), Some("secret")))
+                .await
+        );
+        assert_eq!(
+            PasswordCheck::BadUserNameOrPassword,
+            file.check(&credentials(Some("carol"), Some("dummy"))).await
+        );
+    }
+
+    #[tokio::test]
+    async fn should_check_non_utf8_password() {
+        let password = vec![0xde, 0xad, 0xbe, 0xef, 0xff];
+        let file = PasswordFile::parse(&PasswordFile::entry("device", &password)).unwrap();
+
+        let credentials = Credentials {
+            client_id: String::from("ID"),
+            username: Some(String::from("device")),
+            password: Some(password),
+            peer_addr: None,
+            peer_credentials: None,
+        };
+        assert_eq!(PasswordCheck::Allow, file.check(&credentials).await);
+    }
+
+    #[test]
+    fn should_reject_unsupported_hash() {
+        assert!(PasswordFile::parse("alice:plaintext").is_err());
+    }
+
+    #[tokio::test]
+    async fn should_add_and_remove_users_at_runtime() {
+        let file = password_file();
+        file.set_password("carol", b"pa:ss").unwrap();
+        assert_eq!(vec!["alice", "bob", "carol"], file.users());
+        assert_eq!(
+            PasswordCheck::Allow,
+            file.check(&credentials(Some("carol"), Some("pa:ss"))).await
+        );
+
+        assert!(file.remove_user("alice"));
+        assert!(!file.remove_user("alice"));
+        assert_eq!(
+            PasswordCheck::BadUserNameOrPassword,
+            file.check(&credentials(Some("alice"), Some("secret")))
+                .await
+        );
+        assert!(file.set_password("dave:x", b"secret").is_err());
+    }
+
+    #[tokio::test]
+    async fn should_use_first_backend_that_does_not_defer() {
+        let backends: Vec<Arc<dyn PasswordBackend>> =
+            vec![Arc::new(AllowAnonymous(true)), Arc::new(password_file())];
+
+        assert_eq!(
+            PasswordCheck::Allow,
+            check_credentials(&backends, &credentials(None, None)).await
+        );
+        assert_eq!(
+            PasswordCheck::BadUserNameOrPassword,
+            check_credentials(&backends, &credentials(Some("alice"), Some("wrong"))).await
+        );
+
+        let backends: Vec<Arc<dyn PasswordBackend>> = vec![Arc::new(password_file())];
+        assert_eq!(
+            PasswordCheck::NotAuthorized,
+            check_credentials(&backends, &credentials(None, None)).await
+        );
+    }
+
+    #[tokio::test]
+    async fn should_call_async_closure() {
+        let backend = AuthFn::new(|credentials: Credentials| async move {
+            if credentials.client_id == "ID" {
+                PasswordCheck::Allow
+            } else {
+                PasswordCheck::NotAuthorized
+            }
+        });
+
+        assert_eq!(
+            PasswordCheck::Allow,
+            backend.check(&credentials(None, None)).await
+        );
+    }
+}
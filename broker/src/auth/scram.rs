@@ -0,0 +1,453 @@
+//! SCRAM-SHA-256 (RFC 5802, RFC 7677) carried in the Authentication Data of the CONNECT, AUTH and
+//! CONNACK packets.
+//!
+//! CONNECT   client-first-message
+//! AUTH 0x18 server-first-message
+//! AUTH 0x18 client-final-message
+//! CONNACK   server-final-message
+//!
+//! Unknown users get a server-first-message as well and fail with the client-final-message, like
+//! a wrong password, so that the exchange does not tell which users exist (RFC 5802 section 5.1).
+
+use crate::auth::{AuthError, AuthExchange, AuthStep, Authenticator};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng, RngCore};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+pub const SCRAM_SHA_256: &str = "SCRAM-SHA-256";
+pub const DEFAULT_ITERATIONS: u32 = 4096;
+
+// base64 of the gs2 header "n,,", no channel binding
+const CHANNEL_BINDING: &str = "biws";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScramCredentials {
+    pub salt: Vec<u8>,
+    pub iterations: u32,
+    pub stored_key: Vec<u8>,
+    pub server_key: Vec<u8>,
+}
+
+impl ScramCredentials {
+    pub fn new(password: &[u8], salt: Vec<u8>, iterations: u32) -> ScramCredentials {
+        let salted_password = salted_password(password, &salt, iterations);
+        let client_key = hmac(&salted_password, b"Client Key");
+
+        ScramCredentials {
+            stored_key: Sha256::digest(client_key).to_vec(),
+            server_key: hmac(&salted_password, b"Server Key"),
+            salt,
+            iterations,
+        }
+    }
+}
+
+/// Server side SCRAM-SHA-256 mechanism.
+#[derive(Clone)]
+pub struct ScramSha256 {
+    users: Arc<HashMap<String, ScramCredentials>>,
+    /// Derives the salts of unknown users.
+    mock_key: [u8; 32],
+}
+
+impl Default for ScramSha256 {
+    fn default() -> Self {
+        let mut mock_key = [0u8; 32];
+        thread_rng().fill_bytes(&mut mock_key);
+        ScramSha256 {
+            users: Default::default(),
+            mock_key,
+        }
+    }
+}
+
+impl ScramSha256 {
+    pub fn new() -> ScramSha256 {
+        Default::default()
+    }
+
+    /// Adds a user with a random salt and the default iteration count.
+    pub fn add_user(self, username: &str, password: &[u8]) -> Self {
+        let mut salt = vec![0u8; 16];
+        thread_rng().fill_bytes(&mut salt);
+        self.add_credentials(
+            username,
+            ScramCredentials::new(password, salt, DEFAULT_ITERATIONS),
+        )
+    }
+
+    pub fn add_credentials(mut self, username: &str, credentials: ScramCredentials) -> Self {
+        Arc::make_mut(&mut self.users).insert(username.to_string(), credentials);
+        self
+    }
+}
+
+impl Authenticator for ScramSha256 {
+    fn method(&self) -> &str {
+        SCRAM_SHA_256
+    }
+
+    fn start(&self) -> Box<dyn AuthExchange> {
+        Box::new(ScramExchange {
+            users: self.users.clone(),
+            mock_key: self.mock_key,
+            state: ScramState::Initial,
+            username: None,
+        })
+    }
+}
+
+enum ScramState {
+    Initial,
+    ServerFirstSent {
+        client_first_bare: String,
+        server_first: String,
+        nonce: String,
+        credentials: ScramCredentials,
+    },
+    Done,
+}
+
+struct ScramExchange {
+    users: Arc<HashMap<String, ScramCredentials>>,
+    mock_key: [u8; 32],
+    state: ScramState,
+    username: Option<String>,
+}
+
+impl ScramExchange {
+    fn client_first(&mut self, data: &[u8]) -> Result<AuthStep, AuthError> {
+        let message = utf8(data)?;
+        let client_first_bare = message
+            .strip_prefix("n,,")
+            .ok_or_else(|| malformed("channel binding is not supported"))?;
+
+        let attributes = attributes(client_first_bare);
+        let username = unescape_username(
+            attributes
+                .get("n")
+                .ok_or_else(|| malformed("username missing"))?,
+        );
+        let client_nonce = attributes
+            .get("r")
+            .ok_or_else(|| malformed("nonce missing"))?;
+
+        let credentials = match self.users.get(&username) {
+            Some(credentials) => credentials.clone(),
+            None => self.mock_credentials(&username),
+        };
+
+        let nonce = format!("{client_nonce}{}", nonce());
+        let server_first = format!(
+            "r={nonce},s={},i={}",
+            STANDARD.encode(&credentials.salt),
+            credentials.iterations
+        );
+
+        self.username = Some(username);
+        self.state = ScramState::ServerFirstSent {
+            client_first_bare: client_first_bare.to_string(),
+            server_first: server_first.clone(),
+            nonce,
+            credentials,
+        };
+
+        Ok(AuthStep::Continue(server_first.into_bytes()))
+    }
+
+    /// Credentials of a user that does not exist, with the same salt on every attempt and keys
+    /// that match no proof.
+    fn mock_credentials(&self, username: &str) -> ScramCredentials {
+        let mut stored_key = vec![0u8; 32];
+        let mut server_key = vec![0u8; 32];
+        thread_rng().fill_bytes(&mut stored_key);
+        thread_rng().fill_bytes(&mut server_key);
+        ScramCredentials {
+            salt: hmac(&self.mock_key, username.as_bytes())[..16].to_vec(),
+            iterations: DEFAULT_ITERATIONS,
+            stored_key,
+            server_key,
+        }
+    }
+
+    fn client_final(
+        &self,
+        data: &[u8],
+        client_first_bare: &str,
+        server_first: &str,
+        nonce: &str,
+        credentials: &ScramCredentials,
+    ) -> Result<AuthStep, AuthError> {
+        let username = self.username.clone().unwrap_or_default();
+        let message = utf8(data)?;
+        let (client_final_without_proof, proof) = message
+            .rsplit_once(",p=")
+            .ok_or_else(|| malformed("proof missing"))?;
+
+        let attributes = attributes(client_final_without_proof);
+        if attributes.get("c").map(String::as_str) != Some(CHANNEL_BINDING) {
+            return Err(malformed("unexpected channel binding"));
+        }
+        if attributes.get("r").map(String::as_str) != Some(nonce) {
+            return Err(AuthError::Failed(username));
+        }
+
+        let proof = STANDARD
+            .decode(proof)
+            .map_err(|_| malformed("proof is not base64"))?;
+
+        let auth_message =
+            format!("{client_first_bare},{server_first},{client_final_without_proof}");
+        let client_signature = hmac(&credentials.stored_key, auth_message.as_bytes());
+        if proof.len() != client_signature.len() {
+            return Err(AuthError::Failed(username));
+        }
+
+        let client_key = xor(&proof, &client_signature);
+        if !constant_time_eq(&Sha256::digest(client_key), &credentials.stored_key) {
+            return Err(AuthError::Failed(username));
+        }
+
+        let server_signature = hmac(&credentials.server_key, auth_message.as_bytes());
+        let server_final = format!("v={}", STANDARD.encode(server_signature));
+        Ok(AuthStep::Success(Some(server_final.into_bytes())))
+    }
+}
+
+impl AuthExchange for ScramExchange {
+    fn step(&mut self, data: Option<&[u8]>) -> Result<AuthStep, AuthError> {
+        let data = data.ok_or_else(|| malformed("authentication data missing"))?;
+
+        match std::mem::replace(&mut self.state, ScramState::Done) {
+            ScramState::Initial => self.client_first(data),
+            ScramState::ServerFirstSent {
+                client_first_bare,
+                server_first,
+                nonce,
+                credentials,
+            } => self.client_final(
+                data,
+                &client_first_bare,
+                &server_first,
+                &nonce,
+                &credentials,
+            ),
+            ScramState::Done => Err(malformed("exchange already completed")),
+        }
+    }
+
+    fn identity(&self) -> Option<String> {
+        self.username.clone()
+    }
+}
+
+/// Client side of the exchange, used by clients connecting with SCRAM-SHA-256.
+pub struct ScramClient {
+    username: String,
+    password: Vec<u8>,
+    client_nonce: String,
+    client_first_bare: String,
+    server_signature: Option<Vec<u8>>,
+}
+
+impl ScramClient {
+    pub fn new(username: &str, password: &[u8]) -> ScramClient {
+        let client_nonce = nonce();
+        ScramClient {
+            client_first_bare: format!("n={},r={client_nonce}", escape_username(username)),
+            username: username.to_string(),
+            password: password.to_vec(),
+            client_nonce,
+            server_signature: None,
+        }
+    }
+
+    /// Authentication Data for the CONNECT packet.
+    pub fn client_first(&self) -> Vec<u8> {
+        format!("n,,{}", self.client_first_bare).into_bytes()
+    }
+
+    /// Authentication Data answering the server-first-message.
+    pub fn client_final(&mut self, server_first: &[u8]) -> Result<Vec<u8>, AuthError> {
+        let server_first = utf8(server_first)?;
+        let attributes = attributes(server_first);
+
+        let nonce = attributes
+            .get("r")
+            .ok_or_else(|| malformed("nonce missing"))?;
+        if !nonce.starts_with(&self.client_nonce) {
+            return Err(AuthError::Failed(self.username.clone()));
+        }
+        let salt = STANDARD
+            .decode(
+                attributes
+                    .get("s")
+                    .ok_or_else(|| malformed("salt missing"))?,
+            )
+            .map_err(|_| malformed("salt is not base64"))?;
+        let iterations = attributes
+            .get("i")
+            .and_then(|i| i.parse::<u32>().ok())
+            .ok_or_else(|| malformed("iteration count missing"))?;
+
+        let client_final_without_proof = format!("c={CHANNEL_BINDING},r={nonce}");
+        let auth_message = format!(
+            "{},{server_first},{client_final_without_proof}",
+            self.client_first_bare
+        );
+
+        let salted_password = salted_password(&self.password, &salt, iterations);
+        let client_key = hmac(&salted_password, b"Client Key");
+        let stored_key = Sha256::digest(&client_key);
+        let client_signature = hmac(&stored_key, auth_message.as_bytes());
+        let server_key = hmac(&salted_password, b"Server Key");
+        self.server_signature = Some(hmac(&server_key, auth_message.as_bytes()));
+
+        let proof = STANDARD.encode(xor(&client_key, &client_signature));
+        Ok(format!("{client_final_without_proof},p={proof}").into_bytes())
+    }
+
+    /// Checks the server-final-message returned in the CONNACK.
+    pub fn verify_server_final(&self, server_final: &[u8]) -> Result<(), AuthError> {
+        let server_final = utf8(server_final)?;
+        let signature = server_final
+            .strip_prefix("v=")
+            .and_then(|v| STANDARD.decode(v).ok())
+            .ok_or_else(|| malformed("server signature missing"))?;
+
+        match &self.server_signature {
+            Some(expected) if constant_time_eq(expected, &signature) => Ok(()),
+            _ => Err(AuthError::Failed(self.username.clone())),
+        }
+    }
+}
+
+fn salted_password(password: &[u8], salt: &[u8], iterations: u32) -> [u8; 32] {
+    let mut salted = [0u8; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(password, salt, iterations, &mut salted);
+    salted
+}
+
+fn hmac(key: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(message);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn xor(a: &[u8], b: &[u8]) -> Vec<u8> {
+    a.iter().zip(b).map(|(x, y)| x ^ y).collect()
+}
+
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn nonce() -> String {
+    thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(24)
+        .map(char::from)
+        .collect()
+}
+
+fn attributes(message: &str) -> HashMap<String, String> {
+    message
+        .split(',')
+        .filter_map(|attribute| attribute.split_once('='))
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+}
+
+fn escape_username(username: &str) -> String {
+    username.replace('=', "=3D").replace(',', "=2C")
+}
+
+fn unescape_username(username: &str) -> String {
+    username.replace("=2C", ",").replace("=3D", "=")
+}
+
+fn utf8(data: &[u8]) -> Result<&str, AuthError> {
+    std::str::from_utf8(data).map_err(|_| malformed("not valid utf-8"))
+}
+
+fn malformed(reason: &str) -> AuthError {
+    AuthError::MalformedData(reason.to_string())
+}
+
+#[cfg(test)]
+pub mod test {
+    use crate::auth::scram::{ScramClient, ScramSha256};
+    use crate::auth::{AuthError, AuthStep, Authenticator};
+
+    #[test]
+    pub fn should_authenticate_with_correct_password() {
+        let scram = ScramSha256::new().add_user("alice", b"secret");
+        let mut exchange = scram.start();
+        let mut client = ScramClient::new("alice", b"secret");
+
+        let server_first = match exchange.step(Some(&client.client_first())).unwrap() {
+            AuthStep::Continue(data) => data,
+            step => panic!("unexpected step {step:?}"),
+        };
+        let client_final = client.client_final(&server_first).unwrap();
+
+        match exchange.step(Some(&client_final)).unwrap() {
+            AuthStep::Success(Some(server_final)) => {
+                assert_eq!(Ok(()), client.verify_server_final(&server_final))
+            }
+            step => panic!("unexpected step {step:?}"),
+        }
+        assert_eq!(Some(String::from("alice")), exchange.identity());
+    }
+
+    #[test]
+    pub fn should_reject_wrong_password() {
+        let scram = ScramSha256::new().add_user("alice", b"secret");
+        let mut exchange = scram.start();
+        let mut client = ScramClient::new("alice", b"not the secret");
+
+        let server_first = match exchange.step(Some(&client.client_first())).unwrap() {
+            AuthStep::Continue(data) => data,
+            step => panic!("unexpected step {step:?}"),
+        };
+        let client_final = client.client_final(&server_first).unwrap();
+
+        assert_eq!(
+            Err(AuthError::Failed(String::from("alice"))),
+            exchange.step(Some(&client_final))
+        );
+    }
+
+    #[test]
+    pub fn should_reject_unknown_user_like_a_wrong_password() {
+        let scram = ScramSha256::new().add_user("alice", b"secret");
+        let salt = |server_first: &[u8]| {
+            let server_first = String::from_utf8(server_first.to_vec()).unwrap();
+            server_first.split(',').nth(1).unwrap().to_string()
+        };
+
+        let mut first_salt = None;
+        for _ in 0..2 {
+            let mut exchange = scram.start();
+            let mut client = ScramClient::new("bob", b"secret");
+            let server_first = match exchange.step(Some(&client.client_first())).unwrap() {
+                AuthStep::Continue(data) => data,
+                step => panic!("unexpected step {step:?}"),
+            };
+            // the same salt on every attempt, as for a user that exists
+            let salt = salt(&server_first);
+            assert_eq!(&salt, first_salt.get_or_insert_with(|| salt.clone()));
+
+            let client_final = client.client_final(&server_first).unwrap();
+            assert_eq!(
+                Err(AuthError::Failed(String::from("bob"))),
+                exchange.step(Some(&client_final))
+            );
+        }
+    }
+}
@@ -0,0 +1,562 @@
+//! Bridges forwarding topics between the broker and a remote broker.
+//!
+//! A bridge connects to the remote broker with the client of this repository, and to the local
+//! broker through a session of its own, `$bridge/<name>`. Messages going out are queued in that
+//! session, then in the buffer of the client, while the remote broker is unreachable. Both
+//! sessions outlive restarts: with a store, messages queued locally are not lost, and the remote
+//! broker keeps the messages coming in while the bridge is away.
+//!
+//! A mapping forwards the topics matching its pattern under the local prefix to the same topics
+//! under the remote prefix, and back. `TopicMapping::new("sensors/#", Direction::Out)
+//! .prefixes("", "edge-1/")` publishes `sensors/kitchen` on the remote broker as
+//! `edge-1/sensors/kitchen`.
+//!
+//! A message forwarded one way must not come back the other way when both directions overlap.
+//! By default the bridge subscribes with No Local on both brokers, which relies on the remote
+//! broker honouring it. With `LoopPrevention::UserProperty` it marks the messages it forwards
+//! with a `bridge` user property holding its name, and drops the ones carrying it, which also
+//! breaks loops through other brokers.
+
+use crate::broker::Broker;
+use client::{
+    Backoff, BufferOptions, Client, ClientError, ConnectOptions, Message, QoS, Subscription,
+    TlsOptions,
+};
+use deser::packets::publish::Publish;
+use deser::packets::subscribe::{SubscriptionOptions, TopicFilterAndSubscriptionOptions};
+use deser::primitive_types::Utf8StringPair;
+use deser::properties::Property;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+/// Name of the user property marking the messages forwarded by a bridge.
+pub const MARKER: &str = "bridge";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// From the remote broker to the local one.
+    In,
+    /// From the local broker to the remote one.
+    Out,
+    Both,
+}
+
+impl Direction {
+    fn incoming(self) -> bool {
+        matches!(self, Direction::In | Direction::Both)
+    }
+
+    fn outgoing(self) -> bool {
+        matches!(self, Direction::Out | Direction::Both)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LoopPrevention {
+    /// Subscriptions with No Local, so that neither broker sends back what the bridge published.
+    #[default]
+    NoLocal,
+    /// A user property on the forwarded messages, which are dropped when they come back.
+    UserProperty,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TopicMapping {
+    pattern: String,
+    direction: Direction,
+    qos: QoS,
+    local_prefix: String,
+    remote_prefix: String,
+}
+
+impl TopicMapping {
+    /// Forwards the topics matching the pattern, a topic filter, at QoS 0 and without prefixes.
+    pub fn new(pattern: &str, direction: Direction) -> Self {
+        TopicMapping {
+            pattern: pattern.to_string(),
+            direction,
+            qos: QoS::AtMostOnce,
+            local_prefix: String::new(),
+            remote_prefix: String::new(),
+        }
+    }
+
+    /// Maximum QoS of the forwarded messages.
+    pub fn qos(mut self, qos: QoS) -> Self {
+        self.qos = qos;
+        self
+    }
+
+    /// Prefixes of the topics on the local and the remote broker, usually ending with `/`.
+    pub fn prefixes(mut self, local_prefix: &str, remote_prefix: &str) -> Self {
+        self.local_prefix = local_prefix.to_string();
+        self.remote_prefix = remote_prefix.to_string();
+        self
+    }
+
+    fn local_filter(&self) -> String {
+        format!("{}{}", self.local_prefix, self.pattern)
+    }
+
+    fn remote_filter(&self) -> String {
+        format!("{}{}", self.remote_prefix, self.pattern)
+    }
+
+    /// Remote topic of a local message going out.
+    fn to_remote(&self, local_topic: &str) -> Option<String> {
+        let topic = local_topic.strip_prefix(&self.local_prefix)?;
+        (self.direction.outgoing() && crate::topic::matches(&self.pattern, topic))
+            .then(|| format!("{}{topic}", self.remote_prefix))
+    }
+
+    /// Local topic of a remote message coming in.
+    fn to_local(&self, remote_topic: &str) -> Option<String> {
+        let topic = remote_topic.strip_prefix(&self.remote_prefix)?;
+        (self.direction.incoming() && crate::topic::matches(&self.pattern, topic))
+            .then(|| format!("{}{topic}", self.local_prefix))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BridgeConfig {
+    name: String,
+    options: ConnectOptions,
+    backoff: Backoff,
+    mappings: Vec<TopicMapping>,
+    loop_prevention: LoopPrevention,
+}
+
+impl BridgeConfig {
+    /// Bridge to the broker at `address`, `host:port`, connecting with the name as client
+    /// identifier.
+    pub fn new(name: &str, address: &str) -> Self {
+        BridgeConfig {
+            name: name.to_string(),
+            options: ConnectOptions::new(address)
+                .client_id(name)
+                .clean_start(false)
+                .session_expiry_interval(u32::MAX),
+            backoff: Backoff::default(),
+            mappings: vec![],
+            loop_prevention: LoopPrevention::default(),
+        }
+    }
+
+    pub fn client_id(mut self, client_id: &str) -> Self {
+        self.options = self.options.client_id(client_id);
+        self
+    }
+
+    pub fn credentials(mut self, username: &str, password: &[u8]) -> Self {
+        self.options = self.options.credentials(username, password);
+        self
+    }
+
+    pub fn tls(mut self, tls: TlsOptions) -> Self {
+        self.options = self.options.tls(tls);
+        self
+    }
+
+    /// Delays between the attempts to connect to the remote broker.
+    pub fn backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Buffer of the client, holding the messages going out while the remote broker is
+    /// unreachable. The local session queues them once it is full.
+    pub fn buffer(mut self, buffer: BufferOptions) -> Self {
+        self.options = self.options.buffer(buffer);
+        self
+    }
+
+    /// Adds a mapping. A topic matching several mappings is forwarded by the first one.
+    pub fn mapping(mut self, mapping: TopicMapping) -> Self {
+        self.mappings.push(mapping);
+        self
+    }
+
+    pub fn loop_prevention(mut self, loop_prevention: LoopPrevention) -> Self {
+        self.loop_prevention = loop_prevention;
+        self
+    }
+
+    fn local_client_id(&self) -> String {
+        format!("$bridge/{}", self.name)
+    }
+
+    fn no_local(&self) -> bool {
+        self.loop_prevention == LoopPrevention::NoLocal
+    }
+
+    /// Whether the message was forwarded by this bridge.
+    fn marked(&self, properties: &[Property]) -> bool {
+        self.loop_prevention == LoopPrevention::UserProperty
+            && properties.iter().any(|property| {
+                matches!(property, Property::User(Utf8StringPair(key, value))
+                    if key == MARKER && *value == self.name)
+            })
+    }
+
+    fn mark(&self, properties: &mut Vec<Property>) {
+        if self.loop_prevention == LoopPrevention::UserProperty {
+            properties.push(Property::User(Utf8StringPair(
+                MARKER.to_string(),
+                self.name.clone(),
+            )));
+        }
+    }
+
+    /// Subscription of the local session for an outgoing mapping: retain flags as published,
+    /// and retained messages only when the subscription is new.
+    fn local_subscription(&self, mapping: &TopicMapping) -> TopicFilterAndSubscriptionOptions {
+        let no_local = if self.no_local() { 0b0000_0100 } else { 0 };
+        TopicFilterAndSubscriptionOptions::new(
+            mapping.local_filter(),
+            SubscriptionOptions {
+                raw_value: 0b0001_1000 | no_local | mapping.qos as u8,
+            },
+        )
+    }
+
+    fn remote_subscription(&self, mapping: &TopicMapping) -> Subscription {
+        Subscription::new(&mapping.remote_filter())
+            .qos(mapping.qos)
+            .no_local(self.no_local())
+            .retain_as_published(true)
+            .retain_handling(1)
+    }
+
+    /// Connects to the remote broker, retrying until it succeeds or fails for good.
+    async fn connect(&self) -> Result<(Client, mpsc::Receiver<Message>), ClientError> {
+        let options = self.options.clone().reconnect(self.backoff.clone());
+        let mut failed = 0;
+        loop {
+            match Client::connect(options.clone()).await {
+                Ok(connected) => return Ok(connected),
+                Err(e @ (ClientError::Io(_) | ClientError::Redirected { .. })) => {
+                    let delay = self.backoff.delay(failed);
+                    warn!(
+                        "bridge {} connection failed: {e}, retry in {delay:?}",
+                        self.name
+                    );
+                    tokio::time::sleep(delay).await;
+                    failed = failed.saturating_add(1);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl Broker {
+    /// Runs the bridge until the remote broker refuses it for good.
+    pub fn bridge(self: Arc<Self>, config: BridgeConfig) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let local_id = config.local_client_id();
+            let (notifier, mut notifications) = mpsc::channel(1);
+            {
+                let mut router = self.router.lock().unwrap();
+                router.connect(&local_id, notifier, false, u32::MAX);
+                for mapping in config.mappings.iter().filter(|m| m.direction.outgoing()) {
+                    router.subscribe(&local_id, config.local_subscription(mapping));
+                }
+            }
+
+            let (client, mut messages) = match config.connect().await {
+                Ok(connected) => connected,
+                Err(e) => return warn!("bridge {} stopped: {e}", config.name),
+            };
+            info!("bridge {} connected", config.name);
+
+            let subscriptions: Vec<Subscription> = config
+                .mappings
+                .iter()
+                .filter(|m| m.direction.incoming())
+                .map(|m| config.remote_subscription(m))
+                .collect();
+            // subscriptions are renewed when the client reconnects
+            if !subscriptions.is_empty() {
+                if let Err(e) = client.subscribe_many(subscriptions).await {
+                    warn!("bridge {} subscription failed: {e}", config.name);
+                }
+            }
+
+            // messages handed to the previous client and not completed
+            let (in_flight, released) = self.router.lock().unwrap().in_flight(&local_id);
+            for packet_id in released {
+                self.router.lock().unwrap().complete(&local_id, packet_id);
+            }
+            for publish in in_flight {
+                if let Err(e) = self.bridge_out(&config, &local_id, &client, publish).await {
+                    return warn!("bridge {} stopped: {e}", config.name);
+                }
+            }
+
+            loop {
+                loop {
+                    let next = self
+                        .router
+                        .lock()
+                        .unwrap()
+                        .next_delivery(&local_id, u16::MAX);
+                    let Some(publish) = next else { break };
+                    if let Err(e) = self.bridge_out(&config, &local_id, &client, publish).await {
+                        return warn!("bridge {} stopped: {e}", config.name);
+                    }
+                }
+
+                tokio::select! {
+                    Some(()) = notifications.recv() => {}
+                    message = messages.recv() => match message {
+                        Some(message) => self.bridge_in(&config, &local_id, message),
+                        None => return warn!("bridge {} stopped", config.name),
+                    }
+                }
+            }
+        })
+    }
+
+    /// Publishes a message taken from the local session on the remote broker.
+    async fn bridge_out(
+        &self,
+        config: &BridgeConfig,
+        local_id: &str,
+        client: &Client,
+        publish: Publish,
+    ) -> Result<(), ClientError> {
+        let remote_topic = config
+            .mappings
+            .iter()
+            .find_map(|mapping| mapping.to_remote(&publish.topic_name));
+        let mut message = Message::from(publish.clone());
+
+        if let Some(topic) = remote_topic.filter(|_| !config.marked(&message.properties)) {
+            message.topic = topic;
+            config.mark(&mut message.properties);
+            // the buffer of the client keeps it from now on
+            client.publish_message(&message).await?;
+        }
+        self.router.lock().unwrap().delivered(local_id, &publish);
+        Ok(())
+    }
+
+    /// Publishes a message received from the remote broker on the local broker.
+    fn bridge_in(&self, config: &BridgeConfig, local_id: &str, mut message: Message) {
+        let local_topic = config
+            .mappings
+            .iter()
+            .find_map(|mapping| mapping.to_local(&message.topic));
+        let Some(topic) = local_topic.filter(|_| !config.marked(&message.properties)) else {
+            return;
+        };
+
+        message.topic = topic;
+        config.mark(&mut message.properties);
+        if !self.publish(local_id, &Publish::from(&message)) {
+            warn!(
+                "bridge {} dropped a message to {}",
+                config.name, message.topic
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use crate::bridge::{BridgeConfig, Direction, LoopPrevention, TopicMapping};
+    use crate::broker::Broker;
+    use crate::connection::Connection;
+    use crate::proxy::ProxyProtocol;
+    use client::{Backoff, Client, ConnectOptions, Message, QoS};
+    use deser::packets::connect::Connect;
+    use deser::packets::subscribe::{
+        Subscribe, SubscriptionOptions, TopicFilterAndSubscriptionOptions,
+    };
+    use deser::ControlPacket;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::io::DuplexStream;
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+    use tokio::time::timeout;
+
+    #[test]
+    pub fn should_map_topics_between_prefixes() {
+        let mapping = TopicMapping::new("sensors/#", Direction::Out).prefixes("site/", "edge-1/");
+        assert_eq!(
+            Some(String::from("edge-1/sensors/kitchen")),
+            mapping.to_remote("site/sensors/kitchen")
+        );
+        assert_eq!(None, mapping.to_remote("sensors/kitchen"));
+        assert_eq!(None, mapping.to_remote("site/actuators/door"));
+        assert_eq!(None, mapping.to_local("edge-1/sensors/kitchen"));
+
+        let mapping = TopicMapping::new("commands/+", Direction::Both);
+        assert_eq!(
+            Some(String::from("commands/door")),
+            mapping.to_local("commands/door")
+        );
+        assert_eq!(
+            Some(String::from("commands/door")),
+            mapping.to_remote("commands/door")
+        );
+    }
+
+    fn serve(broker: &Arc<Broker>, listener: TcpListener) {
+        tokio::spawn(broker.clone().serve(listener, ProxyProtocol::Reject));
+    }
+
+    async fn client(addr: SocketAddr, topic_filter: &str) -> (Client, mpsc::Receiver<Message>) {
+        let (client, messages) = Client::connect(ConnectOptions::new(&addr.to_string()))
+            .await
+            .unwrap();
+        client
+            .subscribe(topic_filter, QoS::AtLeastOnce)
+            .await
+            .unwrap();
+        (client, messages)
+    }
+
+    /// Subscriber connected to the broker in-process, without a listener.
+    async fn subscribe(broker: &Arc<Broker>, topic_filter: &str) -> Connection<DuplexStream> {
+        let (client, server) = tokio::io::duplex(4096);
+        tokio::spawn(broker.clone().handle(server));
+        let mut client = Connection::new(client);
+
+        let connect = Connect {
+            client_id: String::from("subscriber"),
+            ..Default::default()
+        };
+        client
+            .write_packet(&ControlPacket::Connect(connect))
+            .await
+            .unwrap();
+        client.read_packet().await.unwrap();
+        let subscribe = Subscribe {
+            packet_id: 1,
+            topic_filters: vec![TopicFilterAndSubscriptionOptions::new(
+                String::from(topic_filter),
+                SubscriptionOptions { raw_value: 1 },
+            )],
+            ..Default::default()
+        };
+        client
+            .write_packet(&ControlPacket::Subscribe(subscribe))
+            .await
+            .unwrap();
+        client.read_packet().await.unwrap();
+        client
+    }
+
+    async fn receive(messages: &mut mpsc::Receiver<Message>) -> Option<Message> {
+        timeout(Duration::from_millis(500), messages.recv())
+            .await
+            .ok()
+            .flatten()
+    }
+
+    #[tokio::test]
+    async fn should_buffer_messages_until_remote_broker_is_up() {
+        let edge = Broker::builder().build();
+        let edge_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let edge_addr = edge_listener.local_addr().unwrap();
+        serve(&edge, edge_listener);
+
+        let remote_addr = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let config = BridgeConfig::new("edge-1", &remote_addr.to_string())
+            .backoff(Backoff::default().initial(Duration::from_millis(20)))
+            .mapping(
+                TopicMapping::new("sensors/#", Direction::Out)
+                    .qos(QoS::AtLeastOnce)
+                    .prefixes("", "edge-1/"),
+            );
+        edge.clone().bridge(config);
+
+        let (publisher, _) = Client::connect(ConnectOptions::new(&edge_addr.to_string()))
+            .await
+            .unwrap();
+        publisher
+            .publish("sensors/kitchen", QoS::AtLeastOnce, b"21.5")
+            .await
+            .unwrap()
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let central = Broker::builder().build();
+        let mut subscriber = subscribe(&central, "edge-1/sensors/#").await;
+        let listener = TcpListener::bind(remote_addr).await.unwrap();
+        serve(&central, listener);
+
+        match subscriber.read_packet().await.unwrap() {
+            Some(ControlPacket::Publish(publish)) => {
+                assert_eq!("edge-1/sensors/kitchen", publish.topic_name);
+                assert_eq!(Some(b"21.5".to_vec()), publish.application_message);
+                assert_eq!(1, publish.qos_number());
+            }
+            packet => panic!("expected PUBLISH, received {packet:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn should_forward_both_ways_without_loops() {
+        for loop_prevention in [LoopPrevention::NoLocal, LoopPrevention::UserProperty] {
+            let central = Broker::builder().build();
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let central_addr = listener.local_addr().unwrap();
+            serve(&central, listener);
+
+            let edge = Broker::builder().build();
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let edge_addr = listener.local_addr().unwrap();
+            serve(&edge, listener);
+
+            let config = BridgeConfig::new("edge-1", &central_addr.to_string())
+                .loop_prevention(loop_prevention)
+                .mapping(TopicMapping::new("shared/#", Direction::Both).qos(QoS::AtLeastOnce))
+                .mapping(
+                    TopicMapping::new("commands/#", Direction::In)
+                        .qos(QoS::AtLeastOnce)
+                        .prefixes("", "edge-1/"),
+                );
+            edge.clone().bridge(config);
+
+            let (edge_client, mut edge_messages) = client(edge_addr, "#").await;
+            let (central_client, mut central_messages) = client(central_addr, "#").await;
+            // let the bridge connect and subscribe
+            tokio::time::sleep(Duration::from_millis(200)).await;
+
+            edge_client
+                .publish("shared/a", QoS::AtLeastOnce, b"from edge")
+                .await
+                .unwrap();
+            let message = receive(&mut central_messages).await.unwrap();
+            assert_eq!("shared/a", message.topic);
+            assert_eq!(b"from edge".to_vec(), message.payload);
+
+            central_client
+                .publish("edge-1/commands/open", QoS::AtLeastOnce, b"door")
+                .await
+                .unwrap();
+            let message = receive(&mut edge_messages).await.unwrap();
+            assert_eq!("shared/a", message.topic);
+            let message = receive(&mut edge_messages).await.unwrap();
+            assert_eq!("commands/open", message.topic);
+            assert_eq!(b"door".to_vec(), message.payload);
+
+            // each message went through the bridge once
+            let message = receive(&mut central_messages).await.unwrap();
+            assert_eq!("edge-1/commands/open", message.topic);
+            assert_eq!(None, receive(&mut central_messages).await);
+            assert_eq!(None, receive(&mut edge_messages).await);
+        }
+    }
+}
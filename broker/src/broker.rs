@@ -0,0 +1,512 @@
+use crate::acl::{Access, Acl};
+use crate::admin::ConnectedClient;
+use crate::auth::password::PasswordBackend;
+use crate::auth::{Authenticator, Authenticators};
+use crate::cluster::{Cluster, ClusterConfig};
+#[cfg(unix)]
+use crate::connection::PeerCredentials;
+use crate::connection::{Connection, ConnectionInfo};
+use crate::hook::BrokerHook;
+use crate::limits::{Limits, TokenBucket};
+use crate::metrics::Metrics;
+use crate::proxy;
+use crate::proxy::ProxyProtocol;
+use crate::queue::{QueueConfig, QueueStats};
+use crate::router::Router;
+use crate::rules::RuleEngine;
+use crate::session;
+use crate::session::SessionError;
+use crate::store::{SessionStore, StoreError};
+use crate::tls::TlsAcceptor;
+use crate::websocket;
+use crate::websocket::WebSocketConfig;
+use deser::packets::publish::Publish;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, Once};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tracing::{info, warn};
+
+/// Largest packet accepted from clients unless configured otherwise, 1 MiB.
+pub const DEFAULT_MAXIMUM_PACKET_SIZE: u32 = 1 << 20;
+
+/// Time a new connection has to send its PROXY protocol header and complete its TLS and
+/// WebSocket handshakes unless configured otherwise.
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often sessions are checked for expiry, the resolution of their expiry interval.
+const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Server clients are sent to while the broker is in maintenance, with DISCONNECT or CONNACK
+/// 0x9C Use another server, or 0x9D Server moved when the move is permanent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Redirect {
+    /// Server Reference property, `host:port` or several of them separated by spaces.
+    pub server_reference: String,
+    pub permanent: bool,
+}
+
+pub struct Broker {
+    pub(crate) authenticators: Authenticators,
+    pub(crate) password_backends: Vec<Arc<dyn PasswordBackend>>,
+    pub(crate) acl: Option<Arc<Acl>>,
+    pub(crate) hooks: Vec<Arc<dyn BrokerHook>>,
+    pub(crate) rules: Option<Arc<RuleEngine>>,
+    pub(crate) limits: Limits,
+    pub(crate) listener_limits: HashMap<String, Limits>,
+    pub(crate) user_limits: HashMap<String, Limits>,
+    /// Connections recently accepted from each address, by listener.
+    pub(crate) connection_rates: Mutex<HashMap<(&'static str, IpAddr), TokenBucket>>,
+    pub(crate) router: Mutex<Router>,
+    /// Connected clients by client identifier.
+    pub(crate) clients: Mutex<HashMap<String, ConnectedClient>>,
+    maintenance: Mutex<Option<Redirect>>,
+    pub(crate) cluster: Option<Cluster>,
+    pub(crate) response_topic_prefix: String,
+    /// Largest packet accepted from clients, announced in CONNACK.
+    pub(crate) maximum_packet_size: u32,
+    /// Time a new connection has to send its PROXY protocol header and complete each of its
+    /// handshakes.
+    pub(crate) handshake_timeout: Duration,
+    pub(crate) started: Instant,
+    pub(crate) metrics: Arc<Metrics>,
+    /// Starts the task removing expired sessions with the first listener or connection.
+    expiry: Once,
+}
+
+#[derive(Default)]
+pub struct BrokerBuilder {
+    authenticators: Authenticators,
+    password_backends: Vec<Arc<dyn PasswordBackend>>,
+    acl: Option<Arc<Acl>>,
+    hooks: Vec<Arc<dyn BrokerHook>>,
+    rules: Option<Arc<RuleEngine>>,
+    limits: Limits,
+    listener_limits: HashMap<String, Limits>,
+    user_limits: HashMap<String, Limits>,
+    store: Option<Arc<dyn SessionStore>>,
+    queue: QueueConfig,
+    response_topic_prefix: Option<String>,
+    cluster: Option<ClusterConfig>,
+    maximum_packet_size: Option<u32>,
+    handshake_timeout: Option<Duration>,
+}
+
+impl BrokerBuilder {
+    /// Registers an enhanced authentication mechanism, selected by its Authentication Method.
+    pub fn authenticator(mut self, authenticator: Arc<dyn Authenticator>) -> Self {
+        self.authenticators.register(authenticator);
+        self
+    }
+
+    /// Adds a user name and password backend. Backends are consulted in the order they are
+    /// added. Without any backend every client is allowed to connect.
+    pub fn password_backend(mut self, backend: Arc<dyn PasswordBackend>) -> Self {
+        self.password_backends.push(backend);
+        self
+    }
+
+    /// Restricts the topics clients may publish and subscribe to. Without an ACL every client
+    /// may use every topic.
+    pub fn acl(mut self, acl: Arc<Acl>) -> Self {
+        self.acl = Some(acl);
+        self
+    }
+
+    /// Adds a hook, run after the ones added before it.
+    pub fn hook(mut self, hook: Arc<dyn BrokerHook>) -> Self {
+        self.hooks.push(hook);
+        self
+    }
+
+    /// Republishes, transforms or drops the messages clients publish, after the hooks.
+    pub fn rules(mut self, rules: Arc<RuleEngine>) -> Self {
+        self.rules = Some(rules);
+        self
+    }
+
+    /// Limits of every client, unless replaced for its listener or user.
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Replaces the limits for the clients of a listener, `tcp`, `tls`, `unix`, `ws` or `wss`.
+    pub fn listener_limits(mut self, listener: &str, limits: Limits) -> Self {
+        self.listener_limits.insert(listener.to_string(), limits);
+        self
+    }
+
+    /// Replaces the limits for the clients authenticated as the user, whatever their listener.
+    pub fn user_limits(mut self, username: &str, limits: Limits) -> Self {
+        self.user_limits.insert(username.to_string(), limits);
+        self
+    }
+
+    /// Keeps sessions and retained messages in the store, and recovers the ones it holds.
+    /// Without a store they are lost when the broker stops.
+    pub fn store(mut self, store: Arc<dyn SessionStore>) -> Self {
+        self.store = Some(store);
+        self
+    }
+
+    /// Limits of the queue of each session.
+    pub fn queue(mut self, queue: QueueConfig) -> Self {
+        self.queue = queue;
+        self
+    }
+
+    /// Prefix of the Response Information returned to clients asking for it with Request
+    /// Response Information, `response` by default. A client gets `<prefix>/<client id>` and
+    /// builds the response topics of its requests under it.
+    ///
+    /// With an ACL, responders need to publish and requesters to subscribe to those topics, for
+    /// instance with `allow publish any response/#` and `allow subscribe any response/%c/#`.
+    pub fn response_topic_prefix(mut self, prefix: &str) -> Self {
+        self.response_topic_prefix = Some(prefix.to_string());
+        self
+    }
+
+    /// Largest packet accepted from clients, [`DEFAULT_MAXIMUM_PACKET_SIZE`] by default. Clients
+    /// sending larger packets are disconnected with 0x95 Packet too large.
+    pub fn maximum_packet_size(mut self, size: u32) -> Self {
+        self.maximum_packet_size = Some(size);
+        self
+    }
+
+    /// Time a new connection has to send its PROXY protocol header, and then to complete each of
+    /// its TLS and WebSocket handshakes, [`DEFAULT_HANDSHAKE_TIMEOUT`] by default. Slower
+    /// connections are closed.
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = Some(timeout);
+        self
+    }
+
+    /// Makes the broker a node of a cluster, which `Broker::serve_cluster` or
+    /// `Broker::serve_cluster_tls` connects to.
+    pub fn cluster(mut self, cluster: ClusterConfig) -> Self {
+        self.cluster = Some(cluster);
+        self
+    }
+
+    pub fn build(self) -> Arc<Broker> {
+        let metrics = Arc::new(Metrics::default());
+        Arc::new(Broker {
+            authenticators: self.authenticators,
+            password_backends: self.password_backends,
+            acl: self.acl,
+            hooks: self.hooks,
+            rules: self.rules,
+            limits: self.limits,
+            listener_limits: self.listener_limits,
+            user_limits: self.user_limits,
+            connection_rates: Mutex::new(HashMap::new()),
+            router: Mutex::new(Router::new(self.store, self.queue).metrics(metrics.clone())),
+            clients: Mutex::new(HashMap::new()),
+            maintenance: Mutex::new(None),
+            cluster: self.cluster.map(Cluster::new),
+            response_topic_prefix: self
+                .response_topic_prefix
+                .unwrap_or_else(|| String::from("response")),
+            maximum_packet_size: self
+                .maximum_packet_size
+                .unwrap_or(DEFAULT_MAXIMUM_PACKET_SIZE),
+            handshake_timeout: self.handshake_timeout.unwrap_or(DEFAULT_HANDSHAKE_TIMEOUT),
+            started: Instant::now(),
+            metrics,
+            expiry: Once::new(),
+        })
+    }
+}
+
+impl Broker {
+    pub fn builder() -> BrokerBuilder {
+        BrokerBuilder::default()
+    }
+
+    /// Accepts MQTT clients until the listener fails.
+    pub async fn serve(
+        self: Arc<Self>,
+        listener: TcpListener,
+        proxy: ProxyProtocol,
+    ) -> std::io::Result<()> {
+        self.expire_sessions();
+        loop {
+            let (mut socket, addr) = listener.accept().await?;
+
+            let broker = self.clone();
+            tokio::spawn(async move {
+                let addr =
+                    match proxy::accept(&mut socket, addr, proxy, broker.handshake_timeout).await {
+                        Ok(client_addr) => client_addr,
+                        Err(e) => return warn!("connection from {addr} refused: {e}"),
+                    };
+                info!("accepted connection from {addr}");
+
+                let info = ConnectionInfo {
+                    peer_addr: Some(addr),
+                    listener: "tcp",
+                    ..Default::default()
+                };
+                if let Err(e) = broker.handle_connection(socket, info).await {
+                    warn!("connection from {addr} closed: {e}");
+                }
+            });
+        }
+    }
+
+    /// Accepts MQTT over TLS clients until the listener fails. A PROXY protocol header comes
+    /// before the TLS handshake.
+    pub async fn serve_tls(
+        self: Arc<Self>,
+        listener: TcpListener,
+        tls: Arc<TlsAcceptor>,
+        proxy: ProxyProtocol,
+    ) -> std::io::Result<()> {
+        self.expire_sessions();
+        loop {
+            let (mut socket, addr) = listener.accept().await?;
+
+            let broker = self.clone();
+            let tls = tls.clone();
+            tokio::spawn(async move {
+                let addr =
+                    match proxy::accept(&mut socket, addr, proxy, broker.handshake_timeout).await {
+                        Ok(client_addr) => client_addr,
+                        Err(e) => return warn!("connection from {addr} refused: {e}"),
+                    };
+                info!("accepted TLS connection from {addr}");
+
+                let handshake = tokio::time::timeout(broker.handshake_timeout, tls.accept(socket));
+                let (stream, certificate_identity) = match handshake.await {
+                    Ok(Ok(accepted)) => accepted,
+                    Ok(Err(e)) => return warn!("TLS handshake with {addr} failed: {e}"),
+                    Err(_) => return warn!("TLS handshake with {addr} timed out"),
+                };
+                let info = ConnectionInfo {
+                    peer_addr: Some(addr),
+                    certificate_identity,
+                    listener: "tls",
+                    ..Default::default()
+                };
+
+                if let Err(e) = broker.handle_connection(stream, info).await {
+                    warn!("connection from {addr} closed: {e}");
+                }
+            });
+        }
+    }
+
+    /// Accepts clients on a Unix domain socket until the listener fails. The credentials of the
+    /// peer process are handed to the password backends.
+    #[cfg(unix)]
+    pub async fn serve_unix(self: Arc<Self>, listener: UnixListener) -> std::io::Result<()> {
+        self.expire_sessions();
+        loop {
+            let (socket, _) = listener.accept().await?;
+            let peer_credentials = socket.peer_cred().ok().map(|cred| PeerCredentials {
+                uid: cred.uid(),
+                gid: cred.gid(),
+                pid: cred.pid(),
+            });
+            info!("accepted Unix socket connection from {peer_credentials:?}");
+
+            let broker = self.clone();
+            let info = ConnectionInfo {
+                peer_credentials,
+                listener: "unix",
+                ..Default::default()
+            };
+            tokio::spawn(async move {
+                if let Err(e) = broker.handle_connection(socket, info).await {
+                    warn!("Unix socket connection from {peer_credentials:?} closed: {e}");
+                }
+            });
+        }
+    }
+
+    /// Accepts MQTT over WebSocket clients until the listener fails. With a TLS acceptor the
+    /// handshake runs over TLS (wss).
+    pub async fn serve_websocket(
+        self: Arc<Self>,
+        listener: TcpListener,
+        config: Arc<WebSocketConfig>,
+        tls: Option<Arc<TlsAcceptor>>,
+        proxy: ProxyProtocol,
+    ) -> std::io::Result<()> {
+        self.expire_sessions();
+        loop {
+            let (mut socket, addr) = listener.accept().await?;
+
+            let broker = self.clone();
+            let config = config.clone();
+            let tls = tls.clone();
+            tokio::spawn(async move {
+                let addr =
+                    match proxy::accept(&mut socket, addr, proxy, broker.handshake_timeout).await {
+                        Ok(client_addr) => client_addr,
+                        Err(e) => return warn!("connection from {addr} refused: {e}"),
+                    };
+                info!("accepted WebSocket connection from {addr}");
+
+                let mut info = ConnectionInfo {
+                    peer_addr: Some(addr),
+                    listener: "ws",
+                    ..Default::default()
+                };
+
+                let result = match tls {
+                    Some(tls) => {
+                        let handshake =
+                            tokio::time::timeout(broker.handshake_timeout, tls.accept(socket));
+                        let (stream, certificate_identity) = match handshake.await {
+                            Ok(Ok(accepted)) => accepted,
+                            Ok(Err(e)) => return warn!("TLS handshake with {addr} failed: {e}"),
+                            Err(_) => return warn!("TLS handshake with {addr} timed out"),
+                        };
+                        info.certificate_identity = certificate_identity;
+                        info.listener = "wss";
+
+                        let upgrade = tokio::time::timeout(
+                            broker.handshake_timeout,
+                            websocket::accept(stream, &config),
+                        );
+                        match upgrade.await {
+                            Ok(Ok(stream)) => broker.handle_connection(stream, info).await,
+                            Ok(Err(e)) => return warn!("{addr}: {e}"),
+                            Err(_) => return warn!("WebSocket handshake with {addr} timed out"),
+                        }
+                    }
+                    None => {
+                        let upgrade = tokio::time::timeout(
+                            broker.handshake_timeout,
+                            websocket::accept(socket, &config),
+                        );
+                        match upgrade.await {
+                            Ok(Ok(stream)) => broker.handle_connection(stream, info).await,
+                            Ok(Err(e)) => return warn!("{addr}: {e}"),
+                            Err(_) => return warn!("WebSocket handshake with {addr} timed out"),
+                        }
+                    }
+                };
+
+                if let Err(e) = result {
+                    warn!("connection from {addr} closed: {e}");
+                }
+            });
+        }
+    }
+
+    /// Runs a single client connection to completion.
+    pub async fn handle<T>(self: Arc<Self>, stream: T) -> Result<(), SessionError>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        self.handle_connection(stream, ConnectionInfo::default())
+            .await
+    }
+
+    /// Runs a single client connection to completion, with what its transport established
+    /// about the client.
+    pub async fn handle_connection<T>(
+        self: Arc<Self>,
+        stream: T,
+        info: ConnectionInfo,
+    ) -> Result<(), SessionError>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        self.expire_sessions();
+        self.metrics.connection(match info.listener {
+            "" => "other",
+            listener => listener,
+        });
+        let connection = Connection::new(stream)
+            .count(self.metrics.clone())
+            .maximum_packet_size(self.maximum_packet_size as usize);
+        session::run(self, connection, info).await
+    }
+
+    /// Removes the expired sessions every second, telling the hooks, until the broker is
+    /// dropped. Started by the first listener served or connection handled, so that sessions
+    /// recovered from the store expire even if their clients never come back.
+    fn expire_sessions(self: &Arc<Self>) {
+        self.expiry.call_once(|| {
+            let broker = Arc::downgrade(self);
+            tokio::spawn(async move {
+                let mut ticks = tokio::time::interval(EXPIRY_CHECK_INTERVAL);
+                loop {
+                    ticks.tick().await;
+                    let Some(broker) = broker.upgrade() else {
+                        return;
+                    };
+                    let expired = broker.router.lock().unwrap().expire();
+                    for client_id in expired {
+                        broker.hook_session_expired(&client_id).await;
+                    }
+                }
+            });
+        });
+    }
+
+    pub(crate) fn authorize(
+        &self,
+        client_id: &str,
+        username: Option<&str>,
+        address: Option<IpAddr>,
+        access: Access,
+        topic: &str,
+    ) -> bool {
+        match &self.acl {
+            Some(acl) => acl.check(client_id, username, address, access, topic),
+            None => true,
+        }
+    }
+
+    /// Forwards an application message to the sessions subscribed to its topic, and to the
+    /// nodes of the cluster with such sessions. Returns false when the publisher must be
+    /// disconnected because a queue is full.
+    pub(crate) fn publish(&self, publisher: &str, publish: &Publish) -> bool {
+        if let Some(cluster) = &self.cluster {
+            cluster.forward(publish);
+        }
+        self.router.lock().unwrap().route(publisher, publish)
+    }
+
+    /// Waits for the changes routed so far to be stored.
+    pub(crate) async fn written(&self) -> Result<(), StoreError> {
+        let store = self.router.lock().unwrap().store();
+        match store {
+            Some(store) => store.written().await,
+            None => Ok(()),
+        }
+    }
+
+    /// Disconnects every client, and refuses new connections, pointing them to another server.
+    pub fn enter_maintenance(&self, redirect: Redirect) {
+        *self.maintenance.lock().unwrap() = Some(redirect.clone());
+        for client in self.clients.lock().unwrap().values() {
+            // a full channel already holds a request
+            let _ = client
+                .disconnect
+                .try_send(SessionError::Redirected(redirect.clone()));
+        }
+    }
+
+    pub fn leave_maintenance(&self) {
+        *self.maintenance.lock().unwrap() = None;
+    }
+
+    pub fn maintenance(&self) -> Option<Redirect> {
+        self.maintenance.lock().unwrap().clone()
+    }
+
+    /// Queue of every session, connected or not.
+    pub fn queue_stats(&self) -> Vec<QueueStats> {
+        self.router.lock().unwrap().queue_stats()
+    }
+}
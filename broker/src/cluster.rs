@@ -0,0 +1,857 @@
+//! Cluster of brokers sharing their clients, so that any node can serve any client.
+//!
+//! Nodes are listed statically: each one is identified by the address its peers reach it on,
+//! dials every peer, and sends its frames over the connection it dialed. The first frame
+//! identifies the sender; the connection to a peer is redialed while the peer is down.
+//!
+//! - Each node sends its peers the topic filters of its sessions whenever they change, and
+//!   forwards a PUBLISH only to the peers with a matching filter. Messages received from a peer
+//!   are routed to local sessions only. Topics starting with `$` stay on their node.
+//! - Retained messages are sent to every peer, and all of them when a connection to a peer is
+//!   established, so a node joining the cluster gets them.
+//! - A client connecting to a node takes its session over from the other nodes before the
+//!   CONNACK: the node holding it closes its connection with 0x8E Session taken over, and hands
+//!   its subscriptions and messages over, unless the client asks for a clean start.
+//!
+//! Frames are sent at most once: those for a peer whose connection is down are dropped.
+//!
+//! A node trusts its peers with every client: they publish to any topic, receive the messages
+//! of the topics they subscribe to and take any session over. The first frame of a connection
+//! carries a secret shared by the nodes, and connections with another secret are closed. The
+//! secret crosses the network in clear unless the nodes connect over TLS, with
+//! `ClusterConfig::tls` and `Broker::serve_cluster_tls`. Either way, the cluster listener
+//! should only be reachable from the other nodes.
+
+use crate::auth::scram::constant_time_eq;
+use crate::broker::Broker;
+use crate::store::file::{
+    get_publish, get_string, get_u32, get_u64, get_u8, put_publish, put_string,
+};
+use crate::store::StoreError;
+use crate::tls::{TlsAcceptor, TlsConnector};
+use crate::topic;
+use bytes::{BufMut, BytesMut};
+use deser::packets::publish::Publish;
+use deser::packets::subscribe::{SubscriptionOptions, TopicFilterAndSubscriptionOptions};
+use std::collections::{BTreeSet, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+/// Delay before dialing a peer again.
+const RECONNECT_DELAY: Duration = Duration::from_millis(500);
+/// Time a connecting client waits for the other nodes to hand its session over.
+const TAKEOVER_TIMEOUT: Duration = Duration::from_millis(500);
+/// Frames waiting to be written to a peer, beyond which new ones are dropped.
+const LINK_CAPACITY: usize = 1024;
+const MAX_FRAME_LENGTH: usize = 16 * 1024 * 1024;
+/// Longest first frame, read before the peer proves it knows the secret.
+const MAX_HELLO_LENGTH: usize = 4096;
+
+#[derive(Error, Debug)]
+pub enum ClusterError {
+    #[error("IO error {0}")]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Encoding(#[from] StoreError),
+    #[error("Malformed frame")]
+    Malformed,
+    #[error("Connection from unknown node {0}")]
+    UnknownNode(String),
+    #[error("Connection from node {0} with another secret")]
+    WrongSecret(String),
+}
+
+pub struct ClusterConfig {
+    node: String,
+    secret: String,
+    peers: Vec<String>,
+    tls: Option<Arc<TlsConnector>>,
+}
+
+impl ClusterConfig {
+    /// Node reached by its peers on `node`, the `host:port` its cluster listener is bound to.
+    /// Every node of the cluster is configured with the same secret.
+    pub fn new(node: &str, secret: &str) -> ClusterConfig {
+        ClusterConfig {
+            node: node.to_string(),
+            secret: secret.to_string(),
+            peers: vec![],
+            tls: None,
+        }
+    }
+
+    /// Connects to the peers over TLS, checking their certificates are valid for the host of
+    /// their address. The peers accept with `Broker::serve_cluster_tls`.
+    pub fn tls(mut self, connector: Arc<TlsConnector>) -> ClusterConfig {
+        self.tls = Some(connector);
+        self
+    }
+
+    /// Adds a node, identified by the address it is listed with in its own configuration.
+    pub fn peer(mut self, node: &str) -> ClusterConfig {
+        if node != self.node {
+            self.peers.push(node.to_string());
+        }
+        self
+    }
+}
+
+/// Subscriptions and messages of a session handed over by another node.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct MovedSession {
+    pub(crate) subscriptions: Vec<TopicFilterAndSubscriptionOptions>,
+    pub(crate) messages: Vec<Publish>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Frame {
+    Hello {
+        node: String,
+        secret: String,
+    },
+    /// Replaces the topic filters of the sender.
+    Interest(Vec<String>),
+    Publish(Publish),
+    Takeover {
+        request: u64,
+        client_id: String,
+        clean_start: bool,
+    },
+    /// Answer to a takeover, with the session when the sender had it.
+    Session {
+        request: u64,
+        session: Option<MovedSession>,
+    },
+}
+
+mod tag {
+    pub const HELLO: u8 = 1;
+    pub const INTEREST: u8 = 2;
+    pub const PUBLISH: u8 = 3;
+    pub const TAKEOVER: u8 = 4;
+    pub const SESSION: u8 = 5;
+}
+
+#[derive(Default)]
+struct Peer {
+    /// Frames for the peer, while connected to it.
+    link: Mutex<Option<mpsc::Sender<Frame>>>,
+    /// Topic filters of the sessions of the peer.
+    interest: Mutex<Vec<String>>,
+}
+
+pub(crate) struct Cluster {
+    node: String,
+    secret: String,
+    tls: Option<Arc<TlsConnector>>,
+    peers: HashMap<String, Peer>,
+    next_request: AtomicU64,
+    /// Takeovers waiting for the answers of the peers.
+    takeovers: Mutex<HashMap<u64, mpsc::Sender<Option<MovedSession>>>>,
+}
+
+impl Cluster {
+    pub(crate) fn new(config: ClusterConfig) -> Cluster {
+        Cluster {
+            node: config.node,
+            secret: config.secret,
+            tls: config.tls,
+            peers: config
+                .peers
+                .into_iter()
+                .map(|node| (node, Peer::default()))
+                .collect(),
+            next_request: AtomicU64::new(0),
+            takeovers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns false when the peer is not connected or too far behind.
+    fn send(&self, node: &str, frame: Frame) -> bool {
+        let link = self
+            .peers
+            .get(node)
+            .and_then(|peer| peer.link.lock().unwrap().clone());
+        match link.map(|link| link.try_send(frame)) {
+            Some(Ok(())) => true,
+            Some(Err(mpsc::error::TrySendError::Full(_))) => {
+                warn!("connection to node {node} is full, dropping frame");
+                false
+            }
+            _ => false,
+        }
+    }
+
+    fn broadcast(&self, frame: Frame) -> usize {
+        self.peers
+            .keys()
+            .filter(|node| self.send(node, frame.clone()))
+            .count()
+    }
+
+    /// Sends the message to the peers with a matching subscription, or to every peer when it is
+    /// retained.
+    pub(crate) fn forward(&self, publish: &Publish) {
+        if publish.topic_name.starts_with('$') {
+            return;
+        }
+        let publish = Publish {
+            packet_id: None,
+            ..publish.clone()
+        };
+        for (node, peer) in &self.peers {
+            let interested = publish.retain()
+                || peer
+                    .interest
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .any(|filter| topic::matches(filter, &publish.topic_name));
+            if interested {
+                self.send(node, Frame::Publish(publish.clone()));
+            }
+        }
+    }
+}
+
+impl Broker {
+    /// Accepts the connections of the other nodes of the cluster until the listener fails, and
+    /// connects to them.
+    pub async fn serve_cluster(self: Arc<Self>, listener: TcpListener) -> std::io::Result<()> {
+        self.accept_nodes(listener, None).await
+    }
+
+    /// Accepts the connections of the other nodes of the cluster over TLS until the listener
+    /// fails, and connects to them.
+    pub async fn serve_cluster_tls(
+        self: Arc<Self>,
+        listener: TcpListener,
+        tls: Arc<TlsAcceptor>,
+    ) -> std::io::Result<()> {
+        self.accept_nodes(listener, Some(tls)).await
+    }
+
+    async fn accept_nodes(
+        self: Arc<Self>,
+        listener: TcpListener,
+        tls: Option<Arc<TlsAcceptor>>,
+    ) -> std::io::Result<()> {
+        let Some(cluster) = &self.cluster else {
+            return Err(std::io::Error::other("the broker is not part of a cluster"));
+        };
+        for node in cluster.peers.keys() {
+            tokio::spawn(self.clone().dial(node.clone()));
+        }
+        tokio::spawn(self.clone().send_interest());
+
+        loop {
+            let (socket, addr) = listener.accept().await?;
+            let broker = self.clone();
+            let tls = tls.clone();
+            tokio::spawn(async move {
+                let result = match tls {
+                    Some(tls) => match tls.accept(socket).await {
+                        Ok((stream, _)) => broker.receive_frames(stream).await,
+                        Err(e) => Err(e.into()),
+                    },
+                    None => broker.receive_frames(socket).await,
+                };
+                if let Err(e) = result {
+                    warn!("cluster connection from {addr} closed: {e}");
+                }
+            });
+        }
+    }
+
+    /// Hands the session over to this node, from the node holding it. Without a cluster, or
+    /// when no node answers in time, the session is not present.
+    pub(crate) async fn take_over(
+        &self,
+        client_id: &str,
+        clean_start: bool,
+    ) -> Option<MovedSession> {
+        let cluster = self.cluster.as_ref()?;
+        let request = cluster.next_request.fetch_add(1, Ordering::Relaxed);
+        let (tx, mut rx) = mpsc::channel(cluster.peers.len().max(1));
+        cluster.takeovers.lock().unwrap().insert(request, tx);
+
+        let mut pending = cluster.broadcast(Frame::Takeover {
+            request,
+            client_id: client_id.to_string(),
+            clean_start,
+        });
+        let mut moved = None;
+        let deadline = tokio::time::sleep(TAKEOVER_TIMEOUT);
+        tokio::pin!(deadline);
+        while pending > 0 {
+            tokio::select! {
+                Some(session) = rx.recv() => {
+                    pending -= 1;
+                    moved = moved.or(session);
+                }
+                _ = &mut deadline => {
+                    warn!("takeover of client {client_id}: {pending} nodes did not answer");
+                    break;
+                }
+            }
+        }
+        cluster.takeovers.lock().unwrap().remove(&request);
+        moved
+    }
+
+    /// Keeps a connection to the peer, writing the frames queued for it.
+    async fn dial(self: Arc<Self>, node: String) {
+        let cluster = self.cluster.as_ref().unwrap();
+        loop {
+            let result = match TcpStream::connect(&node).await {
+                Ok(socket) => match &cluster.tls {
+                    Some(tls) => match tls.connect(host(&node), socket).await {
+                        Ok(socket) => self.link(&node, socket).await,
+                        Err(e) => Err(e.into()),
+                    },
+                    None => self.link(&node, socket).await,
+                },
+                Err(e) => Err(e.into()),
+            };
+            if let Err(e) = result {
+                warn!("connection to cluster node {node} failed: {e}");
+            }
+            tokio::time::sleep(RECONNECT_DELAY).await;
+        }
+    }
+
+    /// Writes the frames queued for the peer until the connection fails.
+    async fn link<T>(&self, node: &str, mut socket: T) -> Result<(), ClusterError>
+    where
+        T: AsyncWrite + Unpin,
+    {
+        let cluster = self.cluster.as_ref().unwrap();
+        let peer = &cluster.peers[node];
+        info!("connected to cluster node {node}");
+        let (tx, mut rx) = mpsc::channel(LINK_CAPACITY);
+        let result = async {
+            let hello = Frame::Hello {
+                node: cluster.node.clone(),
+                secret: cluster.secret.clone(),
+            };
+            write_frame(&mut socket, &hello).await?;
+            *peer.link.lock().unwrap() = Some(tx);
+
+            let (interest, retained) = {
+                let router = self.router.lock().unwrap();
+                (router.topic_filters(), router.retained())
+            };
+            write_frame(
+                &mut socket,
+                &Frame::Interest(interest.into_iter().collect()),
+            )
+            .await?;
+            for publish in retained {
+                write_frame(&mut socket, &Frame::Publish(publish)).await?;
+            }
+            while let Some(frame) = rx.recv().await {
+                write_frame(&mut socket, &frame).await?;
+            }
+            Ok(())
+        }
+        .await;
+        *peer.link.lock().unwrap() = None;
+        result
+    }
+
+    /// Sends the topic filters of the sessions to the peers whenever they change.
+    async fn send_interest(self: Arc<Self>) {
+        let cluster = self.cluster.as_ref().unwrap();
+        let changed = self.router.lock().unwrap().interest();
+        let mut sent = BTreeSet::new();
+        loop {
+            changed.notified().await;
+            let interest = self.router.lock().unwrap().topic_filters();
+            if interest != sent {
+                cluster.broadcast(Frame::Interest(interest.iter().cloned().collect()));
+                sent = interest;
+            }
+        }
+    }
+
+    async fn receive_frames<T>(&self, mut socket: T) -> Result<(), ClusterError>
+    where
+        T: AsyncRead + Unpin,
+    {
+        let cluster = self.cluster.as_ref().unwrap();
+        let (node, secret) = match read_frame(&mut socket, MAX_HELLO_LENGTH).await? {
+            Some(Frame::Hello { node, secret }) => (node, secret),
+            _ => return Err(ClusterError::Malformed),
+        };
+        if !constant_time_eq(secret.as_bytes(), cluster.secret.as_bytes()) {
+            return Err(ClusterError::WrongSecret(node));
+        }
+        let peer = cluster
+            .peers
+            .get(&node)
+            .ok_or_else(|| ClusterError::UnknownNode(node.clone()))?;
+        info!("cluster node {node} connected");
+
+        let result = async {
+            while let Some(frame) = read_frame(&mut socket, MAX_FRAME_LENGTH).await? {
+                match frame {
+                    Frame::Hello { .. } => return Err(ClusterError::Malformed),
+                    Frame::Interest(interest) => *peer.interest.lock().unwrap() = interest,
+                    Frame::Publish(publish) => {
+                        self.router.lock().unwrap().route("", &publish);
+                    }
+                    Frame::Takeover {
+                        request,
+                        client_id,
+                        clean_start,
+                    } => {
+                        let session = self.router.lock().unwrap().take_session(&client_id);
+                        let session = session.filter(|_| !clean_start);
+                        cluster.send(&node, Frame::Session { request, session });
+                    }
+                    Frame::Session { request, session } => {
+                        let takeover = cluster.takeovers.lock().unwrap().get(&request).cloned();
+                        if let Some(takeover) = takeover {
+                            let _ = takeover.try_send(session);
+                        }
+                    }
+                }
+            }
+            Ok(())
+        }
+        .await;
+
+        // the peer resends its interest when it reconnects
+        peer.interest.lock().unwrap().clear();
+        result
+    }
+}
+
+async fn write_frame<T>(stream: &mut T, frame: &Frame) -> Result<(), ClusterError>
+where
+    T: AsyncWrite + Unpin,
+{
+    let mut buffer = BytesMut::new();
+    encode_frame(frame, &mut buffer)?;
+    stream.write_all(&buffer).await?;
+    Ok(())
+}
+
+/// Host of a `host:port` node address, without the brackets of an IPv6 address.
+fn host(node: &str) -> &str {
+    let host = node.rsplit_once(':').map_or(node, |(host, _)| host);
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+/// Returns None when the connection is closed between frames.
+async fn read_frame<T>(stream: &mut T, max_length: usize) -> Result<Option<Frame>, ClusterError>
+where
+    T: AsyncRead + Unpin,
+{
+    let length = match stream.read_u32().await {
+        Ok(length) => length as usize,
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    if length > max_length {
+        return Err(ClusterError::Malformed);
+    }
+    let mut frame = vec![0; length];
+    stream.read_exact(&mut frame).await?;
+    decode_frame(&frame)
+        .map(Some)
+        .ok_or(ClusterError::Malformed)
+}
+
+/// Frames are encoded like the records of the file store: a four byte big endian length, a tag
+/// and the fields.
+fn encode_frame(frame: &Frame, buffer: &mut BytesMut) -> Result<(), StoreError> {
+    let mut record = BytesMut::new();
+
+    match frame {
+        Frame::Hello { node, secret } => {
+            record.put_u8(tag::HELLO);
+            put_string(&mut record, node);
+            put_string(&mut record, secret);
+        }
+        Frame::Interest(interest) => {
+            record.put_u8(tag::INTEREST);
+            record.put_u32(interest.len() as u32);
+            for topic_filter in interest {
+                put_string(&mut record, topic_filter);
+            }
+        }
+        Frame::Publish(publish) => {
+            record.put_u8(tag::PUBLISH);
+            put_publish(&mut record, publish)?;
+        }
+        Frame::Takeover {
+            request,
+            client_id,
+            clean_start,
+        } => {
+            record.put_u8(tag::TAKEOVER);
+            record.put_u64(*request);
+            put_string(&mut record, client_id);
+            record.put_u8(*clean_start as u8);
+        }
+        Frame::Session { request, session } => {
+            record.put_u8(tag::SESSION);
+            record.put_u64(*request);
+            match session {
+                Some(session) => {
+                    record.put_u8(1);
+                    record.put_u32(session.subscriptions.len() as u32);
+                    for subscription in &session.subscriptions {
+                        put_string(&mut record, &subscription.topic_filter);
+                        record.put_u8(subscription.subscription_options.raw_value);
+                    }
+                    record.put_u32(session.messages.len() as u32);
+                    for publish in &session.messages {
+                        put_publish(&mut record, publish)?;
+                    }
+                }
+                None => record.put_u8(0),
+            }
+        }
+    }
+
+    buffer.put_u32(record.len() as u32);
+    buffer.put(record);
+    Ok(())
+}
+
+/// Returns None when the frame is malformed.
+fn decode_frame(mut record: &[u8]) -> Option<Frame> {
+    let record = &mut record;
+
+    let frame = match get_u8(record)? {
+        tag::HELLO => Frame::Hello {
+            node: get_string(record)?,
+            secret: get_string(record)?,
+        },
+        tag::INTEREST => Frame::Interest(
+            (0..get_u32(record)?)
+                .map(|_| get_string(record))
+                .collect::<Option<_>>()?,
+        ),
+        tag::PUBLISH => Frame::Publish(get_publish(record)?),
+        tag::TAKEOVER => Frame::Takeover {
+            request: get_u64(record)?,
+            client_id: get_string(record)?,
+            clean_start: get_u8(record)? == 1,
+        },
+        tag::SESSION => Frame::Session {
+            request: get_u64(record)?,
+            session: match get_u8(record)? {
+                0 => None,
+                _ => Some(MovedSession {
+                    subscriptions: (0..get_u32(record)?)
+                        .map(|_| {
+                            Some(TopicFilterAndSubscriptionOptions::new(
+                                get_string(record)?,
+                                SubscriptionOptions {
+                                    raw_value: get_u8(record)?,
+                                },
+                            ))
+                        })
+                        .collect::<Option<_>>()?,
+                    messages: (0..get_u32(record)?)
+                        .map(|_| get_publish(record))
+                        .collect::<Option<_>>()?,
+                }),
+            },
+        },
+        _ => return None,
+    };
+
+    record.is_empty().then_some(frame)
+}
+
+#[cfg(test)]
+pub mod test {
+    use crate::broker::Broker;
+    use crate::cluster::{
+        decode_frame, encode_frame, write_frame, Cluster, ClusterConfig, Frame, MovedSession,
+    };
+    use crate::proxy::ProxyProtocol;
+    use crate::tls::test::Pki;
+    use crate::tls::{TlsAcceptor, TlsConfig, TlsConnector};
+    use bytes::BytesMut;
+    use client::{Client, ConnectOptions, Event, Message, QoS};
+    use deser::packets::publish::Publish;
+    use deser::packets::subscribe::{SubscriptionOptions, TopicFilterAndSubscriptionOptions};
+    use rcgen::ExtendedKeyUsagePurpose;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::mpsc;
+    use tokio::time::timeout;
+
+    fn publish(topic: &str, retain: bool) -> Publish {
+        Publish {
+            packet_type_low_nibble: 0b0010 | retain as u8,
+            packet_id: Some(3),
+            topic_name: topic.to_string(),
+            application_message: Some(b"payload".to_vec()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    pub fn should_encode_and_decode_frames() {
+        let frames = vec![
+            Frame::Hello {
+                node: String::from("127.0.0.1:7001"),
+                secret: String::from("secret"),
+            },
+            Frame::Interest(vec![String::from("a/#"), String::from("b/+")]),
+            Frame::Publish(Publish {
+                packet_id: None,
+                ..publish("a/b", true)
+            }),
+            Frame::Takeover {
+                request: 7,
+                client_id: String::from("sensor"),
+                clean_start: false,
+            },
+            Frame::Session {
+                request: 7,
+                session: Some(MovedSession {
+                    subscriptions: vec![TopicFilterAndSubscriptionOptions::new(
+                        String::from("a/#"),
+                        SubscriptionOptions { raw_value: 0b0110 },
+                    )],
+                    messages: vec![Publish {
+                        packet_id: None,
+                        ..publish("a/b", false)
+                    }],
+                }),
+            },
+            Frame::Session {
+                request: 8,
+                session: None,
+            },
+        ];
+
+        for frame in frames {
+            let mut buffer = BytesMut::new();
+            encode_frame(&frame, &mut buffer).unwrap();
+            assert_eq!(Some(frame), decode_frame(&buffer[4..]));
+        }
+        assert_eq!(None, decode_frame(&[9]));
+    }
+
+    #[test]
+    pub fn should_forward_to_nodes_with_matching_interest() {
+        let cluster = Cluster::new(
+            ClusterConfig::new("node-a", "secret")
+                .peer("node-a")
+                .peer("node-b")
+                .peer("node-c"),
+        );
+        let mut links = vec![];
+        for node in ["node-b", "node-c"] {
+            let (tx, rx) = mpsc::channel(8);
+            *cluster.peers[node].link.lock().unwrap() = Some(tx);
+            links.push(rx);
+        }
+        *cluster.peers["node-b"].interest.lock().unwrap() = vec![String::from("sensors/#")];
+
+        cluster.forward(&publish("sensors/kitchen", false));
+        cluster.forward(&publish("actuators/door", false));
+        cluster.forward(&publish("$SYS/broker/uptime", true));
+        cluster.forward(&publish("status", true));
+
+        let (b, c) = links.split_at_mut(1);
+        let forwarded = |rx: &mut mpsc::Receiver<Frame>| {
+            let mut topics = vec![];
+            while let Ok(Frame::Publish(publish)) = rx.try_recv() {
+                assert_eq!(None, publish.packet_id);
+                topics.push(publish.topic_name);
+            }
+            topics
+        };
+        assert_eq!(vec!["sensors/kitchen", "status"], forwarded(&mut b[0]));
+        assert_eq!(vec!["status"], forwarded(&mut c[0]));
+    }
+
+    async fn receive(messages: &mut mpsc::Receiver<Message>) -> Option<Message> {
+        timeout(Duration::from_millis(500), messages.recv())
+            .await
+            .ok()
+            .flatten()
+    }
+
+    #[tokio::test]
+    async fn should_share_messages_and_sessions_across_three_nodes() {
+        let mut cluster_listeners = vec![];
+        for _ in 0..3 {
+            cluster_listeners.push(TcpListener::bind("127.0.0.1:0").await.unwrap());
+        }
+        let nodes: Vec<String> = cluster_listeners
+            .iter()
+            .map(|listener| listener.local_addr().unwrap().to_string())
+            .collect();
+
+        let mut addresses = vec![];
+        for (node, cluster_listener) in nodes.iter().zip(cluster_listeners) {
+            let config = nodes
+                .iter()
+                .fold(ClusterConfig::new(node, "secret"), |config, peer| {
+                    config.peer(peer)
+                });
+            let broker = Broker::builder().cluster(config).build();
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            addresses.push(listener.local_addr().unwrap().to_string());
+            tokio::spawn(broker.clone().serve(listener, ProxyProtocol::Reject));
+            tokio::spawn(broker.serve_cluster(cluster_listener));
+        }
+        let options = |node: usize, client_id: &str| {
+            ConnectOptions::new(&addresses[node])
+                .client_id(client_id)
+                .clean_start(false)
+                .session_expiry_interval(60)
+        };
+        // let the nodes connect to each other
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let (first, mut first_messages) = Client::connect(options(0, "sensor")).await.unwrap();
+        first
+            .subscribe("sensors/#", QoS::AtLeastOnce)
+            .await
+            .unwrap();
+        let (publisher, _) = Client::connect(options(2, "publisher")).await.unwrap();
+        // let the interest of node 0 reach node 2
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        publisher
+            .publish_message(
+                &Message::new("sensors/kitchen", b"21.5")
+                    .qos(QoS::AtLeastOnce)
+                    .retain(true),
+            )
+            .await
+            .unwrap()
+            .await
+            .unwrap();
+        let message = receive(&mut first_messages).await.unwrap();
+        assert_eq!("sensors/kitchen", message.topic);
+        assert_eq!(b"21.5".to_vec(), message.payload);
+
+        // session taken over by node 1, with its subscription
+        let mut events = first.events();
+        let (second, mut second_messages) = Client::connect(options(1, "sensor")).await.unwrap();
+        assert!(second.session_present());
+        assert!(matches!(events.recv().await, Ok(Event::Disconnected(_))));
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        publisher
+            .publish("sensors/garage", QoS::AtLeastOnce, b"12.0")
+            .await
+            .unwrap()
+            .await
+            .unwrap();
+        let message = receive(&mut second_messages).await.unwrap();
+        assert_eq!("sensors/garage", message.topic);
+        assert_eq!(None, receive(&mut first_messages).await);
+
+        // retained message replicated to node 1
+        let (late, mut late_messages) = Client::connect(options(1, "late")).await.unwrap();
+        late.subscribe("sensors/kitchen", QoS::AtMostOnce)
+            .await
+            .unwrap();
+        let message = receive(&mut late_messages).await.unwrap();
+        assert_eq!(b"21.5".to_vec(), message.payload);
+        assert!(message.retain);
+    }
+
+    #[tokio::test]
+    async fn should_close_connections_with_another_secret() {
+        let cluster_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let node = cluster_listener.local_addr().unwrap().to_string();
+        let peer = "127.0.0.1:1";
+        let config = ClusterConfig::new(&node, "secret").peer(&node).peer(peer);
+        let broker = Broker::builder().cluster(config).build();
+        tokio::spawn(broker.clone().serve_cluster(cluster_listener));
+
+        let hello = |secret: &str| Frame::Hello {
+            node: String::from(peer),
+            secret: secret.to_string(),
+        };
+        let mut socket = TcpStream::connect(&node).await.unwrap();
+        write_frame(&mut socket, &hello("guess")).await.unwrap();
+        let _ = write_frame(&mut socket, &Frame::Publish(publish("status", true))).await;
+        assert_eq!(0, socket.read(&mut [0; 16]).await.unwrap_or(0));
+        assert!(broker.router.lock().unwrap().retained().is_empty());
+
+        let mut socket = TcpStream::connect(&node).await.unwrap();
+        write_frame(&mut socket, &hello("secret")).await.unwrap();
+        write_frame(&mut socket, &Frame::Publish(publish("status", true)))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(1, broker.router.lock().unwrap().retained().len());
+    }
+
+    #[tokio::test]
+    async fn should_link_nodes_over_tls() {
+        let pki = Pki::new("cluster");
+        pki.issue("node", "node", ExtendedKeyUsagePurpose::ServerAuth);
+        let connector = Arc::new(TlsConnector::new(pki.path("ca.pem")).unwrap());
+
+        let mut cluster_listeners = vec![];
+        for _ in 0..2 {
+            cluster_listeners.push(TcpListener::bind("127.0.0.1:0").await.unwrap());
+        }
+        let nodes: Vec<String> = cluster_listeners
+            .iter()
+            .map(|listener| format!("localhost:{}", listener.local_addr().unwrap().port()))
+            .collect();
+
+        let mut brokers = vec![];
+        let mut addresses = vec![];
+        for (node, cluster_listener) in nodes.iter().zip(cluster_listeners) {
+            let config = nodes
+                .iter()
+                .fold(ClusterConfig::new(node, "secret"), |config, peer| {
+                    config.peer(peer)
+                })
+                .tls(connector.clone());
+            let broker = Broker::builder().cluster(config).build();
+            let tls = TlsConfig::new(pki.path("node.pem"), pki.path("node.key"));
+            let tls = Arc::new(TlsAcceptor::new(tls).unwrap());
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            addresses.push(listener.local_addr().unwrap().to_string());
+            tokio::spawn(broker.clone().serve(listener, ProxyProtocol::Reject));
+            tokio::spawn(broker.clone().serve_cluster_tls(cluster_listener, tls));
+            brokers.push(broker);
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let (publisher, _) = Client::connect(ConnectOptions::new(&addresses[1]))
+            .await
+            .unwrap();
+        publisher
+            .publish_message(
+                &Message::new("status", b"up")
+                    .qos(QoS::AtLeastOnce)
+                    .retain(true),
+            )
+            .await
+            .unwrap()
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let retained = brokers[0].router.lock().unwrap().retained();
+        assert_eq!(1, retained.len());
+        assert_eq!("status", retained[0].topic_name);
+    }
+}
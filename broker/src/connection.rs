@@ -0,0 +1,131 @@
+use crate::metrics::Metrics;
+use bytes::BytesMut;
+use deser::codec::{decode_packet, encode_packet, next_frame, CodecError};
+use deser::ControlPacket;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::trace;
+
+#[derive(Error, Debug)]
+pub enum ConnectionError {
+    #[error("IO error {0}")]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Codec(#[from] CodecError),
+    #[error("Connection reset by peer with a partial packet in the buffer")]
+    ResetByPeer,
+}
+
+/// What the transport established about the client before its first packet.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    /// Address of the client, as reported by the PROXY protocol header when the listener
+    /// requires one.
+    pub peer_addr: Option<SocketAddr>,
+    /// Taken from the verified TLS client certificate. Replaces the user name of the CONNECT
+    /// and stands in for the password.
+    pub certificate_identity: Option<String>,
+    /// Process at the other end of a Unix domain socket.
+    pub peer_credentials: Option<PeerCredentials>,
+    /// Kind of listener the client connected to, `tcp`, `tls`, `unix`, `ws` or `wss`, empty for
+    /// streams handed to the broker directly.
+    pub listener: &'static str,
+}
+
+/// Credentials of a local peer process, as reported by SO_PEERCRED.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerCredentials {
+    pub uid: u32,
+    pub gid: u32,
+    pub pid: Option<i32>,
+}
+
+/// Reads and writes whole control packets over a byte stream.
+pub struct Connection<T> {
+    stream: T,
+    buffer: BytesMut,
+    metrics: Option<Arc<Metrics>>,
+    /// Size of the last packet read.
+    last_size: usize,
+    maximum_packet_size: usize,
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> Connection<T> {
+    pub fn new(stream: T) -> Connection<T> {
+        Connection {
+            stream,
+            buffer: BytesMut::with_capacity(4096),
+            metrics: None,
+            last_size: 0,
+            maximum_packet_size: usize::MAX,
+        }
+    }
+
+    /// Refuses packets larger than `size` bytes with `CodecError::PacketTooLarge`.
+    pub fn maximum_packet_size(mut self, size: usize) -> Self {
+        self.maximum_packet_size = size;
+        self
+    }
+
+    /// Counts the packets read and written, and decoding errors, in the metrics of the broker.
+    pub(crate) fn count(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Returns None when the peer closed the stream cleanly. Cancel safe, partially read packets
+    /// stay in the buffer.
+    pub async fn read_packet(&mut self) -> Result<Option<ControlPacket>, ConnectionError> {
+        loop {
+            if let Some(frame) = self.next_frame()? {
+                let size = frame.len();
+                self.last_size = size;
+                let packet = decode_packet(frame).inspect_err(|e| self.decode_error(e))?;
+                if let Some(metrics) = &self.metrics {
+                    metrics.received(&packet, size);
+                }
+                trace!("read {} packet", packet.name());
+                return Ok(Some(packet));
+            }
+
+            if 0 == self.stream.read_buf(&mut self.buffer).await? {
+                if self.buffer.is_empty() {
+                    return Ok(None);
+                }
+                return Err(ConnectionError::ResetByPeer);
+            }
+        }
+    }
+
+    /// Size in bytes of the packet `read_packet` last returned.
+    pub(crate) fn last_packet_size(&self) -> usize {
+        self.last_size
+    }
+
+    pub async fn write_packet(&mut self, packet: &ControlPacket) -> Result<(), ConnectionError> {
+        let bytes = encode_packet(packet)?;
+        trace!("writing {} packet", packet.name());
+        self.stream.write_all(&bytes).await?;
+        self.stream.flush().await?;
+        if let Some(metrics) = &self.metrics {
+            metrics.sent(packet, bytes.len());
+        }
+        Ok(())
+    }
+
+    fn next_frame(&mut self) -> Result<Option<BytesMut>, CodecError> {
+        let frame = next_frame(&mut self.buffer, self.maximum_packet_size);
+        if let Err(e) = &frame {
+            self.decode_error(e);
+        }
+        frame
+    }
+
+    fn decode_error(&self, error: &CodecError) {
+        if let Some(metrics) = &self.metrics {
+            metrics.decode_error(error);
+        }
+    }
+}
@@ -0,0 +1,449 @@
+//! Hooks extending the broker: validation, enrichment or auditing of connections, messages and
+//! subscriptions.
+//!
+//! Hooks run in the order they are registered with `BrokerBuilder::hook`. Each callback sees
+//! what the previous hooks changed, and the first one refusing a connection or subscription, or
+//! dropping a message, stops the chain. Every callback has a default implementation letting
+//! everything through, so a hook only implements the ones it needs.
+//!
+//! Callbacks run in the task of the client connection: a slow hook slows its client down.
+
+pub use crate::auth::password::BoxFuture;
+use crate::broker::Broker;
+use deser::packets::connect::Connect;
+use deser::packets::publish::Publish;
+use deser::packets::reason_codes::{CONNECTACK, SUBACK};
+use deser::packets::subscribe::TopicFilterAndSubscriptionOptions;
+use deser::properties::Property;
+use std::net::SocketAddr;
+
+/// Client a callback runs for.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientContext {
+    pub client_id: String,
+    /// Identity established by authentication.
+    pub username: Option<String>,
+    pub peer_addr: Option<SocketAddr>,
+    /// Kind of listener the client connected to, `tcp`, `tls`, `ws`, `wss` or `unix`.
+    pub listener: &'static str,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectVerdict {
+    Accept,
+    /// Refuses the connection with this CONNACK reason code.
+    Refuse(CONNECTACK),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SubscribeVerdict {
+    Accept,
+    /// Refuses the subscription with this SUBACK reason code.
+    Refuse(SUBACK),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageVerdict {
+    Forward,
+    /// Drops the message. The publisher is acknowledged as if it had been forwarded.
+    Drop,
+}
+
+pub trait BrokerHook: Send + Sync {
+    /// Authenticated client connecting, before its session is created. The properties are
+    /// those of the CONNACK.
+    fn on_connect<'a>(
+        &'a self,
+        _client: &'a ClientContext,
+        _connect: &'a Connect,
+        _properties: &'a mut Vec<Property>,
+    ) -> BoxFuture<'a, ConnectVerdict> {
+        Box::pin(async { ConnectVerdict::Accept })
+    }
+
+    /// Message published by a client and authorized by the ACL, before it is routed. Changing
+    /// its topic reroutes it.
+    fn on_publish<'a>(
+        &'a self,
+        _client: &'a ClientContext,
+        _publish: &'a mut Publish,
+    ) -> BoxFuture<'a, MessageVerdict> {
+        Box::pin(async { MessageVerdict::Forward })
+    }
+
+    /// Subscription authorized by the ACL, before it is added.
+    fn on_subscribe<'a>(
+        &'a self,
+        _client: &'a ClientContext,
+        _subscription: &'a mut TopicFilterAndSubscriptionOptions,
+    ) -> BoxFuture<'a, SubscribeVerdict> {
+        Box::pin(async { SubscribeVerdict::Accept })
+    }
+
+    /// Message about to be sent to a subscriber. Messages sent again after a reconnection do
+    /// not go through it again.
+    fn on_deliver<'a>(
+        &'a self,
+        _client: &'a ClientContext,
+        _publish: &'a mut Publish,
+    ) -> BoxFuture<'a, MessageVerdict> {
+        Box::pin(async { MessageVerdict::Forward })
+    }
+
+    /// Connection closed, with the reason unless the client disconnected normally.
+    fn on_disconnect<'a>(
+        &'a self,
+        _client: &'a ClientContext,
+        _reason: Option<&'a str>,
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(async {})
+    }
+
+    /// Session removed after its expiry interval. Expired sessions are looked for every second
+    /// once the broker serves a listener or handles a connection.
+    fn on_session_expired<'a>(&'a self, _client_id: &'a str) -> BoxFuture<'a, ()> {
+        Box::pin(async {})
+    }
+}
+
+impl Broker {
+    pub(crate) async fn hook_connect(
+        &self,
+        client: &ClientContext,
+        connect: &Connect,
+        properties: &mut Vec<Property>,
+    ) -> ConnectVerdict {
+        for hook in &self.hooks {
+            if let refused @ ConnectVerdict::Refuse(_) =
+                hook.on_connect(client, connect, properties).await
+            {
+                return refused;
+            }
+        }
+        ConnectVerdict::Accept
+    }
+
+    pub(crate) async fn hook_publish(
+        &self,
+        client: &ClientContext,
+        publish: &mut Publish,
+    ) -> MessageVerdict {
+        for hook in &self.hooks {
+            if hook.on_publish(client, publish).await == MessageVerdict::Drop {
+                return MessageVerdict::Drop;
+            }
+        }
+        MessageVerdict::Forward
+    }
+
+    pub(crate) async fn hook_subscribe(
+        &self,
+        client: &ClientContext,
+        subscription: &mut TopicFilterAndSubscriptionOptions,
+    ) -> SubscribeVerdict {
+        for hook in &self.hooks {
+            if let refused @ SubscribeVerdict::Refuse(_) =
+                hook.on_subscribe(client, subscription).await
+            {
+                return refused;
+            }
+        }
+        SubscribeVerdict::Accept
+    }
+
+    pub(crate) async fn hook_deliver(
+        &self,
+        client: &ClientContext,
+        publish: &mut Publish,
+    ) -> MessageVerdict {
+        for hook in &self.hooks {
+            if hook.on_deliver(client, publish).await == MessageVerdict::Drop {
+                return MessageVerdict::Drop;
+            }
+        }
+        MessageVerdict::Forward
+    }
+
+    pub(crate) async fn hook_disconnect(&self, client: &ClientContext, reason: Option<&str>) {
+        for hook in &self.hooks {
+            hook.on_disconnect(client, reason).await;
+        }
+    }
+
+    pub(crate) async fn hook_session_expired(&self, client_id: &str) {
+        for hook in &self.hooks {
+            hook.on_session_expired(client_id).await;
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use crate::broker::Broker;
+    use crate::connection::Connection;
+    use crate::hook::{
+        BoxFuture, BrokerHook, ClientContext, ConnectVerdict, MessageVerdict, SubscribeVerdict,
+    };
+    use crate::proxy::ProxyProtocol;
+    use crate::store::{Change, MemoryStore, SessionStore};
+    use client::{Client, ConnectOptions, QoS, SUBACK};
+    use deser::packets::connect::Connect;
+    use deser::packets::publish::Publish;
+    use deser::packets::reason_codes::CONNECTACK;
+    use deser::packets::subscribe::TopicFilterAndSubscriptionOptions;
+    use deser::primitive_types::Utf8StringPair;
+    use deser::properties::Property;
+    use deser::ControlPacket;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+
+    fn user_property(key: &str, value: &str) -> Property {
+        Property::User(Utf8StringPair(key.to_string(), value.to_string()))
+    }
+
+    /// Validates and enriches messages, reroutes the legacy topics.
+    struct Validator;
+
+    impl BrokerHook for Validator {
+        fn on_connect<'a>(
+            &'a self,
+            client: &'a ClientContext,
+            _connect: &'a Connect,
+            properties: &'a mut Vec<Property>,
+        ) -> BoxFuture<'a, ConnectVerdict> {
+            Box::pin(async move {
+                if client.client_id == "banned" {
+                    return ConnectVerdict::Refuse(CONNECTACK::Banned);
+                }
+                properties.push(user_property("node", "edge-1"));
+                ConnectVerdict::Accept
+            })
+        }
+
+        fn on_publish<'a>(
+            &'a self,
+            _client: &'a ClientContext,
+            publish: &'a mut Publish,
+        ) -> BoxFuture<'a, MessageVerdict> {
+            Box::pin(async move {
+                let payload = publish.application_message.as_deref().unwrap_or_default();
+                if std::str::from_utf8(payload).is_err() {
+                    return MessageVerdict::Drop;
+                }
+                if let Some(topic) = publish.topic_name.strip_prefix("legacy/") {
+                    publish.topic_name = format!("current/{topic}");
+                }
+                publish
+                    .variable_header_properties
+                    .get_or_insert_with(Vec::new)
+                    .push(user_property("validated", "yes"));
+                MessageVerdict::Forward
+            })
+        }
+    }
+
+    /// Records what the previous hooks let through.
+    #[derive(Default)]
+    struct Audit(Mutex<Vec<String>>);
+
+    impl Audit {
+        fn record(&self, event: String) {
+            self.0.lock().unwrap().push(event);
+        }
+
+        fn events(&self) -> Vec<String> {
+            self.0.lock().unwrap().clone()
+        }
+    }
+
+    impl BrokerHook for Audit {
+        fn on_publish<'a>(
+            &'a self,
+            client: &'a ClientContext,
+            publish: &'a mut Publish,
+        ) -> BoxFuture<'a, MessageVerdict> {
+            Box::pin(async move {
+                self.record(format!(
+                    "{} published {}",
+                    client.client_id, publish.topic_name
+                ));
+                MessageVerdict::Forward
+            })
+        }
+
+        fn on_subscribe<'a>(
+            &'a self,
+            _client: &'a ClientContext,
+            subscription: &'a mut TopicFilterAndSubscriptionOptions,
+        ) -> BoxFuture<'a, SubscribeVerdict> {
+            Box::pin(async move {
+                match subscription.topic_filter.starts_with("secret/") {
+                    true => SubscribeVerdict::Refuse(SUBACK::NotAuthorized),
+                    false => SubscribeVerdict::Accept,
+                }
+            })
+        }
+
+        fn on_deliver<'a>(
+            &'a self,
+            _client: &'a ClientContext,
+            publish: &'a mut Publish,
+        ) -> BoxFuture<'a, MessageVerdict> {
+            Box::pin(async move {
+                match publish.topic_name.as_str() {
+                    "current/hidden" => MessageVerdict::Drop,
+                    _ => MessageVerdict::Forward,
+                }
+            })
+        }
+
+        fn on_disconnect<'a>(
+            &'a self,
+            client: &'a ClientContext,
+            reason: Option<&'a str>,
+        ) -> BoxFuture<'a, ()> {
+            Box::pin(async move {
+                self.record(format!("{} disconnected: {reason:?}", client.client_id));
+            })
+        }
+
+        fn on_session_expired<'a>(&'a self, client_id: &'a str) -> BoxFuture<'a, ()> {
+            Box::pin(async move { self.record(format!("{client_id} expired")) })
+        }
+    }
+
+    async fn connack(broker: &Arc<Broker>, client_id: &str) -> (u8, Option<Vec<Property>>) {
+        let (client, server) = tokio::io::duplex(4096);
+        tokio::spawn(broker.clone().handle(server));
+        let mut client = Connection::new(client);
+
+        let connect = Connect {
+            client_id: String::from(client_id),
+            ..Default::default()
+        };
+        client
+            .write_packet(&ControlPacket::Connect(connect))
+            .await
+            .unwrap();
+        match client.read_packet().await.unwrap() {
+            Some(ControlPacket::ConnAck(connack)) => (
+                connack.connect_reason_code,
+                connack.variable_header_properties,
+            ),
+            packet => panic!("expected CONNACK, received {packet:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn should_refuse_connections_and_add_connack_properties() {
+        let broker = Broker::builder().hook(Arc::new(Validator)).build();
+
+        let (reason_code, _) = connack(&broker, "banned").await;
+        assert_eq!(CONNECTACK::Banned as u8, reason_code);
+
+        let (reason_code, properties) = connack(&broker, "sensor").await;
+        assert_eq!(CONNECTACK::Success as u8, reason_code);
+        assert!(properties
+            .unwrap()
+            .contains(&user_property("node", "edge-1")));
+    }
+
+    #[tokio::test]
+    async fn should_run_hooks_in_order_on_messages() {
+        let audit = Arc::new(Audit::default());
+        let broker = Broker::builder()
+            .hook(Arc::new(Validator))
+            .hook(audit.clone())
+            .build();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(broker.serve(listener, ProxyProtocol::Reject));
+        let options = |client_id: &str| ConnectOptions::new(&addr).client_id(client_id);
+
+        let (subscriber, mut messages) = Client::connect(options("subscriber")).await.unwrap();
+        let granted = subscriber
+            .subscribe("current/#", QoS::AtLeastOnce)
+            .await
+            .unwrap();
+        assert_eq!(SUBACK::GrantedQos1, granted);
+        let refused = subscriber
+            .subscribe("secret/#", QoS::AtLeastOnce)
+            .await
+            .unwrap();
+        assert_eq!(SUBACK::NotAuthorized, refused);
+
+        let (publisher, _) = Client::connect(options("publisher")).await.unwrap();
+        for (topic, payload) in [
+            ("legacy/temperature", b"21.5".as_slice()),
+            ("current/binary", b"\xff\xfe"),
+            ("current/hidden", b"hidden"),
+            ("current/end", b"end"),
+        ] {
+            publisher
+                .publish(topic, QoS::AtLeastOnce, payload)
+                .await
+                .unwrap()
+                .await
+                .unwrap();
+        }
+
+        let message = messages.recv().await.unwrap();
+        assert_eq!("current/temperature", message.topic);
+        assert_eq!(vec![user_property("validated", "yes")], message.properties);
+        let message = messages.recv().await.unwrap();
+        assert_eq!("current/end", message.topic);
+
+        publisher.disconnect().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(
+            vec![
+                "publisher published current/temperature",
+                "publisher published current/hidden",
+                "publisher published current/end",
+                "publisher disconnected: None",
+            ],
+            audit.events()
+        );
+    }
+
+    #[tokio::test]
+    async fn should_tell_hooks_about_expired_sessions() {
+        let audit = Arc::new(Audit::default());
+        let broker = Broker::builder().hook(audit.clone()).build();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(broker.serve(listener, ProxyProtocol::Reject));
+
+        let options = ConnectOptions::new(&addr)
+            .client_id("sensor")
+            .session_expiry_interval(1);
+        let (sensor, _) = Client::connect(options).await.unwrap();
+        sensor.disconnect().await.unwrap();
+        // no other client connects: the sessions expire on their own
+        tokio::time::sleep(Duration::from_millis(2500)).await;
+        assert_eq!(
+            vec!["sensor disconnected: None", "sensor expired"],
+            audit.events()
+        );
+    }
+
+    #[tokio::test]
+    async fn should_expire_recovered_sessions_without_connections() {
+        let store = Arc::new(MemoryStore::default());
+        store
+            .apply(&Change::Session {
+                client_id: String::from("sensor"),
+                expiry_interval: 1,
+                disconnected_at: None,
+            })
+            .unwrap();
+        let audit = Arc::new(Audit::default());
+        let broker = Broker::builder().store(store).hook(audit.clone()).build();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        tokio::spawn(broker.serve(listener, ProxyProtocol::Reject));
+
+        tokio::time::sleep(Duration::from_millis(2500)).await;
+        assert_eq!(vec!["sensor expired"], audit.events());
+    }
+}
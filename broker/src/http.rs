@@ -0,0 +1,227 @@
+//! Minimal HTTP/1.1 server for the monitoring and administration endpoints of the broker.
+//!
+//! Each connection carries a single request, answered with `Connection: close`. Request heads
+//! are limited to 8 KiB and bodies to 1 MiB.
+
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tracing::{debug, warn};
+
+const MAX_HEAD_LENGTH: usize = 8 * 1024;
+const MAX_BODY_LENGTH: usize = 1024 * 1024;
+const MAX_HEADERS: usize = 64;
+
+#[derive(Error, Debug)]
+pub(crate) enum HttpError {
+    #[error("IO error {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Malformed request: {0}")]
+    Malformed(#[from] httparse::Error),
+    #[error("Connection closed before the end of the request")]
+    Incomplete,
+    #[error("Request too large")]
+    TooLarge,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Request {
+    pub(crate) method: String,
+    /// Path of the request target, without the query.
+    pub(crate) path: String,
+    pub(crate) query: Option<String>,
+    pub(crate) headers: Vec<(String, String)>,
+    pub(crate) body: Vec<u8>,
+}
+
+impl Request {
+    /// Value of the first header with this name, which is case insensitive.
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Response {
+    pub(crate) status: u16,
+    pub(crate) content_type: &'static str,
+    pub(crate) body: Vec<u8>,
+}
+
+impl Response {
+    pub(crate) fn new(status: u16, content_type: &'static str, body: impl Into<Vec<u8>>) -> Self {
+        Response {
+            status,
+            content_type,
+            body: body.into(),
+        }
+    }
+
+    pub(crate) fn text(status: u16, body: &str) -> Self {
+        Response::new(status, "text/plain; charset=utf-8", body)
+    }
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        413 => "Payload Too Large",
+        _ => "Internal Server Error",
+    }
+}
+
+/// Answers the requests of every connection with the handler until the listener fails. The
+/// handler runs on the blocking thread pool, it may hash passwords or touch files.
+pub(crate) async fn serve<H>(listener: TcpListener, handler: Arc<H>) -> std::io::Result<()>
+where
+    H: Fn(Request) -> Response + Send + Sync + 'static,
+{
+    loop {
+        let (mut socket, addr) = listener.accept().await?;
+
+        let handler = handler.clone();
+        tokio::spawn(async move {
+            let response = match read_request(&mut socket).await {
+                Ok(request) => {
+                    debug!("HTTP {} {} from {addr}", request.method, request.path);
+                    match tokio::task::spawn_blocking(move || handler(request)).await {
+                        Ok(response) => response,
+                        Err(e) => {
+                            warn!("HTTP handler for {addr} failed: {e}");
+                            Response::text(500, "internal server error\n")
+                        }
+                    }
+                }
+                Err(HttpError::TooLarge) => Response::text(413, "request too large\n"),
+                Err(HttpError::Malformed(e)) => Response::text(400, &format!("{e}\n")),
+                Err(e) => return warn!("HTTP request from {addr} failed: {e}"),
+            };
+            if let Err(e) = write_response(&mut socket, &response).await {
+                warn!("HTTP response to {addr} failed: {e}");
+            }
+        });
+    }
+}
+
+pub(crate) async fn read_request<T>(stream: &mut T) -> Result<Request, HttpError>
+where
+    T: AsyncRead + Unpin,
+{
+    let mut buffer = Vec::with_capacity(1024);
+    let (head_length, mut request) = loop {
+        if stream.read_buf(&mut buffer).await? == 0 {
+            return Err(HttpError::Incomplete);
+        }
+
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut parsed = httparse::Request::new(&mut headers);
+        if let httparse::Status::Complete(length) = parsed.parse(&buffer)? {
+            let target = parsed.path.unwrap_or("/");
+            let (path, query) = match target.split_once('?') {
+                Some((path, query)) => (path, Some(query.to_string())),
+                None => (target, None),
+            };
+            let request = Request {
+                method: parsed.method.unwrap_or_default().to_string(),
+                path: path.to_string(),
+                query,
+                headers: parsed
+                    .headers
+                    .iter()
+                    .map(|h| {
+                        (
+                            h.name.to_string(),
+                            String::from_utf8_lossy(h.value).into_owned(),
+                        )
+                    })
+                    .collect(),
+                body: vec![],
+            };
+            break (length, request);
+        }
+        if buffer.len() > MAX_HEAD_LENGTH {
+            return Err(HttpError::TooLarge);
+        }
+    };
+
+    let body_length = request
+        .header("Content-Length")
+        .and_then(|length| length.trim().parse::<usize>().ok())
+        .unwrap_or(0);
+    if body_length > MAX_BODY_LENGTH {
+        return Err(HttpError::TooLarge);
+    }
+    while buffer.len() < head_length + body_length {
+        if stream.read_buf(&mut buffer).await? == 0 {
+            return Err(HttpError::Incomplete);
+        }
+    }
+    request.body = buffer[head_length..head_length + body_length].to_vec();
+    Ok(request)
+}
+
+pub(crate) async fn write_response<T>(stream: &mut T, response: &Response) -> std::io::Result<()>
+where
+    T: AsyncWrite + Unpin,
+{
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        reason_phrase(response.status),
+        response.content_type,
+        response.body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(&response.body).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+pub mod test {
+    use crate::http::{read_request, write_response, Response};
+    use tokio::io::AsyncWriteExt;
+
+    #[tokio::test]
+    async fn should_read_request_split_over_several_writes() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        tokio::spawn(async move {
+            client
+                .write_all(b"POST /acl?reload=1 HTTP/1.1\r\nHost: localhost\r\n")
+                .await
+                .unwrap();
+            client
+                .write_all(b"content-length: 5\r\n\r\nhel")
+                .await
+                .unwrap();
+            client.write_all(b"lo").await.unwrap();
+        });
+
+        let request = read_request(&mut server).await.unwrap();
+        assert_eq!("POST", request.method);
+        assert_eq!("/acl", request.path);
+        assert_eq!(Some(String::from("reload=1")), request.query);
+        assert_eq!(Some("5"), request.header("Content-Length"));
+        assert_eq!(b"hello".to_vec(), request.body);
+
+        let mut written = vec![];
+        write_response(&mut written, &Response::text(404, "not found\n"))
+            .await
+            .unwrap();
+        assert_eq!(
+            "HTTP/1.1 404 Not Found\r\nContent-Type: text/plain; charset=utf-8\r\n\
+             Content-Length: 10\r\nConnection: close\r\n\r\nnot found\n",
+            String::from_utf8(written).unwrap()
+        );
+    }
+}
@@ -6,13 +6,36 @@
 
 use bytes::BytesMut;
 use std::error::Error;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 use tokio::net::{TcpListener, ToSocketAddrs};
-use tokio::sync::mpsc;
 use tokio::sync::mpsc::{Receiver, Sender};
 use tokio::task::JoinSet;
 
+pub mod acl;
+pub mod admin;
+pub mod auth;
+pub mod bridge;
+pub mod broker;
+pub mod cluster;
+pub mod connection;
+pub mod hook;
+mod http;
+pub mod limits;
+pub mod metrics;
+pub mod proxy;
+pub mod queue;
+mod router;
+pub mod rules;
+mod session;
+pub mod store;
+pub mod sys;
+pub mod tls;
+pub mod topic;
+#[cfg(unix)]
+pub mod unix;
+pub mod websocket;
+
 mod control_packets {
     use bytes::BytesMut;
     use deser::ControlPacket;
@@ -47,13 +70,32 @@ mod control_packets {
 pub async fn connection_listener(
     addr: impl ToSocketAddrs,
     from_mqtt_client_sender: Sender<BytesMut>,
-    mut bytes_to_mqtt_client_receiver: Receiver<BytesMut>,
+    bytes_to_mqtt_client_receiver: Receiver<BytesMut>,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
     let listener = TcpListener::bind(addr).await?;
-    let (tx, mut rx) = mpsc::channel::<BytesMut>(32);
 
     let (socket, _) = listener.accept().await?;
-    let (mut owned_read_half, mut owned_write_half) = socket.into_split();
+    stream_transport(
+        socket,
+        from_mqtt_client_sender,
+        bytes_to_mqtt_client_receiver,
+    )
+    .await
+}
+
+///
+/// Moves bytes between a client stream, TCP, TLS, Unix socket or any other, and the channels
+/// until either side closes.
+///
+pub async fn stream_transport<T>(
+    stream: T,
+    from_mqtt_client_sender: Sender<BytesMut>,
+    mut bytes_to_mqtt_client_receiver: Receiver<BytesMut>,
+) -> Result<(), Box<dyn Error + Send + Sync>>
+where
+    T: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (mut owned_read_half, mut owned_write_half) = tokio::io::split(stream);
 
     let mut set = JoinSet::new();
 
@@ -63,10 +105,14 @@ pub async fn connection_listener(
         loop {
             match owned_read_half.read_buf(&mut bytes_read).await {
                 Ok(n) if n > 0 => {
-                    from_mqtt_client_sender
-                        .send(bytes_read.clone())
+                    // hands over what was read and keeps reading into an empty buffer
+                    if from_mqtt_client_sender
+                        .send(bytes_read.split())
                         .await
-                        .unwrap();
+                        .is_err()
+                    {
+                        break;
+                    }
                 }
 
                 _ => break,
@@ -76,12 +122,9 @@ pub async fn connection_listener(
 
     set.spawn(async move {
         // Sends bytes to tcp stream
-        while let Some(mut bytes) = bytes_to_mqtt_client_receiver.recv().await {
-            match owned_write_half.write_buf(&mut bytes).await {
-                Ok(n) if n > 0 => {
-                    // no sure what todo here;
-                }
-                _ => break,
+        while let Some(bytes) = bytes_to_mqtt_client_receiver.recv().await {
+            if owned_write_half.write_all(&bytes).await.is_err() {
+                break;
             }
         }
 
@@ -94,9 +137,45 @@ pub async fn connection_listener(
 
 #[cfg(test)]
 mod tests {
+    use crate::stream_transport;
+    use bytes::BytesMut;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::sync::mpsc;
 
     #[test]
     fn it_works() {}
 
+    #[tokio::test]
+    async fn should_move_bytes_over_any_stream() {
+        let (from_mqtt_client_sender, mut from_mqtt_client_receiver) =
+            mpsc::channel::<BytesMut>(10);
+        let (to_mqtt_client_sender, to_mqtt_client_receiver) = mpsc::channel::<BytesMut>(10);
+        let (mut client, server) = tokio::io::duplex(64);
+        tokio::spawn(stream_transport(
+            server,
+            from_mqtt_client_sender,
+            to_mqtt_client_receiver,
+        ));
+
+        client.write_all(b"hello").await.unwrap();
+        assert_eq!(
+            b"hello".as_slice(),
+            from_mqtt_client_receiver.recv().await.unwrap()
+        );
+        client.write_all(b"again").await.unwrap();
+        assert_eq!(
+            b"again".as_slice(),
+            from_mqtt_client_receiver.recv().await.unwrap()
+        );
+
+        to_mqtt_client_sender
+            .send(BytesMut::from(&b"world"[..]))
+            .await
+            .unwrap();
+        let mut received = BytesMut::with_capacity(100);
+        client.read_buf(&mut received).await.unwrap();
+        assert_eq!(b"world".as_slice(), received);
+    }
+
     pub async fn should_return_rx_tx_channels_for_a_new_client_connection() {}
 }
@@ -0,0 +1,530 @@
+//! Limits keeping a single client from saturating the broker: the rate of connections from an
+//! address, the rate of messages and bytes a client sends, and the number of its subscriptions.
+//!
+//! Limits are set for the whole broker, and replaced for the clients of a listener or for a
+//! user. The limits of a user apply to its connections once authenticated, the rate of
+//! connections is only known per listener.
+
+use crate::broker::Broker;
+use crate::connection::ConnectionInfo;
+use crate::session::SessionError;
+use std::time::{Duration, Instant};
+
+/// Addresses whose rate of connections is remembered, per listener. Beyond that the idle ones
+/// are forgotten, then those that connected least recently.
+const MAX_TRACKED_ADDRESSES: usize = 4096;
+
+/// Longest a connection is held back, throttled connections beyond it are refused.
+const MAX_CONNECTION_DELAY: Duration = Duration::from_secs(5);
+
+/// What happens to a client going over a rate.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Violation {
+    /// Its packets are read later, until it is back within the rate. Connections are held back
+    /// before their CONNECT is read, and refused like with `Disconnect` when that would take
+    /// more than five seconds. Subscriptions beyond the limit are refused with SUBACK 0x97
+    /// Quota exceeded.
+    #[default]
+    Throttle,
+    /// It is disconnected with DISCONNECT 0x96 Message rate too high, or 0x97 Quota exceeded
+    /// for bytes and subscriptions. Connections are refused with CONNACK 0x9F Connection rate
+    /// exceeded.
+    Disconnect,
+}
+
+/// Every limit is unset by default.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Limits {
+    pub(crate) connections_per_second: Option<f64>,
+    pub(crate) messages_per_second: Option<f64>,
+    pub(crate) bytes_per_second: Option<f64>,
+    pub(crate) max_subscriptions: Option<usize>,
+    pub(crate) violation: Violation,
+}
+
+impl Limits {
+    /// Connections accepted from a single IP address, with bursts of as many and at least one.
+    pub fn connections_per_second(mut self, rate: f64) -> Self {
+        self.connections_per_second = Some(rate);
+        self
+    }
+
+    /// PUBLISH packets read from a client, with bursts of as many and at least one.
+    pub fn messages_per_second(mut self, rate: f64) -> Self {
+        self.messages_per_second = Some(rate);
+        self
+    }
+
+    /// Bytes of the packets read from a client, with bursts of as many and at least the
+    /// maximum packet size, so that any packet the broker accepts can get through.
+    pub fn bytes_per_second(mut self, rate: f64) -> Self {
+        self.bytes_per_second = Some(rate);
+        self
+    }
+
+    pub fn max_subscriptions(mut self, max_subscriptions: usize) -> Self {
+        self.max_subscriptions = Some(max_subscriptions);
+        self
+    }
+
+    pub fn violation(mut self, violation: Violation) -> Self {
+        self.violation = violation;
+        self
+    }
+}
+
+/// Tokens added at a steady rate, up to one second worth of them or the capacity when larger.
+/// The capacity is the largest amount taken at once, so that it can be taken without waiting
+/// whatever the rate.
+#[derive(Debug, Clone)]
+pub(crate) struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(rate: f64, capacity: f64) -> TokenBucket {
+        let capacity = capacity.max(rate);
+        TokenBucket {
+            rate,
+            capacity,
+            tokens: capacity,
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.updated = now;
+    }
+
+    /// Takes the tokens if there are enough of them.
+    pub(crate) fn try_take(&mut self, amount: f64) -> bool {
+        self.refill();
+        let available = self.tokens >= amount;
+        if available {
+            self.tokens -= amount;
+        }
+        available
+    }
+
+    /// Takes the tokens, borrowing the missing ones. Returns how long it takes to pay them back.
+    pub(crate) fn take(&mut self, amount: f64) -> Duration {
+        self.refill();
+        self.tokens -= amount;
+        match self.tokens < 0.0 {
+            true => Duration::from_secs_f64(-self.tokens / self.rate),
+            false => Duration::ZERO,
+        }
+    }
+
+    /// Takes the tokens like `take`, unless paying them back would take longer than
+    /// `max_delay`.
+    pub(crate) fn take_within(&mut self, amount: f64, max_delay: Duration) -> Option<Duration> {
+        self.refill();
+        match self.tokens - amount < -max_delay.as_secs_f64() * self.rate {
+            true => None,
+            false => Some(self.take(amount)),
+        }
+    }
+
+    /// Leaves the time tokens were last taken untouched.
+    fn is_full(&self) -> bool {
+        let elapsed = self.updated.elapsed().as_secs_f64();
+        self.tokens + elapsed * self.rate >= self.capacity
+    }
+}
+
+/// Rates of the packets read from a connected client.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ClientLimits {
+    limits: Limits,
+    messages: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+}
+
+impl ClientLimits {
+    pub(crate) fn new(limits: &Limits, maximum_packet_size: u32) -> ClientLimits {
+        ClientLimits {
+            limits: limits.clone(),
+            messages: limits
+                .messages_per_second
+                .map(|rate| TokenBucket::new(rate, 1.0)),
+            bytes: limits
+                .bytes_per_second
+                .map(|rate| TokenBucket::new(rate, maximum_packet_size as f64)),
+        }
+    }
+
+    /// Counts a packet read from the client. Returns how long to wait before reading the next
+    /// one, or the error to disconnect the client with.
+    pub(crate) fn received(
+        &mut self,
+        publish: bool,
+        size: usize,
+    ) -> Result<Duration, SessionError> {
+        let mut delay = Duration::ZERO;
+
+        if let Some(messages) = self.messages.as_mut().filter(|_| publish) {
+            match self.limits.violation {
+                Violation::Throttle => delay = delay.max(messages.take(1.0)),
+                Violation::Disconnect if !messages.try_take(1.0) => {
+                    return Err(SessionError::MessageRateTooHigh)
+                }
+                Violation::Disconnect => {}
+            }
+        }
+        if let Some(bytes) = &mut self.bytes {
+            match self.limits.violation {
+                Violation::Throttle => delay = delay.max(bytes.take(size as f64)),
+                Violation::Disconnect if !bytes.try_take(size as f64) => {
+                    return Err(SessionError::ByteRateExceeded)
+                }
+                Violation::Disconnect => {}
+            }
+        }
+
+        Ok(delay)
+    }
+
+    /// Whether the client may have one more subscription than the `current` ones. Refused
+    /// subscriptions disconnect the client when the limits say so.
+    pub(crate) fn may_subscribe(&self, current: usize) -> Result<bool, SessionError> {
+        match self.limits.max_subscriptions {
+            Some(max) if current >= max => match self.limits.violation {
+                Violation::Throttle => Ok(false),
+                Violation::Disconnect => Err(SessionError::TooManySubscriptions(max)),
+            },
+            _ => Ok(true),
+        }
+    }
+}
+
+impl Broker {
+    /// Limits of a client of the listener, authenticated as the user.
+    pub(crate) fn limits(&self, listener: &str, username: Option<&str>) -> &Limits {
+        username
+            .and_then(|username| self.user_limits.get(username))
+            .or_else(|| self.listener_limits.get(listener))
+            .unwrap_or(&self.limits)
+    }
+
+    /// Counts a connection against the rate of its address on the listener. Returns how long to
+    /// hold it back, or the error to refuse it with.
+    pub(crate) fn admit_connection(&self, info: &ConnectionInfo) -> Result<Duration, SessionError> {
+        let limits = self.limits(info.listener, None);
+        let (Some(rate), Some(addr)) = (limits.connections_per_second, info.peer_addr) else {
+            return Ok(Duration::ZERO);
+        };
+
+        let mut rates = self.connection_rates.lock().unwrap();
+        let key = (info.listener, addr.ip());
+        if !rates.contains_key(&key) && rates.len() >= MAX_TRACKED_ADDRESSES {
+            rates.retain(|_, bucket| !bucket.is_full());
+            if rates.len() >= MAX_TRACKED_ADDRESSES {
+                let least_recent = rates
+                    .iter()
+                    .min_by_key(|(_, bucket)| bucket.updated)
+                    .map(|(key, _)| *key);
+                if let Some(least_recent) = least_recent {
+                    rates.remove(&least_recent);
+                }
+            }
+        }
+        let bucket = rates
+            .entry(key)
+            .or_insert_with(|| TokenBucket::new(rate, 1.0));
+
+        let admitted = match limits.violation {
+            Violation::Throttle => bucket.take_within(1.0, MAX_CONNECTION_DELAY),
+            Violation::Disconnect => bucket.try_take(1.0).then_some(Duration::ZERO),
+        };
+        admitted.ok_or(SessionError::ConnectionRateExceeded(addr.ip()))
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use crate::broker::Broker;
+    use crate::connection::{Connection, ConnectionInfo};
+    use crate::limits::{ClientLimits, Limits, TokenBucket, Violation, MAX_TRACKED_ADDRESSES};
+    use crate::session::SessionError;
+    use deser::packets::connect::Connect;
+    use deser::packets::publish::Publish;
+    use deser::packets::reason_codes::{CONNECTACK, DISCONNECT, SUBACK};
+    use deser::packets::subscribe::{
+        Subscribe, SubscriptionOptions, TopicFilterAndSubscriptionOptions,
+    };
+    use deser::ControlPacket;
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::io::DuplexStream;
+
+    #[test]
+    pub fn should_borrow_tokens_when_throttling() {
+        let mut bucket = TokenBucket::new(10.0, 1.0);
+
+        assert!(bucket.try_take(6.0));
+        assert!(!bucket.try_take(6.0));
+        assert_eq!(Duration::ZERO, bucket.take(4.0));
+        let delay = bucket.take(5.0);
+        assert!(delay > Duration::from_millis(450) && delay <= Duration::from_millis(500));
+    }
+
+    #[test]
+    pub fn should_bound_connection_delay_per_listener_and_address() {
+        let broker = Broker::builder()
+            .limits(Limits::default().connections_per_second(1.0))
+            .build();
+        let info = |listener, ip: Ipv4Addr| ConnectionInfo {
+            peer_addr: Some(SocketAddr::new(IpAddr::V4(ip), 40000)),
+            listener,
+            ..Default::default()
+        };
+        let client = Ipv4Addr::new(192, 0, 2, 1);
+
+        let mut delays = vec![];
+        while let Ok(delay) = broker.admit_connection(&info("tcp", client)) {
+            delays.push(delay.as_secs_f64().round() as u64);
+        }
+        assert_eq!(vec![0, 1, 2, 3, 4, 5], delays);
+        assert!(matches!(
+            broker.admit_connection(&info("tcp", client)),
+            Err(SessionError::ConnectionRateExceeded(_))
+        ));
+        assert_eq!(
+            Duration::ZERO,
+            broker.admit_connection(&info("ws", client)).unwrap()
+        );
+
+        for i in 0..MAX_TRACKED_ADDRESSES as u32 + 10 {
+            let ip = Ipv4Addr::from(0x0a00_0000 + i);
+            broker.admit_connection(&info("tcp", ip)).unwrap();
+            broker.admit_connection(&info("tcp", ip)).unwrap();
+        }
+        let rates = broker.connection_rates.lock().unwrap();
+        assert_eq!(MAX_TRACKED_ADDRESSES, rates.len());
+        assert!(!rates.contains_key(&("tcp", IpAddr::V4(client))));
+    }
+
+    #[test]
+    pub fn should_count_messages_and_bytes() {
+        let limits = Limits::default()
+            .messages_per_second(2.0)
+            .bytes_per_second(100.0);
+
+        let mut throttled = ClientLimits::new(&limits, 64);
+        assert_eq!(Duration::ZERO, throttled.received(true, 10).unwrap());
+        assert_eq!(Duration::ZERO, throttled.received(false, 10).unwrap());
+        assert_eq!(Duration::ZERO, throttled.received(true, 10).unwrap());
+        assert!(throttled.received(true, 10).unwrap() > Duration::from_millis(400));
+
+        let mut disconnected = ClientLimits::new(&limits.violation(Violation::Disconnect), 64);
+        disconnected.received(true, 10).unwrap();
+        assert!(matches!(
+            disconnected.received(false, 95),
+            Err(SessionError::ByteRateExceeded)
+        ));
+        disconnected.received(true, 10).unwrap();
+        assert!(matches!(
+            disconnected.received(true, 10),
+            Err(SessionError::MessageRateTooHigh)
+        ));
+    }
+
+    #[test]
+    pub fn should_let_a_unit_through_at_fractional_rates() {
+        let limits = Limits::default()
+            .connections_per_second(0.5)
+            .messages_per_second(0.5)
+            .bytes_per_second(10.0)
+            .violation(Violation::Disconnect);
+        let broker = Broker::builder().limits(limits.clone()).build();
+        let info = ConnectionInfo {
+            peer_addr: Some("192.0.2.1:40000".parse().unwrap()),
+            listener: "tcp",
+            ..Default::default()
+        };
+
+        assert_eq!(Duration::ZERO, broker.admit_connection(&info).unwrap());
+        assert!(broker.admit_connection(&info).is_err());
+
+        let mut client = ClientLimits::new(&limits, 64);
+        assert_eq!(Duration::ZERO, client.received(true, 64).unwrap());
+        assert!(matches!(
+            client.received(true, 1),
+            Err(SessionError::MessageRateTooHigh)
+        ));
+    }
+
+    #[test]
+    pub fn should_prefer_user_then_listener_limits() {
+        let broker = Broker::builder()
+            .limits(Limits::default().max_subscriptions(10))
+            .listener_limits("ws", Limits::default().max_subscriptions(5))
+            .user_limits("gateway", Limits::default().max_subscriptions(1000))
+            .build();
+
+        assert_eq!(Some(10), broker.limits("tcp", None).max_subscriptions);
+        assert_eq!(
+            Some(10),
+            broker.limits("tcp", Some("alice")).max_subscriptions
+        );
+        assert_eq!(
+            Some(5),
+            broker.limits("ws", Some("alice")).max_subscriptions
+        );
+        assert_eq!(
+            Some(1000),
+            broker.limits("ws", Some("gateway")).max_subscriptions
+        );
+    }
+
+    async fn connect(broker: &Arc<Broker>, client_id: &str) -> (Connection<DuplexStream>, u8) {
+        let (client, server) = tokio::io::duplex(4096);
+        let info = ConnectionInfo {
+            peer_addr: Some("192.0.2.1:40000".parse().unwrap()),
+            listener: "tcp",
+            ..Default::default()
+        };
+        tokio::spawn(broker.clone().handle_connection(server, info));
+        let mut client = Connection::new(client);
+
+        let connect = Connect {
+            client_id: String::from(client_id),
+            ..Default::default()
+        };
+        client
+            .write_packet(&ControlPacket::Connect(connect))
+            .await
+            .unwrap();
+        match client.read_packet().await.unwrap() {
+            Some(ControlPacket::ConnAck(connack)) => (client, connack.connect_reason_code),
+            packet => panic!("expected CONNACK, received {packet:?}"),
+        }
+    }
+
+    fn subscribe(topic_filters: &[&str]) -> ControlPacket {
+        ControlPacket::Subscribe(Subscribe {
+            packet_id: 1,
+            topic_filters: topic_filters
+                .iter()
+                .map(|topic_filter| {
+                    TopicFilterAndSubscriptionOptions::new(
+                        topic_filter.to_string(),
+                        SubscriptionOptions { raw_value: 0 },
+                    )
+                })
+                .collect(),
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn should_refuse_connections_and_subscriptions_over_limits() {
+        let broker = Broker::builder()
+            .listener_limits(
+                "tcp",
+                Limits::default()
+                    .connections_per_second(2.0)
+                    .max_subscriptions(2)
+                    .violation(Violation::Disconnect),
+            )
+            .build();
+
+        let (_first, reason_code) = connect(&broker, "first").await;
+        assert_eq!(CONNECTACK::Success as u8, reason_code);
+        let (mut second, reason_code) = connect(&broker, "second").await;
+        assert_eq!(CONNECTACK::Success as u8, reason_code);
+        let (_, reason_code) = connect(&broker, "third").await;
+        assert_eq!(CONNECTACK::ConnectionRateExceeded as u8, reason_code);
+
+        second
+            .write_packet(&subscribe(&["a", "b", "a"]))
+            .await
+            .unwrap();
+        match second.read_packet().await.unwrap() {
+            Some(ControlPacket::SubAck(suback)) => assert_eq!(
+                vec![
+                    SUBACK::GrantedQos0,
+                    SUBACK::GrantedQos0,
+                    SUBACK::GrantedQos0
+                ],
+                suback.reason_codes
+            ),
+            packet => panic!("expected SUBACK, received {packet:?}"),
+        }
+        second.write_packet(&subscribe(&["c"])).await.unwrap();
+        match second.read_packet().await.unwrap() {
+            Some(ControlPacket::Disconnect(disconnect)) => {
+                assert_eq!(DISCONNECT::QuotaExceeded, disconnect.reason_code)
+            }
+            packet => panic!("expected DISCONNECT, received {packet:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn should_throttle_or_disconnect_fast_publishers() {
+        let broker = Broker::builder()
+            .limits(Limits::default().max_subscriptions(1))
+            .build();
+        let (mut client, _) = connect(&broker, "subscriber").await;
+        client.write_packet(&subscribe(&["a", "b"])).await.unwrap();
+        match client.read_packet().await.unwrap() {
+            Some(ControlPacket::SubAck(suback)) => assert_eq!(
+                vec![SUBACK::GrantedQos0, SUBACK::QuotaExceeded],
+                suback.reason_codes
+            ),
+            packet => panic!("expected SUBACK, received {packet:?}"),
+        }
+
+        let publish = |packet_id| {
+            ControlPacket::Publish(Publish {
+                topic_name: String::from("a"),
+                packet_type_low_nibble: 2,
+                packet_id: Some(packet_id),
+                application_message: Some(b"21.5".to_vec()),
+                ..Default::default()
+            })
+        };
+        let broker = Broker::builder()
+            .limits(Limits::default().messages_per_second(5.0))
+            .build();
+        let (mut client, _) = connect(&broker, "throttled").await;
+        let started = tokio::time::Instant::now();
+        for packet_id in 1..=8 {
+            client.write_packet(&publish(packet_id)).await.unwrap();
+        }
+        for _ in 1..=8 {
+            client.read_packet().await.unwrap();
+        }
+        // a burst of five, then one every 200ms
+        assert!(started.elapsed() >= Duration::from_millis(380));
+
+        let broker = Broker::builder()
+            .limits(
+                Limits::default()
+                    .messages_per_second(5.0)
+                    .violation(Violation::Disconnect),
+            )
+            .build();
+        let (mut client, _) = connect(&broker, "disconnected").await;
+        for packet_id in 1..=6 {
+            client.write_packet(&publish(packet_id)).await.unwrap();
+        }
+        for _ in 1..=5 {
+            client.read_packet().await.unwrap();
+        }
+        match client.read_packet().await.unwrap() {
+            Some(ControlPacket::Disconnect(disconnect)) => {
+                assert_eq!(DISCONNECT::MessageRateTooHigh, disconnect.reason_code)
+            }
+            packet => panic!("expected DISCONNECT, received {packet:?}"),
+        }
+    }
+}
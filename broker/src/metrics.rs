@@ -0,0 +1,512 @@
+//! Prometheus metrics, served in the text exposition format on `/metrics`.
+//!
+//! Counters and histograms are updated by the connections, sessions and router as they go.
+//! Gauges, such as the depth of the queue of each session, are read when the endpoint is
+//! scraped.
+
+use crate::broker::Broker;
+use crate::http;
+use crate::http::{Request, Response};
+use crate::sys::Traffic;
+use deser::codec::CodecError;
+use deser::packets::reason_codes::{CONNECTACK, DISCONNECT};
+use deser::packets::PacketTypes;
+use deser::ControlPacket;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+const PACKET_TYPES: [PacketTypes; 15] = [
+    PacketTypes::Connect,
+    PacketTypes::Connack,
+    PacketTypes::Publish,
+    PacketTypes::Puback,
+    PacketTypes::Pubrec,
+    PacketTypes::Pubrel,
+    PacketTypes::Pubcomp,
+    PacketTypes::Subscribe,
+    PacketTypes::Suback,
+    PacketTypes::Unsubscribe,
+    PacketTypes::Unsuback,
+    PacketTypes::Pingreq,
+    PacketTypes::Pingresp,
+    PacketTypes::Disconnect,
+    PacketTypes::Auth,
+];
+
+/// Subscribers a message is delivered to.
+const FAN_OUT_BUCKETS: &[f64] = &[0.0, 1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0, 500.0, 1000.0];
+/// Seconds from routing a message to handing it to the connection of a subscriber.
+const LATENCY_BUCKETS: &[f64] = &[
+    0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 30.0, 300.0,
+];
+
+/// Counters by label set, the labels being rendered once as `name="value",...`.
+#[derive(Debug, Default)]
+struct Labelled(Mutex<BTreeMap<String, u64>>);
+
+impl Labelled {
+    fn increment(&self, labels: String) {
+        *self.0.lock().unwrap().entry(labels).or_default() += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        header(out, name, help, "counter");
+        for (labels, value) in self.0.lock().unwrap().iter() {
+            let _ = writeln!(out, "{name}{{{labels}}} {value}");
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Histogram {
+    bounds: &'static [f64],
+    state: Mutex<HistogramState>,
+}
+
+#[derive(Debug, Default)]
+struct HistogramState {
+    /// Observations in each bucket, not cumulated.
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Histogram {
+        Histogram {
+            bounds,
+            state: Mutex::new(HistogramState {
+                counts: vec![0; bounds.len()],
+                ..Default::default()
+            }),
+        }
+    }
+
+    fn observe(&self, value: f64) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(bucket) = self.bounds.iter().position(|bound| value <= *bound) {
+            state.counts[bucket] += 1;
+        }
+        state.sum += value;
+        state.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        header(out, name, help, "histogram");
+        let state = self.state.lock().unwrap();
+        let mut cumulated = 0;
+        for (bound, count) in self.bounds.iter().zip(&state.counts) {
+            cumulated += count;
+            let _ = writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {cumulated}");
+        }
+        let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {}", state.count);
+        let _ = writeln!(out, "{name}_sum {}", state.sum);
+        let _ = writeln!(out, "{name}_count {}", state.count);
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Metrics {
+    pub(crate) traffic: Traffic,
+    /// Indexed by packet type.
+    packets_received: [AtomicU64; 16],
+    packets_sent: [AtomicU64; 16],
+    decode_errors: Labelled,
+    connections: Labelled,
+    auth_failures: Labelled,
+    disconnects: Labelled,
+    fan_out: Histogram,
+    delivery_latency: Histogram,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics {
+            traffic: Traffic::default(),
+            packets_received: Default::default(),
+            packets_sent: Default::default(),
+            decode_errors: Labelled::default(),
+            connections: Labelled::default(),
+            auth_failures: Labelled::default(),
+            disconnects: Labelled::default(),
+            fan_out: Histogram::new(FAN_OUT_BUCKETS),
+            delivery_latency: Histogram::new(LATENCY_BUCKETS),
+        }
+    }
+}
+
+impl Metrics {
+    pub(crate) fn received(&self, packet: &ControlPacket, size: usize) {
+        self.traffic
+            .received(size, matches!(packet, ControlPacket::Publish(_)));
+        self.packets_received[packet.packet_type() as usize].fetch_add(1, Ordering::Relaxed);
+        if let ControlPacket::Disconnect(disconnect) = packet {
+            self.disconnect("received", &disconnect.reason_code);
+        }
+    }
+
+    pub(crate) fn sent(&self, packet: &ControlPacket, size: usize) {
+        self.traffic
+            .sent(size, matches!(packet, ControlPacket::Publish(_)));
+        self.packets_sent[packet.packet_type() as usize].fetch_add(1, Ordering::Relaxed);
+        if let ControlPacket::Disconnect(disconnect) = packet {
+            self.disconnect("sent", &disconnect.reason_code);
+        }
+    }
+
+    fn disconnect(&self, direction: &str, reason_code: &DISCONNECT) {
+        self.disconnects.increment(format!(
+            "direction=\"{direction}\",reason_code=\"{reason_code:?}\""
+        ));
+    }
+
+    pub(crate) fn decode_error(&self, error: &CodecError) {
+        self.decode_errors
+            .increment(format!("error=\"{}\"", error.kind()));
+    }
+
+    pub(crate) fn connection(&self, listener: &str) {
+        self.connections
+            .increment(format!("listener=\"{}\"", escape(listener)));
+    }
+
+    pub(crate) fn auth_failure(&self, reason_code: &CONNECTACK) {
+        self.auth_failures
+            .increment(format!("reason_code=\"{reason_code:?}\""));
+    }
+
+    pub(crate) fn fan_out(&self, subscribers: usize) {
+        self.fan_out.observe(subscribers as f64);
+    }
+
+    pub(crate) fn delivery_latency(&self, latency: Duration) {
+        self.delivery_latency.observe(latency.as_secs_f64());
+    }
+
+    fn render(&self, out: &mut String) {
+        for (name, help, packets) in [
+            (
+                "mqtt_packets_received_total",
+                "Control packets received, by type.",
+                &self.packets_received,
+            ),
+            (
+                "mqtt_packets_sent_total",
+                "Control packets sent, by type.",
+                &self.packets_sent,
+            ),
+        ] {
+            header(out, name, help, "counter");
+            for packet_type in PACKET_TYPES {
+                let count = packets[packet_type as usize].load(Ordering::Relaxed);
+                let _ = writeln!(out, "{name}{{type=\"{packet_type:?}\"}} {count}");
+            }
+        }
+
+        let traffic = self.traffic.totals();
+        counter(
+            out,
+            "mqtt_bytes_received_total",
+            "Bytes of the control packets received.",
+            traffic.bytes_received,
+        );
+        counter(
+            out,
+            "mqtt_bytes_sent_total",
+            "Bytes of the control packets sent.",
+            traffic.bytes_sent,
+        );
+
+        self.decode_errors.render(
+            out,
+            "mqtt_decode_errors_total",
+            "Packets which could not be decoded, by error.",
+        );
+        self.connections.render(
+            out,
+            "mqtt_connections_total",
+            "Connections accepted, by listener.",
+        );
+        self.auth_failures.render(
+            out,
+            "mqtt_auth_failures_total",
+            "Connections refused by authentication, by CONNACK reason code.",
+        );
+        self.disconnects.render(
+            out,
+            "mqtt_disconnects_total",
+            "DISCONNECT packets, by direction and reason code.",
+        );
+        self.fan_out.render(
+            out,
+            "mqtt_publish_fan_out",
+            "Sessions each published message is queued for.",
+        );
+        self.delivery_latency.render(
+            out,
+            "mqtt_delivery_latency_seconds",
+            "Time from routing a message to sending it to a subscriber.",
+        );
+    }
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} {kind}");
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: u64) {
+    header(out, name, help, "counter");
+    let _ = writeln!(out, "{name} {value}");
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: impl std::fmt::Display) {
+    header(out, name, help, "gauge");
+    let _ = writeln!(out, "{name} {value}");
+}
+
+/// Escapes a label value.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+impl Broker {
+    /// Every metric in the Prometheus text exposition format.
+    pub fn render_metrics(&self) -> String {
+        let mut out = String::new();
+        self.metrics.render(&mut out);
+
+        let statistics = self.statistics();
+        let sessions = statistics.sessions;
+        gauge(
+            &mut out,
+            "mqtt_uptime_seconds",
+            "Seconds since the broker started.",
+            statistics.uptime.as_secs(),
+        );
+        gauge(
+            &mut out,
+            "mqtt_clients_connected",
+            "Clients connected.",
+            sessions.connected,
+        );
+        gauge(
+            &mut out,
+            "mqtt_sessions_disconnected",
+            "Sessions kept after their client disconnected.",
+            sessions.disconnected,
+        );
+        gauge(
+            &mut out,
+            "mqtt_subscriptions",
+            "Subscriptions of every session.",
+            sessions.subscriptions,
+        );
+        gauge(
+            &mut out,
+            "mqtt_retained_messages",
+            "Retained messages.",
+            sessions.retained,
+        );
+        gauge(
+            &mut out,
+            "mqtt_messages_inflight",
+            "QoS 1 and 2 messages sent and not completed yet.",
+            sessions.in_flight,
+        );
+
+        let mut queues = self.queue_stats();
+        queues.sort_by(|a, b| a.client_id.cmp(&b.client_id));
+        for (name, help, kind) in [
+            (
+                "mqtt_queue_messages",
+                "Messages queued for each session.",
+                "gauge",
+            ),
+            (
+                "mqtt_queue_bytes",
+                "Size of the messages queued for each session.",
+                "gauge",
+            ),
+            (
+                "mqtt_queue_dropped_total",
+                "Messages dropped because the queue of the session was full.",
+                "counter",
+            ),
+        ] {
+            header(&mut out, name, help, kind);
+            for queue in &queues {
+                let value = match name {
+                    "mqtt_queue_messages" => queue.messages as u64,
+                    "mqtt_queue_bytes" => queue.bytes as u64,
+                    _ => queue.dropped,
+                };
+                let _ = writeln!(
+                    out,
+                    "{name}{{client_id=\"{}\"}} {value}",
+                    escape(&queue.client_id)
+                );
+            }
+        }
+        out
+    }
+
+    /// Serves the metrics on `GET /metrics` until the listener fails.
+    pub async fn serve_metrics(self: Arc<Self>, listener: TcpListener) -> std::io::Result<()> {
+        let handler = move |request: Request| match (request.method.as_str(), request.path.as_str())
+        {
+            ("GET", "/metrics") => Response::new(
+                200,
+                "text/plain; version=0.0.4; charset=utf-8",
+                self.render_metrics(),
+            ),
+            (_, "/metrics") => Response::text(405, "method not allowed\n"),
+            _ => Response::text(404, "not found\n"),
+        };
+        http::serve(listener, Arc::new(handler)).await
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use crate::auth::password::AllowAnonymous;
+    use crate::broker::Broker;
+    use crate::connection::Connection;
+    use crate::metrics::{Histogram, FAN_OUT_BUCKETS};
+    use crate::proxy::ProxyProtocol;
+    use deser::packets::connect::Connect;
+    use deser::packets::publish::Publish;
+    use deser::packets::subscribe::{
+        Subscribe, SubscriptionOptions, TopicFilterAndSubscriptionOptions,
+    };
+    use deser::ControlPacket;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[test]
+    pub fn should_render_cumulative_buckets() {
+        let histogram = Histogram::new(FAN_OUT_BUCKETS);
+        for subscribers in [0, 1, 3, 2000] {
+            histogram.observe(subscribers as f64);
+        }
+
+        let mut out = String::new();
+        histogram.render(&mut out, "fan_out", "Fan out.");
+        assert!(out.contains("fan_out_bucket{le=\"0\"} 1\n"), "{out}");
+        assert!(out.contains("fan_out_bucket{le=\"5\"} 3\n"), "{out}");
+        assert!(out.contains("fan_out_bucket{le=\"1000\"} 3\n"), "{out}");
+        assert!(out.contains("fan_out_bucket{le=\"+Inf\"} 4\n"), "{out}");
+        assert!(out.contains("fan_out_sum 2004\nfan_out_count 4\n"), "{out}");
+    }
+
+    async fn connect(addr: SocketAddr, client_id: &str) -> Connection<TcpStream> {
+        let mut client = Connection::new(TcpStream::connect(addr).await.unwrap());
+        let connect = Connect {
+            client_id: String::from(client_id),
+            ..Default::default()
+        };
+        client
+            .write_packet(&ControlPacket::Connect(connect))
+            .await
+            .unwrap();
+        client.read_packet().await.unwrap();
+        client
+    }
+
+    async fn scrape(addr: SocketAddr) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn should_scrape_metrics_over_http() {
+        let broker = Broker::builder()
+            .password_backend(Arc::new(AllowAnonymous(true)))
+            .build();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mqtt_addr = listener.local_addr().unwrap();
+        tokio::spawn(broker.clone().serve(listener, ProxyProtocol::Reject));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let metrics_addr = listener.local_addr().unwrap();
+        tokio::spawn(broker.clone().serve_metrics(listener));
+
+        let mut subscriber = connect(mqtt_addr, "sub").await;
+        let subscribe = Subscribe {
+            packet_id: 1,
+            topic_filters: vec![TopicFilterAndSubscriptionOptions::new(
+                String::from("a/#"),
+                SubscriptionOptions { raw_value: 0 },
+            )],
+            ..Default::default()
+        };
+        subscriber
+            .write_packet(&ControlPacket::Subscribe(subscribe))
+            .await
+            .unwrap();
+        subscriber.read_packet().await.unwrap();
+
+        let mut publisher = connect(mqtt_addr, "pub").await;
+        publisher
+            .write_packet(&ControlPacket::Publish(Publish {
+                topic_name: String::from("a/b"),
+                application_message: Some(b"21.5".to_vec()),
+                ..Default::default()
+            }))
+            .await
+            .unwrap();
+        subscriber.read_packet().await.unwrap();
+
+        // refused by the password backend, which defers on user names
+        let mut refused = Connection::new(TcpStream::connect(mqtt_addr).await.unwrap());
+        let connect = Connect {
+            client_id: String::from("intruder"),
+            username: Some(String::from("intruder")),
+            connect_flags: 0b1000_0000,
+            ..Default::default()
+        };
+        refused
+            .write_packet(&ControlPacket::Connect(connect))
+            .await
+            .unwrap();
+        refused.read_packet().await.unwrap();
+
+        let response = scrape(metrics_addr).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        for line in [
+            "mqtt_packets_received_total{type=\"Publish\"} 1\n",
+            "mqtt_packets_sent_total{type=\"Publish\"} 1\n",
+            "mqtt_packets_sent_total{type=\"Suback\"} 1\n",
+            "mqtt_connections_total{listener=\"tcp\"} 3\n",
+            "mqtt_auth_failures_total{reason_code=\"NotAuthorised\"} 1\n",
+            "mqtt_publish_fan_out_bucket{le=\"1\"} 1\n",
+            "mqtt_delivery_latency_seconds_count 1\n",
+            "mqtt_clients_connected 2\n",
+            "mqtt_queue_messages{client_id=\"sub\"} 0\n",
+        ] {
+            assert!(response.contains(line), "{line} missing from {response}");
+        }
+
+        let mut stream = TcpStream::connect(metrics_addr).await.unwrap();
+        stream
+            .write_all(b"GET /other HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
@@ -0,0 +1,357 @@
+//! PROXY protocol v1 and v2, as sent by HAProxy and L4 load balancers.
+//!
+//! The proxy opens the TCP connection to the broker and sends a header with the address of the
+//! client before relaying the bytes of the client. Only the header is read here, the MQTT packets
+//! following it are left in the socket.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::net::TcpStream;
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// Longest v1 header, including the trailing CRLF.
+const V1_MAX_LENGTH: usize = 107;
+
+#[derive(Error, Debug)]
+pub enum ProxyError {
+    #[error("IO error {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Connection did not start with a PROXY protocol header")]
+    MissingHeader,
+    #[error("PROXY protocol header sent to a listener that does not accept it")]
+    UnexpectedHeader,
+    #[error("Malformed PROXY protocol header: {0}")]
+    Malformed(&'static str),
+    #[error("No PROXY protocol header received within {0:?}")]
+    Timeout(Duration),
+}
+
+/// Whether a listener expects a PROXY protocol header before the MQTT CONNECT.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ProxyProtocol {
+    /// Clients connect directly. Connections starting with a header are closed, so that a
+    /// misconfigured proxy is noticed instead of every client appearing to come from it.
+    #[default]
+    Reject,
+    /// Every connection comes through a proxy and must start with a v1 or v2 header.
+    Require,
+}
+
+/// Addresses carried by a header. They are None for v1 `UNKNOWN`, v2 `LOCAL` connections such as
+/// health checks of the proxy, and address families other than TCP over IPv4 and IPv6.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ProxyHeader {
+    pub source: Option<SocketAddr>,
+    pub destination: Option<SocketAddr>,
+}
+
+/// Handles the header of a freshly accepted connection and returns the address of the client,
+/// which is the peer address of the socket unless a proxy reported another one. A connection
+/// that sends nothing, or stops in the middle of the header, is given up after the timeout.
+pub(crate) async fn accept(
+    socket: &mut TcpStream,
+    peer_addr: SocketAddr,
+    mode: ProxyProtocol,
+    timeout: Duration,
+) -> Result<SocketAddr, ProxyError> {
+    tokio::time::timeout(timeout, accept_header(socket, peer_addr, mode))
+        .await
+        .map_err(|_| ProxyError::Timeout(timeout))?
+}
+
+async fn accept_header(
+    socket: &mut TcpStream,
+    peer_addr: SocketAddr,
+    mode: ProxyProtocol,
+) -> Result<SocketAddr, ProxyError> {
+    match mode {
+        ProxyProtocol::Reject => {
+            let mut first = [0; 1];
+            match socket.peek(&mut first).await? {
+                1 if first[0] == b'P' || first[0] == V2_SIGNATURE[0] => {
+                    Err(ProxyError::UnexpectedHeader)
+                }
+                _ => Ok(peer_addr),
+            }
+        }
+        ProxyProtocol::Require => {
+            let header = read_header(socket).await?;
+            Ok(header.source.unwrap_or(peer_addr))
+        }
+    }
+}
+
+/// Reads a v1 or v2 header, without reading past its end.
+pub async fn read_header<R: AsyncRead + Unpin>(stream: &mut R) -> Result<ProxyHeader, ProxyError> {
+    match stream.read_u8().await? {
+        b'P' => read_v1(stream).await,
+        first if first == V2_SIGNATURE[0] => read_v2(stream).await,
+        _ => Err(ProxyError::MissingHeader),
+    }
+}
+
+/// `PROXY TCP4 192.0.2.1 198.51.100.1 56324 1883\r\n`, the leading P already read.
+async fn read_v1<R: AsyncRead + Unpin>(stream: &mut R) -> Result<ProxyHeader, ProxyError> {
+    let mut line = vec![b'P'];
+    while !line.ends_with(b"\r\n") {
+        if line.len() == V1_MAX_LENGTH {
+            return Err(ProxyError::Malformed("v1 header longer than 107 bytes"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| ProxyError::Malformed("v1 header is not ASCII"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+
+    match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(ProxyHeader::default()),
+        ["PROXY", family @ ("TCP4" | "TCP6"), source, destination, source_port, destination_port] =>
+        {
+            let address = |ip: &str, port: &str| -> Result<SocketAddr, ProxyError> {
+                let ip: IpAddr = ip
+                    .parse()
+                    .map_err(|_| ProxyError::Malformed("invalid v1 address"))?;
+                let port: u16 = port
+                    .parse()
+                    .map_err(|_| ProxyError::Malformed("invalid v1 port"))?;
+                match (family, ip) {
+                    ("TCP4", IpAddr::V4(_)) | ("TCP6", IpAddr::V6(_)) => {
+                        Ok(SocketAddr::new(ip, port))
+                    }
+                    _ => Err(ProxyError::Malformed(
+                        "v1 address does not match the family",
+                    )),
+                }
+            };
+
+            Ok(ProxyHeader {
+                source: Some(address(source, source_port)?),
+                destination: Some(address(destination, destination_port)?),
+            })
+        }
+        _ => Err(ProxyError::Malformed(
+            "v1 header is not of the form PROXY <TCP4|TCP6|UNKNOWN> ...",
+        )),
+    }
+}
+
+/// Binary header, the first byte of the signature already read.
+async fn read_v2<R: AsyncRead + Unpin>(stream: &mut R) -> Result<ProxyHeader, ProxyError> {
+    let mut fixed = [0; 15];
+    stream.read_exact(&mut fixed).await?;
+    if fixed[..11] != V2_SIGNATURE[1..] {
+        return Err(ProxyError::MissingHeader);
+    }
+
+    let version_command = fixed[11];
+    let family = fixed[12];
+    let length = u16::from_be_bytes([fixed[13], fixed[14]]) as usize;
+    if version_command >> 4 != 2 {
+        return Err(ProxyError::Malformed("unsupported v2 version"));
+    }
+
+    // the addresses are followed by TLVs, which are skipped
+    let mut addresses = vec![0; length];
+    stream.read_exact(&mut addresses).await?;
+
+    match version_command & 0x0f {
+        // LOCAL, sent by the proxy itself
+        0x0 => return Ok(ProxyHeader::default()),
+        0x1 => {}
+        _ => return Err(ProxyError::Malformed("unsupported v2 command")),
+    }
+
+    match family {
+        // TCP over IPv4
+        0x11 => {
+            let a = addresses
+                .get(..12)
+                .ok_or(ProxyError::Malformed("v2 IPv4 addresses are truncated"))?;
+            let ip = |at: usize| Ipv4Addr::new(a[at], a[at + 1], a[at + 2], a[at + 3]);
+            let port = |at: usize| u16::from_be_bytes([a[at], a[at + 1]]);
+
+            Ok(ProxyHeader {
+                source: Some(SocketAddr::new(ip(0).into(), port(8))),
+                destination: Some(SocketAddr::new(ip(4).into(), port(10))),
+            })
+        }
+        // TCP over IPv6
+        0x21 => {
+            let a = addresses
+                .get(..36)
+                .ok_or(ProxyError::Malformed("v2 IPv6 addresses are truncated"))?;
+            let ip = |at: usize| {
+                let octets: [u8; 16] = a[at..at + 16].try_into().unwrap();
+                Ipv6Addr::from(octets)
+            };
+            let port = |at: usize| u16::from_be_bytes([a[at], a[at + 1]]);
+
+            Ok(ProxyHeader {
+                source: Some(SocketAddr::new(ip(0).into(), port(32))),
+                destination: Some(SocketAddr::new(ip(16).into(), port(34))),
+            })
+        }
+        _ => Ok(ProxyHeader::default()),
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use crate::auth::password::{BoxFuture, Credentials, PasswordBackend, PasswordCheck};
+    use crate::broker::Broker;
+    use crate::connection::Connection;
+    use crate::proxy::{read_header, ProxyError, ProxyHeader, ProxyProtocol, V2_SIGNATURE};
+    use deser::packets::connect::Connect;
+    use deser::packets::reason_codes::CONNECTACK;
+    use deser::ControlPacket;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    fn v2_ipv4(command: u8, tlvs: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x20 | command, 0x11]);
+        header.extend_from_slice(&(12 + tlvs.len() as u16).to_be_bytes());
+        header.extend_from_slice(&[192, 0, 2, 1, 198, 51, 100, 1]);
+        header.extend_from_slice(&56324u16.to_be_bytes());
+        header.extend_from_slice(&1883u16.to_be_bytes());
+        header.extend_from_slice(tlvs);
+        header
+    }
+
+    #[tokio::test]
+    async fn should_read_v1_header_and_leave_the_rest() {
+        let mut stream: &[u8] = b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 1883\r\n\x10";
+
+        let header = read_header(&mut stream).await.unwrap();
+        assert_eq!(Some("[2001:db8::1]:56324".parse().unwrap()), header.source);
+        assert_eq!(
+            Some("[2001:db8::2]:1883".parse().unwrap()),
+            header.destination
+        );
+        assert_eq!(b"\x10", stream);
+
+        let mut unknown: &[u8] = b"PROXY UNKNOWN\r\n";
+        assert_eq!(
+            ProxyHeader::default(),
+            read_header(&mut unknown).await.unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn should_read_v2_header_and_skip_tlvs() {
+        let mut wire = v2_ipv4(0x1, &[0x04, 0x00, 0x01, 0xff]);
+        wire.push(0x10);
+        let mut stream = wire.as_slice();
+
+        let header = read_header(&mut stream).await.unwrap();
+        assert_eq!(Some("192.0.2.1:56324".parse().unwrap()), header.source);
+        assert_eq!(
+            Some("198.51.100.1:1883".parse().unwrap()),
+            header.destination
+        );
+        assert_eq!(b"\x10", stream);
+
+        let local = v2_ipv4(0x0, &[]);
+        assert_eq!(
+            ProxyHeader::default(),
+            read_header(&mut local.as_slice()).await.unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn should_refuse_malformed_headers() {
+        let mut connect: &[u8] = b"\x10\x00";
+        assert!(matches!(
+            read_header(&mut connect).await,
+            Err(ProxyError::MissingHeader)
+        ));
+
+        let mut wrong_family: &[u8] = b"PROXY TCP4 2001:db8::1 192.0.2.1 1 2\r\n";
+        assert!(matches!(
+            read_header(&mut wrong_family).await,
+            Err(ProxyError::Malformed(_))
+        ));
+
+        let endless = [b'P'; 200];
+        assert!(matches!(
+            read_header(&mut endless.as_slice()).await,
+            Err(ProxyError::Malformed(_))
+        ));
+    }
+
+    /// Allows the clients connecting from the address.
+    struct AllowAddr(SocketAddr);
+
+    impl PasswordBackend for AllowAddr {
+        fn check<'a>(&'a self, credentials: &'a Credentials) -> BoxFuture<'a, PasswordCheck> {
+            Box::pin(async move {
+                match credentials.peer_addr == Some(self.0) {
+                    true => PasswordCheck::Allow,
+                    false => PasswordCheck::NotAuthorized,
+                }
+            })
+        }
+    }
+
+    async fn connect(addr: SocketAddr, header: &[u8]) -> Option<ControlPacket> {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(header).await.unwrap();
+
+        let mut client = Connection::new(stream);
+        client
+            .write_packet(&ControlPacket::Connect(Connect::default()))
+            .await
+            .unwrap();
+        client.read_packet().await.ok().flatten()
+    }
+
+    #[tokio::test]
+    async fn should_authenticate_the_client_address_reported_by_the_proxy() {
+        let broker = Broker::builder()
+            .password_backend(Arc::new(AllowAddr("192.0.2.1:56324".parse().unwrap())))
+            .build();
+        let require = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let require_addr = require.local_addr().unwrap();
+        let reject = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let reject_addr = reject.local_addr().unwrap();
+        tokio::spawn(broker.clone().serve(require, ProxyProtocol::Require));
+        tokio::spawn(broker.serve(reject, ProxyProtocol::Reject));
+
+        match connect(require_addr, &v2_ipv4(0x1, &[])).await {
+            Some(ControlPacket::ConnAck(connack)) => {
+                assert_eq!(CONNECTACK::Success as u8, connack.connect_reason_code)
+            }
+            packet => panic!("expected CONNACK, received {packet:?}"),
+        }
+        match connect(require_addr, b"PROXY UNKNOWN\r\n").await {
+            Some(ControlPacket::ConnAck(connack)) => {
+                assert_eq!(CONNECTACK::NotAuthorised as u8, connack.connect_reason_code)
+            }
+            packet => panic!("expected CONNACK, received {packet:?}"),
+        }
+        assert!(connect(require_addr, b"").await.is_none());
+        assert!(connect(reject_addr, &v2_ipv4(0x1, &[])).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn should_close_connections_without_header_after_the_timeout() {
+        let broker = Broker::builder()
+            .handshake_timeout(Duration::from_millis(50))
+            .build();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(broker.serve(listener, ProxyProtocol::Require));
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"PROXY TCP4").await.unwrap();
+        let mut rest = vec![];
+        let read = tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut rest));
+        assert_eq!(0, read.await.unwrap().unwrap());
+    }
+}
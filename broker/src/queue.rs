@@ -0,0 +1,86 @@
+//! Limits of the queue of messages waiting to be sent to each session.
+//!
+//! Messages are queued while the client is connected, until the Receive Maximum it announced
+//! lets them through, and while it is disconnected if its session outlives the connection.
+
+use deser::packets::publish::Publish;
+
+/// What happens to a message routed to a session whose queue is full.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// The oldest queued messages are dropped to make room for it.
+    DropOldest,
+    /// The message is dropped for this session.
+    #[default]
+    DropNewest,
+    /// The message is dropped for this session and its publisher is disconnected with
+    /// DISCONNECT 0x97 Quota exceeded.
+    DisconnectPublisher,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueueConfig {
+    pub(crate) max_messages: usize,
+    pub(crate) max_bytes: usize,
+    pub(crate) include_qos0: bool,
+    pub(crate) overflow: OverflowPolicy,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        QueueConfig {
+            max_messages: 1024,
+            max_bytes: usize::MAX,
+            include_qos0: false,
+            overflow: OverflowPolicy::default(),
+        }
+    }
+}
+
+impl QueueConfig {
+    /// Messages a queue holds, 1024 by default.
+    pub fn max_messages(mut self, max_messages: usize) -> Self {
+        self.max_messages = max_messages;
+        self
+    }
+
+    /// Sum of the topic name and payload sizes of the messages a queue holds, unlimited by
+    /// default.
+    pub fn max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    /// Queues QoS 0 messages for disconnected sessions too. Connected sessions always get them.
+    pub fn include_qos0(mut self, include_qos0: bool) -> Self {
+        self.include_qos0 = include_qos0;
+        self
+    }
+
+    pub fn overflow(mut self, overflow: OverflowPolicy) -> Self {
+        self.overflow = overflow;
+        self
+    }
+
+    /// Whether a queue holding `messages` messages of `bytes` bytes has room for one more of
+    /// `size` bytes.
+    pub(crate) fn has_room(&self, messages: usize, bytes: usize, size: usize) -> bool {
+        messages < self.max_messages && bytes.saturating_add(size) <= self.max_bytes
+    }
+}
+
+/// Size of a message counted against `QueueConfig::max_bytes`.
+pub(crate) fn message_size(publish: &Publish) -> usize {
+    publish.topic_name.len() + publish.application_message.as_ref().map_or(0, Vec::len)
+}
+
+/// Queue of a session, for metrics.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueueStats {
+    pub client_id: String,
+    pub connected: bool,
+    pub messages: usize,
+    pub bytes: usize,
+    /// Messages dropped because the queue was full since the broker started.
+    pub dropped: u64,
+}
@@ -0,0 +1,816 @@
+use crate::admin::SessionInfo;
+use crate::cluster::MovedSession;
+use crate::metrics::Metrics;
+use crate::queue::{message_size, OverflowPolicy, QueueConfig, QueueStats};
+use crate::store::{Change, SessionStore, StoredState};
+use crate::sys::SessionTotals;
+use crate::topic;
+use deser::packets::publish::Publish;
+use deser::packets::subscribe::TopicFilterAndSubscriptionOptions;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::{mpsc, Notify};
+use tracing::warn;
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Sessions, their subscriptions and messages, and retained messages. Every modification goes
+/// through `change`, which hands it to the store.
+///
+/// Expired sessions are removed when the router is created, whenever a client connects, and by
+/// the broker every second.
+pub(crate) struct Router {
+    state: StoredState,
+    store: Option<Arc<dyn SessionStore>>,
+    /// Notifies a connected session that messages were queued for it, with the identifier of
+    /// its connection.
+    connections: HashMap<String, (u64, mpsc::Sender<()>)>,
+    next_connection_id: u64,
+    queue: QueueConfig,
+    /// Messages dropped from the queue of each session because it was full.
+    dropped: HashMap<String, u64>,
+    /// When each queued message was routed, unknown for messages recovered from the store.
+    queued_at: HashMap<String, VecDeque<Option<Instant>>>,
+    metrics: Arc<Metrics>,
+    /// Sessions removed because they expired, since `take_expired` was last called.
+    expired: Vec<String>,
+    /// Notified whenever the topic filters of the sessions may have changed.
+    interest: Arc<Notify>,
+}
+
+impl Default for Router {
+    fn default() -> Self {
+        Router::new(None, QueueConfig::default())
+    }
+}
+
+impl Router {
+    /// Recovers the state of the store. Sessions that were connected when the previous broker
+    /// stopped are disconnected as of now.
+    pub(crate) fn new(store: Option<Arc<dyn SessionStore>>, queue: QueueConfig) -> Router {
+        let mut router = Router {
+            state: store.as_ref().map(|s| s.load()).unwrap_or_default(),
+            store,
+            connections: HashMap::new(),
+            next_connection_id: 0,
+            queue,
+            dropped: HashMap::new(),
+            queued_at: HashMap::new(),
+            metrics: Arc::new(Metrics::default()),
+            expired: vec![],
+            interest: Arc::new(Notify::new()),
+        };
+        router.queued_at = router
+            .state
+            .sessions
+            .iter()
+            .map(|(client_id, session)| {
+                (client_id.clone(), vec![None; session.queued.len()].into())
+            })
+            .collect();
+
+        let now = now();
+        let connected: Vec<(String, u32)> = router
+            .state
+            .sessions
+            .iter()
+            .filter(|(_, session)| session.disconnected_at.is_none())
+            .map(|(client_id, session)| (client_id.clone(), session.expiry_interval))
+            .collect();
+        for (client_id, expiry_interval) in connected {
+            router.change(Change::Session {
+                client_id,
+                expiry_interval,
+                disconnected_at: Some(now),
+            });
+        }
+        router.remove_expired(now);
+
+        router
+    }
+
+    /// Records the fan-out and delivery latency in these metrics.
+    pub(crate) fn metrics(mut self, metrics: Arc<Metrics>) -> Router {
+        self.metrics = metrics;
+        self
+    }
+
+    fn change(&mut self, change: Change) {
+        match &change {
+            Change::SessionRemoved(client_id) => {
+                self.dropped.remove(client_id);
+                self.queued_at.remove(client_id);
+            }
+            Change::Queued(client_id, _) => self
+                .queued_at
+                .entry(client_id.clone())
+                .or_default()
+                .push_back(Some(Instant::now())),
+            Change::Dequeued(client_id) => {
+                if let Some(queued_at) = self.queued_at.get_mut(client_id) {
+                    queued_at.pop_front();
+                }
+            }
+            _ => {}
+        }
+        if matches!(
+            change,
+            Change::Subscribed(..) | Change::Unsubscribed(..) | Change::SessionRemoved(_)
+        ) {
+            self.interest.notify_one();
+        }
+        self.state.apply(&change);
+        if let Some(store) = &self.store {
+            if let Err(e) = store.apply(&change) {
+                warn!("failed to store {change:?}: {e}");
+            }
+        }
+    }
+
+    fn remove_expired(&mut self, now: u64) {
+        let expired: Vec<String> = self
+            .state
+            .sessions
+            .iter()
+            .filter(|(_, session)| session.is_expired(now))
+            .map(|(client_id, _)| client_id.clone())
+            .collect();
+        for client_id in expired {
+            self.change(Change::SessionRemoved(client_id.clone()));
+            self.expired.push(client_id);
+        }
+    }
+
+    pub(crate) fn store(&self) -> Option<Arc<dyn SessionStore>> {
+        self.store.clone()
+    }
+
+    pub(crate) fn take_expired(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.expired)
+    }
+
+    /// Removes the sessions that expired by now, returning all those removed since
+    /// `take_expired` was last called.
+    pub(crate) fn expire(&mut self) -> Vec<String> {
+        self.remove_expired(now());
+        self.take_expired()
+    }
+
+    /// Registers the notification channel of a session and returns the identifier of the
+    /// connection, and whether an existing session was resumed. A session already connected
+    /// with the same client identifier loses its channel, which tells it that it has been
+    /// taken over.
+    pub(crate) fn connect(
+        &mut self,
+        client_id: &str,
+        notifier: mpsc::Sender<()>,
+        clean_start: bool,
+        expiry_interval: u32,
+    ) -> (u64, bool) {
+        self.remove_expired(now());
+        if clean_start && self.state.sessions.contains_key(client_id) {
+            self.change(Change::SessionRemoved(client_id.to_string()));
+        }
+
+        let session_present = self.state.sessions.contains_key(client_id);
+        self.change(Change::Session {
+            client_id: client_id.to_string(),
+            expiry_interval,
+            disconnected_at: None,
+        });
+
+        self.next_connection_id += 1;
+        self.connections
+            .insert(client_id.to_string(), (self.next_connection_id, notifier));
+        (self.next_connection_id, session_present)
+    }
+
+    /// Ends the session, or keeps it until it expires, unless another connection took it over.
+    pub(crate) fn disconnect(&mut self, client_id: &str, connection_id: u64) {
+        match self.connections.get(client_id) {
+            Some((current, _)) if *current == connection_id => {
+                self.connections.remove(client_id);
+            }
+            _ => return,
+        }
+
+        let expiry_interval = match self.state.sessions.get(client_id) {
+            Some(session) => session.expiry_interval,
+            None => return,
+        };
+        match expiry_interval {
+            0 => self.change(Change::SessionRemoved(client_id.to_string())),
+            _ => self.change(Change::Session {
+                client_id: client_id.to_string(),
+                expiry_interval,
+                disconnected_at: Some(now()),
+            }),
+        }
+    }
+
+    /// Adds the subscription, replacing one with the same topic filter, and queues the retained
+    /// messages it asks for. Returns whether a subscription was replaced.
+    pub(crate) fn subscribe(
+        &mut self,
+        client_id: &str,
+        subscription: TopicFilterAndSubscriptionOptions,
+    ) -> bool {
+        let existed = self.state.sessions.get(client_id).is_some_and(|session| {
+            session
+                .subscriptions
+                .iter()
+                .any(|s| s.topic_filter == subscription.topic_filter)
+        });
+
+        let send_retained = match subscription.retain_handling() {
+            0 => true,
+            1 => !existed,
+            _ => false,
+        };
+        if send_retained {
+            let retained: Vec<Publish> = self
+                .state
+                .retained
+                .values()
+                .filter(|publish| topic::matches(&subscription.topic_filter, &publish.topic_name))
+                .map(|publish| Publish {
+                    packet_type_low_nibble: (publish.qos_number().min(subscription.qos()) << 1) | 1,
+                    ..publish.clone()
+                })
+                .collect();
+            for publish in retained {
+                self.queue(client_id, publish);
+            }
+        }
+
+        self.change(Change::Subscribed(client_id.to_string(), subscription));
+        existed
+    }
+
+    /// Subscriptions of the session, none without a session.
+    pub(crate) fn subscriptions(&self, client_id: &str) -> &[TopicFilterAndSubscriptionOptions] {
+        self.state
+            .sessions
+            .get(client_id)
+            .map_or(&[], |session| &session.subscriptions)
+    }
+
+    /// Returns whether the subscription existed.
+    pub(crate) fn unsubscribe(&mut self, client_id: &str, topic_filter: &str) -> bool {
+        let existed = self.state.sessions.get(client_id).is_some_and(|session| {
+            session
+                .subscriptions
+                .iter()
+                .any(|s| s.topic_filter == topic_filter)
+        });
+
+        if existed {
+            self.change(Change::Unsubscribed(
+                client_id.to_string(),
+                topic_filter.to_string(),
+            ));
+        }
+        existed
+    }
+
+    /// Keeps or clears the retained message of the topic, then queues the message for every
+    /// session with a matching subscription. A session with overlapping subscriptions receives
+    /// the message once, at the highest granted QoS. Disconnected sessions only get QoS 0
+    /// messages when the queue configuration includes them.
+    ///
+    /// Returns false when a full queue requires the publisher to be disconnected.
+    pub(crate) fn route(&mut self, publisher: &str, publish: &Publish) -> bool {
+        if publish.retain() {
+            match publish.application_message.as_deref() {
+                None | Some([]) => {
+                    if self.state.retained.contains_key(&publish.topic_name) {
+                        self.change(Change::RetainedRemoved(publish.topic_name.clone()));
+                    }
+                }
+                Some(_) => self.change(Change::Retained(Publish {
+                    packet_type_low_nibble: publish.packet_type_low_nibble & 0b0111,
+                    packet_id: None,
+                    ..publish.clone()
+                })),
+            }
+        }
+
+        let qos = publish.qos_number();
+        let mut deliveries = vec![];
+
+        for (client_id, session) in &self.state.sessions {
+            let matching = session.subscriptions.iter().filter(|s| {
+                topic::matches(&s.topic_filter, &publish.topic_name)
+                    && !(s.no_local() && client_id == publisher)
+            });
+            let subscription = match matching.max_by_key(|s| s.qos()) {
+                Some(subscription) => subscription,
+                None => continue,
+            };
+
+            let qos = qos.min(subscription.qos());
+            if qos == 0 && !self.queue.include_qos0 && !self.connections.contains_key(client_id) {
+                continue;
+            }
+
+            let retain = subscription.retain_as_published() && publish.retain();
+            deliveries.push((
+                client_id.clone(),
+                Publish {
+                    packet_type_low_nibble: (qos << 1) | retain as u8,
+                    topic_name: publish.topic_name.clone(),
+                    variable_header_properties: publish.variable_header_properties.clone(),
+                    application_message: publish.application_message.clone(),
+                    ..Default::default()
+                },
+            ));
+        }
+
+        self.metrics.fan_out(deliveries.len());
+        deliveries
+            .into_iter()
+            .fold(true, |accepted, (client_id, delivery)| {
+                self.queue(&client_id, delivery) && accepted
+            })
+    }
+
+    /// Applies the overflow policy when the queue is full. Returns false when the publisher must
+    /// be disconnected.
+    fn queue(&mut self, client_id: &str, publish: Publish) -> bool {
+        let size = message_size(&publish);
+
+        loop {
+            let session = match self.state.sessions.get(client_id) {
+                Some(session) => session,
+                None => return true,
+            };
+            if self
+                .queue
+                .has_room(session.queued.len(), session.queued_bytes, size)
+            {
+                break;
+            }
+
+            *self.dropped.entry(client_id.to_string()).or_default() += 1;
+            if self.queue.overflow != OverflowPolicy::DropOldest || session.queued.is_empty() {
+                warn!("queue of client {client_id} is full, dropping message");
+                return self.queue.overflow != OverflowPolicy::DisconnectPublisher;
+            }
+            warn!("queue of client {client_id} is full, dropping its oldest message");
+            self.change(Change::Dequeued(client_id.to_string()));
+        }
+
+        self.change(Change::Queued(client_id.to_string(), publish));
+        if let Some((_, notifier)) = self.connections.get(client_id) {
+            // a full channel already holds a notification
+            let _ = notifier.try_send(());
+        }
+        true
+    }
+
+    pub(crate) fn queue_stats(&self) -> Vec<QueueStats> {
+        self.state
+            .sessions
+            .iter()
+            .map(|(client_id, session)| QueueStats {
+                client_id: client_id.clone(),
+                connected: self.connections.contains_key(client_id),
+                messages: session.queued.len(),
+                bytes: session.queued_bytes,
+                dropped: self.dropped.get(client_id).copied().unwrap_or(0),
+            })
+            .collect()
+    }
+
+    pub(crate) fn session_totals(&self) -> SessionTotals {
+        let sessions = self.state.sessions.values();
+        SessionTotals {
+            connected: self.connections.len(),
+            disconnected: self.state.sessions.len() - self.connections.len(),
+            subscriptions: sessions.clone().map(|s| s.subscriptions.len()).sum(),
+            retained: self.state.retained.len(),
+            in_flight: sessions
+                .clone()
+                .map(|s| s.outgoing.len() + s.released.len())
+                .sum(),
+            queued: sessions.map(|s| s.queued.len()).sum(),
+        }
+    }
+
+    pub(crate) fn sessions(&self) -> Vec<SessionInfo> {
+        let mut sessions: Vec<SessionInfo> = self
+            .state
+            .sessions
+            .keys()
+            .filter_map(|client_id| self.session(client_id))
+            .collect();
+        sessions.sort_by(|a, b| a.client_id.cmp(&b.client_id));
+        sessions
+    }
+
+    pub(crate) fn session(&self, client_id: &str) -> Option<SessionInfo> {
+        let session = self.state.sessions.get(client_id)?;
+        Some(SessionInfo {
+            client_id: client_id.to_string(),
+            connected: self.connections.contains_key(client_id),
+            expiry_interval: session.expiry_interval,
+            disconnected_at: session.disconnected_at,
+            subscriptions: session.subscriptions.clone(),
+            queued: session.queued.len(),
+            queued_bytes: session.queued_bytes,
+            in_flight: session.outgoing.len() + session.released.len(),
+            dropped: self.dropped.get(client_id).copied().unwrap_or(0),
+        })
+    }
+
+    /// Removes the session with its subscriptions and messages. A connected client keeps its
+    /// notification channel until it disconnects, which finds no session left to keep.
+    pub(crate) fn remove_session(&mut self, client_id: &str) -> bool {
+        let existed = self.state.sessions.contains_key(client_id);
+        if existed {
+            self.change(Change::SessionRemoved(client_id.to_string()));
+        }
+        existed
+    }
+
+    /// Topic filters of the subscriptions of every session.
+    pub(crate) fn topic_filters(&self) -> BTreeSet<String> {
+        self.state
+            .sessions
+            .values()
+            .flat_map(|session| &session.subscriptions)
+            .map(|subscription| subscription.topic_filter.clone())
+            .collect()
+    }
+
+    pub(crate) fn interest(&self) -> Arc<Notify> {
+        self.interest.clone()
+    }
+
+    /// Removes the session for another node of the cluster, closing its connection as taken
+    /// over. The messages in flight come first in the messages returned, to be sent again.
+    pub(crate) fn take_session(&mut self, client_id: &str) -> Option<MovedSession> {
+        self.connections.remove(client_id);
+        let session = self.state.sessions.get(client_id)?;
+        let moved = MovedSession {
+            subscriptions: session.subscriptions.clone(),
+            messages: session
+                .outgoing
+                .values()
+                .map(|publish| Publish {
+                    packet_type_low_nibble: publish.packet_type_low_nibble & 0b0111,
+                    packet_id: None,
+                    ..publish.clone()
+                })
+                .chain(session.queued.iter().cloned())
+                .collect(),
+        };
+        self.change(Change::SessionRemoved(client_id.to_string()));
+        Some(moved)
+    }
+
+    /// Adds the subscriptions and messages of a session taken from another node.
+    pub(crate) fn adopt_session(&mut self, client_id: &str, moved: MovedSession) {
+        for subscription in moved.subscriptions {
+            self.change(Change::Subscribed(client_id.to_string(), subscription));
+        }
+        for publish in moved.messages {
+            self.queue(client_id, publish);
+        }
+    }
+
+    /// Retained messages, by topic name.
+    pub(crate) fn retained(&self) -> Vec<Publish> {
+        self.state.retained.values().cloned().collect()
+    }
+
+    /// Removes the retained messages of the topics matching the filter and returns how many.
+    pub(crate) fn clear_retained(&mut self, topic_filter: &str) -> usize {
+        let topics: Vec<String> = self
+            .state
+            .retained
+            .keys()
+            .filter(|topic_name| topic::matches(topic_filter, topic_name))
+            .cloned()
+            .collect();
+        for topic_name in &topics {
+            self.change(Change::RetainedRemoved(topic_name.clone()));
+        }
+        topics.len()
+    }
+
+    /// Takes the next queued message, giving it a packet identifier if its QoS needs one.
+    /// QoS 1 and 2 messages stay queued while the client has `receive_maximum` of them in
+    /// flight.
+    pub(crate) fn next_delivery(
+        &mut self,
+        client_id: &str,
+        receive_maximum: u16,
+    ) -> Option<Publish> {
+        let session = self.state.sessions.get(client_id)?;
+        let mut publish = session.queued.front()?.clone();
+
+        if publish.qos_number() > 0 {
+            if session.outgoing.len() + session.released.len() >= receive_maximum as usize {
+                return None;
+            }
+
+            let mut packet_id = session.last_packet_id;
+            loop {
+                packet_id = packet_id.checked_add(1).unwrap_or(1);
+                if !session.outgoing.contains_key(&packet_id)
+                    && !session.released.contains(&packet_id)
+                {
+                    break;
+                }
+            }
+            publish.packet_id = Some(packet_id);
+            self.change(Change::Sent(client_id.to_string(), publish.clone()));
+        }
+
+        if let Some(Some(queued_at)) = self.queued_at.get(client_id).and_then(|q| q.front()) {
+            self.metrics.delivery_latency(queued_at.elapsed());
+        }
+        self.change(Change::Dequeued(client_id.to_string()));
+        Some(publish)
+    }
+
+    /// Messages sent to the client before it reconnected and not acknowledged yet, to send
+    /// again, and PUBREL to send again.
+    pub(crate) fn in_flight(&self, client_id: &str) -> (Vec<Publish>, Vec<u16>) {
+        match self.state.sessions.get(client_id) {
+            Some(session) => (
+                session.outgoing.values().cloned().collect(),
+                session.released.iter().copied().collect(),
+            ),
+            None => (vec![], vec![]),
+        }
+    }
+
+    /// PUBACK received.
+    pub(crate) fn acknowledge(&mut self, client_id: &str, packet_id: u16) {
+        self.change(Change::Acknowledged(client_id.to_string(), packet_id));
+    }
+
+    /// PUBREC received.
+    pub(crate) fn release(&mut self, client_id: &str, packet_id: u16) {
+        self.change(Change::Released(client_id.to_string(), packet_id));
+    }
+
+    /// PUBCOMP received.
+    pub(crate) fn complete(&mut self, client_id: &str, packet_id: u16) {
+        self.change(Change::Completed(client_id.to_string(), packet_id));
+    }
+
+    /// Completes a message taken with `next_delivery`, as if the client acknowledged it.
+    pub(crate) fn delivered(&mut self, client_id: &str, publish: &Publish) {
+        if let Some(packet_id) = publish.packet_id {
+            match publish.qos_number() {
+                1 => self.acknowledge(client_id, packet_id),
+                _ => {
+                    self.release(client_id, packet_id);
+                    self.complete(client_id, packet_id);
+                }
+            }
+        }
+    }
+
+    /// Records a QoS 2 message received from the client. Returns false when the message was
+    /// already received, and must not be routed again.
+    pub(crate) fn receive(&mut self, client_id: &str, packet_id: u16) -> bool {
+        let received = self
+            .state
+            .sessions
+            .get(client_id)
+            .is_some_and(|session| session.incoming.contains(&packet_id));
+
+        if !received {
+            self.change(Change::Received(client_id.to_string(), packet_id));
+        }
+        !received
+    }
+
+    /// PUBREL received from the client.
+    pub(crate) fn receive_released(&mut self, client_id: &str, packet_id: u16) {
+        self.change(Change::ReceivedReleased(client_id.to_string(), packet_id));
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use crate::queue::{OverflowPolicy, QueueConfig};
+    use crate::router::Router;
+    use crate::store::{MemoryStore, SessionStore};
+    use deser::packets::publish::Publish;
+    use deser::packets::subscribe::{SubscriptionOptions, TopicFilterAndSubscriptionOptions};
+    use std::sync::Arc;
+    use tokio::sync::mpsc;
+
+    fn subscription(topic_filter: &str, raw_value: u8) -> TopicFilterAndSubscriptionOptions {
+        TopicFilterAndSubscriptionOptions::new(
+            String::from(topic_filter),
+            SubscriptionOptions { raw_value },
+        )
+    }
+
+    fn publish(topic_name: &str) -> Publish {
+        Publish {
+            packet_type_low_nibble: 0b0010,
+            topic_name: String::from(topic_name),
+            packet_id: Some(1),
+            ..Default::default()
+        }
+    }
+
+    fn connect(router: &mut Router, client_id: &str) -> (u64, mpsc::Receiver<()>) {
+        let (notifier, notifications) = mpsc::channel(1);
+        let (connection_id, _) = router.connect(client_id, notifier, false, 0);
+        (connection_id, notifications)
+    }
+
+    #[test]
+    pub fn should_deliver_once_at_highest_qos() {
+        let mut router = Router::default();
+        let (_, mut notifications) = connect(&mut router, "sub");
+        router.subscribe("sub", subscription("a/+", 0));
+        router.subscribe("sub", subscription("a/#", 2));
+
+        router.route("pub", &publish("a/b"));
+
+        assert!(notifications.try_recv().is_ok());
+        let delivery = router.next_delivery("sub", u16::MAX).unwrap();
+        assert_eq!(0b0010, delivery.packet_type_low_nibble);
+        assert_eq!(Some(1), delivery.packet_id);
+        assert!(router.next_delivery("sub", u16::MAX).is_none());
+    }
+
+    #[test]
+    pub fn should_honour_no_local() {
+        let mut router = Router::default();
+        connect(&mut router, "ID");
+        router.subscribe("ID", subscription("a/b", 0b0000_0100));
+
+        router.route("ID", &publish("a/b"));
+        assert!(router.next_delivery("ID", u16::MAX).is_none());
+
+        router.route("other", &publish("a/b"));
+        assert!(router.next_delivery("ID", u16::MAX).is_some());
+    }
+
+    #[test]
+    pub fn should_keep_subscriptions_of_session_taken_over() {
+        let mut router = Router::default();
+        let (first, _) = connect(&mut router, "ID");
+        router.subscribe("ID", subscription("a/b", 0));
+        connect(&mut router, "ID");
+
+        router.disconnect("ID", first);
+        router.route("pub", &publish("a/b"));
+
+        assert!(router.next_delivery("ID", u16::MAX).is_some());
+        assert!(router.unsubscribe("ID", "a/b"));
+        assert!(!router.unsubscribe("ID", "a/b"));
+    }
+
+    #[test]
+    pub fn should_send_retained_messages_to_new_subscriptions() {
+        let mut router = Router::default();
+        connect(&mut router, "ID");
+        let mut retained = publish("a/b");
+        retained.packet_type_low_nibble = 0b0101;
+        retained.application_message = Some(b"on".to_vec());
+        router.route("pub", &retained);
+
+        router.subscribe("ID", subscription("a/#", 0));
+        let delivery = router.next_delivery("ID", u16::MAX).unwrap();
+        assert_eq!(0b0001, delivery.packet_type_low_nibble);
+        assert_eq!(Some(b"on".to_vec()), delivery.application_message);
+
+        // retain handling 1: only for new subscriptions
+        router.subscribe("ID", subscription("a/#", 0b0001_0000));
+        assert!(router.next_delivery("ID", u16::MAX).is_none());
+
+        retained.application_message = None;
+        router.route("pub", &retained);
+        assert!(router.next_delivery("ID", u16::MAX).is_some());
+        router.subscribe("ID", subscription("a/b", 0));
+        assert!(router.next_delivery("ID", u16::MAX).is_none());
+    }
+
+    #[test]
+    pub fn should_recover_sessions_and_messages_in_flight_from_store() {
+        let store = Arc::new(MemoryStore::default());
+        let mut router = Router::new(Some(store.clone()), QueueConfig::default());
+        let (notifier, _notifications) = mpsc::channel(1);
+        let (connection_id, _) = router.connect("ID", notifier, false, 60);
+        router.subscribe("ID", subscription("a/b", 1));
+        router.route("pub", &publish("a/b"));
+        router.route("pub", &publish("a/b"));
+        let sent = router.next_delivery("ID", 1).unwrap();
+        assert!(router.next_delivery("ID", 1).is_none());
+        router.disconnect("ID", connection_id);
+
+        let mut router = Router::new(
+            Some(store.clone() as Arc<dyn SessionStore>),
+            QueueConfig::default(),
+        );
+        let (notifier, _notifications) = mpsc::channel(1);
+        assert_eq!((1, true), router.connect("ID", notifier, false, 60));
+        assert_eq!((vec![sent.clone()], vec![]), router.in_flight("ID"));
+
+        router.acknowledge("ID", sent.packet_id.unwrap());
+        assert!(router.next_delivery("ID", 1).is_some());
+    }
+
+    fn offline_session(queue: QueueConfig) -> Router {
+        let mut router = Router::new(None, queue);
+        let (notifier, _) = mpsc::channel(1);
+        let (connection_id, _) = router.connect("ID", notifier, false, 60);
+        router.subscribe("ID", subscription("a/+", 1));
+        router.disconnect("ID", connection_id);
+        router
+    }
+
+    fn payloads(router: &mut Router) -> Vec<Vec<u8>> {
+        std::iter::from_fn(|| router.next_delivery("ID", u16::MAX))
+            .map(|publish| publish.application_message.unwrap())
+            .collect()
+    }
+
+    fn message(low_nibble: u8, payload: &[u8]) -> Publish {
+        Publish {
+            packet_type_low_nibble: low_nibble,
+            topic_name: String::from("a/b"),
+            packet_id: Some(1),
+            application_message: Some(payload.to_vec()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    pub fn should_apply_overflow_policy() {
+        let queue = QueueConfig::default().max_messages(2);
+
+        let mut router = offline_session(queue.clone().overflow(OverflowPolicy::DropNewest));
+        for payload in [b"1", b"2", b"3"] {
+            assert!(router.route("pub", &message(0b0010, payload)));
+        }
+        assert_eq!(1, router.queue_stats()[0].dropped);
+        assert_eq!(vec![b"1".to_vec(), b"2".to_vec()], payloads(&mut router));
+
+        let mut router = offline_session(queue.clone().overflow(OverflowPolicy::DropOldest));
+        for payload in [b"1", b"2", b"3"] {
+            assert!(router.route("pub", &message(0b0010, payload)));
+        }
+        assert_eq!(vec![b"2".to_vec(), b"3".to_vec()], payloads(&mut router));
+
+        let mut router = offline_session(queue.overflow(OverflowPolicy::DisconnectPublisher));
+        assert!(router.route("pub", &message(0b0010, b"1")));
+        assert!(router.route("pub", &message(0b0010, b"2")));
+        assert!(!router.route("pub", &message(0b0010, b"3")));
+    }
+
+    #[test]
+    pub fn should_limit_queued_bytes_and_qos0() {
+        // topic name and payload count against the limit
+        let mut router = offline_session(QueueConfig::default().max_bytes(10));
+        router.route("pub", &message(0b0010, b"1234"));
+        router.route("pub", &message(0b0010, b"12345"));
+        router.route("pub", &message(0b0000, b"1"));
+        let stats = &router.queue_stats()[0];
+        assert_eq!(
+            (1, 7, false),
+            (stats.messages, stats.bytes, stats.connected)
+        );
+
+        let mut router = offline_session(QueueConfig::default().include_qos0(true));
+        router.route("pub", &message(0b0000, b"1"));
+        assert_eq!(vec![b"1".to_vec()], payloads(&mut router));
+    }
+
+    #[test]
+    pub fn should_drain_queue_within_receive_maximum() {
+        let mut router = offline_session(QueueConfig::default());
+        for payload in [b"1", b"2", b"3"] {
+            router.route("pub", &message(0b0010, payload));
+        }
+
+        let (notifier, _notifications) = mpsc::channel(1);
+        router.connect("ID", notifier, false, 60);
+        let first = router.next_delivery("ID", 2).unwrap();
+        router.next_delivery("ID", 2).unwrap();
+        assert!(router.next_delivery("ID", 2).is_none());
+
+        router.acknowledge("ID", first.packet_id.unwrap());
+        assert_eq!(
+            Some(b"3".to_vec()),
+            router.next_delivery("ID", 2).unwrap().application_message
+        );
+    }
+}
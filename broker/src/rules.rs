@@ -0,0 +1,722 @@
+//! Rules republishing, transforming or dropping the messages clients publish, declared in a
+//! file that can be reloaded while the broker runs.
+//!
+//! A rule starts with `rule <topic filter>`, followed by its conditions and actions, one per
+//! line. Blank lines and lines starting with `#` are ignored:
+//!
+//! ```text
+//! rule sensors/+/temperature
+//!   if content-type application/json
+//!   if property unit=celsius
+//!   add-property source=rules
+//!   republish processed/{topic[1]}/temperature
+//!   copy /var/log/mqtt/temperature.jsonl
+//!
+//! rule debug/#
+//!   drop
+//! ```
+//!
+//! A rule applies to the messages matching its topic filter and every condition:
+//!
+//! - `if property KEY` or `if property KEY=VALUE`: a user property is present, with that value.
+//! - `if payload-format utf8|binary`: the Payload Format Indicator, binary when absent.
+//! - `if content-type VALUE`: the Content Type.
+//!
+//! Its actions run in order, each seeing what the previous ones changed:
+//!
+//! - `add-property KEY=VALUE` and `remove-property KEY` change the user properties.
+//! - `republish TEMPLATE` publishes a copy to the topic of the template, where `{topic}` is
+//!   the topic of the message, `{topic[N]}` its level N counted from 0, and `{client_id}` the
+//!   publisher. Copies do not go through the rules again, and overflow queues like the
+//!   message they copy.
+//! - `copy FILE` appends the message to the file, as a line of JSON.
+//! - `drop` keeps the message from its subscribers. The actions after it still run.
+//!
+//! Every rule applying to a message runs, in the order of the file.
+
+use crate::broker::Broker;
+use crate::session::SessionError;
+use crate::topic;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use deser::packets::publish::Publish;
+use deser::primitive_types::{Byte, Utf8EncodedString, Utf8StringPair};
+use deser::properties::Property;
+use serde_json::json;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use tokio::io::AsyncWriteExt;
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+#[derive(Error, Debug)]
+pub enum RuleError {
+    #[error("Reading rule file {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Line {0} of the rule file: {1}")]
+    MalformedLine(usize, &'static str),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PayloadFormat {
+    Utf8,
+    Binary,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Condition {
+    /// User property with this key, and this value when set.
+    Property(String, Option<String>),
+    PayloadFormat(PayloadFormat),
+    ContentType(String),
+}
+
+impl Condition {
+    fn holds(&self, publish: &Publish) -> bool {
+        let mut properties = publish.variable_header_properties.iter().flatten();
+        match self {
+            Condition::Property(key, value) => properties.any(|property| {
+                matches!(property, Property::User(Utf8StringPair(k, v))
+                    if k == key && value.as_ref().is_none_or(|value| v == value))
+            }),
+            Condition::PayloadFormat(format) => {
+                let utf8 = properties.any(|property| {
+                    matches!(property, Property::PayloadFormatIndicator(Byte(1)))
+                });
+                *format == if utf8 { PayloadFormat::Utf8 } else { PayloadFormat::Binary }
+            }
+            Condition::ContentType(content_type) => properties.any(|property| {
+                matches!(property, Property::ContentType(Utf8EncodedString(c)) if c == content_type)
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    /// Publishes a copy to the topic of the template.
+    Republish(String),
+    AddProperty(String, String),
+    RemoveProperty(String),
+    Drop,
+    /// Appends the message to the file.
+    Copy(PathBuf),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+    pub topic_filter: String,
+    pub conditions: Vec<Condition>,
+    pub actions: Vec<Action>,
+}
+
+impl Rule {
+    pub fn new(topic_filter: &str) -> Rule {
+        Rule {
+            topic_filter: topic_filter.to_string(),
+            conditions: vec![],
+            actions: vec![],
+        }
+    }
+
+    pub fn condition(mut self, condition: Condition) -> Rule {
+        self.conditions.push(condition);
+        self
+    }
+
+    pub fn action(mut self, action: Action) -> Rule {
+        self.actions.push(action);
+        self
+    }
+
+    pub fn applies(&self, publish: &Publish) -> bool {
+        topic::matches(&self.topic_filter, &publish.topic_name)
+            && self.conditions.iter().all(|c| c.holds(publish))
+    }
+}
+
+/// What the rules did to a message.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Outcome {
+    /// The message for its subscribers, None when dropped.
+    pub forward: Option<Publish>,
+    pub republish: Vec<Publish>,
+    /// Messages to append to files.
+    pub copies: Vec<(PathBuf, Publish)>,
+}
+
+/// Rules, optionally loaded from a file that can be reloaded while the broker runs.
+#[derive(Debug, Default)]
+pub struct RuleEngine {
+    path: Option<PathBuf>,
+    rules: RwLock<Vec<Rule>>,
+}
+
+impl RuleEngine {
+    pub fn new(rules: Vec<Rule>) -> RuleEngine {
+        RuleEngine {
+            path: None,
+            rules: RwLock::new(rules),
+        }
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<RuleEngine, RuleError> {
+        let path = path.as_ref().to_path_buf();
+        let rules = RuleEngine::parse(&std::fs::read_to_string(&path)?)?;
+
+        Ok(RuleEngine {
+            path: Some(path),
+            rules: RwLock::new(rules),
+        })
+    }
+
+    pub fn parse(contents: &str) -> Result<Vec<Rule>, RuleError> {
+        let mut rules: Vec<Rule> = vec![];
+
+        for (index, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let malformed = |reason| RuleError::MalformedLine(index + 1, reason);
+            let (keyword, argument) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let argument = argument.trim();
+
+            if keyword == "rule" {
+                if !topic::is_valid_topic_filter(argument) {
+                    return Err(malformed("expected rule <topic filter>"));
+                }
+                rules.push(Rule::new(argument));
+                continue;
+            }
+            let rule = rules
+                .last_mut()
+                .ok_or(malformed("expected rule <topic filter> first"))?;
+
+            match keyword {
+                "if" => rule
+                    .conditions
+                    .push(parse_condition(argument).ok_or(malformed(
+                        "expected if <property KEY[=VALUE]|payload-format utf8|binary|content-type VALUE>",
+                    ))?),
+                "republish" if valid_template(argument) => {
+                    rule.actions.push(Action::Republish(argument.to_string()))
+                }
+                "republish" => return Err(malformed("expected republish <topic template>")),
+                "add-property" => match argument.split_once('=') {
+                    Some((key, value)) if !key.is_empty() => rule
+                        .actions
+                        .push(Action::AddProperty(key.to_string(), value.to_string())),
+                    _ => return Err(malformed("expected add-property KEY=VALUE")),
+                },
+                "remove-property" if !argument.is_empty() => rule
+                    .actions
+                    .push(Action::RemoveProperty(argument.to_string())),
+                "remove-property" => return Err(malformed("expected remove-property KEY")),
+                "drop" if argument.is_empty() => rule.actions.push(Action::Drop),
+                "copy" if !argument.is_empty() => {
+                    rule.actions.push(Action::Copy(PathBuf::from(argument)))
+                }
+                _ => return Err(malformed("unknown condition or action")),
+            }
+        }
+
+        Ok(rules)
+    }
+
+    /// Reads the file again. The current rules are kept when the file cannot be parsed.
+    pub fn reload(&self) -> Result<(), RuleError> {
+        if let Some(path) = &self.path {
+            let rules = RuleEngine::parse(&std::fs::read_to_string(path)?)?;
+            self.set_rules(rules);
+        }
+        Ok(())
+    }
+
+    /// Reloads the file whenever its modification time changes, checking every period.
+    pub fn watch(self: Arc<Self>, period: Duration) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut modified = self.modified();
+            let mut interval = tokio::time::interval(period);
+
+            loop {
+                interval.tick().await;
+                let current = self.modified();
+                if current == modified {
+                    continue;
+                }
+                modified = current;
+
+                match self.reload() {
+                    Ok(()) => info!("reloaded rule file {:?}", self.path),
+                    Err(e) => warn!("keeping previous rules: {e}"),
+                }
+            }
+        })
+    }
+
+    fn modified(&self) -> Option<SystemTime> {
+        let path = self.path.as_ref()?;
+        std::fs::metadata(path).and_then(|m| m.modified()).ok()
+    }
+
+    pub fn rules(&self) -> Vec<Rule> {
+        self.rules.read().unwrap().clone()
+    }
+
+    pub fn set_rules(&self, rules: Vec<Rule>) {
+        *self.rules.write().unwrap() = rules;
+    }
+
+    /// Runs the rules applying to a message published by the client.
+    pub fn evaluate(&self, client_id: &str, mut publish: Publish) -> Outcome {
+        let mut outcome = Outcome::default();
+        let mut dropped = false;
+
+        for rule in self.rules.read().unwrap().iter() {
+            if !rule.applies(&publish) {
+                continue;
+            }
+            for action in &rule.actions {
+                match action {
+                    Action::Republish(template) => {
+                        match expand(template, &publish.topic_name, client_id) {
+                            Some(topic_name) if topic::is_valid_topic_name(&topic_name) => {
+                                outcome.republish.push(Publish {
+                                    topic_name,
+                                    packet_id: None,
+                                    ..publish.clone()
+                                })
+                            }
+                            _ => debug!(
+                                "cannot republish {} with template {template}",
+                                publish.topic_name
+                            ),
+                        }
+                    }
+                    Action::AddProperty(key, value) => publish
+                        .variable_header_properties
+                        .get_or_insert_with(Vec::new)
+                        .push(Property::User(Utf8StringPair(key.clone(), value.clone()))),
+                    Action::RemoveProperty(key) => {
+                        if let Some(properties) = &mut publish.variable_header_properties {
+                            properties.retain(|property| {
+                                !matches!(property, Property::User(Utf8StringPair(k, _)) if k == key)
+                            });
+                        }
+                    }
+                    Action::Drop => dropped = true,
+                    Action::Copy(path) => outcome.copies.push((path.clone(), publish.clone())),
+                }
+            }
+        }
+
+        outcome.forward = (!dropped).then_some(publish);
+        outcome
+    }
+}
+
+fn parse_condition(argument: &str) -> Option<Condition> {
+    let (kind, value) = argument.split_once(char::is_whitespace)?;
+    let value = value.trim();
+    match kind {
+        "property" => Some(match value.split_once('=') {
+            Some((key, value)) => Condition::Property(key.to_string(), Some(value.to_string())),
+            None => Condition::Property(value.to_string(), None),
+        }),
+        "payload-format" => match value {
+            "utf8" => Some(Condition::PayloadFormat(PayloadFormat::Utf8)),
+            "binary" => Some(Condition::PayloadFormat(PayloadFormat::Binary)),
+            _ => None,
+        },
+        "content-type" => Some(Condition::ContentType(value.to_string())),
+        _ => None,
+    }
+}
+
+/// Whether every placeholder of the template is known. Levels are only checked against a topic.
+fn valid_template(template: &str) -> bool {
+    !template.is_empty()
+        && template.split('{').skip(1).all(|part| {
+            part.split_once('}')
+                .is_some_and(|(name, _)| placeholder(name))
+        })
+}
+
+fn placeholder(name: &str) -> bool {
+    match name {
+        "topic" | "client_id" => true,
+        _ => level_index(name).is_some(),
+    }
+}
+
+fn level_index(name: &str) -> Option<usize> {
+    name.strip_prefix("topic[")?.strip_suffix(']')?.parse().ok()
+}
+
+/// Topic of the template for the message, None when a placeholder is unknown or refers to a
+/// level the topic does not have.
+fn expand(template: &str, topic_name: &str, client_id: &str) -> Option<String> {
+    let mut expanded = String::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        expanded.push_str(&rest[..start]);
+        let (name, after) = rest[start + 1..].split_once('}')?;
+        match name {
+            "topic" => expanded.push_str(topic_name),
+            "client_id" => expanded.push_str(client_id),
+            _ => expanded.push_str(topic_name.split('/').nth(level_index(name)?)?),
+        }
+        rest = after;
+    }
+    expanded.push_str(rest);
+    Some(expanded)
+}
+
+impl Broker {
+    /// Runs the rules on a message published by the client. Returns the message to route,
+    /// None when a rule dropped it, or the error to disconnect the client with when a copy it
+    /// republished overflowed a queue.
+    pub(crate) async fn apply_rules(
+        &self,
+        client_id: &str,
+        publish: Publish,
+    ) -> Result<Option<Publish>, SessionError> {
+        let Some(rules) = &self.rules else {
+            return Ok(Some(publish));
+        };
+        let outcome = rules.evaluate(client_id, publish);
+
+        for republished in &outcome.republish {
+            if !self.publish(client_id, republished) {
+                return Err(SessionError::QuotaExceeded);
+            }
+        }
+        for (path, publish) in &outcome.copies {
+            if let Err(e) = copy(path, client_id, publish).await {
+                warn!("cannot copy message to {path:?}: {e}");
+            }
+        }
+        Ok(outcome.forward)
+    }
+}
+
+/// Appends the message as a line of JSON, with a UTF-8 payload as text and any other in base64.
+async fn copy(path: &Path, client_id: &str, publish: &Publish) -> std::io::Result<()> {
+    let payload = publish.application_message.as_deref().unwrap_or_default();
+    let mut line = json!({
+        "time": SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+        "client_id": client_id,
+        "topic": publish.topic_name,
+        "qos": publish.qos_number(),
+        "retain": publish.retain(),
+    });
+    match std::str::from_utf8(payload) {
+        Ok(text) => line["payload"] = json!(text),
+        Err(_) => line["payload_base64"] = json!(STANDARD.encode(payload)),
+    }
+
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    file.write_all(format!("{line}\n").as_bytes()).await
+}
+
+#[cfg(test)]
+pub mod test {
+    use crate::broker::Broker;
+    use crate::connection::Connection;
+    use crate::proxy::ProxyProtocol;
+    use crate::queue::{OverflowPolicy, QueueConfig};
+    use crate::rules::{Action, Condition, PayloadFormat, Rule, RuleEngine, RuleError};
+    use crate::session::SessionError;
+    use client::{Client, ConnectOptions, Message, QoS};
+    use deser::packets::connect::Connect;
+    use deser::packets::publish::Publish;
+    use deser::packets::subscribe::{
+        Subscribe, SubscriptionOptions, TopicFilterAndSubscriptionOptions,
+    };
+    use deser::primitive_types::{Byte, TwoByteInteger, Utf8EncodedString, Utf8StringPair};
+    use deser::properties::Property;
+    use deser::ControlPacket;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+
+    fn user_property(key: &str, value: &str) -> Property {
+        Property::User(Utf8StringPair(key.to_string(), value.to_string()))
+    }
+
+    fn publish(topic: &str, properties: Vec<Property>) -> Publish {
+        Publish {
+            topic_name: topic.to_string(),
+            packet_type_low_nibble: 2,
+            packet_id: Some(7),
+            variable_header_properties: Some(properties),
+            application_message: Some(b"21.5".to_vec()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    pub fn should_parse_rule_blocks() {
+        let rules = RuleEngine::parse(
+            "# temperatures\n\
+             rule sensors/+/temperature\n\
+             \x20 if content-type application/json\n\
+             \x20 if property unit=celsius\n\
+             \x20 if property calibrated\n\
+             \x20 if payload-format utf8\n\
+             \x20 republish processed/{topic[1]}\n\
+             \x20 add-property source=rules\n\
+             \x20 remove-property debug\n\
+             \x20 copy /tmp/temperatures.jsonl\n\
+             \n\
+             rule debug/#\n\
+             \x20 drop\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            vec![
+                Rule::new("sensors/+/temperature")
+                    .condition(Condition::ContentType(String::from("application/json")))
+                    .condition(Condition::Property(
+                        String::from("unit"),
+                        Some(String::from("celsius"))
+                    ))
+                    .condition(Condition::Property(String::from("calibrated"), None))
+                    .condition(Condition::PayloadFormat(PayloadFormat::Utf8))
+                    .action(Action::Republish(String::from("processed/{topic[1]}")))
+                    .action(Action::AddProperty(
+                        String::from("source"),
+                        String::from("rules")
+                    ))
+                    .action(Action::RemoveProperty(String::from("debug")))
+                    .action(Action::Copy(PathBuf::from("/tmp/temperatures.jsonl"))),
+                Rule::new("debug/#").action(Action::Drop),
+            ],
+            rules
+        );
+
+        for (contents, line) in [
+            ("drop\n", 1),
+            ("rule a/#/b\n", 1),
+            ("rule a\n  if payload-format text\n", 2),
+            ("rule a\n\n  republish processed/{level}\n", 3),
+            ("rule a\n  add-property source\n", 2),
+            ("rule a\n  forward b\n", 2),
+        ] {
+            match RuleEngine::parse(contents) {
+                Err(RuleError::MalformedLine(l, _)) => assert_eq!(line, l, "{contents}"),
+                result => panic!("expected a malformed line in {contents}, got {result:?}"),
+            }
+        }
+    }
+
+    #[test]
+    pub fn should_transform_messages_matching_conditions() {
+        let engine = RuleEngine::new(vec![
+            Rule::new("sensors/+/temperature")
+                .condition(Condition::ContentType(String::from("application/json")))
+                .condition(Condition::Property(String::from("unit"), None))
+                .action(Action::RemoveProperty(String::from("debug")))
+                .action(Action::AddProperty(
+                    String::from("source"),
+                    String::from("rules"),
+                ))
+                .action(Action::Republish(String::from(
+                    "processed/{client_id}/{topic[1]}",
+                )))
+                .action(Action::Republish(String::from("processed/{topic[5]}"))),
+            Rule::new("sensors/#")
+                .condition(Condition::PayloadFormat(PayloadFormat::Binary))
+                .action(Action::Copy(PathBuf::from("binary.jsonl"))),
+        ]);
+        let properties = vec![
+            Property::ContentType(Utf8EncodedString(String::from("application/json"))),
+            user_property("unit", "celsius"),
+            user_property("debug", "1"),
+        ];
+
+        let outcome = engine.evaluate("sensor", publish("sensors/kitchen/temperature", properties));
+        let transformed = publish(
+            "sensors/kitchen/temperature",
+            vec![
+                Property::ContentType(Utf8EncodedString(String::from("application/json"))),
+                user_property("unit", "celsius"),
+                user_property("source", "rules"),
+            ],
+        );
+        assert_eq!(Some(transformed.clone()), outcome.forward);
+        assert_eq!(
+            vec![Publish {
+                topic_name: String::from("processed/sensor/kitchen"),
+                packet_id: None,
+                ..transformed.clone()
+            }],
+            outcome.republish
+        );
+        assert_eq!(
+            vec![(PathBuf::from("binary.jsonl"), transformed)],
+            outcome.copies
+        );
+
+        let utf8 = vec![
+            Property::PayloadFormatIndicator(Byte(1)),
+            user_property("unit", "celsius"),
+        ];
+        let outcome = engine.evaluate("sensor", publish("sensors/kitchen/temperature", utf8));
+        assert!(outcome.republish.is_empty());
+        assert!(outcome.copies.is_empty());
+    }
+
+    #[test]
+    pub fn should_drop_messages_and_keep_rules_when_reload_fails() {
+        let path = std::env::temp_dir().join(format!("rules-{}", std::process::id()));
+        std::fs::write(&path, "rule debug/#\n  drop\n").unwrap();
+        let engine = RuleEngine::load(&path).unwrap();
+
+        let outcome = engine.evaluate("ID", publish("debug/trace", vec![]));
+        assert_eq!(None, outcome.forward);
+        let outcome = engine.evaluate("ID", publish("sensors/1", vec![]));
+        assert_eq!(Some(publish("sensors/1", vec![])), outcome.forward);
+
+        std::fs::write(&path, "rule debug/#\n  dorp\n").unwrap();
+        assert!(engine.reload().is_err());
+        assert_eq!(
+            vec![Rule::new("debug/#").action(Action::Drop)],
+            engine.rules()
+        );
+
+        std::fs::write(&path, "rule sensors/#\n  drop\n").unwrap();
+        engine.reload().unwrap();
+        let outcome = engine.evaluate("ID", publish("debug/trace", vec![]));
+        assert!(outcome.forward.is_some());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn should_republish_and_copy_published_messages() {
+        let sink = std::env::temp_dir().join(format!("rules-sink-{}", std::process::id()));
+        let rules = format!(
+            "rule sensors/+/temperature\n\
+             \x20 republish processed/{{topic[1]}}\n\
+             \x20 copy {}\n\
+             rule sensors/+/debug\n\
+             \x20 drop\n",
+            sink.display()
+        );
+        let engine = Arc::new(RuleEngine::new(RuleEngine::parse(&rules).unwrap()));
+        let broker = Broker::builder().rules(engine).build();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(broker.serve(listener, ProxyProtocol::Reject));
+        let options = |client_id: &str| ConnectOptions::new(&addr).client_id(client_id);
+
+        let (subscriber, mut messages) = Client::connect(options("subscriber")).await.unwrap();
+        subscriber.subscribe("#", QoS::AtLeastOnce).await.unwrap();
+
+        let (publisher, _) = Client::connect(options("publisher")).await.unwrap();
+        for message in [
+            Message::new("sensors/kitchen/debug", b"trace"),
+            Message::new("sensors/kitchen/temperature", b"21.5").retain(true),
+            Message::new("sensors/kitchen/humidity", b"40"),
+        ] {
+            let message = message.qos(QoS::AtLeastOnce);
+            publisher
+                .publish_message(&message)
+                .await
+                .unwrap()
+                .await
+                .unwrap();
+        }
+
+        let mut topics = vec![];
+        for _ in 0..3 {
+            topics.push(messages.recv().await.unwrap().topic);
+        }
+        topics.sort();
+        assert_eq!(
+            vec![
+                "processed/kitchen",
+                "sensors/kitchen/humidity",
+                "sensors/kitchen/temperature",
+            ],
+            topics
+        );
+
+        let line = std::fs::read_to_string(&sink).unwrap();
+        let copied: serde_json::Value = serde_json::from_str(line.trim()).unwrap();
+        assert_eq!("publisher", copied["client_id"]);
+        assert_eq!("sensors/kitchen/temperature", copied["topic"]);
+        assert_eq!(1, copied["qos"]);
+        assert_eq!(true, copied["retain"]);
+        assert_eq!("21.5", copied["payload"]);
+        std::fs::remove_file(sink).unwrap();
+    }
+
+    #[tokio::test]
+    async fn should_disconnect_publisher_when_republished_copy_overflows_a_queue() {
+        let rules = RuleEngine::parse("rule sensors/#\n  republish copies/{topic}\n").unwrap();
+        let broker = Broker::builder()
+            .rules(Arc::new(RuleEngine::new(rules)))
+            .queue(
+                QueueConfig::default()
+                    .max_messages(1)
+                    .overflow(OverflowPolicy::DisconnectPublisher),
+            )
+            .build();
+
+        // takes a single copy in flight and never acknowledges it
+        let (client, server) = tokio::io::duplex(4096);
+        tokio::spawn(broker.clone().handle(server));
+        let mut subscriber = Connection::new(client);
+        subscriber
+            .write_packet(&ControlPacket::Connect(Connect {
+                client_id: String::from("sub"),
+                variable_header_properties: Some(vec![Property::ReceiveMaximum(TwoByteInteger(1))]),
+                ..Default::default()
+            }))
+            .await
+            .unwrap();
+        subscriber.read_packet().await.unwrap();
+        subscriber
+            .write_packet(&ControlPacket::Subscribe(Subscribe {
+                packet_id: 1,
+                topic_filters: vec![TopicFilterAndSubscriptionOptions::new(
+                    String::from("copies/#"),
+                    SubscriptionOptions { raw_value: 1 },
+                )],
+                ..Default::default()
+            }))
+            .await
+            .unwrap();
+        subscriber.read_packet().await.unwrap();
+
+        let message = Publish {
+            packet_type_low_nibble: 0b0010,
+            packet_id: Some(1),
+            ..publish("sensors/kitchen", vec![])
+        };
+        let forwarded = broker.apply_rules("pub", message.clone()).await;
+        assert_eq!("sensors/kitchen", forwarded.unwrap().unwrap().topic_name);
+        match subscriber.read_packet().await.unwrap() {
+            Some(ControlPacket::Publish(copy)) => {
+                assert_eq!("copies/sensors/kitchen", copy.topic_name)
+            }
+            packet => panic!("expected PUBLISH, received {packet:?}"),
+        }
+        assert!(broker.apply_rules("pub", message.clone()).await.is_ok());
+        assert!(matches!(
+            broker.apply_rules("pub", message).await,
+            Err(SessionError::QuotaExceeded)
+        ));
+    }
+}
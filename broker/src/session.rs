@@ -0,0 +1,1879 @@
+use crate::acl::Access;
+use crate::admin::ConnectedClient;
+use crate::auth::password::{check_credentials, Credentials, PasswordCheck};
+use crate::auth::{
+    authentication_data, authentication_method, authentication_properties, AuthError, AuthExchange,
+    AuthStep,
+};
+use crate::broker::{Broker, Redirect};
+use crate::connection::{Connection, ConnectionError, ConnectionInfo};
+use crate::hook::{ClientContext, ConnectVerdict, MessageVerdict, SubscribeVerdict};
+use crate::limits::ClientLimits;
+use crate::router::Router;
+use crate::store::StoreError;
+use crate::topic::{is_valid_topic_filter, is_valid_topic_name};
+use deser::codec::CodecError;
+use deser::packets::auth::Auth;
+use deser::packets::connack::ConnAck;
+use deser::packets::connect::Connect;
+use deser::packets::disconnect::Disconnect;
+use deser::packets::pingresp::PingResp;
+use deser::packets::puback::PubAck;
+use deser::packets::pubcomp::PubComp;
+use deser::packets::publish::{Publish, Qos};
+use deser::packets::pubrec::PubRec;
+use deser::packets::pubrel::PubRel;
+use deser::packets::reason_codes::{
+    AUTH, CONNECTACK, DISCONNECT, PUBACK, PUBREC, SUBACK, UNSUBACK,
+};
+use deser::packets::suback::SubAck;
+use deser::packets::subscribe::Subscribe;
+use deser::packets::unsuback::UnsubAck;
+use deser::packets::unsubscribe::UnSubscribe;
+use deser::primitive_types::{Byte, FourByteInteger, TwoByteInteger, Utf8EncodedString};
+use deser::properties::Property;
+use deser::ControlPacket;
+use rand::distributions::{Alphanumeric, DistString};
+use std::net::IpAddr;
+use std::sync::{Arc, MutexGuard};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tracing::{debug, trace, warn};
+
+#[derive(Error, Debug)]
+pub enum SessionError {
+    #[error(transparent)]
+    Connection(#[from] ConnectionError),
+    #[error("Connection closed by the client")]
+    ConnectionClosed,
+    #[error("Protocol error: {0}")]
+    ProtocolError(String),
+    #[error("Authentication method {0} is not supported")]
+    BadAuthenticationMethod(String),
+    #[error("Client identifier {0:?} is reserved for the broker")]
+    ClientIdentifierNotValid(String),
+    #[error("Bad user name or password for client {0}")]
+    BadUserNameOrPassword(String),
+    #[error(transparent)]
+    NotAuthorized(#[from] AuthError),
+    #[error("Topic name {0:?} is not valid")]
+    TopicNameInvalid(String),
+    #[error("Session taken over by another connection with the same client identifier")]
+    SessionTakenOver,
+    #[error("Queue of a subscriber is full")]
+    QuotaExceeded,
+    #[error("Disconnected by an administrator")]
+    AdministrativeAction,
+    #[error("No packet received within one and a half times the keep alive")]
+    KeepAliveTimeout,
+    #[error("Broker in maintenance, use {}", .0.server_reference)]
+    Redirected(Redirect),
+    #[error("Connection refused by a hook with {0:?}")]
+    Refused(CONNECTACK),
+    #[error("Too many connections from {0}")]
+    ConnectionRateExceeded(IpAddr),
+    #[error("Client published more messages than its rate allows")]
+    MessageRateTooHigh,
+    #[error("Client sent more bytes than its rate allows")]
+    ByteRateExceeded,
+    #[error("Client asked for more than {0} subscriptions")]
+    TooManySubscriptions(usize),
+    #[error(transparent)]
+    Store(#[from] StoreError),
+}
+
+impl SessionError {
+    fn connack_reason_code(&self) -> CONNECTACK {
+        match self {
+            SessionError::ProtocolError(_) => CONNECTACK::ProtocolError,
+            SessionError::BadAuthenticationMethod(_) => CONNECTACK::BadAuthenticationMethod,
+            SessionError::ClientIdentifierNotValid(_) => CONNECTACK::ClientIdentifierNotValid,
+            SessionError::BadUserNameOrPassword(_) => CONNECTACK::BadUserNameOrPassword,
+            SessionError::NotAuthorized(_) => CONNECTACK::NotAuthorised,
+            SessionError::Connection(ConnectionError::Codec(CodecError::PacketTooLarge(_))) => {
+                CONNECTACK::PacketTooLarge
+            }
+            SessionError::Connection(ConnectionError::Codec(_)) => CONNECTACK::MalformedPacket,
+            SessionError::Redirected(redirect) if redirect.permanent => CONNECTACK::ServerMoved,
+            SessionError::Redirected(_) => CONNECTACK::UseAnotherServer,
+            SessionError::Refused(reason_code) => reason_code.clone(),
+            SessionError::ConnectionRateExceeded(_) => CONNECTACK::ConnectionRateExceeded,
+            _ => CONNECTACK::UnspecifiedError,
+        }
+    }
+
+    fn disconnect_reason_code(&self) -> DISCONNECT {
+        match self {
+            SessionError::ProtocolError(_) | SessionError::BadAuthenticationMethod(_) => {
+                DISCONNECT::ProtocolError
+            }
+            SessionError::NotAuthorized(_) => DISCONNECT::NotAuthorized,
+            SessionError::TopicNameInvalid(_) => DISCONNECT::TopicNameInvalid,
+            SessionError::SessionTakenOver => DISCONNECT::SessionTakenOver,
+            SessionError::QuotaExceeded
+            | SessionError::ByteRateExceeded
+            | SessionError::TooManySubscriptions(_) => DISCONNECT::QuotaExceeded,
+            SessionError::MessageRateTooHigh => DISCONNECT::MessageRateTooHigh,
+            SessionError::AdministrativeAction => DISCONNECT::AdministrativeAction,
+            SessionError::KeepAliveTimeout => DISCONNECT::KeepAliveTimeout,
+            SessionError::Connection(ConnectionError::Codec(CodecError::PacketTooLarge(_))) => {
+                DISCONNECT::PacketTooLarge
+            }
+            SessionError::Connection(ConnectionError::Codec(_)) => DISCONNECT::MalformedPacket,
+            SessionError::Redirected(redirect) if redirect.permanent => DISCONNECT::ServerMoved,
+            SessionError::Redirected(_) => DISCONNECT::UseAnotherServer,
+            _ => DISCONNECT::UnspecifiedError,
+        }
+    }
+
+    /// The connection is still usable to tell the client why it is being closed.
+    fn can_notify_client(&self) -> bool {
+        !matches!(
+            self,
+            SessionError::Connection(ConnectionError::Io(_)) | SessionError::ConnectionClosed
+        )
+    }
+}
+
+struct Session<T> {
+    broker: Arc<Broker>,
+    connection: Connection<T>,
+    client_id: String,
+    /// Authentication Method used for the CONNECT, re-authentication must use the same one.
+    auth_method: Option<String>,
+    /// Identity established by authentication.
+    username: Option<String>,
+    info: ConnectionInfo,
+    /// Tells that the router queued messages for this session.
+    notifications: mpsc::Receiver<()>,
+    /// Errors the broker closes the connection with, from outside of the session.
+    disconnects: mpsc::Receiver<SessionError>,
+    /// Identifies this connection to the router, which may hand the session to a newer one.
+    connection_id: u64,
+    /// QoS 1 and 2 messages the client accepts to have in flight.
+    receive_maximum: u16,
+    /// Longest time without a packet from the client, one and a half times its Keep Alive,
+    /// zero when disabled.
+    keep_alive: Duration,
+    last_received: Instant,
+    /// Whether the client accepts a Reason String with CONNACK and DISCONNECT.
+    problem_information: bool,
+    /// The client as seen by the hooks, once connected.
+    context: ClientContext,
+    /// Rates of the client, once authenticated.
+    limits: ClientLimits,
+    /// Packets are not read before then while the client goes over its rates.
+    throttled_until: Instant,
+    /// Re-authentication waiting for the next AUTH of the client, which may send other packets
+    /// in between.
+    reauthentication: Option<Box<dyn AuthExchange>>,
+}
+
+pub(crate) async fn run<T>(
+    broker: Arc<Broker>,
+    mut connection: Connection<T>,
+    info: ConnectionInfo,
+) -> Result<(), SessionError>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let admitted = broker.admit_connection(&info);
+    if let Some(delay) = admitted.as_ref().ok().filter(|delay| !delay.is_zero()) {
+        debug!(
+            "holding back connection from {:?} for {delay:?}",
+            info.peer_addr
+        );
+        tokio::time::sleep(*delay).await;
+    }
+
+    let connect = match connection.read_packet().await {
+        Ok(Some(ControlPacket::Connect(connect))) => connect,
+        Ok(Some(packet)) => {
+            return Err(SessionError::ProtocolError(format!(
+                "expected CONNECT, received {}",
+                packet.name()
+            )))
+        }
+        Ok(None) => return Ok(()),
+        Err(e) => {
+            let e = SessionError::Connection(e);
+            if e.can_notify_client() {
+                let connack = ConnAck {
+                    connect_reason_code: e.connack_reason_code() as u8,
+                    ..Default::default()
+                };
+                let _ = connection
+                    .write_packet(&ControlPacket::ConnAck(connack))
+                    .await;
+            }
+            return Err(e);
+        }
+    };
+
+    let (notifier, notifications) = mpsc::channel(1);
+    let (disconnect, disconnects) = mpsc::channel(1);
+    let mut session = Session {
+        broker,
+        connection,
+        client_id: connect.client_id.clone(),
+        auth_method: authentication_method(&connect.variable_header_properties),
+        username: info
+            .certificate_identity
+            .clone()
+            .or_else(|| connect.username.clone()),
+        info,
+        notifications,
+        disconnects,
+        connection_id: 0,
+        receive_maximum: receive_maximum(&connect.variable_header_properties),
+        keep_alive: Duration::from_millis(connect.keep_alive as u64 * 1500),
+        last_received: Instant::now(),
+        problem_information: requests_problem_information(&connect.variable_header_properties),
+        context: ClientContext::default(),
+        limits: ClientLimits::default(),
+        throttled_until: Instant::now(),
+        reauthentication: None,
+    };
+
+    let authenticated = match (session.broker.maintenance(), admitted) {
+        (Some(redirect), _) => Err(SessionError::Redirected(redirect)),
+        (None, Err(e)) => Err(e),
+        // client identifiers starting with $ belong to the broker's own sessions, like bridges
+        (None, Ok(_)) if connect.client_id.starts_with('$') => Err(
+            SessionError::ClientIdentifierNotValid(connect.client_id.clone()),
+        ),
+        (None, Ok(_)) => session.authenticate(&connect).await,
+    };
+    let mut connack_properties = match authenticated {
+        Ok(properties) => properties,
+        Err(e) => {
+            if matches!(
+                e,
+                SessionError::BadAuthenticationMethod(_)
+                    | SessionError::BadUserNameOrPassword(_)
+                    | SessionError::NotAuthorized(_)
+            ) {
+                session
+                    .broker
+                    .metrics
+                    .auth_failure(&e.connack_reason_code());
+            }
+            if e.can_notify_client() {
+                let properties = session.error_properties(&e);
+                let _ = session
+                    .send_connack(e.connack_reason_code(), properties, false)
+                    .await;
+            }
+            return Err(e);
+        }
+    };
+
+    if session.client_id.is_empty() {
+        session.client_id = format!(
+            "auto-{}",
+            Alphanumeric.sample_string(&mut rand::thread_rng(), 16)
+        );
+        connack_properties
+            .get_or_insert_with(Vec::new)
+            .push(Property::AssignedClientIdentifier(Utf8EncodedString(
+                session.client_id.clone(),
+            )));
+    }
+
+    connack_properties
+        .get_or_insert_with(Vec::new)
+        .push(Property::MaximumPacketSize(FourByteInteger(
+            session.broker.maximum_packet_size,
+        )));
+
+    if requests_response_information(&connect.variable_header_properties) {
+        connack_properties
+            .get_or_insert_with(Vec::new)
+            .push(Property::ResponseInformation(Utf8EncodedString(format!(
+                "{}/{}",
+                session.broker.response_topic_prefix, session.client_id
+            ))));
+    }
+
+    session.context = ClientContext {
+        client_id: session.client_id.clone(),
+        username: session.username.clone(),
+        peer_addr: session.info.peer_addr,
+        listener: session.info.listener,
+    };
+    session.limits = ClientLimits::new(
+        session
+            .broker
+            .limits(session.info.listener, session.username.as_deref()),
+        session.broker.maximum_packet_size,
+    );
+    if !session.broker.hooks.is_empty() {
+        let mut properties = connack_properties.take().unwrap_or_default();
+        let verdict = session
+            .broker
+            .hook_connect(&session.context, &connect, &mut properties)
+            .await;
+        connack_properties = Some(properties).filter(|properties| !properties.is_empty());
+
+        if let ConnectVerdict::Refuse(reason_code) = verdict {
+            let e = SessionError::Refused(reason_code);
+            let properties = session.error_properties(&e);
+            let _ = session
+                .send_connack(e.connack_reason_code(), properties, false)
+                .await;
+            return Err(e);
+        }
+    }
+
+    let moved = session
+        .broker
+        .take_over(&session.client_id, connect.clean_start_flag())
+        .await;
+    let (connection_id, session_present, expired) = {
+        let mut router = session.broker.router.lock().unwrap();
+        let (connection_id, session_present) = router.connect(
+            &session.client_id,
+            notifier,
+            connect.clean_start_flag(),
+            session_expiry_interval(&connect.variable_header_properties),
+        );
+        let session_present = match moved {
+            Some(moved) => {
+                router.adopt_session(&session.client_id, moved);
+                true
+            }
+            None => session_present,
+        };
+        (connection_id, session_present, router.take_expired())
+    };
+    session.connection_id = connection_id;
+    session.broker.register_client(ConnectedClient {
+        client_id: session.client_id.clone(),
+        connection_id,
+        peer_addr: session.info.peer_addr,
+        listener: session.info.listener,
+        username: session.username.clone(),
+        protocol_version: connect.protocol_version,
+        keep_alive: connect.keep_alive,
+        disconnect,
+    });
+
+    // the client is registered from here on, a CONNACK that cannot be sent goes through the
+    // same cleanup as a failed session
+    let connected = session
+        .send_connack(CONNECTACK::Success, connack_properties, session_present)
+        .await;
+    for client_id in expired {
+        session.broker.hook_session_expired(&client_id).await;
+    }
+
+    let result = match connected {
+        Ok(()) => {
+            debug!(
+                "client {} connected from {:?} as {:?}, session present: {session_present}",
+                session.client_id, session.info.peer_addr, session.username
+            );
+            session.process_packets().await
+        }
+        Err(e) => Err(SessionError::Connection(e)),
+    };
+    session
+        .broker
+        .router
+        .lock()
+        .unwrap()
+        .disconnect(&session.client_id, session.connection_id);
+    session
+        .broker
+        .unregister_client(&session.client_id, session.connection_id);
+
+    if let Err(e) = &result {
+        if e.can_notify_client() {
+            let _ = session.send_disconnect(e).await;
+        }
+    }
+    let reason = result.as_ref().err().map(|e| e.to_string());
+    session
+        .broker
+        .hook_disconnect(&session.context, reason.as_deref())
+        .await;
+    result
+}
+
+/// Seconds the session outlives its connection, 0 when the CONNECT does not say.
+fn session_expiry_interval(properties: &Option<Vec<Property>>) -> u32 {
+    properties
+        .iter()
+        .flatten()
+        .find_map(|p| match p {
+            Property::SessionExpiryInterval(FourByteInteger(interval)) => Some(*interval),
+            _ => None,
+        })
+        .unwrap_or(0)
+}
+
+fn receive_maximum(properties: &Option<Vec<Property>>) -> u16 {
+    properties
+        .iter()
+        .flatten()
+        .find_map(|p| match p {
+            Property::ReceiveMaximum(TwoByteInteger(maximum)) => Some(*maximum),
+            _ => None,
+        })
+        .unwrap_or(u16::MAX)
+}
+
+/// Request Problem Information, 1 when the CONNECT does not say.
+fn requests_problem_information(properties: &Option<Vec<Property>>) -> bool {
+    !properties
+        .iter()
+        .flatten()
+        .any(|p| matches!(p, Property::RequestProblemInformation(Byte(0))))
+}
+
+fn requests_response_information(properties: &Option<Vec<Property>>) -> bool {
+    properties
+        .iter()
+        .flatten()
+        .any(|p| matches!(p, Property::RequestResponseInformation(Byte(1))))
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> Session<T> {
+    /// Returns the properties to send with a successful CONNACK. Clients using enhanced
+    /// authentication, or identified by their TLS certificate, are not checked against the
+    /// password backends.
+    async fn authenticate(
+        &mut self,
+        connect: &Connect,
+    ) -> Result<Option<Vec<Property>>, SessionError> {
+        let method = match self.auth_method.clone() {
+            Some(method) => method,
+            None if self.info.certificate_identity.is_some() => return Ok(None),
+            None => {
+                self.check_password(connect).await?;
+                return Ok(None);
+            }
+        };
+
+        let data = authentication_data(&connect.variable_header_properties);
+        let final_data = self.exchange(&method, data).await?;
+        Ok(Some(authentication_properties(&method, final_data)))
+    }
+
+    async fn check_password(&self, connect: &Connect) -> Result<(), SessionError> {
+        if self.broker.password_backends.is_empty() {
+            return Ok(());
+        }
+
+        let credentials = Credentials {
+            client_id: connect.client_id.clone(),
+            username: connect.username.clone(),
+            password: connect.password.clone(),
+            peer_addr: self.info.peer_addr,
+            peer_credentials: self.info.peer_credentials,
+        };
+
+        match check_credentials(&self.broker.password_backends, &credentials).await {
+            PasswordCheck::Allow => Ok(()),
+            PasswordCheck::BadUserNameOrPassword => {
+                Err(SessionError::BadUserNameOrPassword(self.client_id.clone()))
+            }
+            _ => Err(SessionError::NotAuthorized(AuthError::NotAuthorized(
+                self.client_id.clone(),
+            ))),
+        }
+    }
+
+    /// Takes one step of the re-authentication the client starts with AUTH 0x19 and continues
+    /// with AUTH 0x18, between the other packets of the session.
+    async fn reauthenticate(&mut self, auth: Auth) -> Result<(), SessionError> {
+        let method = authentication_method(&auth.variable_header_properties);
+        let method = match (method, &self.auth_method) {
+            (Some(method), Some(connect_method)) if &method == connect_method => method,
+            _ => {
+                return Err(SessionError::ProtocolError(String::from(
+                    "re-authentication must use the Authentication Method of the CONNECT",
+                )))
+            }
+        };
+
+        let mut exchange = match (&auth.reason_code, self.reauthentication.take()) {
+            (AUTH::ReAuthenticate, None) => self.start_exchange(&method)?,
+            (AUTH::ContinueAuthentication, Some(exchange)) => exchange,
+            _ => {
+                return Err(SessionError::ProtocolError(String::from(
+                    "AUTH received outside of an authentication exchange",
+                )))
+            }
+        };
+
+        let data = authentication_data(&auth.variable_header_properties);
+        match exchange.step(data.as_deref())? {
+            AuthStep::Continue(server_data) => {
+                self.send_auth(AUTH::ContinueAuthentication, &method, Some(server_data))
+                    .await?;
+                self.reauthentication = Some(exchange);
+            }
+            AuthStep::Success(final_data) => {
+                self.username = self.verified_identity(exchange.as_ref());
+                self.send_auth(AUTH::Success, &method, final_data).await?;
+                debug!(
+                    "client {} re-authenticated as {:?}",
+                    self.client_id, self.username
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// The user name of a client authenticated by an exchange. The User Name of the CONNECT is
+    /// not checked by the exchange, so it is never used in its place.
+    fn verified_identity(&self, exchange: &dyn AuthExchange) -> Option<String> {
+        exchange
+            .identity()
+            .or_else(|| self.info.certificate_identity.clone())
+    }
+
+    fn start_exchange(&self, method: &str) -> Result<Box<dyn AuthExchange>, SessionError> {
+        let authenticator = self
+            .broker
+            .authenticators
+            .get(method)
+            .ok_or_else(|| SessionError::BadAuthenticationMethod(method.to_string()))?;
+        Ok(authenticator.start())
+    }
+
+    async fn send_auth(
+        &mut self,
+        reason_code: AUTH,
+        method: &str,
+        data: Option<Vec<u8>>,
+    ) -> Result<(), ConnectionError> {
+        self.connection
+            .write_packet(&ControlPacket::Auth(Auth {
+                reason_code,
+                variable_header_properties: Some(authentication_properties(method, data)),
+                ..Default::default()
+            }))
+            .await
+    }
+
+    /// Drives the exchange of the CONNECT until the authenticator succeeds or fails, returning
+    /// the final Authentication Data. The client sends nothing but AUTH before the CONNACK.
+    async fn exchange(
+        &mut self,
+        method: &str,
+        mut data: Option<Vec<u8>>,
+    ) -> Result<Option<Vec<u8>>, SessionError> {
+        let mut exchange = self.start_exchange(method)?;
+
+        loop {
+            match exchange.step(data.as_deref())? {
+                AuthStep::Success(final_data) => {
+                    self.username = self.verified_identity(exchange.as_ref());
+                    return Ok(final_data);
+                }
+                AuthStep::Continue(server_data) => {
+                    self.send_auth(AUTH::ContinueAuthentication, method, Some(server_data))
+                        .await?;
+
+                    data = match self.connection.read_packet().await? {
+                        Some(ControlPacket::Auth(auth))
+                            if auth.reason_code == AUTH::ContinueAuthentication
+                                && authentication_method(&auth.variable_header_properties)
+                                    .as_deref()
+                                    == Some(method) =>
+                        {
+                            authentication_data(&auth.variable_header_properties)
+                        }
+                        Some(packet) => {
+                            return Err(SessionError::ProtocolError(format!(
+                                "expected AUTH continue authentication, received {}",
+                                packet.name()
+                            )))
+                        }
+                        None => return Err(SessionError::ConnectionClosed),
+                    };
+                }
+            }
+        }
+    }
+
+    async fn process_packets(&mut self) -> Result<(), SessionError> {
+        self.resume().await?;
+
+        loop {
+            // packets held back by throttling do not count against the keep alive
+            let deadline = self.last_received.max(self.throttled_until) + self.keep_alive;
+            let throttled = self.throttled_until > Instant::now();
+            tokio::select! {
+                packet = self.connection.read_packet(), if !throttled => {
+                    self.last_received = Instant::now();
+                    match packet? {
+                        Some(ControlPacket::Disconnect(_)) | None => return Ok(()),
+                        Some(packet) => {
+                            self.throttle(&packet)?;
+                            self.handle_packet(packet).await?
+                        }
+                    }
+                }
+                _ = tokio::time::sleep_until(self.throttled_until), if throttled => {}
+                notification = self.notifications.recv() => {
+                    // the broker only drops the sender when a new connection takes the
+                    // client identifier over
+                    notification.ok_or(SessionError::SessionTakenOver)?;
+                    self.deliver_queued().await?;
+                }
+                // the sender is dropped once another connection registers the client identifier
+                Some(e) = self.disconnects.recv() => return Err(e),
+                _ = tokio::time::sleep_until(deadline), if !self.keep_alive.is_zero() => {
+                    return Err(SessionError::KeepAliveTimeout);
+                }
+            }
+        }
+    }
+
+    async fn handle_packet(&mut self, packet: ControlPacket) -> Result<(), SessionError> {
+        match packet {
+            ControlPacket::PingReq(_) => {
+                self.connection
+                    .write_packet(&ControlPacket::PingResp(PingResp::default()))
+                    .await?
+            }
+            ControlPacket::Auth(auth) => self.reauthenticate(auth).await?,
+            ControlPacket::Publish(publish) => self.publish(publish).await?,
+            ControlPacket::PubAck(puback) => {
+                self.router().acknowledge(&self.client_id, puback.packet_id);
+                self.deliver_queued().await?
+            }
+            ControlPacket::PubRec(pubrec) => {
+                self.router().release(&self.client_id, pubrec.packet_id);
+                self.connection
+                    .write_packet(&ControlPacket::PubRel(PubRel {
+                        packet_id: pubrec.packet_id,
+                        ..Default::default()
+                    }))
+                    .await?
+            }
+            ControlPacket::PubComp(pubcomp) => {
+                self.router().complete(&self.client_id, pubcomp.packet_id);
+                self.deliver_queued().await?
+            }
+            ControlPacket::PubRel(pubrel) => {
+                self.router()
+                    .receive_released(&self.client_id, pubrel.packet_id);
+                self.connection
+                    .write_packet(&ControlPacket::PubComp(PubComp {
+                        packet_id: pubrel.packet_id,
+                        ..Default::default()
+                    }))
+                    .await?
+            }
+            ControlPacket::Subscribe(subscribe) => self.subscribe(subscribe).await?,
+            ControlPacket::Unsubscribe(unsubscribe) => self.unsubscribe(unsubscribe).await?,
+            packet => trace!("{} packet is not handled", packet.name()),
+        }
+
+        Ok(())
+    }
+
+    /// Holds the next packets back while the client goes over its rates.
+    fn throttle(&mut self, packet: &ControlPacket) -> Result<(), SessionError> {
+        let publish = matches!(packet, ControlPacket::Publish(_));
+        let delay = self
+            .limits
+            .received(publish, self.connection.last_packet_size())?;
+        if !delay.is_zero() {
+            trace!("throttling client {} for {delay:?}", self.client_id);
+            self.throttled_until = Instant::now() + delay;
+        }
+        Ok(())
+    }
+
+    /// Whether the limits let the client subscribe to the topic filter. Replacing a
+    /// subscription is always allowed.
+    fn may_subscribe(&self, topic_filter: &str) -> Result<bool, SessionError> {
+        let router = self.router();
+        let subscriptions = router.subscriptions(&self.client_id);
+        if subscriptions.iter().any(|s| s.topic_filter == topic_filter) {
+            return Ok(true);
+        }
+        self.limits.may_subscribe(subscriptions.len())
+    }
+
+    fn router(&self) -> MutexGuard<'_, Router> {
+        self.broker.router.lock().unwrap()
+    }
+
+    fn authorize(&self, access: Access, topic: &str) -> bool {
+        self.broker.authorize(
+            &self.client_id,
+            self.username.as_deref(),
+            self.info.peer_addr.map(|addr| addr.ip()),
+            access,
+            topic,
+        )
+    }
+
+    /// Routes the application message unless the ACL denies it or a hook drops it. A denied QoS
+    /// 0 message is dropped silently, the client has no way of being told. A QoS 2 message sent
+    /// again before its PUBREL is not routed again.
+    async fn publish(&mut self, mut publish: Publish) -> Result<(), SessionError> {
+        if !is_valid_topic_name(&publish.topic_name) {
+            return Err(SessionError::TopicNameInvalid(publish.topic_name));
+        }
+        if publish.qos_number() == 3 {
+            return Err(SessionError::ProtocolError(String::from(
+                "PUBLISH with both QoS bits set",
+            )));
+        }
+
+        let qos = publish.qos();
+        let authorized = self.authorize(Access::Publish, &publish.topic_name);
+        let duplicate = match qos {
+            Qos::Q2(packet_id) if authorized => !self.router().receive(&self.client_id, packet_id),
+            _ => false,
+        };
+
+        if !authorized {
+            debug!(
+                "client {} is not authorized to publish to {}",
+                self.client_id, publish.topic_name
+            );
+        } else if !duplicate {
+            let verdict = self.broker.hook_publish(&self.context, &mut publish).await;
+            if verdict == MessageVerdict::Drop {
+                debug!("message of client {} dropped by a hook", self.client_id);
+            } else if !is_valid_topic_name(&publish.topic_name) {
+                warn!(
+                    "hook rerouted a message to invalid topic {}",
+                    publish.topic_name
+                );
+            } else if let Some(publish) = self.broker.apply_rules(&self.client_id, publish).await? {
+                if !self.broker.publish(&self.client_id, &publish) {
+                    return Err(SessionError::QuotaExceeded);
+                }
+            }
+        }
+
+        // the client forgets the message once it is acknowledged
+        if matches!(qos, Qos::Q1(_) | Qos::Q2(_)) {
+            self.broker.written().await?;
+        }
+
+        match qos {
+            Qos::Q1(packet_id) => {
+                self.connection
+                    .write_packet(&ControlPacket::PubAck(PubAck {
+                        packet_id,
+                        reason_code: match authorized {
+                            true => PUBACK::Success,
+                            false => PUBACK::NotAuthorized,
+                        },
+                        ..Default::default()
+                    }))
+                    .await?
+            }
+            Qos::Q2(packet_id) => {
+                self.connection
+                    .write_packet(&ControlPacket::PubRec(PubRec {
+                        packet_id,
+                        reason_code: match authorized {
+                            true => PUBREC::Success,
+                            false => PUBREC::NotAuthorized,
+                        },
+                        ..Default::default()
+                    }))
+                    .await?
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    async fn subscribe(&mut self, subscribe: Subscribe) -> Result<(), SessionError> {
+        let mut reason_codes = vec![];
+
+        for mut subscription in subscribe.topic_filters {
+            let reason_code = if !is_valid_topic_filter(&subscription.topic_filter) {
+                SUBACK::TopicFilterInvalid
+            } else if !self.authorize(Access::Subscribe, &subscription.topic_filter) {
+                debug!(
+                    "client {} is not authorized to subscribe to {}",
+                    self.client_id, subscription.topic_filter
+                );
+                SUBACK::NotAuthorized
+            } else if !self.may_subscribe(&subscription.topic_filter)? {
+                debug!(
+                    "client {} has too many subscriptions for {}",
+                    self.client_id, subscription.topic_filter
+                );
+                SUBACK::QuotaExceeded
+            } else if let SubscribeVerdict::Refuse(reason_code) = self
+                .broker
+                .hook_subscribe(&self.context, &mut subscription)
+                .await
+            {
+                reason_code
+            } else {
+                let granted = match subscription.qos() {
+                    0 => SUBACK::GrantedQos0,
+                    1 => SUBACK::GrantedQos1,
+                    2 => SUBACK::GrantedQos2,
+                    _ => {
+                        return Err(SessionError::ProtocolError(String::from(
+                            "subscription with QoS 3",
+                        )))
+                    }
+                };
+                self.router().subscribe(&self.client_id, subscription);
+                granted
+            };
+            reason_codes.push(reason_code);
+        }
+
+        self.connection
+            .write_packet(&ControlPacket::SubAck(SubAck {
+                packet_id: subscribe.packet_id,
+                reason_codes,
+                ..Default::default()
+            }))
+            .await?;
+        Ok(())
+    }
+
+    async fn unsubscribe(&mut self, unsubscribe: UnSubscribe) -> Result<(), SessionError> {
+        let reason_codes = {
+            let mut router = self.router();
+            unsubscribe
+                .topic_filters
+                .iter()
+                .map(
+                    |topic_filter| match router.unsubscribe(&self.client_id, topic_filter) {
+                        true => UNSUBACK::Success,
+                        false => UNSUBACK::NoSubscriptionExisted,
+                    },
+                )
+                .collect()
+        };
+
+        self.connection
+            .write_packet(&ControlPacket::UnsubAck(UnsubAck {
+                packet_id: unsubscribe.packet_id,
+                topic_filters: reason_codes,
+                ..Default::default()
+            }))
+            .await?;
+        Ok(())
+    }
+
+    /// Sends again, with the DUP flag, the messages and PUBREL the client did not acknowledge
+    /// before it reconnected, then the messages queued while it was away.
+    async fn resume(&mut self) -> Result<(), SessionError> {
+        let (outgoing, released) = self.router().in_flight(&self.client_id);
+
+        for mut publish in outgoing {
+            publish.packet_type_low_nibble |= 0b1000;
+            self.connection
+                .write_packet(&ControlPacket::Publish(publish))
+                .await?;
+        }
+        for packet_id in released {
+            self.connection
+                .write_packet(&ControlPacket::PubRel(PubRel {
+                    packet_id,
+                    ..Default::default()
+                }))
+                .await?;
+        }
+
+        self.deliver_queued().await
+    }
+
+    /// Sends the messages the router queued for this session, as long as the Receive Maximum of
+    /// the client allows. Messages dropped by a hook are completed as if the client had
+    /// acknowledged them.
+    async fn deliver_queued(&mut self) -> Result<(), SessionError> {
+        loop {
+            let publish = self
+                .router()
+                .next_delivery(&self.client_id, self.receive_maximum);
+            let Some(mut publish) = publish else {
+                return Ok(());
+            };
+
+            if self.broker.hook_deliver(&self.context, &mut publish).await == MessageVerdict::Drop {
+                self.router().delivered(&self.client_id, &publish);
+                continue;
+            }
+            self.connection
+                .write_packet(&ControlPacket::Publish(publish))
+                .await?
+        }
+    }
+
+    async fn send_connack(
+        &mut self,
+        reason_code: CONNECTACK,
+        properties: Option<Vec<Property>>,
+        session_present: bool,
+    ) -> Result<(), ConnectionError> {
+        self.connection
+            .write_packet(&ControlPacket::ConnAck(ConnAck {
+                connect_ack_flags: session_present as u8,
+                connect_reason_code: reason_code as u8,
+                variable_header_properties: properties,
+                ..Default::default()
+            }))
+            .await
+    }
+
+    async fn send_disconnect(&mut self, e: &SessionError) -> Result<(), ConnectionError> {
+        self.connection
+            .write_packet(&ControlPacket::Disconnect(Disconnect {
+                reason_code: e.disconnect_reason_code(),
+                variable_header_properties: self.error_properties(e),
+                ..Default::default()
+            }))
+            .await
+    }
+
+    /// Reason String, unless the client asked for no problem information, and Server Reference
+    /// of a redirection.
+    fn error_properties(&self, e: &SessionError) -> Option<Vec<Property>> {
+        let mut properties = vec![];
+        if self.problem_information {
+            properties.push(Property::ReasonString(Utf8EncodedString(e.to_string())));
+        }
+        if let SessionError::Redirected(redirect) = e {
+            properties.push(Property::ServerReference(Utf8EncodedString(
+                redirect.server_reference.clone(),
+            )));
+        }
+        (!properties.is_empty()).then_some(properties)
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use crate::acl::{Access, Acl, AclRule, Principal};
+    use crate::auth::password::{AllowAnonymous, AuthFn, Credentials, PasswordCheck};
+    use crate::auth::scram::{ScramClient, ScramSha256, SCRAM_SHA_256};
+    use crate::auth::{
+        authentication_data, authentication_properties, AuthError, AuthExchange, AuthStep,
+        Authenticator,
+    };
+    use crate::broker::{Broker, Redirect};
+    use crate::connection::Connection;
+    use crate::proxy::ProxyProtocol;
+    use crate::queue::{OverflowPolicy, QueueConfig};
+    use crate::session::SessionError;
+    use crate::store::FileStore;
+    use deser::codec::encode_packet;
+    use deser::packets::auth::Auth;
+    use deser::packets::connect::builder::ConnectBuilder;
+    use deser::packets::connect::Connect;
+    use deser::packets::pingreq::PingReq;
+    use deser::packets::publish::Publish;
+    use deser::packets::pubrel::PubRel;
+    use deser::packets::reason_codes::{AUTH, CONNECTACK, DISCONNECT, PUBACK, PUBREC, SUBACK};
+    use deser::packets::subscribe::{
+        Subscribe, SubscriptionOptions, TopicFilterAndSubscriptionOptions,
+    };
+    use deser::packets::BuilderLifecycle;
+    use deser::primitive_types::{Byte, FourByteInteger, TwoByteInteger, Utf8EncodedString};
+    use deser::properties::Property;
+    use deser::ControlPacket;
+    use std::io::{BufRead, BufReader};
+    use std::process::Stdio;
+    use std::sync::Arc;
+    use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, DuplexStream};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::task::JoinSet;
+
+    fn start_broker() -> Connection<DuplexStream> {
+        let broker = Broker::builder()
+            .authenticator(Arc::new(ScramSha256::new().add_user("alice", b"secret")))
+            .build();
+        let (client, server) = tokio::io::duplex(4096);
+        tokio::spawn(broker.handle(server));
+        Connection::new(client)
+    }
+
+    fn auth_packet(reason_code: AUTH, data: Vec<u8>) -> ControlPacket {
+        ControlPacket::Auth(Auth {
+            reason_code,
+            variable_header_properties: Some(authentication_properties(SCRAM_SHA_256, Some(data))),
+            ..Default::default()
+        })
+    }
+
+    /// Answers the server-first-message and returns the packet that completes the exchange.
+    async fn complete_exchange(
+        client: &mut Connection<DuplexStream>,
+        scram: &mut ScramClient,
+    ) -> ControlPacket {
+        let server_first = match client.read_packet().await.unwrap() {
+            Some(ControlPacket::Auth(auth)) => {
+                assert_eq!(AUTH::ContinueAuthentication, auth.reason_code);
+                authentication_data(&auth.variable_header_properties).unwrap()
+            }
+            packet => panic!("expected AUTH, received {packet:?}"),
+        };
+        let client_final = scram.client_final(&server_first).unwrap();
+        client
+            .write_packet(&auth_packet(AUTH::ContinueAuthentication, client_final))
+            .await
+            .unwrap();
+        client.read_packet().await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn should_authenticate_and_reauthenticate_with_scram() {
+        let mut client = start_broker();
+        let mut scram = ScramClient::new("alice", b"secret");
+
+        let connect = Connect {
+            client_id: String::from("ID"),
+            variable_header_properties: Some(authentication_properties(
+                SCRAM_SHA_256,
+                Some(scram.client_first()),
+            )),
+            ..Default::default()
+        };
+        client
+            .write_packet(&ControlPacket::Connect(connect))
+            .await
+            .unwrap();
+
+        match complete_exchange(&mut client, &mut scram).await {
+            ControlPacket::ConnAck(connack) => {
+                assert_eq!(CONNECTACK::Success as u8, connack.connect_reason_code);
+                let server_final = authentication_data(&connack.variable_header_properties);
+                assert!(scram.verify_server_final(&server_final.unwrap()).is_ok());
+            }
+            packet => panic!("expected CONNACK, received {packet:?}"),
+        }
+
+        let mut scram = ScramClient::new("alice", b"secret");
+        client
+            .write_packet(&auth_packet(AUTH::ReAuthenticate, scram.client_first()))
+            .await
+            .unwrap();
+
+        match complete_exchange(&mut client, &mut scram).await {
+            ControlPacket::Auth(auth) => assert_eq!(AUTH::Success, auth.reason_code),
+            packet => panic!("expected AUTH, received {packet:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn should_handle_other_packets_while_reauthenticating() {
+        let mut client = start_broker();
+        let mut scram = ScramClient::new("alice", b"secret");
+        let connect = Connect {
+            client_id: String::from("ID"),
+            variable_header_properties: Some(authentication_properties(
+                SCRAM_SHA_256,
+                Some(scram.client_first()),
+            )),
+            ..Default::default()
+        };
+        client
+            .write_packet(&ControlPacket::Connect(connect))
+            .await
+            .unwrap();
+        complete_exchange(&mut client, &mut scram).await;
+
+        let mut scram = ScramClient::new("alice", b"secret");
+        client
+            .write_packet(&auth_packet(AUTH::ReAuthenticate, scram.client_first()))
+            .await
+            .unwrap();
+        let server_first = match client.read_packet().await.unwrap() {
+            Some(ControlPacket::Auth(auth)) => {
+                authentication_data(&auth.variable_header_properties).unwrap()
+            }
+            packet => panic!("expected AUTH, received {packet:?}"),
+        };
+
+        client
+            .write_packet(&ControlPacket::PingReq(PingReq::default()))
+            .await
+            .unwrap();
+        assert!(matches!(
+            client.read_packet().await.unwrap(),
+            Some(ControlPacket::PingResp(_))
+        ));
+        client
+            .write_packet(&publish("a/b", 0b0010, 1))
+            .await
+            .unwrap();
+        assert!(matches!(
+            client.read_packet().await.unwrap(),
+            Some(ControlPacket::PubAck(_))
+        ));
+
+        let client_final = scram.client_final(&server_first).unwrap();
+        client
+            .write_packet(&auth_packet(AUTH::ContinueAuthentication, client_final))
+            .await
+            .unwrap();
+        match client.read_packet().await.unwrap() {
+            Some(ControlPacket::Auth(auth)) => assert_eq!(AUTH::Success, auth.reason_code),
+            packet => panic!("expected AUTH, received {packet:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn should_refuse_connection_with_wrong_password() {
+        let mut client = start_broker();
+        let mut scram = ScramClient::new("alice", b"wrong");
+
+        let connect = Connect {
+            variable_header_properties: Some(authentication_properties(
+                SCRAM_SHA_256,
+                Some(scram.client_first()),
+            )),
+            ..Default::default()
+        };
+        client
+            .write_packet(&ControlPacket::Connect(connect))
+            .await
+            .unwrap();
+
+        match complete_exchange(&mut client, &mut scram).await {
+            ControlPacket::ConnAck(connack) => {
+                assert_eq!(CONNECTACK::NotAuthorised as u8, connack.connect_reason_code)
+            }
+            packet => panic!("expected CONNACK, received {packet:?}"),
+        }
+    }
+
+    struct AcceptAnyToken;
+
+    impl Authenticator for AcceptAnyToken {
+        fn method(&self) -> &str {
+            "TOKEN"
+        }
+
+        fn start(&self) -> Box<dyn AuthExchange> {
+            Box::new(AcceptAnyToken)
+        }
+    }
+
+    impl AuthExchange for AcceptAnyToken {
+        fn step(&mut self, _data: Option<&[u8]>) -> Result<AuthStep, AuthError> {
+            Ok(AuthStep::Success(None))
+        }
+    }
+
+    #[tokio::test]
+    async fn should_not_trust_the_user_name_of_an_exchange_without_identity() {
+        let acl = Acl::new(vec![AclRule::allow(
+            Principal::User(String::from("admin")),
+            Access::Subscribe,
+            "#",
+        )]);
+        let broker = Broker::builder()
+            .authenticator(Arc::new(AcceptAnyToken))
+            .acl(Arc::new(acl))
+            .build();
+        let (client, server) = tokio::io::duplex(4096);
+        tokio::spawn(broker.handle(server));
+        let mut client = Connection::new(client);
+
+        let connect = Connect {
+            client_id: String::from("ID"),
+            connect_flags: 0b1000_0000,
+            username: Some(String::from("admin")),
+            variable_header_properties: Some(authentication_properties("TOKEN", None)),
+            ..Default::default()
+        };
+        client
+            .write_packet(&ControlPacket::Connect(connect))
+            .await
+            .unwrap();
+        match client.read_packet().await.unwrap() {
+            Some(ControlPacket::ConnAck(connack)) => {
+                assert_eq!(CONNECTACK::Success as u8, connack.connect_reason_code)
+            }
+            packet => panic!("expected CONNACK, received {packet:?}"),
+        }
+
+        let subscribe = Subscribe {
+            packet_id: 1,
+            topic_filters: vec![TopicFilterAndSubscriptionOptions::new(
+                String::from("secrets"),
+                SubscriptionOptions { raw_value: 0 },
+            )],
+            ..Default::default()
+        };
+        client
+            .write_packet(&ControlPacket::Subscribe(subscribe))
+            .await
+            .unwrap();
+        match client.read_packet().await.unwrap() {
+            Some(ControlPacket::SubAck(suback)) => {
+                assert_eq!(vec![SUBACK::NotAuthorized], suback.reason_codes)
+            }
+            packet => panic!("expected SUBACK, received {packet:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn should_refuse_unknown_authentication_method() {
+        let mut client = start_broker();
+
+        let connect = Connect {
+            variable_header_properties: Some(authentication_properties("KERBEROS", None)),
+            ..Default::default()
+        };
+        client
+            .write_packet(&ControlPacket::Connect(connect))
+            .await
+            .unwrap();
+
+        match client.read_packet().await.unwrap() {
+            Some(ControlPacket::ConnAck(connack)) => assert_eq!(
+                CONNECTACK::BadAuthenticationMethod as u8,
+                connack.connect_reason_code
+            ),
+            packet => panic!("expected CONNACK, received {packet:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn should_disconnect_when_reauthenticating_without_a_method() {
+        let mut client = start_broker();
+
+        client
+            .write_packet(&ControlPacket::Connect(Connect::default()))
+            .await
+            .unwrap();
+        client.read_packet().await.unwrap();
+
+        client
+            .write_packet(&auth_packet(AUTH::ReAuthenticate, vec![]))
+            .await
+            .unwrap();
+
+        match client.read_packet().await.unwrap() {
+            Some(ControlPacket::Disconnect(disconnect)) => {
+                assert_eq!(DISCONNECT::ProtocolError, disconnect.reason_code)
+            }
+            packet => panic!("expected DISCONNECT, received {packet:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn should_check_user_name_and_password() {
+        let broker = Broker::builder()
+            .password_backend(Arc::new(AllowAnonymous(false)))
+            .password_backend(AuthFn::new(|credentials: Credentials| async move {
+                match credentials.password.as_deref() {
+                    Some(b"hunter2") => PasswordCheck::Allow,
+                    _ => PasswordCheck::BadUserNameOrPassword,
+                }
+            }))
+            .build();
+
+        for (username, password, reason_code) in [
+            (Some("bob"), Some("hunter2"), CONNECTACK::Success),
+            (
+                Some("bob"),
+                Some("wrong"),
+                CONNECTACK::BadUserNameOrPassword,
+            ),
+            (None, None, CONNECTACK::NotAuthorised),
+        ] {
+            let (client, server) = tokio::io::duplex(4096);
+            tokio::spawn(broker.clone().handle(server));
+            let mut client = Connection::new(client);
+
+            let connect = ConnectBuilder::new()
+                .client_id(String::from("ID"))
+                .username(username.map(String::from))
+                .password(password.map(|p| p.as_bytes().to_vec()))
+                .build()
+                .unwrap();
+            client
+                .write_packet(&ControlPacket::Connect(connect))
+                .await
+                .unwrap();
+
+            match client.read_packet().await.unwrap() {
+                Some(ControlPacket::ConnAck(connack)) => {
+                    assert_eq!(reason_code as u8, connack.connect_reason_code)
+                }
+                packet => panic!("expected CONNACK, received {packet:?}"),
+            }
+        }
+    }
+
+    async fn connect(broker: &Arc<Broker>, client_id: &str) -> Connection<DuplexStream> {
+        let (client, server) = tokio::io::duplex(4096);
+        tokio::spawn(broker.clone().handle(server));
+        let mut client = Connection::new(client);
+
+        let connect = Connect {
+            client_id: String::from(client_id),
+            ..Default::default()
+        };
+        client
+            .write_packet(&ControlPacket::Connect(connect))
+            .await
+            .unwrap();
+        client.read_packet().await.unwrap();
+        client
+    }
+
+    fn publish(topic_name: &str, low_nibble: u8, packet_id: u16) -> ControlPacket {
+        ControlPacket::Publish(Publish {
+            packet_type_low_nibble: low_nibble,
+            topic_name: String::from(topic_name),
+            packet_id: Some(packet_id),
+            application_message: Some(b"21.5".to_vec()),
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn should_refuse_client_identifiers_reserved_for_the_broker() {
+        let broker = Broker::builder().build();
+        let (client, server) = tokio::io::duplex(4096);
+        tokio::spawn(broker.handle(server));
+        let mut client = Connection::new(client);
+
+        let connect = Connect {
+            client_id: String::from("$bridge/remote"),
+            ..Default::default()
+        };
+        client
+            .write_packet(&ControlPacket::Connect(connect))
+            .await
+            .unwrap();
+        match client.read_packet().await.unwrap() {
+            Some(ControlPacket::ConnAck(connack)) => assert_eq!(
+                CONNECTACK::ClientIdentifierNotValid as u8,
+                connack.connect_reason_code
+            ),
+            packet => panic!("expected CONNACK, received {packet:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn should_enforce_topic_acl() {
+        let acl = Acl::new(vec![
+            AclRule::allow(Principal::Anyone, Access::Both, "devices/%c/#"),
+            AclRule::allow(Principal::Anyone, Access::Subscribe, "devices/+/status"),
+        ]);
+        let broker = Broker::builder().acl(Arc::new(acl)).build();
+        let mut subscriber = connect(&broker, "monitor").await;
+        let mut publisher = connect(&broker, "sensor").await;
+
+        let subscribe = Subscribe {
+            packet_id: 1,
+            topic_filters: ["devices/+/status", "devices/#"]
+                .into_iter()
+                .map(|filter| {
+                    TopicFilterAndSubscriptionOptions::new(
+                        String::from(filter),
+                        SubscriptionOptions { raw_value: 1 },
+                    )
+                })
+                .collect(),
+            ..Default::default()
+        };
+        subscriber
+            .write_packet(&ControlPacket::Subscribe(subscribe))
+            .await
+            .unwrap();
+        match subscriber.read_packet().await.unwrap() {
+            Some(ControlPacket::SubAck(suback)) => assert_eq!(
+                vec![SUBACK::GrantedQos1, SUBACK::NotAuthorized],
+                suback.reason_codes
+            ),
+            packet => panic!("expected SUBACK, received {packet:?}"),
+        }
+
+        publisher
+            .write_packet(&publish("devices/sensor/status", 0b0010, 7))
+            .await
+            .unwrap();
+        match publisher.read_packet().await.unwrap() {
+            Some(ControlPacket::PubAck(puback)) => {
+                assert_eq!(7, puback.packet_id);
+                assert_eq!(PUBACK::Success, puback.reason_code);
+            }
+            packet => panic!("expected PUBACK, received {packet:?}"),
+        }
+
+        publisher
+            .write_packet(&publish("devices/other/status", 0b0100, 8))
+            .await
+            .unwrap();
+        match publisher.read_packet().await.unwrap() {
+            Some(ControlPacket::PubRec(pubrec)) => {
+                assert_eq!(8, pubrec.packet_id);
+                assert_eq!(PUBREC::NotAuthorized, pubrec.reason_code);
+            }
+            packet => panic!("expected PUBREC, received {packet:?}"),
+        }
+
+        match subscriber.read_packet().await.unwrap() {
+            Some(ControlPacket::Publish(publish)) => {
+                assert_eq!("devices/sensor/status", publish.topic_name);
+                assert_eq!(1, publish.qos_number());
+                assert_eq!(Some(b"21.5".to_vec()), publish.application_message);
+            }
+            packet => panic!("expected PUBLISH, received {packet:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn should_disconnect_session_taken_over() {
+        let broker = Broker::builder().build();
+        let mut first = connect(&broker, "ID").await;
+        let _second = connect(&broker, "ID").await;
+
+        match first.read_packet().await.unwrap() {
+            Some(ControlPacket::Disconnect(disconnect)) => {
+                assert_eq!(DISCONNECT::SessionTakenOver, disconnect.reason_code)
+            }
+            packet => panic!("expected DISCONNECT, received {packet:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn should_disconnect_client_silent_for_longer_than_keep_alive() {
+        let broker = Broker::builder().build();
+        let (client, server) = tokio::io::duplex(4096);
+        tokio::spawn(broker.handle(server));
+        let mut client = Connection::new(client);
+        let connect = Connect {
+            client_id: String::from("ID"),
+            keep_alive: 1,
+            ..Default::default()
+        };
+        client
+            .write_packet(&ControlPacket::Connect(connect))
+            .await
+            .unwrap();
+        client.read_packet().await.unwrap();
+
+        let started = std::time::Instant::now();
+        match client.read_packet().await.unwrap() {
+            Some(ControlPacket::Disconnect(disconnect)) => {
+                assert_eq!(DISCONNECT::KeepAliveTimeout, disconnect.reason_code);
+                assert!(matches!(
+                    disconnect.variable_header_properties.as_deref(),
+                    Some([Property::ReasonString(_)])
+                ));
+            }
+            packet => panic!("expected DISCONNECT, received {packet:?}"),
+        }
+        assert!(started.elapsed() >= std::time::Duration::from_millis(1400));
+    }
+
+    #[tokio::test]
+    async fn should_redirect_clients_during_maintenance() {
+        let broker = Broker::builder().build();
+        let mut connected = connect(&broker, "ID").await;
+        broker.enter_maintenance(Redirect {
+            server_reference: String::from("standby:1883"),
+            permanent: false,
+        });
+
+        let reference = Property::ServerReference(Utf8EncodedString(String::from("standby:1883")));
+        match connected.read_packet().await.unwrap() {
+            Some(ControlPacket::Disconnect(disconnect)) => {
+                assert_eq!(DISCONNECT::UseAnotherServer, disconnect.reason_code);
+                let properties = disconnect.variable_header_properties.unwrap();
+                assert!(matches!(properties[0], Property::ReasonString(_)));
+                assert_eq!(reference, properties[1]);
+            }
+            packet => panic!("expected DISCONNECT, received {packet:?}"),
+        }
+
+        // without problem information, only the Server Reference
+        let (client, server) = tokio::io::duplex(4096);
+        tokio::spawn(broker.clone().handle(server));
+        let mut client = Connection::new(client);
+        let refused = Connect {
+            client_id: String::from("other"),
+            variable_header_properties: Some(vec![Property::RequestProblemInformation(Byte(0))]),
+            ..Default::default()
+        };
+        client
+            .write_packet(&ControlPacket::Connect(refused))
+            .await
+            .unwrap();
+        match client.read_packet().await.unwrap() {
+            Some(ControlPacket::ConnAck(connack)) => {
+                assert_eq!(
+                    CONNECTACK::UseAnotherServer as u8,
+                    connack.connect_reason_code
+                );
+                assert_eq!(Some(vec![reference]), connack.variable_header_properties);
+            }
+            packet => panic!("expected CONNACK, received {packet:?}"),
+        }
+
+        broker.leave_maintenance();
+        connect(&broker, "ID").await;
+        assert_eq!(1, broker.clients().len());
+    }
+
+    /// Runs the session in one of the tasks, see `resume_session`.
+    async fn connect_persistent(
+        tasks: &mut JoinSet<Result<(), SessionError>>,
+        broker: &Arc<Broker>,
+        client_id: &str,
+    ) -> (Connection<DuplexStream>, bool) {
+        let (client, server) = tokio::io::duplex(4096);
+        tasks.spawn(broker.clone().handle(server));
+        resume_session(Connection::new(client), client_id).await
+    }
+
+    /// Connects with a session kept for an hour and returns whether the broker resumed it.
+    async fn resume_session<T>(mut client: Connection<T>, client_id: &str) -> (Connection<T>, bool)
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        let connect = Connect {
+            client_id: String::from(client_id),
+            variable_header_properties: Some(vec![Property::SessionExpiryInterval(
+                FourByteInteger(3600),
+            )]),
+            ..Default::default()
+        };
+        client
+            .write_packet(&ControlPacket::Connect(connect))
+            .await
+            .unwrap();
+        match client.read_packet().await.unwrap() {
+            Some(ControlPacket::ConnAck(mut connack)) => {
+                let session_present = connack.session_present();
+                (client, session_present)
+            }
+            packet => panic!("expected CONNACK, received {packet:?}"),
+        }
+    }
+
+    /// Store of the broker run by `crashing_broker`.
+    const CRASHING_BROKER_STORE: &str = "CRASHING_BROKER_STORE";
+
+    /// Broker of `should_recover_messages_in_flight_after_crash`, in a child process which the
+    /// test kills. Returns at once when run with the other tests.
+    #[tokio::test]
+    async fn crashing_broker() {
+        let Ok(path) = std::env::var(CRASHING_BROKER_STORE) else {
+            return;
+        };
+        let broker = Broker::builder()
+            .store(Arc::new(FileStore::open(path).unwrap()))
+            .build();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        println!("listening on {}", listener.local_addr().unwrap());
+        broker.serve(listener, ProxyProtocol::Reject).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn should_recover_messages_in_flight_after_crash() {
+        let path = std::env::temp_dir().join(format!("session-store-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut child = std::process::Command::new(std::env::current_exe().unwrap())
+            .args(["session::test::crashing_broker", "--exact", "--nocapture"])
+            .env(CRASHING_BROKER_STORE, &path)
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let addr = BufReader::new(child.stdout.take().unwrap())
+            .lines()
+            .find_map(|line| Some(line.ok()?.split_once("listening on ")?.1.to_string()))
+            .unwrap();
+        let connect = |client_id| {
+            let addr = addr.clone();
+            async move {
+                let client = Connection::new(TcpStream::connect(addr).await.unwrap());
+                resume_session(client, client_id).await
+            }
+        };
+
+        let (mut subscriber, _) = connect("sub").await;
+        subscriber
+            .write_packet(&ControlPacket::Subscribe(Subscribe {
+                packet_id: 1,
+                topic_filters: vec![TopicFilterAndSubscriptionOptions::new(
+                    String::from("a/+"),
+                    SubscriptionOptions { raw_value: 2 },
+                )],
+                ..Default::default()
+            }))
+            .await
+            .unwrap();
+        subscriber.read_packet().await.unwrap();
+
+        let (mut publisher, _) = connect("pub").await;
+        publisher
+            .write_packet(&publish("a/1", 0b0010, 1))
+            .await
+            .unwrap();
+        publisher.read_packet().await.unwrap();
+        publisher
+            .write_packet(&publish("a/2", 0b0100, 2))
+            .await
+            .unwrap();
+        publisher.read_packet().await.unwrap();
+        for _ in 0..2 {
+            assert!(matches!(
+                subscriber.read_packet().await.unwrap(),
+                Some(ControlPacket::Publish(_))
+            ));
+        }
+
+        // acknowledged once every change before it is stored, the deliveries to the subscriber too
+        publisher
+            .write_packet(&publish("b", 0b0010, 3))
+            .await
+            .unwrap();
+        publisher.read_packet().await.unwrap();
+
+        // killed before the subscriber acknowledges and the publisher releases its QoS 2 message,
+        // with no chance to write what the store still holds
+        child.kill().unwrap();
+        child.wait().unwrap();
+
+        let broker = Broker::builder()
+            .store(Arc::new(FileStore::open(&path).unwrap()))
+            .build();
+        let mut tasks = JoinSet::new();
+        let (mut publisher, session_present) = connect_persistent(&mut tasks, &broker, "pub").await;
+        assert!(session_present);
+        publisher
+            .write_packet(&publish("a/2", 0b1100, 2))
+            .await
+            .unwrap();
+        assert!(matches!(
+            publisher.read_packet().await.unwrap(),
+            Some(ControlPacket::PubRec(_))
+        ));
+        publisher
+            .write_packet(&ControlPacket::PubRel(PubRel {
+                packet_id: 2,
+                ..Default::default()
+            }))
+            .await
+            .unwrap();
+        assert!(matches!(
+            publisher.read_packet().await.unwrap(),
+            Some(ControlPacket::PubComp(_))
+        ));
+
+        let (mut subscriber, session_present) =
+            connect_persistent(&mut tasks, &broker, "sub").await;
+        assert!(session_present);
+        for topic_name in ["a/1", "a/2"] {
+            match subscriber.read_packet().await.unwrap() {
+                Some(ControlPacket::Publish(publish)) => {
+                    assert!(publish.dup());
+                    assert_eq!(topic_name, publish.topic_name);
+                }
+                packet => panic!("expected PUBLISH, received {packet:?}"),
+            }
+        }
+        // the duplicate QoS 2 message was not routed again
+        subscriber
+            .write_packet(&ControlPacket::PingReq(PingReq::default()))
+            .await
+            .unwrap();
+        assert!(matches!(
+            subscriber.read_packet().await.unwrap(),
+            Some(ControlPacket::PingResp(_))
+        ));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn should_disconnect_publisher_when_queue_is_full() {
+        let broker = Broker::builder()
+            .queue(
+                QueueConfig::default()
+                    .max_messages(1)
+                    .overflow(OverflowPolicy::DisconnectPublisher),
+            )
+            .build();
+
+        // takes a single message in flight and never acknowledges it
+        let (client, server) = tokio::io::duplex(4096);
+        tokio::spawn(broker.clone().handle(server));
+        let mut subscriber = Connection::new(client);
+        subscriber
+            .write_packet(&ControlPacket::Connect(Connect {
+                client_id: String::from("sub"),
+                variable_header_properties: Some(vec![Property::ReceiveMaximum(TwoByteInteger(1))]),
+                ..Default::default()
+            }))
+            .await
+            .unwrap();
+        subscriber.read_packet().await.unwrap();
+        subscriber
+            .write_packet(&ControlPacket::Subscribe(Subscribe {
+                packet_id: 1,
+                topic_filters: vec![TopicFilterAndSubscriptionOptions::new(
+                    String::from("a/b"),
+                    SubscriptionOptions { raw_value: 1 },
+                )],
+                ..Default::default()
+            }))
+            .await
+            .unwrap();
+        subscriber.read_packet().await.unwrap();
+
+        let mut publisher = connect(&broker, "pub").await;
+        publisher
+            .write_packet(&publish("a/b", 0b0010, 1))
+            .await
+            .unwrap();
+        publisher.read_packet().await.unwrap();
+        assert!(matches!(
+            subscriber.read_packet().await.unwrap(),
+            Some(ControlPacket::Publish(_))
+        ));
+
+        publisher
+            .write_packet(&publish("a/b", 0b0010, 2))
+            .await
+            .unwrap();
+        publisher.read_packet().await.unwrap();
+        publisher
+            .write_packet(&publish("a/b", 0b0010, 3))
+            .await
+            .unwrap();
+        match publisher.read_packet().await.unwrap() {
+            Some(ControlPacket::Disconnect(disconnect)) => {
+                assert_eq!(DISCONNECT::QuotaExceeded, disconnect.reason_code)
+            }
+            packet => panic!("expected DISCONNECT, received {packet:?}"),
+        }
+        assert_eq!(
+            1,
+            broker.queue_stats().iter().map(|q| q.dropped).sum::<u64>()
+        );
+    }
+
+    #[tokio::test]
+    async fn should_return_response_information_when_requested() {
+        let broker = Broker::builder().response_topic_prefix("replies").build();
+
+        for (request, expected) in [(1, Some("replies/ID")), (0, None)] {
+            let (client, server) = tokio::io::duplex(4096);
+            tokio::spawn(broker.clone().handle(server));
+            let mut client = Connection::new(client);
+
+            let connect = Connect {
+                client_id: String::from("ID"),
+                variable_header_properties: Some(vec![Property::RequestResponseInformation(Byte(
+                    request,
+                ))]),
+                ..Default::default()
+            };
+            client
+                .write_packet(&ControlPacket::Connect(connect))
+                .await
+                .unwrap();
+            let response_information = match client.read_packet().await.unwrap() {
+                Some(ControlPacket::ConnAck(connack)) => connack
+                    .variable_header_properties
+                    .into_iter()
+                    .flatten()
+                    .find_map(|p| match p {
+                        Property::ResponseInformation(Utf8EncodedString(info)) => Some(info),
+                        _ => None,
+                    }),
+                packet => panic!("expected CONNACK, received {packet:?}"),
+            };
+            assert_eq!(expected.map(String::from), response_information);
+        }
+    }
+
+    #[tokio::test]
+    async fn should_disconnect_client_sending_packet_too_large() {
+        let broker = Broker::builder().maximum_packet_size(64).build();
+        let (client, server) = tokio::io::duplex(4096);
+        tokio::spawn(broker.clone().handle(server));
+        let mut client = Connection::new(client);
+
+        client
+            .write_packet(&ControlPacket::Connect(Connect {
+                client_id: String::from("ID"),
+                ..Default::default()
+            }))
+            .await
+            .unwrap();
+        match client.read_packet().await.unwrap() {
+            Some(ControlPacket::ConnAck(connack)) => assert!(connack
+                .variable_header_properties
+                .unwrap()
+                .contains(&Property::MaximumPacketSize(FourByteInteger(64)))),
+            packet => panic!("expected CONNACK, received {packet:?}"),
+        }
+
+        client
+            .write_packet(&ControlPacket::Publish(Publish {
+                topic_name: String::from("a/b"),
+                application_message: Some(vec![0; 100]),
+                ..Default::default()
+            }))
+            .await
+            .unwrap();
+        match client.read_packet().await.unwrap() {
+            Some(ControlPacket::Disconnect(disconnect)) => {
+                assert_eq!(DISCONNECT::PacketTooLarge, disconnect.reason_code)
+            }
+            packet => panic!("expected DISCONNECT, received {packet:?}"),
+        }
+        assert!(!broker.clients.lock().unwrap().contains_key("ID"));
+    }
+
+    #[tokio::test]
+    async fn should_disconnect_client_sending_malformed_packet() {
+        let broker = Broker::builder().build();
+        let (mut client, server) = tokio::io::duplex(4096);
+        tokio::spawn(broker.clone().handle(server));
+
+        let connect = ControlPacket::Connect(Connect {
+            client_id: String::from("ID"),
+            ..Default::default()
+        });
+        client
+            .write_all(&encode_packet(&connect).unwrap())
+            .await
+            .unwrap();
+        // PUBLISH whose topic name claims 5 bytes when only 1 follows
+        client
+            .write_all(&[0x30, 0x03, 0x00, 0x05, b'a'])
+            .await
+            .unwrap();
+
+        let mut client = Connection::new(client);
+        assert!(matches!(
+            client.read_packet().await.unwrap(),
+            Some(ControlPacket::ConnAck(_))
+        ));
+        match client.read_packet().await.unwrap() {
+            Some(ControlPacket::Disconnect(disconnect)) => {
+                assert_eq!(DISCONNECT::MalformedPacket, disconnect.reason_code)
+            }
+            packet => panic!("expected DISCONNECT, received {packet:?}"),
+        }
+        assert!(!broker.clients.lock().unwrap().contains_key("ID"));
+    }
+}
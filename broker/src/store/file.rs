@@ -0,0 +1,545 @@
+//! Append-only log of changes.
+//!
+//! Each record is a four byte big endian length followed by the encoded change. Application
+//! messages are encoded as PUBLISH packets. Records are written without fsync: they survive the
+//! broker process being killed, not the host losing power.
+//!
+//! Changes are written by a thread of their own, the router does not wait for the disk.
+//! Sessions wait for the changes of a QoS 1 or 2 message to be written before acknowledging it,
+//! so that only changes the clients can repeat are lost when the process is killed.
+//!
+//! Opening the store replays the log and rewrites it as the shortest list of changes leading to
+//! the same state. The log is compacted the same way whenever it grows to twice its size after
+//! the last compaction.
+
+use crate::auth::password::BoxFuture;
+use crate::store::{Change, SessionStore, StoreError, StoredState};
+use bytes::{Buf, BufMut, BytesMut};
+use deser::codec::{decode_packet, encode_packet};
+use deser::packets::publish::Publish;
+use deser::packets::subscribe::{SubscriptionOptions, TopicFilterAndSubscriptionOptions};
+use deser::ControlPacket;
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread::JoinHandle;
+use tokio::sync::oneshot;
+use tracing::warn;
+
+/// Size of the log below which it is never compacted.
+const MIN_COMPACTION_RECORDS: usize = 1024;
+
+pub struct FileStore {
+    /// Commands for the writer thread, None once the store is dropped.
+    commands: Option<mpsc::Sender<Command>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+enum Command {
+    Apply(Change),
+    Load(mpsc::SyncSender<StoredState>),
+    Compact(mpsc::SyncSender<Result<(), StoreError>>),
+    /// Answered with whether the changes applied before are in the log.
+    Written(oneshot::Sender<bool>),
+}
+
+/// Owns the log, on the writer thread.
+struct Writer {
+    path: PathBuf,
+    file: File,
+    state: StoredState,
+    records: usize,
+    /// Records in the log right after the last compaction.
+    compacted_records: usize,
+    /// A change failed to be written since the last compaction.
+    failed: bool,
+}
+
+impl FileStore {
+    /// Opens the log, creating it if needed. A record cut short by a crash while it was being
+    /// written is dropped.
+    pub fn open(path: impl AsRef<Path>) -> Result<FileStore, StoreError> {
+        let path = path.as_ref().to_path_buf();
+
+        let mut contents = vec![];
+        match File::open(&path) {
+            Ok(mut file) => {
+                file.read_to_end(&mut contents)?;
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        let mut state = StoredState::default();
+        let mut offset = 0;
+        while let Some(record) = next_record(&contents[offset..]) {
+            let change = decode_change(record).ok_or(StoreError::Corrupt(offset as u64))?;
+            state.apply(&change);
+            offset += 4 + record.len();
+        }
+        if offset < contents.len() {
+            warn!(
+                "dropping {} bytes of a partially written record at the end of {path:?}",
+                contents.len() - offset
+            );
+        }
+
+        let (file, records) = rewrite(&path, &state)?;
+        let writer = Writer {
+            path,
+            file,
+            state,
+            records,
+            compacted_records: records,
+            failed: false,
+        };
+        let (commands, receiver) = mpsc::channel();
+        let writer = std::thread::Builder::new()
+            .name(String::from("store-writer"))
+            .spawn(move || writer.run(receiver))?;
+
+        Ok(FileStore {
+            commands: Some(commands),
+            writer: Some(writer),
+        })
+    }
+
+    /// Rewrites the log as the changes leading to the current state, once the changes applied
+    /// before are written.
+    pub fn compact(&self) -> Result<(), StoreError> {
+        let (reply, result) = mpsc::sync_channel(1);
+        self.send(Command::Compact(reply))?;
+        result.recv().map_err(|_| StoreError::WriterStopped)?
+    }
+
+    fn send(&self, command: Command) -> Result<(), StoreError> {
+        self.commands
+            .as_ref()
+            .and_then(|commands| commands.send(command).ok())
+            .ok_or(StoreError::WriterStopped)
+    }
+}
+
+impl SessionStore for FileStore {
+    /// State once the changes applied before are written.
+    fn load(&self) -> StoredState {
+        let (reply, state) = mpsc::sync_channel(1);
+        self.send(Command::Load(reply))
+            .ok()
+            .and_then(|_| state.recv().ok())
+            .unwrap_or_default()
+    }
+
+    /// Hands the change to the writer thread, which logs the errors of writing it.
+    fn apply(&self, change: &Change) -> Result<(), StoreError> {
+        self.send(Command::Apply(change.clone()))
+    }
+
+    fn written(&self) -> BoxFuture<'_, Result<(), StoreError>> {
+        let (reply, written) = oneshot::channel();
+        let sent = self.send(Command::Written(reply));
+        Box::pin(async move {
+            sent?;
+            match written.await {
+                Ok(true) => Ok(()),
+                Ok(false) => Err(StoreError::WriteFailed),
+                Err(_) => Err(StoreError::WriterStopped),
+            }
+        })
+    }
+}
+
+impl Drop for FileStore {
+    /// Waits for the changes applied before to be written.
+    fn drop(&mut self) {
+        self.commands.take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+impl Writer {
+    fn run(mut self, commands: mpsc::Receiver<Command>) {
+        for command in commands {
+            match command {
+                Command::Apply(change) => {
+                    if let Err(e) = self.apply(&change) {
+                        warn!("failed to store {change:?} in {:?}: {e}", self.path);
+                        self.failed = true;
+                    }
+                }
+                Command::Load(reply) => {
+                    let _ = reply.send(self.state.clone());
+                }
+                Command::Compact(reply) => {
+                    let _ = reply.send(self.compact());
+                }
+                Command::Written(reply) => {
+                    let _ = reply.send(!self.failed);
+                }
+            }
+        }
+    }
+
+    fn apply(&mut self, change: &Change) -> Result<(), StoreError> {
+        let mut record = BytesMut::new();
+        encode_change(change, &mut record)?;
+
+        self.state.apply(change);
+        self.file.write_all(&record)?;
+        self.records += 1;
+
+        if self.records >= MIN_COMPACTION_RECORDS.max(2 * self.compacted_records) {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    fn compact(&mut self) -> Result<(), StoreError> {
+        let (file, records) = rewrite(&self.path, &self.state)?;
+        self.file = file;
+        self.records = records;
+        self.compacted_records = records;
+        self.failed = false;
+        Ok(())
+    }
+}
+
+/// Writes the state to a new log which then replaces the current one. Returns the new log,
+/// opened for appending, and its number of records.
+fn rewrite(path: &Path, state: &StoredState) -> Result<(File, usize), StoreError> {
+    let changes = state.changes();
+    let mut contents = BytesMut::new();
+    for change in &changes {
+        encode_change(change, &mut contents)?;
+    }
+
+    let compacted = path.with_extension("compacting");
+    let mut file = File::create(&compacted)?;
+    file.write_all(&contents)?;
+    file.sync_all()?;
+    std::fs::rename(&compacted, path)?;
+
+    let file = OpenOptions::new().append(true).open(path)?;
+    Ok((file, changes.len()))
+}
+
+/// Returns the next complete record, without its length.
+fn next_record(contents: &[u8]) -> Option<&[u8]> {
+    let length = u32::from_be_bytes(contents.get(..4)?.try_into().unwrap()) as usize;
+    contents.get(4..4 + length)
+}
+
+mod tag {
+    pub const SESSION: u8 = 1;
+    pub const SESSION_REMOVED: u8 = 2;
+    pub const SUBSCRIBED: u8 = 3;
+    pub const UNSUBSCRIBED: u8 = 4;
+    pub const QUEUED: u8 = 5;
+    pub const DEQUEUED: u8 = 6;
+    pub const SENT: u8 = 7;
+    pub const ACKNOWLEDGED: u8 = 8;
+    pub const RELEASED: u8 = 9;
+    pub const COMPLETED: u8 = 10;
+    pub const RECEIVED: u8 = 11;
+    pub const RECEIVED_RELEASED: u8 = 12;
+    pub const RETAINED: u8 = 13;
+    pub const RETAINED_REMOVED: u8 = 14;
+}
+
+fn encode_change(change: &Change, buffer: &mut BytesMut) -> Result<(), StoreError> {
+    let mut record = BytesMut::new();
+
+    match change {
+        Change::Session {
+            client_id,
+            expiry_interval,
+            disconnected_at,
+        } => {
+            record.put_u8(tag::SESSION);
+            put_string(&mut record, client_id);
+            record.put_u32(*expiry_interval);
+            match disconnected_at {
+                Some(disconnected_at) => {
+                    record.put_u8(1);
+                    record.put_u64(*disconnected_at);
+                }
+                None => record.put_u8(0),
+            }
+        }
+        Change::SessionRemoved(client_id) => {
+            record.put_u8(tag::SESSION_REMOVED);
+            put_string(&mut record, client_id);
+        }
+        Change::Subscribed(client_id, subscription) => {
+            record.put_u8(tag::SUBSCRIBED);
+            put_string(&mut record, client_id);
+            put_string(&mut record, &subscription.topic_filter);
+            record.put_u8(subscription.subscription_options.raw_value);
+        }
+        Change::Unsubscribed(client_id, topic_filter) => {
+            record.put_u8(tag::UNSUBSCRIBED);
+            put_string(&mut record, client_id);
+            put_string(&mut record, topic_filter);
+        }
+        Change::Queued(client_id, publish) => {
+            record.put_u8(tag::QUEUED);
+            put_string(&mut record, client_id);
+            put_publish(&mut record, publish)?;
+        }
+        Change::Dequeued(client_id) => {
+            record.put_u8(tag::DEQUEUED);
+            put_string(&mut record, client_id);
+        }
+        Change::Sent(client_id, publish) => {
+            record.put_u8(tag::SENT);
+            put_string(&mut record, client_id);
+            put_publish(&mut record, publish)?;
+        }
+        Change::Acknowledged(client_id, packet_id)
+        | Change::Released(client_id, packet_id)
+        | Change::Completed(client_id, packet_id)
+        | Change::Received(client_id, packet_id)
+        | Change::ReceivedReleased(client_id, packet_id) => {
+            record.put_u8(match change {
+                Change::Acknowledged(..) => tag::ACKNOWLEDGED,
+                Change::Released(..) => tag::RELEASED,
+                Change::Completed(..) => tag::COMPLETED,
+                Change::Received(..) => tag::RECEIVED,
+                _ => tag::RECEIVED_RELEASED,
+            });
+            put_string(&mut record, client_id);
+            record.put_u16(*packet_id);
+        }
+        Change::Retained(publish) => {
+            record.put_u8(tag::RETAINED);
+            put_publish(&mut record, publish)?;
+        }
+        Change::RetainedRemoved(topic_name) => {
+            record.put_u8(tag::RETAINED_REMOVED);
+            put_string(&mut record, topic_name);
+        }
+    }
+
+    buffer.put_u32(record.len() as u32);
+    buffer.put(record);
+    Ok(())
+}
+
+pub(crate) fn put_string(buffer: &mut BytesMut, value: &str) {
+    buffer.put_u32(value.len() as u32);
+    buffer.put_slice(value.as_bytes());
+}
+
+/// Queued messages have a QoS but no packet identifier yet, which a PUBLISH packet cannot
+/// express, so whether the packet identifier is set is recorded before the packet.
+pub(crate) fn put_publish(buffer: &mut BytesMut, publish: &Publish) -> Result<(), StoreError> {
+    let mut publish = publish.clone();
+    buffer.put_u8(publish.packet_id.is_some() as u8);
+    if publish.qos_number() > 0 {
+        publish.packet_id.get_or_insert(0);
+    }
+
+    let packet = encode_packet(&ControlPacket::Publish(publish))?;
+    buffer.put_u32(packet.len() as u32);
+    buffer.put(packet);
+    Ok(())
+}
+
+/// Returns None when the record is malformed.
+fn decode_change(mut record: &[u8]) -> Option<Change> {
+    let record = &mut record;
+
+    let change = match get_u8(record)? {
+        tag::SESSION => Change::Session {
+            client_id: get_string(record)?,
+            expiry_interval: get_u32(record)?,
+            disconnected_at: match get_u8(record)? {
+                0 => None,
+                _ => Some(get_u64(record)?),
+            },
+        },
+        tag::SESSION_REMOVED => Change::SessionRemoved(get_string(record)?),
+        tag::SUBSCRIBED => Change::Subscribed(
+            get_string(record)?,
+            TopicFilterAndSubscriptionOptions::new(
+                get_string(record)?,
+                SubscriptionOptions {
+                    raw_value: get_u8(record)?,
+                },
+            ),
+        ),
+        tag::UNSUBSCRIBED => Change::Unsubscribed(get_string(record)?, get_string(record)?),
+        tag::QUEUED => Change::Queued(get_string(record)?, get_publish(record)?),
+        tag::DEQUEUED => Change::Dequeued(get_string(record)?),
+        tag::SENT => Change::Sent(get_string(record)?, get_publish(record)?),
+        tag::ACKNOWLEDGED => Change::Acknowledged(get_string(record)?, get_u16(record)?),
+        tag::RELEASED => Change::Released(get_string(record)?, get_u16(record)?),
+        tag::COMPLETED => Change::Completed(get_string(record)?, get_u16(record)?),
+        tag::RECEIVED => Change::Received(get_string(record)?, get_u16(record)?),
+        tag::RECEIVED_RELEASED => Change::ReceivedReleased(get_string(record)?, get_u16(record)?),
+        tag::RETAINED => Change::Retained(get_publish(record)?),
+        tag::RETAINED_REMOVED => Change::RetainedRemoved(get_string(record)?),
+        _ => return None,
+    };
+
+    record.is_empty().then_some(change)
+}
+
+pub(crate) fn get_u8(record: &mut &[u8]) -> Option<u8> {
+    (record.remaining() >= 1).then(|| record.get_u8())
+}
+
+fn get_u16(record: &mut &[u8]) -> Option<u16> {
+    (record.remaining() >= 2).then(|| record.get_u16())
+}
+
+pub(crate) fn get_u32(record: &mut &[u8]) -> Option<u32> {
+    (record.remaining() >= 4).then(|| record.get_u32())
+}
+
+pub(crate) fn get_u64(record: &mut &[u8]) -> Option<u64> {
+    (record.remaining() >= 8).then(|| record.get_u64())
+}
+
+fn get_bytes<'a>(record: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let length = get_u32(record)? as usize;
+    let bytes = record.get(..length)?;
+    record.advance(length);
+    Some(bytes)
+}
+
+pub(crate) fn get_string(record: &mut &[u8]) -> Option<String> {
+    String::from_utf8(get_bytes(record)?.to_vec()).ok()
+}
+
+pub(crate) fn get_publish(record: &mut &[u8]) -> Option<Publish> {
+    let has_packet_id = get_u8(record)? == 1;
+
+    match decode_packet(BytesMut::from(get_bytes(record)?)).ok()? {
+        ControlPacket::Publish(mut publish) => {
+            if !has_packet_id {
+                publish.packet_id = None;
+            }
+            Some(publish)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use crate::store::file::FileStore;
+    use crate::store::{Change, SessionStore, StoreError};
+    use deser::packets::publish::Publish;
+    use deser::packets::subscribe::{SubscriptionOptions, TopicFilterAndSubscriptionOptions};
+    use std::io::Write;
+
+    fn path(name: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("store-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn publish(qos: u8, packet_id: Option<u16>) -> Publish {
+        Publish {
+            packet_type_low_nibble: qos << 1,
+            topic_name: String::from("a/b"),
+            packet_id,
+            application_message: Some(vec![0, 159, 146, 150]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    pub fn should_recover_changes_after_reopening() {
+        let path = path("reopen");
+        let client_id = String::from("ID");
+        let changes = [
+            Change::Session {
+                client_id: client_id.clone(),
+                expiry_interval: u32::MAX,
+                disconnected_at: None,
+            },
+            Change::Subscribed(
+                client_id.clone(),
+                TopicFilterAndSubscriptionOptions::new(
+                    String::from("a/#"),
+                    SubscriptionOptions { raw_value: 0b10 },
+                ),
+            ),
+            Change::Queued(client_id.clone(), publish(2, None)),
+            Change::Queued(client_id.clone(), publish(0, None)),
+            Change::Sent(client_id.clone(), publish(1, Some(9))),
+            Change::Received(client_id.clone(), 3),
+            Change::Retained(publish(0, None)),
+        ];
+
+        let store = FileStore::open(&path).unwrap();
+        for change in &changes {
+            store.apply(change).unwrap();
+        }
+        let state = store.load();
+        drop(store);
+
+        let recovered = FileStore::open(&path).unwrap().load();
+        assert_eq!(state, recovered);
+        assert_eq!(
+            vec![publish(2, None), publish(0, None)],
+            Vec::from(recovered.sessions["ID"].queued.clone())
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    pub fn should_drop_partially_written_record() {
+        let path = path("torn");
+        let store = FileStore::open(&path).unwrap();
+        store.apply(&Change::Retained(publish(0, None))).unwrap();
+        drop(store);
+
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        file.write_all(&[0, 0, 0, 40, 13, 1]).unwrap();
+        drop(file);
+
+        let state = FileStore::open(&path).unwrap().load();
+        assert!(state.retained.contains_key("a/b"));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    pub fn should_report_corrupt_record() {
+        let path = path("corrupt");
+        // retained PUBLISH whose topic name claims 5 bytes when only 1 follows
+        let record = [13, 1, 0, 0, 0, 5, 0x30, 0x03, 0x00, 0x05, b'a'];
+        let mut contents = vec![0, 0, 0, record.len() as u8];
+        contents.extend_from_slice(&record);
+        std::fs::write(&path, contents).unwrap();
+
+        assert!(matches!(
+            FileStore::open(&path),
+            Err(StoreError::Corrupt(0))
+        ));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    pub fn should_compact_the_log() {
+        let path = path("compact");
+        let store = FileStore::open(&path).unwrap();
+        for _ in 0..3000 {
+            store.apply(&Change::Retained(publish(0, None))).unwrap();
+        }
+
+        assert!(std::fs::metadata(&path).unwrap().len() < 1024 * 50);
+        store.compact().unwrap();
+        assert_eq!(1, store.load().retained.len());
+        assert_eq!(1, FileStore::open(&path).unwrap().load().retained.len());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,337 @@
+//! State of the broker that outlives connections: sessions, with their subscriptions, queued
+//! messages and QoS 1 and 2 messages in flight, and retained messages.
+//!
+//! The router records every modification of that state as a `Change` and hands it to the
+//! `SessionStore` of the broker, which recovers the state at startup.
+
+pub mod file;
+
+pub use file::FileStore;
+
+use crate::auth::password::BoxFuture;
+use crate::queue::message_size;
+use deser::codec::CodecError;
+use deser::packets::publish::Publish;
+use deser::packets::subscribe::TopicFilterAndSubscriptionOptions;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::sync::Mutex;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum StoreError {
+    #[error("IO error {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Corrupt record at offset {0} of the store")]
+    Corrupt(u64),
+    #[error("The thread writing the store stopped")]
+    WriterStopped,
+    #[error("Changes could not be written to the store")]
+    WriteFailed,
+    #[error(transparent)]
+    Codec(#[from] CodecError),
+}
+
+/// A session as kept by the broker while its client is connected and, when it has a Session
+/// Expiry Interval, after the client disconnects.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SessionState {
+    /// Seconds the session is kept after its connection closes, `u32::MAX` for ever.
+    pub expiry_interval: u32,
+    /// Unix time in seconds at which the connection closed, None while connected.
+    pub disconnected_at: Option<u64>,
+    pub subscriptions: Vec<TopicFilterAndSubscriptionOptions>,
+    /// Messages waiting to be sent to the client, without packet identifiers.
+    pub queued: VecDeque<Publish>,
+    /// Size of the queued messages, as counted against the queue limits.
+    pub queued_bytes: usize,
+    /// QoS 1 and 2 messages sent to the client and not acknowledged yet with PUBACK or PUBREC.
+    pub outgoing: BTreeMap<u16, Publish>,
+    /// PUBREL sent to the client and not completed yet with PUBCOMP.
+    pub released: BTreeSet<u16>,
+    /// QoS 2 messages received from the client and not released yet with PUBREL.
+    pub incoming: BTreeSet<u16>,
+    /// Packet identifier of the last message sent to the client.
+    pub last_packet_id: u16,
+}
+
+impl SessionState {
+    pub fn is_expired(&self, now: u64) -> bool {
+        match self.disconnected_at {
+            Some(_) if self.expiry_interval == u32::MAX => false,
+            Some(disconnected_at) => now >= disconnected_at + self.expiry_interval as u64,
+            None => false,
+        }
+    }
+}
+
+/// Modification of the stored state.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    /// Creates the session, or updates it keeping its subscriptions and messages.
+    Session {
+        client_id: String,
+        expiry_interval: u32,
+        disconnected_at: Option<u64>,
+    },
+    SessionRemoved(String),
+    /// Adds the subscription, replacing one with the same topic filter.
+    Subscribed(String, TopicFilterAndSubscriptionOptions),
+    Unsubscribed(String, String),
+    Queued(String, Publish),
+    /// Removes the first queued message.
+    Dequeued(String),
+    /// The message was sent with its packet identifier and waits for PUBACK or PUBREC.
+    Sent(String, Publish),
+    /// PUBACK received, the message is delivered.
+    Acknowledged(String, u16),
+    /// PUBREC received and PUBREL sent.
+    Released(String, u16),
+    /// PUBCOMP received, the message is delivered.
+    Completed(String, u16),
+    /// QoS 2 message received from the client and routed.
+    Received(String, u16),
+    /// PUBREL received from the client.
+    ReceivedReleased(String, u16),
+    Retained(Publish),
+    RetainedRemoved(String),
+}
+
+impl Change {
+    /// Client identifier of the session the change applies to.
+    pub fn client_id(&self) -> Option<&str> {
+        match self {
+            Change::Session { client_id, .. }
+            | Change::SessionRemoved(client_id)
+            | Change::Subscribed(client_id, _)
+            | Change::Unsubscribed(client_id, _)
+            | Change::Queued(client_id, _)
+            | Change::Dequeued(client_id)
+            | Change::Sent(client_id, _)
+            | Change::Acknowledged(client_id, _)
+            | Change::Released(client_id, _)
+            | Change::Completed(client_id, _)
+            | Change::Received(client_id, _)
+            | Change::ReceivedReleased(client_id, _) => Some(client_id),
+            Change::Retained(_) | Change::RetainedRemoved(_) => None,
+        }
+    }
+}
+
+/// Everything a store holds.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StoredState {
+    pub sessions: HashMap<String, SessionState>,
+    /// Retained messages by topic name.
+    pub retained: BTreeMap<String, Publish>,
+}
+
+impl StoredState {
+    pub fn apply(&mut self, change: &Change) {
+        match change {
+            Change::Session {
+                client_id,
+                expiry_interval,
+                disconnected_at,
+            } => {
+                let session = self.sessions.entry(client_id.clone()).or_default();
+                session.expiry_interval = *expiry_interval;
+                session.disconnected_at = *disconnected_at;
+            }
+            Change::SessionRemoved(client_id) => {
+                self.sessions.remove(client_id);
+            }
+            Change::Retained(publish) => {
+                self.retained
+                    .insert(publish.topic_name.clone(), publish.clone());
+            }
+            Change::RetainedRemoved(topic_name) => {
+                self.retained.remove(topic_name);
+            }
+            change => {
+                let session = change
+                    .client_id()
+                    .and_then(|client_id| self.sessions.get_mut(client_id));
+                if let Some(session) = session {
+                    apply_to_session(session, change);
+                }
+            }
+        }
+    }
+
+    /// Changes rebuilding this state from an empty one.
+    pub fn changes(&self) -> Vec<Change> {
+        let mut changes = vec![];
+
+        for (client_id, session) in &self.sessions {
+            changes.push(Change::Session {
+                client_id: client_id.clone(),
+                expiry_interval: session.expiry_interval,
+                disconnected_at: session.disconnected_at,
+            });
+            for subscription in &session.subscriptions {
+                changes.push(Change::Subscribed(client_id.clone(), subscription.clone()));
+            }
+            for publish in session.queued.iter() {
+                changes.push(Change::Queued(client_id.clone(), publish.clone()));
+            }
+            for publish in session.outgoing.values() {
+                changes.push(Change::Sent(client_id.clone(), publish.clone()));
+            }
+            for packet_id in &session.released {
+                changes.push(Change::Released(client_id.clone(), *packet_id));
+            }
+            for packet_id in &session.incoming {
+                changes.push(Change::Received(client_id.clone(), *packet_id));
+            }
+        }
+        for publish in self.retained.values() {
+            changes.push(Change::Retained(publish.clone()));
+        }
+
+        changes
+    }
+}
+
+fn apply_to_session(session: &mut SessionState, change: &Change) {
+    match change {
+        Change::Subscribed(_, subscription) => {
+            match session
+                .subscriptions
+                .iter_mut()
+                .find(|s| s.topic_filter == subscription.topic_filter)
+            {
+                Some(existing) => *existing = subscription.clone(),
+                None => session.subscriptions.push(subscription.clone()),
+            }
+        }
+        Change::Unsubscribed(_, topic_filter) => {
+            session
+                .subscriptions
+                .retain(|s| &s.topic_filter != topic_filter);
+        }
+        Change::Queued(_, publish) => {
+            session.queued_bytes += message_size(publish);
+            session.queued.push_back(publish.clone());
+        }
+        Change::Dequeued(_) => {
+            if let Some(publish) = session.queued.pop_front() {
+                session.queued_bytes -= message_size(&publish);
+            }
+        }
+        Change::Sent(_, publish) => {
+            if let Some(packet_id) = publish.packet_id {
+                session.outgoing.insert(packet_id, publish.clone());
+                session.last_packet_id = packet_id;
+            }
+        }
+        Change::Acknowledged(_, packet_id) => {
+            session.outgoing.remove(packet_id);
+        }
+        Change::Released(_, packet_id) => {
+            session.outgoing.remove(packet_id);
+            session.released.insert(*packet_id);
+        }
+        Change::Completed(_, packet_id) => {
+            session.released.remove(packet_id);
+        }
+        Change::Received(_, packet_id) => {
+            session.incoming.insert(*packet_id);
+        }
+        Change::ReceivedReleased(_, packet_id) => {
+            session.incoming.remove(packet_id);
+        }
+        _ => {}
+    }
+}
+
+/// Durable storage of the broker state. Changes are applied while the router is locked, so
+/// implementations should return quickly.
+pub trait SessionStore: Send + Sync {
+    /// State to recover at startup.
+    fn load(&self) -> StoredState;
+
+    fn apply(&self, change: &Change) -> Result<(), StoreError>;
+
+    /// Resolves once the changes applied before are stored. PUBACK and PUBREC wait for it, so
+    /// that the client does not forget a message the broker could still lose.
+    fn written(&self) -> BoxFuture<'_, Result<(), StoreError>> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// Keeps the state in memory. A broker built with the store of a previous broker of the same
+/// process takes over its sessions and retained messages.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    state: Mutex<StoredState>,
+}
+
+impl SessionStore for MemoryStore {
+    fn load(&self) -> StoredState {
+        self.state.lock().unwrap().clone()
+    }
+
+    fn apply(&self, change: &Change) -> Result<(), StoreError> {
+        self.state.lock().unwrap().apply(change);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use crate::store::{Change, StoredState};
+    use deser::packets::publish::Publish;
+
+    fn publish(packet_id: Option<u16>) -> Publish {
+        Publish {
+            packet_type_low_nibble: 0b0100,
+            topic_name: String::from("a/b"),
+            packet_id,
+            application_message: Some(b"hello".to_vec()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    pub fn should_rebuild_state_from_its_changes() {
+        let client_id = String::from("ID");
+        let mut state = StoredState::default();
+        for change in [
+            Change::Session {
+                client_id: client_id.clone(),
+                expiry_interval: 60,
+                disconnected_at: Some(1000),
+            },
+            Change::Queued(client_id.clone(), publish(None)),
+            Change::Queued(client_id.clone(), publish(None)),
+            Change::Queued(client_id.clone(), publish(None)),
+            Change::Sent(client_id.clone(), publish(Some(1))),
+            Change::Dequeued(client_id.clone()),
+            Change::Sent(client_id.clone(), publish(Some(2))),
+            Change::Dequeued(client_id.clone()),
+            Change::Released(client_id.clone(), 2),
+            Change::Received(client_id.clone(), 7),
+            Change::Retained(publish(None)),
+        ] {
+            state.apply(&change);
+        }
+
+        let session = &state.sessions["ID"];
+        assert_eq!(1, session.queued.len());
+        assert_eq!(vec![&1], session.outgoing.keys().collect::<Vec<_>>());
+        assert!(session.released.contains(&2));
+        assert!(session.incoming.contains(&7));
+        assert!(!session.is_expired(1059));
+        assert!(session.is_expired(1060));
+
+        let mut rebuilt = StoredState::default();
+        for change in state.changes() {
+            rebuilt.apply(&change);
+        }
+        assert_eq!(state.retained, rebuilt.retained);
+        assert_eq!(session.queued, rebuilt.sessions["ID"].queued);
+        assert_eq!(session.outgoing, rebuilt.sessions["ID"].outgoing);
+        assert_eq!(session.released, rebuilt.sessions["ID"].released);
+        assert_eq!(session.incoming, rebuilt.sessions["ID"].incoming);
+    }
+}
@@ -0,0 +1,356 @@
+//! Broker statistics published under `$SYS/broker/`.
+//!
+//! The statistics are published as QoS 0 messages every interval, to the sessions subscribed to
+//! them. They are not retained, so they never reach the store. Subscribing to `$SYS` topics goes
+//! through the ACL like any other topic, and since a filter starting with a wildcard does not
+//! match `$` topics, a rule such as `allow subscribe any #` does not grant it: monitoring
+//! clients need a rule of their own, e.g. `allow subscribe user monitor $SYS/#`.
+//!
+//! `$SYS/broker/clients/list` holds the connected clients as a JSON array of objects with their
+//! `client_id` and `address`, the address told by a PROXY protocol header when there is one.
+
+use crate::broker::Broker;
+use deser::packets::publish::Publish;
+use serde_json::json;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Packets and bytes that went through every connection since the broker started. Messages
+/// are PUBLISH packets.
+#[derive(Debug, Default)]
+pub(crate) struct Traffic {
+    messages_received: AtomicU64,
+    messages_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    bytes_sent: AtomicU64,
+}
+
+impl Traffic {
+    pub(crate) fn received(&self, bytes: usize, message: bool) {
+        self.bytes_received
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+        if message {
+            self.messages_received.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn sent(&self, bytes: usize, message: bool) {
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+        if message {
+            self.messages_sent.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn totals(&self) -> TrafficTotals {
+        TrafficTotals {
+            messages_received: self.messages_received.load(Ordering::Relaxed),
+            messages_sent: self.messages_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TrafficTotals {
+    pub messages_received: u64,
+    pub messages_sent: u64,
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+}
+
+/// Sessions, subscriptions and messages held by the router.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SessionTotals {
+    pub connected: usize,
+    /// Sessions kept after their client disconnected.
+    pub disconnected: usize,
+    pub subscriptions: usize,
+    pub retained: usize,
+    /// QoS 1 and 2 messages sent and not completed yet.
+    pub in_flight: usize,
+    pub queued: usize,
+}
+
+/// Everything published under `$SYS/broker/`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Statistics {
+    pub uptime: Duration,
+    pub traffic: TrafficTotals,
+    pub sessions: SessionTotals,
+    /// Connected clients by client identifier, with their address.
+    pub clients: Vec<(String, Option<SocketAddr>)>,
+}
+
+impl Statistics {
+    /// Topics and payloads, with the rates per second since the previous statistics.
+    pub fn topics(&self, previous: &Statistics) -> Vec<(&'static str, String)> {
+        let elapsed = self.uptime.saturating_sub(previous.uptime).as_secs_f64();
+        let rate = |current: u64, previous: u64| match elapsed > 0.0 {
+            true => format!("{:.2}", current.saturating_sub(previous) as f64 / elapsed),
+            false => String::from("0.00"),
+        };
+        let (traffic, last) = (&self.traffic, &previous.traffic);
+
+        vec![
+            ("$SYS/broker/version", VERSION.to_string()),
+            ("$SYS/broker/uptime", self.uptime.as_secs().to_string()),
+            (
+                "$SYS/broker/clients/connected",
+                self.sessions.connected.to_string(),
+            ),
+            (
+                "$SYS/broker/clients/disconnected",
+                self.sessions.disconnected.to_string(),
+            ),
+            ("$SYS/broker/clients/list", self.client_list()),
+            (
+                "$SYS/broker/messages/received",
+                traffic.messages_received.to_string(),
+            ),
+            (
+                "$SYS/broker/messages/sent",
+                traffic.messages_sent.to_string(),
+            ),
+            (
+                "$SYS/broker/load/messages/received",
+                rate(traffic.messages_received, last.messages_received),
+            ),
+            (
+                "$SYS/broker/load/messages/sent",
+                rate(traffic.messages_sent, last.messages_sent),
+            ),
+            (
+                "$SYS/broker/bytes/received",
+                traffic.bytes_received.to_string(),
+            ),
+            ("$SYS/broker/bytes/sent", traffic.bytes_sent.to_string()),
+            (
+                "$SYS/broker/load/bytes/received",
+                rate(traffic.bytes_received, last.bytes_received),
+            ),
+            (
+                "$SYS/broker/load/bytes/sent",
+                rate(traffic.bytes_sent, last.bytes_sent),
+            ),
+            (
+                "$SYS/broker/messages/retained",
+                self.sessions.retained.to_string(),
+            ),
+            (
+                "$SYS/broker/subscriptions/count",
+                self.sessions.subscriptions.to_string(),
+            ),
+            (
+                "$SYS/broker/messages/inflight",
+                self.sessions.in_flight.to_string(),
+            ),
+            (
+                "$SYS/broker/messages/queued",
+                self.sessions.queued.to_string(),
+            ),
+        ]
+    }
+}
+
+impl Statistics {
+    fn client_list(&self) -> String {
+        let clients: Vec<_> = self
+            .clients
+            .iter()
+            .map(|(client_id, address)| {
+                json!({
+                    "client_id": client_id,
+                    "address": address.map(|addr| addr.to_string()),
+                })
+            })
+            .collect();
+        serde_json::Value::from(clients).to_string()
+    }
+}
+
+impl Broker {
+    pub fn statistics(&self) -> Statistics {
+        let mut clients: Vec<_> = self
+            .clients
+            .lock()
+            .unwrap()
+            .values()
+            .map(|client| (client.client_id.clone(), client.peer_addr))
+            .collect();
+        clients.sort();
+
+        Statistics {
+            uptime: self.started.elapsed(),
+            traffic: self.metrics.traffic.totals(),
+            sessions: self.router.lock().unwrap().session_totals(),
+            clients,
+        }
+    }
+
+    /// Publishes the statistics under `$SYS/broker/` every interval, until the broker is
+    /// dropped.
+    pub fn publish_sys(self: Arc<Self>, interval: Duration) -> JoinHandle<()> {
+        let mut previous = self.statistics();
+        let broker = Arc::downgrade(&self);
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(interval);
+
+            loop {
+                ticks.tick().await;
+                let Some(broker) = broker.upgrade() else {
+                    return;
+                };
+                let statistics = broker.statistics();
+                for (topic, payload) in statistics.topics(&previous) {
+                    broker.publish(
+                        "",
+                        &Publish {
+                            topic_name: topic.to_string(),
+                            application_message: Some(payload.into_bytes()),
+                            ..Default::default()
+                        },
+                    );
+                }
+                previous = statistics;
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use crate::acl::{Access, Acl, AclRule, Principal};
+    use crate::broker::Broker;
+    use crate::connection::Connection;
+    use crate::sys::{SessionTotals, Statistics, TrafficTotals};
+    use deser::packets::connect::Connect;
+    use deser::packets::reason_codes::SUBACK;
+    use deser::packets::subscribe::{
+        Subscribe, SubscriptionOptions, TopicFilterAndSubscriptionOptions,
+    };
+    use deser::ControlPacket;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::io::DuplexStream;
+
+    #[test]
+    pub fn should_compute_rates_since_previous_statistics() {
+        let previous = Statistics {
+            uptime: Duration::from_secs(10),
+            traffic: TrafficTotals::default(),
+            sessions: SessionTotals::default(),
+            clients: vec![],
+        };
+        let current = Statistics {
+            uptime: Duration::from_secs(20),
+            traffic: TrafficTotals {
+                messages_received: 50,
+                bytes_sent: 2000,
+                ..Default::default()
+            },
+            sessions: SessionTotals {
+                connected: 3,
+                ..Default::default()
+            },
+            clients: vec![
+                (String::from("a"), Some("192.0.2.7:40000".parse().unwrap())),
+                (String::from("b"), None),
+            ],
+        };
+
+        let topics: HashMap<_, _> = current.topics(&previous).into_iter().collect();
+        assert_eq!("20", topics["$SYS/broker/uptime"]);
+        assert_eq!("3", topics["$SYS/broker/clients/connected"]);
+        assert_eq!("50", topics["$SYS/broker/messages/received"]);
+        assert_eq!("5.00", topics["$SYS/broker/load/messages/received"]);
+        assert_eq!("200.00", topics["$SYS/broker/load/bytes/sent"]);
+        assert_eq!(
+            r#"[{"address":"192.0.2.7:40000","client_id":"a"},{"address":null,"client_id":"b"}]"#,
+            topics["$SYS/broker/clients/list"]
+        );
+    }
+
+    async fn subscribe(
+        broker: &Arc<Broker>,
+        client_id: &str,
+    ) -> (Connection<DuplexStream>, SUBACK) {
+        let (client, server) = tokio::io::duplex(4096);
+        tokio::spawn(broker.clone().handle(server));
+        let mut client = Connection::new(client);
+
+        let connect = Connect {
+            client_id: String::from(client_id),
+            ..Default::default()
+        };
+        client
+            .write_packet(&ControlPacket::Connect(connect))
+            .await
+            .unwrap();
+        client.read_packet().await.unwrap();
+
+        let subscribe = Subscribe {
+            packet_id: 1,
+            topic_filters: vec![TopicFilterAndSubscriptionOptions::new(
+                String::from("$SYS/broker/clients/+"),
+                SubscriptionOptions { raw_value: 0 },
+            )],
+            ..Default::default()
+        };
+        client
+            .write_packet(&ControlPacket::Subscribe(subscribe))
+            .await
+            .unwrap();
+        match client.read_packet().await.unwrap() {
+            Some(ControlPacket::SubAck(mut suback)) => (client, suback.reason_codes.remove(0)),
+            packet => panic!("expected SUBACK, received {packet:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn should_publish_statistics_to_authorized_subscribers() {
+        let acl = Acl::new(vec![
+            AclRule::allow(Principal::Anyone, Access::Both, "#"),
+            AclRule::allow(
+                Principal::ClientId(String::from("monitor")),
+                Access::Subscribe,
+                "$SYS/#",
+            ),
+        ]);
+        let broker = Broker::builder().acl(Arc::new(acl)).build();
+        let (_other, reason_code) = subscribe(&broker, "other").await;
+        assert_eq!(SUBACK::NotAuthorized, reason_code);
+
+        let (mut monitor, reason_code) = subscribe(&broker, "monitor").await;
+        assert_eq!(SUBACK::GrantedQos0, reason_code);
+        broker.clone().publish_sys(Duration::from_millis(10));
+
+        match monitor.read_packet().await.unwrap() {
+            Some(ControlPacket::Publish(publish)) => {
+                assert_eq!("$SYS/broker/clients/connected", publish.topic_name);
+                assert_eq!(Some(b"2".to_vec()), publish.application_message);
+                assert!(!publish.retain());
+            }
+            packet => panic!("expected PUBLISH, received {packet:?}"),
+        }
+        assert!(broker.statistics().traffic.messages_sent >= 1);
+    }
+
+    #[tokio::test]
+    async fn should_stop_publishing_once_the_broker_is_dropped() {
+        let broker = Broker::builder().build();
+        let publisher = broker.clone().publish_sys(Duration::from_millis(10));
+
+        drop(broker);
+        tokio::time::timeout(Duration::from_secs(1), publisher)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
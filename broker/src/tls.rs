@@ -0,0 +1,529 @@
+//! MQTT over TLS.
+//!
+//! Certificates and keys are read from PEM files. Client certificates are optionally verified
+//! against a CA bundle, in which case the subject of the certificate can serve as the user name
+//! of the client. The files are read again on `reload`, connections accepted afterwards use the
+//! new certificates.
+
+use rustls::crypto::ring::default_provider;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{ClientConfig, RootCertStore, ServerConfig};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::task::JoinHandle;
+use tokio_rustls::server::TlsStream;
+use tracing::{info, warn};
+use x509_parser::extensions::GeneralName;
+
+/// IANA registered port of MQTT over TLS.
+pub const DEFAULT_TLS_PORT: u16 = 8883;
+
+#[derive(Error, Debug)]
+pub enum TlsError {
+    #[error("Reading {0}: {1}")]
+    Io(PathBuf, std::io::Error),
+    #[error("No certificate found in {0}")]
+    NoCertificate(PathBuf),
+    #[error("No private key found in {0}")]
+    NoPrivateKey(PathBuf),
+    #[error(transparent)]
+    Rustls(#[from] rustls::Error),
+    #[error("Cannot verify client certificates: {0}")]
+    ClientVerifier(String),
+}
+
+/// Part of the client certificate used as user name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CertificateIdentity {
+    /// CN of the subject.
+    CommonName,
+    /// First DNS name, email address or URI of the Subject Alternative Name extension.
+    SubjectAltName,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ClientAuth {
+    None,
+    Optional(PathBuf),
+    Required(PathBuf),
+}
+
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    client_auth: ClientAuth,
+    identity: Option<CertificateIdentity>,
+}
+
+impl TlsConfig {
+    /// Server certificate chain and private key, both PEM encoded.
+    pub fn new(cert_path: impl Into<PathBuf>, key_path: impl Into<PathBuf>) -> TlsConfig {
+        TlsConfig {
+            cert_path: cert_path.into(),
+            key_path: key_path.into(),
+            client_auth: ClientAuth::None,
+            identity: None,
+        }
+    }
+
+    /// Verifies client certificates against the CA bundle. When not required, clients without a
+    /// certificate are still accepted and authenticate with the CONNECT packet.
+    pub fn client_certificates(mut self, ca_path: impl Into<PathBuf>, required: bool) -> Self {
+        self.client_auth = match required {
+            true => ClientAuth::Required(ca_path.into()),
+            false => ClientAuth::Optional(ca_path.into()),
+        };
+        self
+    }
+
+    /// Uses the verified client certificate as user name, instead of the user name and password
+    /// of the CONNECT packet.
+    pub fn username_from_certificate(mut self, identity: CertificateIdentity) -> Self {
+        self.identity = Some(identity);
+        self
+    }
+
+    fn server_config(&self) -> Result<ServerConfig, TlsError> {
+        let provider = Arc::new(default_provider());
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?;
+
+        let builder = match &self.client_auth {
+            ClientAuth::None => builder.with_no_client_auth(),
+            ClientAuth::Optional(ca_path) | ClientAuth::Required(ca_path) => {
+                let mut roots = RootCertStore::empty();
+                for certificate in load_certificates(ca_path)? {
+                    roots.add(certificate)?;
+                }
+
+                let verifier =
+                    WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+                let verifier = match self.client_auth {
+                    ClientAuth::Optional(_) => verifier.allow_unauthenticated(),
+                    _ => verifier,
+                };
+                builder.with_client_cert_verifier(
+                    verifier
+                        .build()
+                        .map_err(|e| TlsError::ClientVerifier(e.to_string()))?,
+                )
+            }
+        };
+
+        Ok(builder.with_single_cert(
+            load_certificates(&self.cert_path)?,
+            load_private_key(&self.key_path)?,
+        )?)
+    }
+
+    fn paths(&self) -> Vec<&Path> {
+        let mut paths = vec![self.cert_path.as_path(), self.key_path.as_path()];
+        match &self.client_auth {
+            ClientAuth::Optional(ca_path) | ClientAuth::Required(ca_path) => paths.push(ca_path),
+            ClientAuth::None => {}
+        }
+        paths
+    }
+}
+
+fn load_certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>, TlsError> {
+    let file = std::fs::File::open(path).map_err(|e| TlsError::Io(path.to_path_buf(), e))?;
+    let certificates = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| TlsError::Io(path.to_path_buf(), e))?;
+
+    if certificates.is_empty() {
+        return Err(TlsError::NoCertificate(path.to_path_buf()));
+    }
+    Ok(certificates)
+}
+
+fn load_private_key(path: &Path) -> Result<PrivateKeyDer<'static>, TlsError> {
+    let file = std::fs::File::open(path).map_err(|e| TlsError::Io(path.to_path_buf(), e))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .map_err(|e| TlsError::Io(path.to_path_buf(), e))?
+        .ok_or_else(|| TlsError::NoPrivateKey(path.to_path_buf()))
+}
+
+/// Extracts the user name from a DER encoded certificate.
+fn certificate_identity(certificate: &[u8], identity: CertificateIdentity) -> Option<String> {
+    let (_, certificate) = x509_parser::parse_x509_certificate(certificate).ok()?;
+
+    match identity {
+        CertificateIdentity::CommonName => certificate
+            .subject()
+            .iter_common_name()
+            .next()
+            .and_then(|cn| cn.as_str().ok())
+            .map(String::from),
+        CertificateIdentity::SubjectAltName => certificate
+            .subject_alternative_name()
+            .ok()??
+            .value
+            .general_names
+            .iter()
+            .find_map(|name| match name {
+                GeneralName::DNSName(name)
+                | GeneralName::RFC822Name(name)
+                | GeneralName::URI(name) => Some(name.to_string()),
+                _ => None,
+            }),
+    }
+}
+
+/// Performs TLS handshakes with the current certificates.
+pub struct TlsAcceptor {
+    config: TlsConfig,
+    acceptor: RwLock<tokio_rustls::TlsAcceptor>,
+}
+
+impl TlsAcceptor {
+    pub fn new(config: TlsConfig) -> Result<TlsAcceptor, TlsError> {
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(config.server_config()?));
+
+        Ok(TlsAcceptor {
+            config,
+            acceptor: RwLock::new(acceptor),
+        })
+    }
+
+    /// Reads the certificate, key and CA files again. The current certificates are kept when
+    /// the files cannot be loaded.
+    pub fn reload(&self) -> Result<(), TlsError> {
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(self.config.server_config()?));
+        *self.acceptor.write().unwrap() = acceptor;
+        Ok(())
+    }
+
+    /// Reloads the certificates whenever the modification time of one of the files changes,
+    /// checking every period.
+    pub fn watch(self: Arc<Self>, period: Duration) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut modified = self.modified();
+            let mut interval = tokio::time::interval(period);
+
+            loop {
+                interval.tick().await;
+                let current = self.modified();
+                if current == modified {
+                    continue;
+                }
+                modified = current;
+
+                match self.reload() {
+                    Ok(()) => info!("reloaded TLS certificates"),
+                    Err(e) => warn!("keeping previous TLS certificates: {e}"),
+                }
+            }
+        })
+    }
+
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        self.config
+            .paths()
+            .iter()
+            .map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+            .collect()
+    }
+
+    /// Completes the handshake, returning the user name taken from the client certificate when
+    /// the configuration asks for one.
+    pub async fn accept<IO>(&self, stream: IO) -> std::io::Result<(TlsStream<IO>, Option<String>)>
+    where
+        IO: AsyncRead + AsyncWrite + Unpin,
+    {
+        let acceptor = self.acceptor.read().unwrap().clone();
+        let stream = acceptor.accept(stream).await?;
+
+        let identity = self.config.identity.and_then(|identity| {
+            let (_, connection) = stream.get_ref();
+            let certificate = connection.peer_certificates()?.first()?;
+            certificate_identity(certificate, identity)
+        });
+
+        Ok((stream, identity))
+    }
+}
+
+/// Client side of TLS, for the connections between the nodes of a cluster. Server certificates
+/// are verified against a CA bundle.
+pub struct TlsConnector {
+    connector: tokio_rustls::TlsConnector,
+}
+
+impl TlsConnector {
+    pub fn new(ca_path: impl AsRef<Path>) -> Result<TlsConnector, TlsError> {
+        let mut roots = RootCertStore::empty();
+        for certificate in load_certificates(ca_path.as_ref())? {
+            roots.add(certificate)?;
+        }
+        let config = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        Ok(TlsConnector {
+            connector: tokio_rustls::TlsConnector::from(Arc::new(config)),
+        })
+    }
+
+    /// Completes the handshake, checking that the certificate of the server is valid for the
+    /// host name or IP address.
+    pub async fn connect<IO>(
+        &self,
+        host: &str,
+        stream: IO,
+    ) -> std::io::Result<tokio_rustls::client::TlsStream<IO>>
+    where
+        IO: AsyncRead + AsyncWrite + Unpin,
+    {
+        let server_name = ServerName::try_from(host.to_string())
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        self.connector.connect(server_name, stream).await
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use crate::acl::{Access, Acl, AclRule, Principal};
+    use crate::broker::Broker;
+    use crate::connection::Connection;
+    use crate::proxy::ProxyProtocol;
+    use crate::tls::{CertificateIdentity, TlsAcceptor, TlsConfig};
+    use deser::packets::connect::Connect;
+    use deser::packets::reason_codes::{CONNECTACK, SUBACK};
+    use deser::packets::subscribe::{
+        Subscribe, SubscriptionOptions, TopicFilterAndSubscriptionOptions,
+    };
+    use deser::ControlPacket;
+    use rcgen::{
+        BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair,
+    };
+    use rustls::crypto::ring::default_provider;
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+    use rustls::{ClientConfig, RootCertStore};
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_rustls::client::TlsStream;
+    use tokio_rustls::TlsConnector;
+
+    pub(crate) struct Pki {
+        ca: rcgen::Certificate,
+        ca_key: KeyPair,
+        dir: PathBuf,
+    }
+
+    impl Pki {
+        pub(crate) fn new(name: &str) -> Pki {
+            let dir = std::env::temp_dir().join(format!("tls-{}-{name}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+
+            let ca_key = KeyPair::generate().unwrap();
+            let mut params = CertificateParams::new(vec![]).unwrap();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            params
+                .distinguished_name
+                .push(DnType::CommonName, "Test CA");
+            let ca = params.self_signed(&ca_key).unwrap();
+            std::fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+
+            Pki { ca, ca_key, dir }
+        }
+
+        /// Writes <name>.pem and <name>.key, returning the certificate and key.
+        pub(crate) fn issue(
+            &self,
+            name: &str,
+            common_name: &str,
+            usage: ExtendedKeyUsagePurpose,
+        ) -> (rcgen::Certificate, KeyPair) {
+            let key = KeyPair::generate().unwrap();
+            let mut params = CertificateParams::new(vec![String::from("localhost")]).unwrap();
+            params
+                .distinguished_name
+                .push(DnType::CommonName, common_name);
+            params.extended_key_usages = vec![usage];
+            let certificate = params.signed_by(&key, &self.ca, &self.ca_key).unwrap();
+
+            std::fs::write(self.path(&format!("{name}.pem")), certificate.pem()).unwrap();
+            std::fs::write(self.path(&format!("{name}.key")), key.serialize_pem()).unwrap();
+            (certificate, key)
+        }
+
+        pub(crate) fn path(&self, file: &str) -> PathBuf {
+            self.dir.join(file)
+        }
+
+        fn client_config(&self, client: Option<(rcgen::Certificate, KeyPair)>) -> ClientConfig {
+            let mut roots = RootCertStore::empty();
+            roots.add(self.ca.der().clone()).unwrap();
+            let builder = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(roots);
+
+            match client {
+                Some((certificate, key)) => builder
+                    .with_client_auth_cert(
+                        vec![certificate.der().clone()],
+                        PrivateKeyDer::try_from(key.serialize_der()).unwrap(),
+                    )
+                    .unwrap(),
+                None => builder.with_no_client_auth(),
+            }
+        }
+    }
+
+    impl Drop for Pki {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    async fn serve(broker: Arc<Broker>, tls: Arc<TlsAcceptor>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(broker.serve_tls(listener, tls, ProxyProtocol::Reject));
+        addr
+    }
+
+    async fn connect(addr: &str, config: ClientConfig) -> Connection<TlsStream<TcpStream>> {
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let stream = TlsConnector::from(Arc::new(config))
+            .connect(ServerName::try_from("localhost").unwrap(), stream)
+            .await
+            .unwrap();
+        Connection::new(stream)
+    }
+
+    #[tokio::test]
+    async fn should_use_client_certificate_as_user_name() {
+        let pki = Pki::new("identity");
+        pki.issue("server", "broker", ExtendedKeyUsagePurpose::ServerAuth);
+        let client = pki.issue("client", "alice", ExtendedKeyUsagePurpose::ClientAuth);
+
+        let tls = TlsConfig::new(pki.path("server.pem"), pki.path("server.key"))
+            .client_certificates(pki.path("ca.pem"), true)
+            .username_from_certificate(CertificateIdentity::CommonName);
+        let acl = Acl::new(vec![AclRule::allow(
+            Principal::User(String::from("alice")),
+            Access::Both,
+            "alice/#",
+        )]);
+        let broker = Broker::builder().acl(Arc::new(acl)).build();
+        let addr = serve(broker, Arc::new(TlsAcceptor::new(tls).unwrap())).await;
+
+        let mut client = connect(&addr, pki.client_config(Some(client))).await;
+        let connect = Connect {
+            client_id: String::from("ID"),
+            ..Default::default()
+        };
+        client
+            .write_packet(&ControlPacket::Connect(connect))
+            .await
+            .unwrap();
+        match client.read_packet().await.unwrap() {
+            Some(ControlPacket::ConnAck(connack)) => {
+                assert_eq!(CONNECTACK::Success as u8, connack.connect_reason_code)
+            }
+            packet => panic!("expected CONNACK, received {packet:?}"),
+        }
+
+        let subscribe = Subscribe {
+            packet_id: 1,
+            topic_filters: vec![TopicFilterAndSubscriptionOptions::new(
+                String::from("alice/inbox"),
+                SubscriptionOptions::default(),
+            )],
+            ..Default::default()
+        };
+        client
+            .write_packet(&ControlPacket::Subscribe(subscribe))
+            .await
+            .unwrap();
+        match client.read_packet().await.unwrap() {
+            Some(ControlPacket::SubAck(suback)) => {
+                assert_eq!(vec![SUBACK::GrantedQos0], suback.reason_codes)
+            }
+            packet => panic!("expected SUBACK, received {packet:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn should_refuse_client_without_required_certificate() {
+        let pki = Pki::new("required");
+        pki.issue("server", "broker", ExtendedKeyUsagePurpose::ServerAuth);
+
+        let tls = TlsConfig::new(pki.path("server.pem"), pki.path("server.key"))
+            .client_certificates(pki.path("ca.pem"), true);
+        let addr = serve(
+            Broker::builder().build(),
+            Arc::new(TlsAcceptor::new(tls).unwrap()),
+        )
+        .await;
+
+        // with TLS 1.3 the client only learns about the refusal on its first read
+        let mut client = connect(&addr, pki.client_config(None)).await;
+        let _ = client
+            .write_packet(&ControlPacket::Connect(Connect::default()))
+            .await;
+        assert!(!matches!(
+            client.read_packet().await,
+            Ok(Some(ControlPacket::ConnAck(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn should_close_connections_stalling_the_handshake() {
+        let pki = Pki::new("stalling");
+        pki.issue("server", "broker", ExtendedKeyUsagePurpose::ServerAuth);
+
+        let tls = TlsConfig::new(pki.path("server.pem"), pki.path("server.key"));
+        let broker = Broker::builder()
+            .handshake_timeout(Duration::from_millis(50))
+            .build();
+        let addr = serve(broker, Arc::new(TlsAcceptor::new(tls).unwrap())).await;
+
+        // the start of a TLS record that never completes
+        let mut stream = TcpStream::connect(&addr).await.unwrap();
+        stream.write_all(&[0x16, 0x03, 0x01]).await.unwrap();
+        let mut rest = vec![];
+        let read = tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut rest));
+        assert_eq!(0, read.await.unwrap().unwrap());
+    }
+
+    #[tokio::test]
+    async fn should_serve_reloaded_certificate() {
+        let pki = Pki::new("reload");
+        pki.issue("server", "first", ExtendedKeyUsagePurpose::ServerAuth);
+
+        let tls = Arc::new(
+            TlsAcceptor::new(TlsConfig::new(
+                pki.path("server.pem"),
+                pki.path("server.key"),
+            ))
+            .unwrap(),
+        );
+        let addr = serve(Broker::builder().build(), tls.clone()).await;
+
+        let (second, _) = pki.issue("server", "second", ExtendedKeyUsagePurpose::ServerAuth);
+        tls.reload().unwrap();
+
+        let stream = TcpStream::connect(&addr).await.unwrap();
+        let stream = TlsConnector::from(Arc::new(pki.client_config(None)))
+            .connect(ServerName::try_from("localhost").unwrap(), stream)
+            .await
+            .unwrap();
+        let presented: &CertificateDer = &stream.get_ref().1.peer_certificates().unwrap()[0];
+        assert_eq!(second.der(), presented);
+    }
+}
@@ -0,0 +1,158 @@
+//! Topic names and topic filters.
+//!
+//! `matches` is the single wildcard matcher of the broker. It routes PUBLISH packets to
+//! subscriptions and decides which ACL rules apply to a topic. `covers` and `intersects` compare
+//! two topic filters, for the ACL rules applying to a SUBSCRIBE.
+
+/// Whether the topic filter matches the topic name. A filter starting with a wildcard does not
+/// match topics starting with `$`.
+pub fn matches(filter: &str, topic: &str) -> bool {
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return false;
+    }
+
+    let mut filter_levels = filter.split('/');
+    let mut topic_levels = topic.split('/');
+
+    loop {
+        match (filter_levels.next(), topic_levels.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => continue,
+            (Some(filter_level), Some(topic_level)) if filter_level == topic_level => continue,
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+/// Whether every topic matched by `filter` is matched by `outer` too: `#` in `filter` is only
+/// covered by `#` in `outer`, and `+` by `+` or `#`.
+pub fn covers(outer: &str, filter: &str) -> bool {
+    if filter.starts_with('$') && (outer.starts_with('+') || outer.starts_with('#')) {
+        return false;
+    }
+
+    let mut outer_levels = outer.split('/');
+    let mut filter_levels = filter.split('/');
+
+    loop {
+        match (outer_levels.next(), filter_levels.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(level)) if level != "#" => continue,
+            (Some(outer_level), Some(level)) if outer_level == level && level != "+" => continue,
+            (Some(_), Some(_)) => return false,
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+/// Whether some topic name is matched by both topic filters.
+pub fn intersects(first: &str, second: &str) -> bool {
+    let leading_wildcard = |filter: &str| filter.starts_with('+') || filter.starts_with('#');
+    if (first.starts_with('$') && leading_wildcard(second))
+        || (second.starts_with('$') && leading_wildcard(first))
+    {
+        return false;
+    }
+
+    let mut first_levels = first.split('/');
+    let mut second_levels = second.split('/');
+
+    loop {
+        match (first_levels.next(), second_levels.next()) {
+            (Some("#"), _) | (_, Some("#")) => return true,
+            (Some("+"), Some(_)) | (Some(_), Some("+")) => continue,
+            (Some(first_level), Some(second_level)) if first_level == second_level => continue,
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+/// Topic names must not be empty nor contain wildcards.
+pub fn is_valid_topic_name(topic: &str) -> bool {
+    !topic.is_empty() && !topic.contains(['+', '#', '\0'])
+}
+
+/// `#` must be the last level and wildcards must take a whole level.
+pub fn is_valid_topic_filter(filter: &str) -> bool {
+    if filter.is_empty() || filter.contains('\0') {
+        return false;
+    }
+
+    let levels: Vec<&str> = filter.split('/').collect();
+    levels
+        .iter()
+        .enumerate()
+        .all(|(index, level)| match *level {
+            "#" => index == levels.len() - 1,
+            "+" => true,
+            level => !level.contains(['+', '#']),
+        })
+}
+
+#[cfg(test)]
+pub mod test {
+    use crate::topic::{covers, intersects, is_valid_topic_filter, is_valid_topic_name, matches};
+
+    #[test]
+    pub fn should_match_wildcards() {
+        assert!(matches("sport/tennis/player1/#", "sport/tennis/player1"));
+        assert!(matches(
+            "sport/tennis/player1/#",
+            "sport/tennis/player1/ranking"
+        ));
+        assert!(matches("sport/#", "sport"));
+        assert!(matches("#", "sport/tennis"));
+        assert!(matches("sport/+/player1", "sport/tennis/player1"));
+        assert!(matches("+/+", "/finance"));
+        assert!(matches("+", "sport"));
+        assert!(!matches("+", "/finance"));
+        assert!(!matches("sport/+", "sport/tennis/player1"));
+        assert!(!matches("sport/tennis", "sport/tennis/player1"));
+    }
+
+    #[test]
+    pub fn should_not_match_dollar_topics_with_leading_wildcard() {
+        assert!(!matches("#", "$SYS/broker/uptime"));
+        assert!(!matches("+/broker/uptime", "$SYS/broker/uptime"));
+        assert!(matches("$SYS/#", "$SYS/broker/uptime"));
+    }
+
+    #[test]
+    pub fn should_cover_narrower_filters_only() {
+        assert!(covers("sensors/+", "sensors/+"));
+        assert!(covers("sensors/+", "sensors/temperature"));
+        assert!(covers("sensors/#", "sensors/+/temperature"));
+        assert!(covers("sensors/#", "sensors/#"));
+        assert!(covers("sensors/#", "sensors"));
+        assert!(!covers("sensors/temperature", "sensors/+"));
+        assert!(!covers("sensors/+", "sensors/#"));
+        assert!(!covers("sensors/+", "sensors/+/#"));
+        assert!(!covers("sensors/+", "sensors/a/b"));
+        assert!(!covers("#", "$SYS/#"));
+    }
+
+    #[test]
+    pub fn should_intersect_filters_sharing_a_topic() {
+        assert!(intersects("sensors/secret", "sensors/#"));
+        assert!(intersects("sensors/+/secret", "sensors/kitchen/+"));
+        assert!(intersects("sensors/#", "sensors"));
+        assert!(intersects("+/+", "a/+"));
+        assert!(!intersects("sensors/+", "sensors/a/b"));
+        assert!(!intersects("sensors/secret", "actuators/#"));
+        assert!(!intersects("#", "$SYS/broker"));
+    }
+
+    #[test]
+    pub fn should_validate_names_and_filters() {
+        assert!(is_valid_topic_name("sport/tennis"));
+        assert!(!is_valid_topic_name("sport/+"));
+        assert!(!is_valid_topic_name(""));
+        assert!(is_valid_topic_filter("sport/+/player1/#"));
+        assert!(!is_valid_topic_filter("sport/tennis#"));
+        assert!(!is_valid_topic_filter("sport/#/ranking"));
+        assert!(!is_valid_topic_filter("sport+"));
+    }
+}
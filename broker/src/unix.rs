@@ -0,0 +1,116 @@
+//! Unix domain socket listener for processes running on the same host as the broker.
+
+use rand::distributions::{Alphanumeric, DistString};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::path::Path;
+use tokio::net::UnixListener;
+
+/// Binds the socket, replacing a stale socket file left behind by a previous run, and sets the
+/// permissions of the socket file, e.g. 0o660 to restrict clients to the group of the broker.
+pub fn bind(path: impl AsRef<Path>, mode: Option<u32>) -> std::io::Result<UnixListener> {
+    let path = path.as_ref();
+
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)?,
+        _ => {}
+    }
+
+    let Some(mode) = mode else {
+        return UnixListener::bind(path);
+    };
+
+    // bound in a directory only the broker can enter and moved into place once its
+    // permissions are set, so that no client connects in between
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let private = path.with_file_name(format!(
+        ".{file_name}.{}",
+        Alphanumeric.sample_string(&mut rand::thread_rng(), 8)
+    ));
+    std::fs::DirBuilder::new().mode(0o700).create(&private)?;
+    let bound = private.join("socket");
+    let listener = UnixListener::bind(&bound).and_then(|listener| {
+        std::fs::set_permissions(&bound, std::fs::Permissions::from_mode(mode))?;
+        std::fs::rename(&bound, path)?;
+        Ok(listener)
+    });
+    let _ = std::fs::remove_file(&bound);
+    std::fs::remove_dir(&private)?;
+    listener
+}
+
+#[cfg(test)]
+pub mod test {
+    use crate::auth::password::{passwd_name, AllowAnonymous, AllowPeerUids};
+    use crate::broker::Broker;
+    use crate::connection::Connection;
+    use crate::unix::bind;
+    use deser::packets::connect::Connect;
+    use deser::packets::reason_codes::CONNECTACK;
+    use deser::ControlPacket;
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+    use std::sync::Arc;
+    use tokio::net::UnixStream;
+
+    async fn connack_reason_code(
+        uids: impl Fn(u32) -> Vec<u32>,
+        username: impl Fn(Option<String>) -> Option<String>,
+        name: &str,
+    ) -> u8 {
+        let path = std::env::temp_dir().join(format!("mqtt-{}-{name}.sock", std::process::id()));
+        let listener = bind(&path, Some(0o600)).unwrap();
+        let metadata = std::fs::metadata(&path).unwrap();
+        assert_eq!(0o600, metadata.permissions().mode() & 0o777);
+
+        let broker = Broker::builder()
+            .password_backend(Arc::new(AllowPeerUids(uids(metadata.uid()))))
+            .password_backend(Arc::new(AllowAnonymous(false)))
+            .build();
+        tokio::spawn(broker.serve_unix(listener));
+
+        let mut client = Connection::new(UnixStream::connect(&path).await.unwrap());
+        let username = username(passwd_name(metadata.uid()).await);
+        client
+            .write_packet(&ControlPacket::Connect(Connect {
+                connect_flags: match username {
+                    Some(_) => 0b1000_0000,
+                    None => 0,
+                },
+                username,
+                ..Default::default()
+            }))
+            .await
+            .unwrap();
+        let reason_code = match client.read_packet().await.unwrap() {
+            Some(ControlPacket::ConnAck(connack)) => connack.connect_reason_code,
+            packet => panic!("expected CONNACK, received {packet:?}"),
+        };
+
+        std::fs::remove_file(path).unwrap();
+        reason_code
+    }
+
+    #[tokio::test]
+    async fn should_authenticate_local_process_by_uid() {
+        assert_eq!(
+            CONNECTACK::Success as u8,
+            connack_reason_code(|uid| vec![uid], |_| None, "allowed").await
+        );
+        assert_eq!(
+            CONNECTACK::NotAuthorised as u8,
+            connack_reason_code(|uid| vec![uid + 1], |_| None, "refused").await
+        );
+        assert_eq!(
+            CONNECTACK::Success as u8,
+            connack_reason_code(|uid| vec![uid], |own| own, "own-name").await
+        );
+        assert_eq!(
+            CONNECTACK::NotAuthorised as u8,
+            connack_reason_code(
+                |uid| vec![uid],
+                |_| Some(String::from("someone-else")),
+                "other-name"
+            )
+            .await
+        );
+    }
+}
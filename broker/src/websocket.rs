@@ -0,0 +1,328 @@
+//! MQTT over WebSockets.
+//!
+//! The handshake must negotiate the `mqtt` subprotocol. Afterwards MQTT packets travel in
+//! binary frames, a packet may span several frames and a frame may hold several packets, so the
+//! frames are exposed as a plain byte stream and read by the same `Connection` as TCP clients.
+
+use futures_util::{Sink, Stream};
+use std::io::ErrorKind;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::{HeaderValue, StatusCode};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+/// WebSocket subprotocol of MQTT 5.
+pub const MQTT_SUBPROTOCOL: &str = "mqtt";
+
+#[derive(Error, Debug)]
+pub enum WebSocketError {
+    #[error("WebSocket handshake failed: {0}")]
+    Handshake(#[from] tokio_tungstenite::tungstenite::Error),
+}
+
+#[derive(Debug, Clone)]
+pub struct WebSocketConfig {
+    path: String,
+    allowed_origins: Option<Vec<String>>,
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        WebSocketConfig {
+            path: String::from("/mqtt"),
+            allowed_origins: None,
+        }
+    }
+}
+
+impl WebSocketConfig {
+    /// Request path of the handshake, /mqtt by default.
+    pub fn path(mut self, path: &str) -> Self {
+        self.path = path.to_string();
+        self
+    }
+
+    /// Accepts handshakes from this Origin. Once an origin is allowed, handshakes from other
+    /// origins, or without an Origin header, are refused.
+    pub fn allow_origin(mut self, origin: &str) -> Self {
+        self.allowed_origins
+            .get_or_insert_with(Vec::new)
+            .push(origin.to_string());
+        self
+    }
+
+    /// Returns the status refusing the handshake, if any.
+    fn check(&self, request: &Request) -> Result<(), StatusCode> {
+        if request.uri().path() != self.path {
+            return Err(StatusCode::NOT_FOUND);
+        }
+
+        if let Some(allowed_origins) = &self.allowed_origins {
+            let origin = request
+                .headers()
+                .get("Origin")
+                .and_then(|origin| origin.to_str().ok());
+            if !allowed_origins
+                .iter()
+                .any(|allowed| Some(allowed.as_str()) == origin)
+            {
+                return Err(StatusCode::FORBIDDEN);
+            }
+        }
+
+        let offers_mqtt = request
+            .headers()
+            .get_all("Sec-WebSocket-Protocol")
+            .iter()
+            .filter_map(|protocols| protocols.to_str().ok())
+            .flat_map(|protocols| protocols.split(','))
+            .any(|protocol| protocol.trim() == MQTT_SUBPROTOCOL);
+        match offers_mqtt {
+            true => Ok(()),
+            false => Err(StatusCode::BAD_REQUEST),
+        }
+    }
+}
+
+/// Performs the server side of the handshake.
+// the callback signature, with its large error response, is imposed by tungstenite
+#[allow(clippy::result_large_err)]
+pub async fn accept<S>(
+    stream: S,
+    config: &WebSocketConfig,
+) -> Result<WebSocketTransport<S>, WebSocketError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let callback = |request: &Request, mut response: Response| match config.check(request) {
+        Ok(()) => {
+            response.headers_mut().insert(
+                "Sec-WebSocket-Protocol",
+                HeaderValue::from_static(MQTT_SUBPROTOCOL),
+            );
+            Ok(response)
+        }
+        Err(status) => {
+            let mut refusal = ErrorResponse::new(None);
+            *refusal.status_mut() = status;
+            Err(refusal)
+        }
+    };
+    let websocket = tokio_tungstenite::accept_hdr_async(stream, callback).await?;
+
+    Ok(WebSocketTransport::new(websocket))
+}
+
+/// Byte stream over the binary frames of a WebSocket.
+pub struct WebSocketTransport<S> {
+    websocket: WebSocketStream<S>,
+    /// Unread part of the last binary frame.
+    frame: Vec<u8>,
+    position: usize,
+}
+
+impl<S> WebSocketTransport<S> {
+    pub fn new(websocket: WebSocketStream<S>) -> WebSocketTransport<S> {
+        WebSocketTransport {
+            websocket,
+            frame: vec![],
+            position: 0,
+        }
+    }
+}
+
+fn io_error(e: tokio_tungstenite::tungstenite::Error) -> std::io::Error {
+    std::io::Error::other(e)
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WebSocketTransport<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        loop {
+            if self.position < self.frame.len() {
+                let count = buf.remaining().min(self.frame.len() - self.position);
+                let start = self.position;
+                buf.put_slice(&self.frame[start..start + count]);
+                self.position += count;
+                return Poll::Ready(Ok(()));
+            }
+
+            match ready!(Pin::new(&mut self.websocket).poll_next(cx)) {
+                Some(Ok(Message::Binary(data))) => {
+                    self.frame = data;
+                    self.position = 0;
+                }
+                // answered by tungstenite
+                Some(Ok(Message::Ping(_) | Message::Pong(_))) => continue,
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(())),
+                Some(Ok(_)) => {
+                    return Poll::Ready(Err(std::io::Error::new(
+                        ErrorKind::InvalidData,
+                        "MQTT packets must be sent in binary frames",
+                    )))
+                }
+                Some(Err(e)) => return Poll::Ready(Err(io_error(e))),
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WebSocketTransport<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        ready!(Pin::new(&mut self.websocket).poll_ready(cx)).map_err(io_error)?;
+        Pin::new(&mut self.websocket)
+            .start_send(Message::Binary(buf.to_vec()))
+            .map_err(io_error)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.websocket)
+            .poll_flush(cx)
+            .map_err(io_error)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.websocket)
+            .poll_close(cx)
+            .map_err(io_error)
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use crate::broker::Broker;
+    use crate::connection::Connection;
+    use crate::proxy::ProxyProtocol;
+    use crate::websocket::{WebSocketConfig, WebSocketTransport};
+    use deser::codec::encode_packet;
+    use deser::packets::connect::Connect;
+    use deser::packets::pingreq::PingReq;
+    use deser::ControlPacket;
+    use futures_util::SinkExt;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+    use tokio_tungstenite::tungstenite::http::StatusCode;
+    use tokio_tungstenite::tungstenite::{Error, Message};
+
+    async fn serve(config: WebSocketConfig) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let broker = Broker::builder().build();
+        tokio::spawn(broker.serve_websocket(
+            listener,
+            Arc::new(config),
+            None,
+            ProxyProtocol::Reject,
+        ));
+        addr
+    }
+
+    async fn handshake(
+        addr: &str,
+        path: &str,
+        origin: &str,
+    ) -> Result<tokio_tungstenite::WebSocketStream<TcpStream>, Error> {
+        let mut request = format!("ws://{addr}{path}").into_client_request().unwrap();
+        let headers = request.headers_mut();
+        headers.insert("Sec-WebSocket-Protocol", "mqtt".parse().unwrap());
+        headers.insert("Origin", origin.parse().unwrap());
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        tokio_tungstenite::client_async(request, stream)
+            .await
+            .map(|(websocket, _)| websocket)
+    }
+
+    #[tokio::test]
+    async fn should_read_packets_spanning_and_sharing_frames() {
+        let addr = serve(WebSocketConfig::default()).await;
+        let mut websocket = handshake(&addr, "/mqtt", "https://example.com")
+            .await
+            .unwrap();
+
+        let connect = ControlPacket::Connect(Connect {
+            client_id: String::from("ID"),
+            ..Default::default()
+        });
+        let mut wire = encode_packet(&connect).unwrap().to_vec();
+        wire.extend_from_slice(
+            &encode_packet(&ControlPacket::PingReq(PingReq::default())).unwrap(),
+        );
+
+        websocket
+            .send(Message::Binary(wire[..5].to_vec()))
+            .await
+            .unwrap();
+        websocket
+            .send(Message::Binary(wire[5..].to_vec()))
+            .await
+            .unwrap();
+
+        let mut client = Connection::new(WebSocketTransport::new(websocket));
+        assert!(matches!(
+            client.read_packet().await.unwrap(),
+            Some(ControlPacket::ConnAck(_))
+        ));
+        assert!(matches!(
+            client.read_packet().await.unwrap(),
+            Some(ControlPacket::PingResp(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn should_refuse_wrong_path_and_origin() {
+        let config = WebSocketConfig::default()
+            .path("/ws")
+            .allow_origin("https://dashboard.example.com");
+        let addr = serve(config).await;
+
+        match handshake(&addr, "/mqtt", "https://dashboard.example.com").await {
+            Err(Error::Http(response)) => assert_eq!(StatusCode::NOT_FOUND, response.status()),
+            _ => panic!("expected the handshake to fail"),
+        }
+        match handshake(&addr, "/ws", "https://evil.example.com").await {
+            Err(Error::Http(response)) => assert_eq!(StatusCode::FORBIDDEN, response.status()),
+            _ => panic!("expected the handshake to fail"),
+        }
+        assert!(handshake(&addr, "/ws", "https://dashboard.example.com")
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn should_close_connections_stalling_the_upgrade() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let broker = Broker::builder()
+            .handshake_timeout(Duration::from_millis(50))
+            .build();
+        tokio::spawn(broker.serve_websocket(
+            listener,
+            Arc::new(WebSocketConfig::default()),
+            None,
+            ProxyProtocol::Reject,
+        ));
+
+        // an HTTP request whose headers never end
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"GET /mqtt HTTP/1.1\r\n").await.unwrap();
+        let mut rest = vec![];
+        let read = tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut rest));
+        assert_eq!(0, read.await.unwrap().unwrap());
+    }
+}
@@ -0,0 +1,89 @@
+//! Options shared by the command-line tools.
+
+use clap::{ArgAction, Args};
+use client::{ConnectOptions, QoS, TlsOptions};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Where to connect and how to authenticate.
+#[derive(Args, Debug)]
+pub struct ConnectArgs {
+    /// Host of the broker.
+    #[arg(short = 'h', long, default_value = "localhost")]
+    pub host: String,
+
+    /// Port of the broker, 1883 or 8883 with TLS by default.
+    #[arg(short, long)]
+    pub port: Option<u16>,
+
+    /// Client identifier, assigned by the broker when not set.
+    #[arg(short = 'i', long = "id", default_value = "")]
+    pub client_id: String,
+
+    #[arg(short, long)]
+    pub username: Option<String>,
+
+    #[arg(short = 'P', long, requires = "username")]
+    pub password: Option<String>,
+
+    /// Keep alive in seconds.
+    #[arg(short, long, default_value_t = 60)]
+    pub keepalive: u64,
+
+    /// Connects over TLS, verifying the broker certificate against this PEM encoded CA bundle.
+    #[arg(long)]
+    pub cafile: Option<PathBuf>,
+
+    /// PEM encoded certificate presented to the broker.
+    #[arg(long, requires_all = ["cafile", "key"])]
+    pub cert: Option<PathBuf>,
+
+    /// PEM encoded private key of the certificate.
+    #[arg(long, requires = "cert")]
+    pub key: Option<PathBuf>,
+
+    /// Name the broker certificate must be valid for, the host by default.
+    #[arg(long, requires = "cafile")]
+    pub tls_server_name: Option<String>,
+
+    /// Print help.
+    #[arg(long, action = ArgAction::Help)]
+    pub help: Option<bool>,
+}
+
+impl ConnectArgs {
+    pub fn connect_options(&self) -> ConnectOptions {
+        let port = match (self.port, &self.cafile) {
+            (Some(port), _) => port,
+            (None, Some(_)) => 8883,
+            (None, None) => 1883,
+        };
+        let mut options = ConnectOptions::new(&format!("{}:{port}", self.host))
+            .client_id(&self.client_id)
+            .keep_alive(Duration::from_secs(self.keepalive));
+
+        if let Some(username) = &self.username {
+            let password = self.password.as_deref().unwrap_or_default();
+            options = options.credentials(username, password.as_bytes());
+        }
+        if let Some(cafile) = &self.cafile {
+            let mut tls = TlsOptions::new(cafile);
+            if let (Some(cert), Some(key)) = (&self.cert, &self.key) {
+                tls = tls.client_certificate(cert, key);
+            }
+            if let Some(server_name) = &self.tls_server_name {
+                tls = tls.server_name(server_name);
+            }
+            options = options.tls(tls);
+        }
+        options
+    }
+}
+
+pub fn qos(number: u8) -> QoS {
+    match number {
+        0 => QoS::AtMostOnce,
+        1 => QoS::AtLeastOnce,
+        _ => QoS::ExactlyOnce,
+    }
+}
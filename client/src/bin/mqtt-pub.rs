@@ -0,0 +1,211 @@
+//! Publishes a message, in the spirit of mosquitto_pub.
+//!
+//! ```text
+//! mqtt-pub -t sensors/kitchen -q 1 -m 21.5 --content-type text/plain --user-property unit=C
+//! ```
+
+mod cli;
+
+use clap::Parser;
+use cli::ConnectArgs;
+use client::{Client, Message};
+use deser::primitive_types::{
+    BinaryData, Byte, FourByteInteger, Utf8EncodedString, Utf8StringPair,
+};
+use deser::properties::Property;
+use std::io::Read;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+#[derive(Parser, Debug)]
+#[command(
+    about = "Publishes a message to an MQTT 5 broker",
+    disable_help_flag = true
+)]
+struct Args {
+    #[command(flatten)]
+    connect: ConnectArgs,
+
+    #[arg(short, long)]
+    topic: String,
+
+    /// Payload of the message.
+    #[arg(short, long, group = "payload")]
+    message: Option<String>,
+
+    /// Sends the content of a file as payload.
+    #[arg(short, long, group = "payload")]
+    file: Option<PathBuf>,
+
+    /// Sends the standard input as payload.
+    #[arg(short, long, group = "payload")]
+    stdin: bool,
+
+    #[arg(short, long, default_value_t = 0, value_parser = clap::value_parser!(u8).range(0..=2))]
+    qos: u8,
+
+    #[arg(short, long)]
+    retain: bool,
+
+    /// User property, repeatable.
+    #[arg(long = "user-property", value_name = "KEY=VALUE", value_parser = user_property)]
+    user_properties: Vec<(String, String)>,
+
+    /// Content Type, a MIME type for instance.
+    #[arg(long)]
+    content_type: Option<String>,
+
+    /// Message Expiry Interval in seconds.
+    #[arg(long, value_name = "SECONDS")]
+    expiry: Option<u32>,
+
+    /// Marks the payload as UTF-8.
+    #[arg(long)]
+    utf8: bool,
+
+    #[arg(long)]
+    response_topic: Option<String>,
+
+    #[arg(long)]
+    correlation_data: Option<String>,
+}
+
+impl Args {
+    fn payload(&self) -> std::io::Result<Vec<u8>> {
+        if let Some(message) = &self.message {
+            return Ok(message.as_bytes().to_vec());
+        }
+        if let Some(file) = &self.file {
+            return std::fs::read(file);
+        }
+        let mut payload = vec![];
+        if self.stdin {
+            std::io::stdin().read_to_end(&mut payload)?;
+        }
+        Ok(payload)
+    }
+
+    fn message(&self, payload: &[u8]) -> Message {
+        let mut message = Message::new(&self.topic, payload)
+            .qos(cli::qos(self.qos))
+            .retain(self.retain);
+
+        if self.utf8 {
+            message = message.property(Property::PayloadFormatIndicator(Byte(1)));
+        }
+        if let Some(expiry) = self.expiry {
+            message = message.property(Property::MessageExpiryInterval(FourByteInteger(expiry)));
+        }
+        if let Some(content_type) = &self.content_type {
+            message = message.property(Property::ContentType(Utf8EncodedString(
+                content_type.clone(),
+            )));
+        }
+        if let Some(response_topic) = &self.response_topic {
+            message = message.property(Property::ResponseTopic(Utf8EncodedString(
+                response_topic.clone(),
+            )));
+        }
+        if let Some(correlation_data) = &self.correlation_data {
+            message = message.property(Property::CorrelationData(BinaryData(
+                correlation_data.as_bytes().to_vec(),
+            )));
+        }
+        for (key, value) in &self.user_properties {
+            message = message.property(Property::User(Utf8StringPair(key.clone(), value.clone())));
+        }
+        message
+    }
+}
+
+/// Parses a `key=value` user property.
+fn user_property(argument: &str) -> Result<(String, String), String> {
+    argument
+        .split_once('=')
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .ok_or_else(|| format!("expected key=value, got {argument}"))
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args = Args::parse();
+    match publish(&args).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("mqtt-pub: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn publish(args: &Args) -> Result<(), Box<dyn std::error::Error>> {
+    let payload = args.payload()?;
+    let (client, _) = Client::connect(args.connect.connect_options()).await?;
+    client
+        .publish_message(&args.message(&payload))
+        .await?
+        .await?;
+    client.disconnect().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod test {
+    use crate::{user_property, Args};
+    use clap::Parser;
+    use client::QoS;
+    use deser::primitive_types::{FourByteInteger, Utf8EncodedString, Utf8StringPair};
+    use deser::properties::Property;
+
+    #[test]
+    pub fn should_build_message_with_properties() {
+        let args = Args::try_parse_from([
+            "mqtt-pub",
+            "-t",
+            "sensors/kitchen",
+            "-q",
+            "1",
+            "-r",
+            "--content-type",
+            "text/plain",
+            "--expiry",
+            "60",
+            "--user-property",
+            "unit=C",
+            "--user-property",
+            "room=kitchen",
+        ])
+        .unwrap();
+
+        let message = args.message(b"21.5");
+        assert_eq!(QoS::AtLeastOnce, message.qos);
+        assert!(message.retain);
+        assert_eq!(
+            vec![
+                Property::MessageExpiryInterval(FourByteInteger(60)),
+                Property::ContentType(Utf8EncodedString(String::from("text/plain"))),
+                Property::User(Utf8StringPair(String::from("unit"), String::from("C"))),
+                Property::User(Utf8StringPair(
+                    String::from("room"),
+                    String::from("kitchen")
+                )),
+            ],
+            message.properties
+        );
+    }
+
+    #[test]
+    pub fn should_refuse_several_payloads() {
+        assert!(Args::try_parse_from(["mqtt-pub", "-t", "a", "-m", "x", "-s"]).is_err());
+        assert!(Args::try_parse_from(["mqtt-pub", "-t", "a", "-q", "3"]).is_err());
+    }
+
+    #[test]
+    pub fn should_split_user_property_at_first_equal_sign() {
+        assert_eq!(
+            Ok((String::from("query"), String::from("a=b"))),
+            user_property("query=a=b")
+        );
+        assert!(user_property("query").is_err());
+    }
+}
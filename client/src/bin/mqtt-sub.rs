@@ -0,0 +1,305 @@
+//! Subscribes and prints the messages received, in the spirit of mosquitto_sub.
+//!
+//! ```text
+//! mqtt-sub -t 'sensors/#' -t 'alerts/+' -q 1 --format json
+//! ```
+
+mod cli;
+
+use clap::{Parser, ValueEnum};
+use cli::ConnectArgs;
+use client::{Client, Message, Subscription};
+use deser::primitive_types::{
+    BinaryData, Byte, FourByteInteger, TwoByteInteger, Utf8EncodedString, Utf8StringPair,
+    VariableByteInteger,
+};
+use deser::properties::Property;
+use nu_pretty_hex::{hex_write, simple_hex, HexConfig};
+use serde_json::{json, Map, Value};
+use std::io::IsTerminal;
+use std::process::ExitCode;
+
+#[derive(Parser, Debug)]
+#[command(
+    about = "Prints the messages of MQTT 5 subscriptions",
+    disable_help_flag = true
+)]
+struct Args {
+    #[command(flatten)]
+    connect: ConnectArgs,
+
+    /// Topic filter, repeatable.
+    #[arg(short, long = "topic", required = true)]
+    topics: Vec<String>,
+
+    #[arg(short, long, default_value_t = 0, value_parser = clap::value_parser!(u8).range(0..=2))]
+    qos: u8,
+
+    /// Skips the messages published by this client.
+    #[arg(long)]
+    no_local: bool,
+
+    /// Keeps the retain flag the messages were published with.
+    #[arg(long)]
+    retain_as_published: bool,
+
+    /// 0 receives the retained messages, 1 only for new subscriptions, 2 never.
+    #[arg(long, default_value_t = 0, value_parser = clap::value_parser!(u8).range(0..=2))]
+    retain_handling: u8,
+
+    /// Resumes the session the broker kept for the client identifier.
+    #[arg(short = 'c', long)]
+    disable_clean_start: bool,
+
+    /// Seconds the broker keeps the session after the connection closes.
+    #[arg(short = 'x', long, default_value_t = 0)]
+    session_expiry: u32,
+
+    /// Exits after receiving this many messages.
+    #[arg(short = 'C', long)]
+    count: Option<usize>,
+
+    #[arg(short = 'F', long, value_enum, default_value_t = Format::Text)]
+    format: Format,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    /// Topic and payload on a line, followed by the properties.
+    Text,
+    /// Topic and properties, followed by a hex dump of the payload.
+    Hex,
+    /// One JSON object per message.
+    Json,
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args = Args::parse();
+    match subscribe(&args).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("mqtt-sub: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn subscribe(args: &Args) -> Result<(), Box<dyn std::error::Error>> {
+    let options = args
+        .connect
+        .connect_options()
+        .clean_start(!args.disable_clean_start)
+        .session_expiry_interval(args.session_expiry);
+    let (client, mut messages) = Client::connect(options).await?;
+
+    let subscriptions = args
+        .topics
+        .iter()
+        .map(|topic| {
+            Subscription::new(topic)
+                .qos(cli::qos(args.qos))
+                .no_local(args.no_local)
+                .retain_as_published(args.retain_as_published)
+                .retain_handling(args.retain_handling)
+        })
+        .collect();
+    let reason_codes = client.subscribe_many(subscriptions).await?;
+    for (topic, reason_code) in args.topics.iter().zip(reason_codes) {
+        let code = reason_code as u8;
+        if code >= 0x80 {
+            return Err(
+                format!("subscription to {topic} refused with reason code {code:#04x}").into(),
+            );
+        }
+        // the reason code of a granted subscription is its QoS
+        if code != args.qos {
+            eprintln!("mqtt-sub: {topic} granted QoS {code}");
+        }
+    }
+
+    let color = std::io::stdout().is_terminal();
+    let mut received = 0;
+    while args.count.is_none_or(|count| received < count) {
+        let message = tokio::select! {
+            message = messages.recv() => match message {
+                Some(message) => message,
+                None => return Err("connection lost".into()),
+            },
+            _ = tokio::signal::ctrl_c() => break,
+        };
+        println!("{}", format(&message, args.format, color));
+        received += 1;
+    }
+    client.disconnect().await?;
+    Ok(())
+}
+
+fn format(message: &Message, format: Format, color: bool) -> String {
+    match format {
+        Format::Text => {
+            let mut text = format!(
+                "{} {}",
+                message.topic,
+                String::from_utf8_lossy(&message.payload)
+            );
+            for (name, value) in properties(message) {
+                text.push_str(&format!("\n  {name}: {value}"));
+            }
+            text
+        }
+        Format::Hex => {
+            let mut text = message.topic.clone();
+            for (name, value) in properties(message) {
+                text.push_str(&format!("\n  {name}: {value}"));
+            }
+            text.push('\n');
+            let _ = hex_write(
+                &mut text,
+                &message.payload,
+                HexConfig::default(),
+                Some(color),
+            );
+            text
+        }
+        Format::Json => to_json(message).to_string(),
+    }
+}
+
+/// Name and value of the properties of a message, for display.
+fn properties(message: &Message) -> Vec<(&'static str, String)> {
+    message
+        .properties
+        .iter()
+        .map(|property| match property {
+            Property::PayloadFormatIndicator(Byte(indicator)) => {
+                ("payload-format-indicator", indicator.to_string())
+            }
+            Property::MessageExpiryInterval(FourByteInteger(interval)) => {
+                ("message-expiry-interval", interval.to_string())
+            }
+            Property::ContentType(Utf8EncodedString(content_type)) => {
+                ("content-type", content_type.clone())
+            }
+            Property::ResponseTopic(Utf8EncodedString(topic)) => ("response-topic", topic.clone()),
+            Property::CorrelationData(BinaryData(data)) => ("correlation-data", bytes(data)),
+            Property::SubscriptionIdentifier(VariableByteInteger(id)) => {
+                ("subscription-identifier", id.to_string())
+            }
+            Property::TopicAlias(TwoByteInteger(alias)) => ("topic-alias", alias.to_string()),
+            Property::User(Utf8StringPair(key, value)) => {
+                ("user-property", format!("{key}={value}"))
+            }
+            property => ("property", format!("{property:?}")),
+        })
+        .collect()
+}
+
+/// Text when the bytes are UTF-8, hex otherwise.
+fn bytes(data: &[u8]) -> String {
+    match std::str::from_utf8(data) {
+        Ok(text) => text.to_string(),
+        Err(_) => simple_hex(&data),
+    }
+}
+
+fn to_json(message: &Message) -> Value {
+    let mut properties = Map::new();
+    let mut user_properties = vec![];
+    for property in &message.properties {
+        if let Property::User(Utf8StringPair(key, value)) = property {
+            user_properties.push(json!([key, value]));
+        }
+    }
+    for (name, value) in self::properties(message) {
+        if name != "user-property" {
+            properties.insert(name.to_string(), Value::String(value));
+        }
+    }
+    if !user_properties.is_empty() {
+        properties.insert(
+            String::from("user-properties"),
+            Value::Array(user_properties),
+        );
+    }
+
+    let mut object = json!({
+        "topic": message.topic,
+        "qos": message.qos as u8,
+        "retain": message.retain,
+        "properties": properties,
+    });
+    match std::str::from_utf8(&message.payload) {
+        Ok(payload) => object["payload"] = json!(payload),
+        Err(_) => object["payload_hex"] = json!(simple_hex(&message.payload)),
+    }
+    object
+}
+
+#[cfg(test)]
+pub mod test {
+    use crate::{format, Args, Format};
+    use clap::Parser;
+    use client::{Message, QoS};
+    use deser::primitive_types::{Utf8EncodedString, Utf8StringPair};
+    use deser::properties::Property;
+    use serde_json::json;
+
+    fn message() -> Message {
+        Message::new("sensors/kitchen", b"21.5")
+            .qos(QoS::AtLeastOnce)
+            .property(Property::ContentType(Utf8EncodedString(String::from(
+                "text/plain",
+            ))))
+            .property(Property::User(Utf8StringPair(
+                String::from("unit"),
+                String::from("C"),
+            )))
+    }
+
+    #[test]
+    pub fn should_print_topic_payload_and_properties_as_text() {
+        assert_eq!(
+            "sensors/kitchen 21.5\n  content-type: text/plain\n  user-property: unit=C",
+            format(&message(), Format::Text, false)
+        );
+    }
+
+    #[test]
+    pub fn should_print_payload_as_hex_dump() {
+        let text = format(&message(), Format::Hex, false);
+        assert!(text.starts_with("sensors/kitchen\n  content-type: text/plain\n"));
+        assert!(text.contains("32 31 2e 35"), "{text}");
+    }
+
+    #[test]
+    pub fn should_print_message_as_json() {
+        let mut message = message();
+        message.payload = vec![0xff, 0x00];
+        let value: serde_json::Value =
+            serde_json::from_str(&format(&message, Format::Json, false)).unwrap();
+
+        assert_eq!(
+            json!({
+                "topic": "sensors/kitchen",
+                "qos": 1,
+                "retain": false,
+                "properties": {
+                    "content-type": "text/plain",
+                    "user-properties": [["unit", "C"]],
+                },
+                "payload_hex": "ff 00",
+            }),
+            value
+        );
+    }
+
+    #[test]
+    pub fn should_accept_several_topic_filters() {
+        let args =
+            Args::try_parse_from(["mqtt-sub", "-t", "a/#", "-t", "b/+", "-F", "json"]).unwrap();
+        assert_eq!(vec!["a/#", "b/+"], args.topics);
+        assert_eq!(Format::Json, args.format);
+        assert!(Args::try_parse_from(["mqtt-sub"]).is_err());
+    }
+}
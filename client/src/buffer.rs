@@ -0,0 +1,375 @@
+//! Outbound buffer of QoS 1 and 2 messages, from the time they are published until the broker
+//! acknowledges them. Messages published while the client is disconnected wait there for the
+//! next connection and are sent in the order they were published.
+//!
+//! The buffer is bounded: publishing waits while it is full. It can be spooled to a file so
+//! that buffered messages survive the process, they are sent again by the next client opening
+//! the same spool.
+
+use crate::client::ClientError;
+use crate::event_loop::Reply;
+use bytes::{Buf, BufMut, BytesMut};
+use deser::codec::{decode_packet, encode_packet};
+use deser::packets::publish::Publish;
+use deser::primitive_types::FourByteInteger;
+use deser::properties::Property;
+use deser::ControlPacket;
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::OwnedSemaphorePermit;
+use tracing::warn;
+
+/// Size of the spool below which it is never compacted.
+const MIN_COMPACTION_RECORDS: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BufferOptions {
+    pub(crate) max_messages: usize,
+    pub(crate) spool: Option<PathBuf>,
+}
+
+impl Default for BufferOptions {
+    fn default() -> Self {
+        BufferOptions {
+            max_messages: 1000,
+            spool: None,
+        }
+    }
+}
+
+impl BufferOptions {
+    /// QoS 1 and 2 messages published and not acknowledged yet, 1000 by default.
+    pub fn max_messages(mut self, max_messages: usize) -> Self {
+        self.max_messages = max_messages.max(1);
+        self
+    }
+
+    /// Also writes the buffered messages to the file, created if needed. Messages found there
+    /// are sent after connecting, before any other.
+    pub fn spool(mut self, path: impl AsRef<Path>) -> Self {
+        self.spool = Some(path.as_ref().to_path_buf());
+        self
+    }
+}
+
+/// QoS 1 or 2 message in the buffer.
+pub(crate) struct Buffered {
+    pub(crate) id: u64,
+    pub(crate) publish: Publish,
+    /// Unix time in seconds at which the message was published.
+    pub(crate) published_at: u64,
+    /// None for messages recovered from the spool.
+    pub(crate) reply: Option<Reply<()>>,
+    /// Room taken in the buffer, given back when the message leaves it.
+    pub(crate) permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Buffered {
+    /// Lowers the Message Expiry Interval by the time spent in the buffer. Returns false when
+    /// the message expired.
+    pub(crate) fn refresh_expiry(&mut self, now: u64) -> bool {
+        let elapsed = now.saturating_sub(self.published_at);
+        for property in self.publish.variable_header_properties.iter_mut().flatten() {
+            if let Property::MessageExpiryInterval(FourByteInteger(interval)) = property {
+                if elapsed >= *interval as u64 {
+                    return false;
+                }
+                *interval -= elapsed as u32;
+                self.published_at = now;
+            }
+        }
+        true
+    }
+
+    pub(crate) fn complete(self, result: Result<(), ClientError>) {
+        if let Some(reply) = self.reply {
+            let _ = reply.send(result);
+        }
+    }
+}
+
+pub(crate) fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Append-only log of the buffered messages. Each record is a four byte big endian length
+/// followed by the tag of the record: a stored message with its identifier, publication time
+/// and PUBLISH packet, or the identifier of a message removed from the buffer.
+pub(crate) struct Spool {
+    path: PathBuf,
+    file: File,
+    /// Identifiers of the messages stored and not removed.
+    stored: HashSet<u64>,
+    records: usize,
+}
+
+mod tag {
+    pub const STORED: u8 = 1;
+    pub const REMOVED: u8 = 2;
+}
+
+impl Spool {
+    /// Opens the spool and returns the messages it holds, oldest first. A record cut short by a
+    /// crash while it was being written is dropped.
+    pub(crate) fn open(path: &Path) -> Result<(Spool, Vec<Buffered>), ClientError> {
+        let mut contents = vec![];
+        match File::open(path) {
+            Ok(mut file) => {
+                file.read_to_end(&mut contents)?;
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        let mut messages = BTreeMap::new();
+        let mut offset = 0;
+        while let Some(record) = next_record(&contents[offset..]) {
+            match decode_record(record) {
+                Some(Record::Stored(message)) => {
+                    messages.insert(message.id, message);
+                }
+                Some(Record::Removed(id)) => {
+                    messages.remove(&id);
+                }
+                None => {
+                    return Err(ClientError::Io(std::io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!("corrupt record at offset {offset} of {path:?}"),
+                    )))
+                }
+            }
+            offset += 4 + record.len();
+        }
+        if offset < contents.len() {
+            warn!(
+                "dropping {} bytes of a partially written record at the end of {path:?}",
+                contents.len() - offset
+            );
+        }
+
+        let messages: Vec<Buffered> = messages.into_values().collect();
+        let spool = Spool {
+            path: path.to_path_buf(),
+            file: rewrite(path, &messages)?,
+            stored: messages.iter().map(|message| message.id).collect(),
+            records: messages.len(),
+        };
+        Ok((spool, messages))
+    }
+
+    pub(crate) fn store(&mut self, message: &Buffered) -> Result<(), ClientError> {
+        let mut record = BytesMut::new();
+        encode_stored(message, &mut record)?;
+        self.file.write_all(&record)?;
+        self.stored.insert(message.id);
+        self.records += 1;
+        Ok(())
+    }
+
+    pub(crate) fn remove(&mut self, id: u64) -> Result<(), ClientError> {
+        if !self.stored.remove(&id) {
+            return Ok(());
+        }
+        let mut record = BytesMut::new();
+        record.put_u32(9);
+        record.put_u8(tag::REMOVED);
+        record.put_u64(id);
+        self.file.write_all(&record)?;
+        self.records += 1;
+
+        if self.records >= MIN_COMPACTION_RECORDS.max(2 * self.stored.len()) {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    /// Rewrites the spool with the messages still stored, read back from the current one.
+    fn compact(&mut self) -> Result<(), ClientError> {
+        let contents = std::fs::read(&self.path)?;
+        let mut messages = vec![];
+        let mut offset = 0;
+        while let Some(record) = next_record(&contents[offset..]) {
+            if let Some(Record::Stored(message)) = decode_record(record) {
+                if self.stored.contains(&message.id) {
+                    messages.push(message);
+                }
+            }
+            offset += 4 + record.len();
+        }
+        self.file = rewrite(&self.path, &messages)?;
+        self.records = messages.len();
+        Ok(())
+    }
+}
+
+/// Writes the messages to a new spool which then replaces the current one. Returns the new
+/// spool, opened for appending.
+fn rewrite(path: &Path, messages: &[Buffered]) -> Result<File, ClientError> {
+    let mut contents = BytesMut::new();
+    for message in messages {
+        encode_stored(message, &mut contents)?;
+    }
+
+    let compacted = path.with_extension("compacting");
+    let mut file = File::create(&compacted)?;
+    file.write_all(&contents)?;
+    file.sync_all()?;
+    std::fs::rename(&compacted, path)?;
+
+    Ok(OpenOptions::new().append(true).open(path)?)
+}
+
+enum Record {
+    Stored(Buffered),
+    Removed(u64),
+}
+
+/// Returns the next complete record, without its length.
+fn next_record(contents: &[u8]) -> Option<&[u8]> {
+    let length = u32::from_be_bytes(contents.get(..4)?.try_into().unwrap()) as usize;
+    contents.get(4..4 + length)
+}
+
+/// Buffered messages have no packet identifier yet, 0 stands in for it.
+fn encode_stored(message: &Buffered, buffer: &mut BytesMut) -> Result<(), ClientError> {
+    let mut publish = message.publish.clone();
+    publish.packet_id = Some(0);
+    let packet = encode_packet(&ControlPacket::Publish(publish))
+        .map_err(|e| ClientError::ProtocolError(e.to_string()))?;
+
+    buffer.put_u32(1 + 8 + 8 + packet.len() as u32);
+    buffer.put_u8(tag::STORED);
+    buffer.put_u64(message.id);
+    buffer.put_u64(message.published_at);
+    buffer.put(packet);
+    Ok(())
+}
+
+/// Returns None when the record is malformed.
+fn decode_record(mut record: &[u8]) -> Option<Record> {
+    let record = &mut record;
+
+    match get_u8(record)? {
+        tag::STORED => {
+            let id = get_u64(record)?;
+            let published_at = get_u64(record)?;
+            let mut publish = match decode_packet(BytesMut::from(&record[..])).ok()? {
+                ControlPacket::Publish(publish) => publish,
+                _ => return None,
+            };
+            publish.packet_id = None;
+            Some(Record::Stored(Buffered {
+                id,
+                publish,
+                published_at,
+                reply: None,
+                permit: None,
+            }))
+        }
+        tag::REMOVED => {
+            let id = get_u64(record)?;
+            record.is_empty().then_some(Record::Removed(id))
+        }
+        _ => None,
+    }
+}
+
+fn get_u8(record: &mut &[u8]) -> Option<u8> {
+    (record.remaining() >= 1).then(|| record.get_u8())
+}
+
+fn get_u64(record: &mut &[u8]) -> Option<u64> {
+    (record.remaining() >= 8).then(|| record.get_u64())
+}
+
+#[cfg(test)]
+pub mod test {
+    use crate::buffer::{Buffered, Spool};
+    use deser::packets::publish::Publish;
+    use deser::primitive_types::FourByteInteger;
+    use deser::properties::Property;
+    use std::io::Write;
+
+    fn path(name: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("client-spool-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn buffered(id: u64) -> Buffered {
+        Buffered {
+            id,
+            publish: Publish {
+                packet_type_low_nibble: 0b0010,
+                topic_name: String::from("loggers/1"),
+                application_message: Some(id.to_be_bytes().to_vec()),
+                ..Default::default()
+            },
+            published_at: 1000,
+            reply: None,
+            permit: None,
+        }
+    }
+
+    #[test]
+    pub fn should_recover_messages_not_removed() {
+        let path = path("recover");
+        let (mut spool, recovered) = Spool::open(&path).unwrap();
+        assert!(recovered.is_empty());
+        for id in 1..=3 {
+            spool.store(&buffered(id)).unwrap();
+        }
+        spool.remove(2).unwrap();
+        drop(spool);
+
+        // a record cut short by a crash
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        file.write_all(&[0, 0, 0, 9, 2]).unwrap();
+
+        let (_, recovered) = Spool::open(&path).unwrap();
+        let ids: Vec<u64> = recovered.iter().map(|message| message.id).collect();
+        assert_eq!(vec![1, 3], ids);
+        assert_eq!(buffered(3).publish, recovered[1].publish);
+        assert_eq!(1000, recovered[1].published_at);
+    }
+
+    #[test]
+    pub fn should_compact_spool() {
+        let path = path("compact");
+        let (mut spool, _) = Spool::open(&path).unwrap();
+        for id in 0..2000 {
+            spool.store(&buffered(id)).unwrap();
+            if id > 0 {
+                spool.remove(id - 1).unwrap();
+            }
+        }
+        assert!(spool.records < 1024);
+
+        let (_, recovered) = Spool::open(&path).unwrap();
+        assert_eq!(1, recovered.len());
+        assert_eq!(1999, recovered[0].id);
+    }
+
+    #[test]
+    pub fn should_lower_expiry_interval_by_time_buffered() {
+        let mut message = buffered(1);
+        message.publish.variable_header_properties =
+            Some(vec![Property::MessageExpiryInterval(FourByteInteger(60))]);
+
+        assert!(message.refresh_expiry(1045));
+        assert_eq!(
+            Some(vec![Property::MessageExpiryInterval(FourByteInteger(15))]),
+            message.publish.variable_header_properties
+        );
+        assert!(!message.refresh_expiry(1060));
+        assert!(buffered(2).refresh_expiry(u64::MAX));
+    }
+}
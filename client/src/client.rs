@@ -0,0 +1,471 @@
+use crate::buffer::Spool;
+use crate::connection::{Connection, ConnectionError, Transport};
+use crate::event_loop::{Command, EventLoop};
+use crate::message::{Message, QoS};
+use crate::options::{ConnectOptions, Subscription};
+use crate::reconnect::Event;
+use crate::request::Requests;
+use crate::tls::TlsError;
+use deser::packets::disconnect::Disconnect;
+use deser::packets::publish::Publish;
+use deser::packets::reason_codes::{DISCONNECT, SUBACK, UNSUBACK};
+use deser::packets::subscribe::Subscribe;
+use deser::packets::unsubscribe::UnSubscribe;
+use deser::primitive_types::{TwoByteInteger, Utf8EncodedString};
+use deser::properties::Property;
+use deser::ControlPacket;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::net::TcpStream;
+use tokio::sync::{broadcast, mpsc, oneshot, Semaphore};
+use tracing::debug;
+
+#[derive(Error, Debug)]
+pub enum ClientError {
+    #[error("IO error {0}")]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Connection(#[from] ConnectionError),
+    #[error(transparent)]
+    Tls(#[from] TlsError),
+    #[error("Connection refused with reason code {0:#04x}")]
+    Refused(u8),
+    #[error("Protocol error: {0}")]
+    ProtocolError(String),
+    #[error("Not connected to the broker")]
+    Disconnected,
+    #[error("No PINGRESP received within the keep alive")]
+    KeepAliveTimeout,
+    #[error("Publish refused with reason code {0:#04x}")]
+    PublishRefused(u8),
+    #[error("Subscription refused with reason code {0:#04x}")]
+    SubscriptionRefused(u8),
+    #[error("No response received in time")]
+    Timeout,
+    #[error("The message has no Response Topic to respond to")]
+    NotARequest,
+    #[error("Message expired before it could be sent")]
+    Expired,
+    #[error("Disconnected by the broker with reason code {0:#04x}: {1}")]
+    DisconnectedByBroker(u8, String),
+    #[error("Redirected by the broker to {server}")]
+    Redirected {
+        server: String,
+        /// 0x9D Server moved rather than 0x9C Use another server.
+        permanent: bool,
+    },
+}
+
+/// State shared by the handles of a client and its event loop.
+pub(crate) struct Shared {
+    pub(crate) client_id: String,
+    pub(crate) connected: AtomicBool,
+    pub(crate) session_present: AtomicBool,
+    /// Returned by the broker in the first CONNACK.
+    pub(crate) response_information: Option<String>,
+    pub(crate) request_timeout: Duration,
+    pub(crate) requests: Requests,
+    pub(crate) events: broadcast::Sender<Event>,
+    /// Room left in the buffer of QoS 1 and 2 messages.
+    pub(crate) buffer: Arc<Semaphore>,
+}
+
+/// What the CONNACK of a connection tells.
+pub(crate) struct Connected {
+    pub(crate) assigned_client_id: Option<String>,
+    pub(crate) session_present: bool,
+    pub(crate) keep_alive: Duration,
+    pub(crate) receive_maximum: u16,
+    pub(crate) response_information: Option<String>,
+    /// Server the broker permanently moved to, which the following connections go to.
+    pub(crate) moved_to: Option<String>,
+}
+
+/// Redirections followed by a handshake before giving up.
+const MAX_REDIRECTS: usize = 3;
+
+/// Opens a connection and exchanges CONNECT and CONNACK.
+pub(crate) async fn handshake(
+    options: &ConnectOptions,
+) -> Result<(Connection<Transport>, Connected), ClientError> {
+    handshake_with(options, &options.addr).await
+}
+
+/// Opens a connection to the server and exchanges CONNECT and CONNACK, following the Server
+/// Reference of a CONNACK refusing the connection with 0x9C Use another server or 0x9D Server
+/// moved.
+pub(crate) async fn handshake_with(
+    options: &ConnectOptions,
+    server: &str,
+) -> Result<(Connection<Transport>, Connected), ClientError> {
+    let mut server = server.to_string();
+    let mut moved_to = None;
+    for _ in 0..=MAX_REDIRECTS {
+        match connect_to(options, &server).await {
+            Err(ClientError::Redirected {
+                server: reference,
+                permanent,
+            }) => {
+                debug!("{server} redirected the connection to {reference}");
+                if permanent {
+                    moved_to = Some(reference.clone());
+                }
+                server = reference;
+            }
+            Ok((connection, mut connected)) => {
+                connected.moved_to = moved_to;
+                return Ok((connection, connected));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Err(ClientError::ProtocolError(format!(
+        "redirected more than {MAX_REDIRECTS} times"
+    )))
+}
+
+async fn connect_to(
+    options: &ConnectOptions,
+    server: &str,
+) -> Result<(Connection<Transport>, Connected), ClientError> {
+    let stream = TcpStream::connect(server).await?;
+    let stream: Transport = match &options.tls {
+        Some(tls) => Box::new(tls.connect(server, stream).await?),
+        None => Box::new(stream),
+    };
+    let mut connection = Connection::new(stream);
+    connection
+        .write_packet(&ControlPacket::Connect(options.connect_packet()))
+        .await?;
+
+    let mut connack = match connection.read_packet().await? {
+        Some(ControlPacket::ConnAck(connack)) => connack,
+        Some(packet) => {
+            return Err(ClientError::ProtocolError(format!(
+                "expected CONNACK, received {}",
+                packet.name()
+            )))
+        }
+        None => return Err(ClientError::Disconnected),
+    };
+    if connack.connect_reason_code >= 0x80 {
+        let reason_code = connack.connect_reason_code;
+        return Err(
+            redirection(reason_code, &connack.variable_header_properties, server)
+                .unwrap_or(ClientError::Refused(reason_code)),
+        );
+    }
+
+    let mut connected = Connected {
+        assigned_client_id: None,
+        session_present: connack.session_present(),
+        keep_alive: options.keep_alive,
+        receive_maximum: u16::MAX,
+        response_information: None,
+        moved_to: None,
+    };
+    for property in connack.variable_header_properties.into_iter().flatten() {
+        match property {
+            Property::AssignedClientIdentifier(Utf8EncodedString(id)) => {
+                connected.assigned_client_id = Some(id)
+            }
+            Property::ServerKeepAlive(TwoByteInteger(seconds)) => {
+                connected.keep_alive = Duration::from_secs(seconds as u64)
+            }
+            Property::ReceiveMaximum(TwoByteInteger(maximum)) => {
+                connected.receive_maximum = maximum
+            }
+            Property::ResponseInformation(Utf8EncodedString(info)) => {
+                connected.response_information = Some(info)
+            }
+            _ => {}
+        }
+    }
+    debug!(
+        "connected to {server}, session present: {}",
+        connected.session_present
+    );
+    Ok((connection, connected))
+}
+
+/// Error for the DISCONNECT of the broker, a redirection when it points to another server.
+pub(crate) fn disconnected_by_broker(disconnect: Disconnect, server: &str) -> ClientError {
+    let reason_code = disconnect.reason_code as u8;
+    redirection(reason_code, &disconnect.variable_header_properties, server).unwrap_or_else(|| {
+        let reason = disconnect
+            .variable_header_properties
+            .iter()
+            .flatten()
+            .find_map(|p| match p {
+                Property::ReasonString(Utf8EncodedString(reason)) => Some(reason.clone()),
+                _ => None,
+            });
+        ClientError::DisconnectedByBroker(reason_code, reason.unwrap_or_default())
+    })
+}
+
+/// The redirection of a 0x9C Use another server or 0x9D Server moved reason code with a Server
+/// Reference.
+fn redirection(
+    reason_code: u8,
+    properties: &Option<Vec<Property>>,
+    server: &str,
+) -> Option<ClientError> {
+    if reason_code != DISCONNECT::UseAnotherServer as u8
+        && reason_code != DISCONNECT::ServerMoved as u8
+    {
+        return None;
+    }
+    let reference = properties.iter().flatten().find_map(|p| match p {
+        Property::ServerReference(Utf8EncodedString(reference)) => Some(reference),
+        _ => None,
+    })?;
+    Some(ClientError::Redirected {
+        server: server_address(reference, server)?,
+        permanent: reason_code == DISCONNECT::ServerMoved as u8,
+    })
+}
+
+/// First server of a Server Reference, which holds one or more separated by spaces. A server
+/// without a port keeps the port of the current one.
+fn server_address(reference: &str, current: &str) -> Option<String> {
+    let server = reference.split_whitespace().next()?;
+    let has_port = match server.rsplit_once(':') {
+        // the colons of an IPv6 address are enclosed in brackets
+        Some((host, port)) => {
+            !port.is_empty()
+                && port.bytes().all(|b| b.is_ascii_digit())
+                && (!host.contains(':') || host.ends_with(']'))
+        }
+        None => false,
+    };
+    match (has_port, current.rsplit_once(':')) {
+        (false, Some((_, port))) => Some(format!("{server}:{port}")),
+        _ => Some(server.to_string()),
+    }
+}
+
+/// Handle to an MQTT connection. Clones share the connection, which is closed once every
+/// handle is dropped or `disconnect` is called. With `ConnectOptions::reconnect` the client
+/// reconnects when the connection is lost, until then operations wait or fail with
+/// `ClientError::Disconnected`. A broker redirecting the client with a Server Reference, in its
+/// CONNACK or DISCONNECT, is followed at once whether reconnecting is enabled or not.
+#[derive(Clone)]
+pub struct Client {
+    commands: mpsc::Sender<Command>,
+    pub(crate) shared: Arc<Shared>,
+}
+
+/// Resolves when the broker acknowledged a published message: at once at QoS 0, with PUBACK
+/// at QoS 1 and with PUBCOMP at QoS 2.
+pub struct Acknowledgement(oneshot::Receiver<Result<(), ClientError>>);
+
+impl Future for Acknowledgement {
+    type Output = Result<(), ClientError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.0)
+            .poll(cx)
+            .map(|result| result.unwrap_or(Err(ClientError::Disconnected)))
+    }
+}
+
+impl Client {
+    /// Connects and returns the client with the messages received on its subscriptions.
+    /// Messages must be consumed: the connection stops reading packets while the channel is
+    /// full. Messages left in the spool of the buffer are sent first.
+    pub async fn connect(
+        options: ConnectOptions,
+    ) -> Result<(Client, mpsc::Receiver<Message>), ClientError> {
+        let buffer = Arc::new(Semaphore::new(options.buffer.max_messages));
+        let spool = match &options.buffer.spool {
+            Some(path) => {
+                let (spool, mut recovered) = Spool::open(path)?;
+                for message in &mut recovered {
+                    message.permit = buffer.clone().try_acquire_owned().ok();
+                }
+                Some((spool, recovered))
+            }
+            None => None,
+        };
+        let (connection, connected) = handshake(&options).await?;
+        let client_id = connected
+            .assigned_client_id
+            .clone()
+            .unwrap_or_else(|| options.client_id.clone());
+
+        let (events, _) = broadcast::channel(16);
+        let shared = Arc::new(Shared {
+            client_id,
+            connected: AtomicBool::new(true),
+            session_present: AtomicBool::new(connected.session_present),
+            response_information: connected.response_information.clone(),
+            request_timeout: options.request_timeout,
+            requests: Requests::default(),
+            events,
+            buffer,
+        });
+        let (commands, commands_rx) = mpsc::channel(64);
+        let (messages, messages_rx) = mpsc::channel(64);
+        let event_loop = EventLoop::new(
+            options,
+            connection,
+            &connected,
+            commands_rx,
+            messages,
+            shared.clone(),
+            spool,
+        );
+        tokio::spawn(event_loop.run());
+
+        Ok((Client { commands, shared }, messages_rx))
+    }
+
+    /// Client identifier, assigned by the broker when the options did not set one.
+    pub fn client_id(&self) -> &str {
+        &self.shared.client_id
+    }
+
+    /// Whether the broker resumed a session it kept for the client identifier, on the last
+    /// connection.
+    pub fn session_present(&self) -> bool {
+        self.shared.session_present.load(Ordering::Relaxed)
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.shared.connected.load(Ordering::Relaxed)
+    }
+
+    /// Changes of the connection state from now on.
+    pub fn events(&self) -> broadcast::Receiver<Event> {
+        self.shared.events.subscribe()
+    }
+
+    pub async fn publish(
+        &self,
+        topic: &str,
+        qos: QoS,
+        payload: &[u8],
+    ) -> Result<Acknowledgement, ClientError> {
+        self.publish_message(&Message::new(topic, payload).qos(qos))
+            .await
+    }
+
+    /// Returns once the message is handed to the connection. Messages at QoS 1 and 2 wait
+    /// in the buffer while the broker has as many in flight as its Receive Maximum, or while the
+    /// client is disconnected, and this waits while the buffer is full.
+    pub async fn publish_message(&self, message: &Message) -> Result<Acknowledgement, ClientError> {
+        let permit = match message.qos {
+            QoS::AtMostOnce => None,
+            _ => Some(
+                self.shared
+                    .buffer
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .map_err(|_| ClientError::Disconnected)?,
+            ),
+        };
+        let (tx, rx) = oneshot::channel();
+        self.send(Command::Publish(Publish::from(message), tx, permit))
+            .await?;
+        Ok(Acknowledgement(rx))
+    }
+
+    /// Returns the reason code of the SUBACK: the granted QoS or why the subscription was
+    /// refused.
+    pub async fn subscribe(&self, topic_filter: &str, qos: QoS) -> Result<SUBACK, ClientError> {
+        let mut reason_codes = self
+            .subscribe_many(vec![Subscription::new(topic_filter).qos(qos)])
+            .await?;
+        Ok(reason_codes.remove(0))
+    }
+
+    /// Subscribes with a single SUBSCRIBE and returns a reason code for each subscription.
+    pub async fn subscribe_many(
+        &self,
+        subscriptions: Vec<Subscription>,
+    ) -> Result<Vec<SUBACK>, ClientError> {
+        let count = subscriptions.len();
+        let subscribe = Subscribe {
+            topic_filters: subscriptions
+                .iter()
+                .map(Subscription::to_topic_filter)
+                .collect(),
+            ..Default::default()
+        };
+        let (tx, rx) = oneshot::channel();
+        self.send(Command::Subscribe(subscribe, tx)).await?;
+        let suback = rx.await.map_err(|_| ClientError::Disconnected)?;
+
+        if suback.reason_codes.len() != count {
+            return Err(ClientError::ProtocolError(format!(
+                "SUBACK with {} reason codes for {count} subscriptions",
+                suback.reason_codes.len()
+            )));
+        }
+        Ok(suback.reason_codes)
+    }
+
+    /// Returns a reason code for each topic filter.
+    pub async fn unsubscribe(&self, topic_filters: &[&str]) -> Result<Vec<UNSUBACK>, ClientError> {
+        let unsubscribe = UnSubscribe {
+            topic_filters: topic_filters.iter().map(|f| f.to_string()).collect(),
+            ..Default::default()
+        };
+        let (tx, rx) = oneshot::channel();
+        self.send(Command::Unsubscribe(unsubscribe, tx)).await?;
+        let unsuback = rx.await.map_err(|_| ClientError::Disconnected)?;
+
+        if unsuback.topic_filters.len() != topic_filters.len() {
+            return Err(ClientError::ProtocolError(format!(
+                "UNSUBACK with {} reason codes for {} topic filters",
+                unsuback.topic_filters.len(),
+                topic_filters.len()
+            )));
+        }
+        Ok(unsuback.topic_filters)
+    }
+
+    /// Sends DISCONNECT and closes the connection. Messages in flight are abandoned and the
+    /// broker discards the will message.
+    pub async fn disconnect(&self) -> Result<(), ClientError> {
+        let (tx, rx) = oneshot::channel();
+        self.send(Command::Disconnect(tx)).await?;
+        rx.await.map_err(|_| ClientError::Disconnected)?
+    }
+
+    async fn send(&self, command: Command) -> Result<(), ClientError> {
+        self.commands
+            .send(command)
+            .await
+            .map_err(|_| ClientError::Disconnected)
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use crate::client::server_address;
+
+    #[test]
+    pub fn should_take_first_server_of_reference() {
+        assert_eq!(
+            Some(String::from("standby:1884")),
+            server_address("standby:1884 other:1883", "broker:1883")
+        );
+        assert_eq!(
+            Some(String::from("standby:1883")),
+            server_address("standby", "broker:1883")
+        );
+        assert_eq!(
+            Some(String::from("[::1]:1883")),
+            server_address("[::1]", "broker:1883")
+        );
+        assert_eq!(None, server_address(" ", "broker:1883"));
+    }
+}
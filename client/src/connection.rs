@@ -0,0 +1,65 @@
+use bytes::BytesMut;
+use deser::codec::{decode_packet, encode_packet, next_frame, CodecError};
+use deser::ControlPacket;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::trace;
+
+#[derive(Error, Debug)]
+pub enum ConnectionError {
+    #[error("IO error {0}")]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Codec(#[from] CodecError),
+    #[error("Connection reset by peer with a partial packet in the buffer")]
+    ResetByPeer,
+}
+
+/// Byte stream to the broker, TCP or TLS.
+pub(crate) trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+pub(crate) type Transport = Box<dyn Stream>;
+
+/// Reads and writes whole control packets over a byte stream.
+pub struct Connection<T> {
+    stream: T,
+    buffer: BytesMut,
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> Connection<T> {
+    pub fn new(stream: T) -> Connection<T> {
+        Connection {
+            stream,
+            buffer: BytesMut::with_capacity(4096),
+        }
+    }
+
+    /// Returns None when the broker closed the stream cleanly. Cancel safe, partially read
+    /// packets stay in the buffer.
+    pub async fn read_packet(&mut self) -> Result<Option<ControlPacket>, ConnectionError> {
+        loop {
+            if let Some(frame) = next_frame(&mut self.buffer, usize::MAX)? {
+                let packet = decode_packet(frame)?;
+                trace!("read {} packet", packet.name());
+                return Ok(Some(packet));
+            }
+
+            if 0 == self.stream.read_buf(&mut self.buffer).await? {
+                if self.buffer.is_empty() {
+                    return Ok(None);
+                }
+                return Err(ConnectionError::ResetByPeer);
+            }
+        }
+    }
+
+    pub async fn write_packet(&mut self, packet: &ControlPacket) -> Result<(), ConnectionError> {
+        let bytes = encode_packet(packet)?;
+        trace!("writing {} packet", packet.name());
+        self.stream.write_all(&bytes).await?;
+        self.stream.flush().await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,721 @@
+//! Task owning the connection of a client: it writes the packets of the client handles,
+//! dispatches the packets of the broker, pings the broker and keeps the messages in flight
+//! within the Receive Maximum of the broker.
+//!
+//! QoS 1 and 2 messages stay in the buffer, and its spool, until the broker acknowledges them.
+//! Those published while disconnected are sent on the next connection, in order, unless their
+//! Message Expiry Interval elapsed meanwhile.
+//!
+//! When the connection is lost and the client reconnects, QoS 1 and 2 messages in flight are
+//! resent, with DUP set if the broker resumed the session, and the subscriptions are renewed if
+//! it did not.
+
+use crate::buffer::{unix_time, Buffered, Spool};
+use crate::client::{
+    disconnected_by_broker, handshake, handshake_with, ClientError, Connected, Shared,
+};
+use crate::connection::{Connection, Transport};
+use crate::message::Message;
+use crate::options::ConnectOptions;
+use crate::reconnect::{Backoff, Event};
+use deser::packets::disconnect::Disconnect;
+use deser::packets::pingreq::PingReq;
+use deser::packets::puback::PubAck;
+use deser::packets::pubcomp::PubComp;
+use deser::packets::publish::Publish;
+use deser::packets::pubrec::PubRec;
+use deser::packets::pubrel::PubRel;
+use deser::packets::reason_codes::DISCONNECT;
+use deser::packets::suback::SubAck;
+use deser::packets::subscribe::{Subscribe, TopicFilterAndSubscriptionOptions};
+use deser::packets::unsuback::UnsubAck;
+use deser::packets::unsubscribe::UnSubscribe;
+use deser::ControlPacket;
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot, OwnedSemaphorePermit};
+use tokio::time::Instant;
+use tracing::{debug, warn};
+
+pub(crate) type Reply<T> = oneshot::Sender<Result<T, ClientError>>;
+
+pub(crate) enum Command {
+    /// With the room taken in the buffer by QoS 1 and 2 messages.
+    Publish(Publish, Reply<()>, Option<OwnedSemaphorePermit>),
+    Subscribe(Subscribe, oneshot::Sender<SubAck>),
+    Unsubscribe(UnSubscribe, oneshot::Sender<UnsubAck>),
+    Disconnect(Reply<()>),
+}
+
+/// Packets sent and waiting for their acknowledgement.
+enum Pending {
+    Publish {
+        message: Buffered,
+        /// PUBREC received and PUBREL sent, for QoS 2.
+        released: bool,
+    },
+    Subscribe(oneshot::Sender<SubAck>),
+    Unsubscribe(oneshot::Sender<UnsubAck>),
+    /// Subscriptions renewed after the broker lost the session.
+    Resubscribe,
+}
+
+pub(crate) struct EventLoop {
+    /// Options of the reconnections.
+    options: ConnectOptions,
+    connection: Connection<Transport>,
+    commands: mpsc::Receiver<Command>,
+    messages: mpsc::Sender<Message>,
+    shared: Arc<Shared>,
+    /// By packet identifier, in the order they are resent.
+    pending: BTreeMap<u16, Pending>,
+    subscriptions: Vec<TopicFilterAndSubscriptionOptions>,
+    last_packet_id: u16,
+    /// QoS 1 and 2 messages sent and not acknowledged yet.
+    in_flight: usize,
+    /// QoS 1 and 2 messages the broker accepts to have in flight.
+    receive_maximum: usize,
+    /// QoS 1 and 2 messages waiting for room in flight.
+    waiting: VecDeque<Buffered>,
+    spool: Option<Spool>,
+    /// Identifier of the next message in the buffer.
+    next_buffer_id: u64,
+    /// QoS 2 messages received and not released yet with PUBREL.
+    incoming: HashSet<u16>,
+    keep_alive: Duration,
+    last_write: Instant,
+    /// When the PINGREQ waiting for its PINGRESP was sent.
+    ping_sent: Option<Instant>,
+}
+
+impl EventLoop {
+    pub(crate) fn new(
+        mut options: ConnectOptions,
+        connection: Connection<Transport>,
+        connected: &Connected,
+        commands: mpsc::Receiver<Command>,
+        messages: mpsc::Sender<Message>,
+        shared: Arc<Shared>,
+        spool: Option<(Spool, Vec<Buffered>)>,
+    ) -> Self {
+        options.client_id = shared.client_id.clone();
+        options.clean_start = false;
+        if let Some(server) = &connected.moved_to {
+            options.addr = server.clone();
+        }
+        let (spool, recovered) = match spool {
+            Some((spool, recovered)) => (Some(spool), recovered),
+            None => (None, vec![]),
+        };
+        let next_buffer_id = recovered.last().map_or(0, |message| message.id + 1);
+
+        EventLoop {
+            options,
+            connection,
+            commands,
+            messages,
+            shared,
+            pending: BTreeMap::new(),
+            subscriptions: vec![],
+            last_packet_id: 0,
+            in_flight: 0,
+            receive_maximum: connected.receive_maximum as usize,
+            waiting: VecDeque::from(recovered),
+            spool,
+            next_buffer_id,
+            incoming: HashSet::new(),
+            keep_alive: connected.keep_alive,
+            last_write: Instant::now(),
+            ping_sent: None,
+        }
+    }
+
+    /// Subscribes and unsubscribes waiting on a lost connection fail with
+    /// `ClientError::Disconnected`, messages in flight too unless the client reconnects.
+    pub(crate) async fn run(mut self) {
+        loop {
+            let result = self.process().await;
+            self.shared.connected.store(false, Ordering::Relaxed);
+            let Err(e) = result else {
+                return;
+            };
+
+            debug!("client {} disconnected: {e}", self.shared.client_id);
+            let _ = self.shared.events.send(Event::Disconnected(e.to_string()));
+            self.pending
+                .retain(|_, pending| matches!(pending, Pending::Publish { .. }));
+
+            if let ClientError::Redirected { server, permanent } = e {
+                if self.follow(&server, permanent).await {
+                    continue;
+                }
+            }
+
+            let Some(backoff) = self.options.reconnect.clone() else {
+                return;
+            };
+            if !self.reconnect(&backoff).await {
+                return;
+            }
+        }
+    }
+
+    /// Returns false when the application disconnected, or dropped every handle, meanwhile.
+    async fn reconnect(&mut self, backoff: &Backoff) -> bool {
+        let mut failed = 0;
+        loop {
+            let delay = backoff.delay(failed);
+            failed += 1;
+            let _ = self.shared.events.send(Event::Reconnecting {
+                attempt: failed,
+                delay,
+            });
+
+            let sleep = tokio::time::sleep(delay);
+            tokio::pin!(sleep);
+            loop {
+                tokio::select! {
+                    _ = &mut sleep => break,
+                    command = self.commands.recv() => match command {
+                        Some(Command::Disconnect(tx)) => {
+                            let _ = tx.send(Ok(()));
+                            return false;
+                        }
+                        Some(command) => self.hold(command),
+                        None => return false,
+                    },
+                }
+            }
+
+            let result = match handshake(&self.options).await {
+                Ok((connection, connected)) => self.resume(connection, connected).await,
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(()) => return true,
+                Err(e) => debug!("client {} reconnection failed: {e}", self.shared.client_id),
+            }
+        }
+    }
+
+    /// Connects at once to the server the broker redirected the client to, whether reconnecting
+    /// is enabled or not. A temporary redirection only lasts for this connection.
+    async fn follow(&mut self, server: &str, permanent: bool) -> bool {
+        if permanent {
+            self.options.addr = server.to_string();
+        }
+        let result = match handshake_with(&self.options, server).await {
+            Ok((connection, connected)) => self.resume(connection, connected).await,
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(()) => true,
+            Err(e) => {
+                debug!("client {} redirection failed: {e}", self.shared.client_id);
+                false
+            }
+        }
+    }
+
+    /// Keeps QoS 1 and 2 messages published while disconnected for the next connection, and
+    /// fails the other commands.
+    fn hold(&mut self, command: Command) {
+        match command {
+            Command::Publish(publish, reply, permit) if publish.qos_number() > 0 => {
+                self.buffer(publish, reply, permit)
+            }
+            Command::Publish(_, reply, _) | Command::Disconnect(reply) => {
+                let _ = reply.send(Err(ClientError::Disconnected));
+            }
+            Command::Subscribe(..) | Command::Unsubscribe(..) => {}
+        }
+    }
+
+    async fn resume(
+        &mut self,
+        connection: Connection<Transport>,
+        connected: Connected,
+    ) -> Result<(), ClientError> {
+        self.connection = connection;
+        if let Some(server) = &connected.moved_to {
+            self.options.addr = server.clone();
+        }
+        self.keep_alive = connected.keep_alive;
+        self.receive_maximum = connected.receive_maximum as usize;
+        self.last_write = Instant::now();
+        self.ping_sent = None;
+
+        let session_present = connected.session_present;
+        self.shared.connected.store(true, Ordering::Relaxed);
+        self.shared
+            .session_present
+            .store(session_present, Ordering::Relaxed);
+        let _ = self
+            .shared
+            .events
+            .send(Event::Connected { session_present });
+
+        if !session_present {
+            self.incoming.clear();
+            if !self.subscriptions.is_empty() {
+                let subscribe = Subscribe {
+                    packet_id: self.next_packet_id()?,
+                    topic_filters: self.subscriptions.clone(),
+                    ..Default::default()
+                };
+                self.pending
+                    .insert(subscribe.packet_id, Pending::Resubscribe);
+                self.write(ControlPacket::Subscribe(subscribe)).await?;
+            }
+        }
+
+        let resent: Vec<ControlPacket> = self
+            .pending
+            .iter()
+            .filter_map(|(packet_id, pending)| match pending {
+                Pending::Publish { released: true, .. } => Some(ControlPacket::PubRel(PubRel {
+                    packet_id: *packet_id,
+                    ..Default::default()
+                })),
+                Pending::Publish { message, .. } => {
+                    let mut publish = message.publish.clone();
+                    if session_present {
+                        publish.packet_type_low_nibble |= 0b1000;
+                    }
+                    Some(ControlPacket::Publish(publish))
+                }
+                _ => None,
+            })
+            .collect();
+        for packet in resent {
+            self.write(packet).await?;
+        }
+        self.send_waiting().await
+    }
+
+    async fn process(&mut self) -> Result<(), ClientError> {
+        // messages recovered from the spool go first
+        self.send_waiting().await?;
+        loop {
+            let deadline = self.keep_alive_deadline();
+            tokio::select! {
+                command = self.commands.recv() => match command {
+                    Some(Command::Disconnect(tx)) => {
+                        let _ = tx.send(self.disconnect().await);
+                        return Ok(());
+                    }
+                    Some(command) => self.handle_command(command).await?,
+                    None => return self.disconnect().await,
+                },
+                packet = self.connection.read_packet() => match packet? {
+                    Some(packet) => self.handle_packet(packet).await?,
+                    None => return Err(ClientError::Disconnected),
+                },
+                _ = sleep_until(deadline) => {
+                    if self.ping_sent.is_some() {
+                        return Err(ClientError::KeepAliveTimeout);
+                    }
+                    self.write(ControlPacket::PingReq(PingReq::default())).await?;
+                    self.ping_sent = Some(Instant::now());
+                }
+            }
+        }
+    }
+
+    /// A PINGREQ is due a keep alive after the last packet sent, its PINGRESP a keep alive
+    /// after the PINGREQ.
+    fn keep_alive_deadline(&self) -> Option<Instant> {
+        if self.keep_alive.is_zero() {
+            return None;
+        }
+        Some(self.ping_sent.unwrap_or(self.last_write) + self.keep_alive)
+    }
+
+    async fn write(&mut self, packet: ControlPacket) -> Result<(), ClientError> {
+        self.connection.write_packet(&packet).await?;
+        self.last_write = Instant::now();
+        Ok(())
+    }
+
+    async fn handle_command(&mut self, command: Command) -> Result<(), ClientError> {
+        match command {
+            Command::Publish(publish, tx, _) if publish.qos_number() == 0 => {
+                self.write(ControlPacket::Publish(publish)).await?;
+                let _ = tx.send(Ok(()));
+            }
+            Command::Publish(publish, tx, permit) => {
+                self.buffer(publish, tx, permit);
+                self.send_waiting().await?;
+            }
+            Command::Subscribe(mut subscribe, tx) => {
+                for topic_filter in &subscribe.topic_filters {
+                    self.subscriptions
+                        .retain(|s| s.topic_filter != topic_filter.topic_filter);
+                    self.subscriptions.push(topic_filter.clone());
+                }
+                subscribe.packet_id = self.next_packet_id()?;
+                self.pending
+                    .insert(subscribe.packet_id, Pending::Subscribe(tx));
+                self.write(ControlPacket::Subscribe(subscribe)).await?;
+            }
+            Command::Unsubscribe(mut unsubscribe, tx) => {
+                self.subscriptions
+                    .retain(|s| !unsubscribe.topic_filters.contains(&s.topic_filter));
+                unsubscribe.packet_id = self.next_packet_id()?;
+                self.pending
+                    .insert(unsubscribe.packet_id, Pending::Unsubscribe(tx));
+                self.write(ControlPacket::Unsubscribe(unsubscribe)).await?;
+            }
+            Command::Disconnect(_) => {}
+        }
+        Ok(())
+    }
+
+    /// Adds a QoS 1 or 2 message to the buffer, and to its spool.
+    fn buffer(&mut self, publish: Publish, reply: Reply<()>, permit: Option<OwnedSemaphorePermit>) {
+        let message = Buffered {
+            id: self.next_buffer_id,
+            publish,
+            published_at: unix_time(),
+            reply: Some(reply),
+            permit,
+        };
+        self.next_buffer_id += 1;
+        if let Some(spool) = &mut self.spool {
+            if let Err(e) = spool.store(&message) {
+                message.complete(Err(e));
+                return;
+            }
+        }
+        self.waiting.push_back(message);
+    }
+
+    /// Removes a message from the spool once it left the buffer.
+    fn unspool(&mut self, message: &Buffered) {
+        if let Some(spool) = &mut self.spool {
+            if let Err(e) = spool.remove(message.id) {
+                warn!(
+                    "failed to remove message {} from the spool: {e}",
+                    message.id
+                );
+            }
+        }
+    }
+
+    /// Sends the waiting QoS 1 and 2 messages the Receive Maximum of the broker lets through,
+    /// dropping those which expired.
+    async fn send_waiting(&mut self) -> Result<(), ClientError> {
+        while self.in_flight < self.receive_maximum {
+            let Some(mut message) = self.waiting.pop_front() else {
+                break;
+            };
+            if !message.refresh_expiry(unix_time()) {
+                debug!(
+                    "message {} to {} expired",
+                    message.id, message.publish.topic_name
+                );
+                self.unspool(&message);
+                message.complete(Err(ClientError::Expired));
+                continue;
+            }
+            let packet_id = self.next_packet_id()?;
+            message.publish.packet_id = Some(packet_id);
+            let publish = message.publish.clone();
+            self.pending.insert(
+                packet_id,
+                Pending::Publish {
+                    message,
+                    released: false,
+                },
+            );
+            self.in_flight += 1;
+            self.write(ControlPacket::Publish(publish)).await?;
+        }
+        Ok(())
+    }
+
+    async fn handle_packet(&mut self, packet: ControlPacket) -> Result<(), ClientError> {
+        match packet {
+            ControlPacket::Publish(publish) => self.receive(publish).await?,
+            ControlPacket::PubAck(puback) => {
+                let code = puback.reason_code as u8;
+                self.acknowledge(puback.packet_id, code).await?;
+            }
+            ControlPacket::PubRec(pubrec) => {
+                let code = pubrec.reason_code as u8;
+                if code >= 0x80 {
+                    self.acknowledge(pubrec.packet_id, code).await?;
+                } else {
+                    if let Some(Pending::Publish { released, .. }) =
+                        self.pending.get_mut(&pubrec.packet_id)
+                    {
+                        *released = true;
+                    }
+                    let pubrel = PubRel {
+                        packet_id: pubrec.packet_id,
+                        ..Default::default()
+                    };
+                    self.write(ControlPacket::PubRel(pubrel)).await?;
+                }
+            }
+            ControlPacket::PubComp(pubcomp) => self.acknowledge(pubcomp.packet_id, 0).await?,
+            ControlPacket::PubRel(pubrel) => {
+                self.incoming.remove(&pubrel.packet_id);
+                let pubcomp = PubComp {
+                    packet_id: pubrel.packet_id,
+                    ..Default::default()
+                };
+                self.write(ControlPacket::PubComp(pubcomp)).await?;
+            }
+            ControlPacket::SubAck(suback) => match self.pending.remove(&suback.packet_id) {
+                Some(Pending::Subscribe(tx)) => {
+                    let _ = tx.send(suback);
+                }
+                Some(Pending::Resubscribe) => {
+                    debug!(
+                        "client {} resubscribed: {:?}",
+                        self.shared.client_id, suback.reason_codes
+                    );
+                }
+                _ => {}
+            },
+            ControlPacket::UnsubAck(unsuback) => {
+                if let Some(Pending::Unsubscribe(tx)) = self.pending.remove(&unsuback.packet_id) {
+                    let _ = tx.send(unsuback);
+                }
+            }
+            ControlPacket::PingResp(_) => self.ping_sent = None,
+            ControlPacket::Disconnect(disconnect) => {
+                debug!(
+                    "client {} disconnected by the broker: {:?}",
+                    self.shared.client_id, disconnect.reason_code
+                );
+                return Err(disconnected_by_broker(disconnect, &self.options.addr));
+            }
+            packet => debug!("ignoring {} packet", packet.name()),
+        }
+        Ok(())
+    }
+
+    /// Acknowledges a received message and hands it to its request or to the application.
+    async fn receive(&mut self, publish: Publish) -> Result<(), ClientError> {
+        match (publish.qos_number(), publish.packet_id) {
+            (1, Some(packet_id)) => {
+                let puback = PubAck {
+                    packet_id,
+                    ..Default::default()
+                };
+                self.write(ControlPacket::PubAck(puback)).await?;
+            }
+            (2, Some(packet_id)) => {
+                let pubrec = PubRec {
+                    packet_id,
+                    ..Default::default()
+                };
+                self.write(ControlPacket::PubRec(pubrec)).await?;
+                if !self.incoming.insert(packet_id) {
+                    // resent before our PUBREC reached the broker, already delivered
+                    return Ok(());
+                }
+            }
+            _ => {}
+        }
+
+        if let Some(message) = self.shared.requests.resolve(Message::from(publish)) {
+            // the application may have dropped the receiver if it only publishes
+            let _ = self.messages.send(message).await;
+        }
+        Ok(())
+    }
+
+    /// Completes the delivery of a QoS 1 or 2 message, which makes room for a waiting one.
+    async fn acknowledge(&mut self, packet_id: u16, reason_code: u8) -> Result<(), ClientError> {
+        if let Some(Pending::Publish { message, .. }) = self.pending.remove(&packet_id) {
+            let result = if reason_code >= 0x80 {
+                Err(ClientError::PublishRefused(reason_code))
+            } else {
+                Ok(())
+            };
+            self.unspool(&message);
+            message.complete(result);
+            self.in_flight -= 1;
+        }
+        self.send_waiting().await
+    }
+
+    fn next_packet_id(&mut self) -> Result<u16, ClientError> {
+        for _ in 0..u16::MAX {
+            self.last_packet_id = self.last_packet_id.checked_add(1).unwrap_or(1);
+            if !self.pending.contains_key(&self.last_packet_id) {
+                return Ok(self.last_packet_id);
+            }
+        }
+        Err(ClientError::ProtocolError(String::from(
+            "every packet identifier is in use",
+        )))
+    }
+
+    async fn disconnect(&mut self) -> Result<(), ClientError> {
+        let disconnect = Disconnect {
+            reason_code: DISCONNECT::NormalDisconnection,
+            ..Default::default()
+        };
+        self.write(ControlPacket::Disconnect(disconnect)).await
+    }
+}
+
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use crate::client::Client;
+    use crate::connection::Connection;
+    use crate::message::QoS;
+    use crate::options::ConnectOptions;
+    use crate::reconnect::{Backoff, Event};
+    use deser::packets::connack::ConnAck;
+    use deser::packets::disconnect::Disconnect;
+    use deser::packets::puback::PubAck;
+    use deser::packets::reason_codes::{CONNECTACK, DISCONNECT};
+    use deser::primitive_types::Utf8EncodedString;
+    use deser::properties::Property;
+    use deser::ControlPacket;
+    use std::time::Duration;
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// Accepts a connection and answers its CONNECT.
+    async fn accept(listener: &TcpListener, session_present: bool) -> Connection<TcpStream> {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut connection = Connection::new(stream);
+        match connection.read_packet().await.unwrap() {
+            Some(ControlPacket::Connect(_)) => {}
+            packet => panic!("expected CONNECT, received {packet:?}"),
+        }
+        let connack = ConnAck {
+            connect_ack_flags: session_present as u8,
+            ..Default::default()
+        };
+        connection
+            .write_packet(&ControlPacket::ConnAck(connack))
+            .await
+            .unwrap();
+        connection
+    }
+
+    #[tokio::test]
+    async fn should_resend_message_in_flight_with_dup_after_reconnecting() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let options = ConnectOptions::new(&listener.local_addr().unwrap().to_string())
+            .client_id("device")
+            .reconnect(Backoff::default().initial(Duration::from_millis(10)));
+
+        let (connected, mut connection) =
+            tokio::join!(Client::connect(options), accept(&listener, false));
+        let (client, _) = connected.unwrap();
+        let mut events = client.events();
+
+        let acknowledgement = client
+            .publish("a/b", QoS::AtLeastOnce, b"hello")
+            .await
+            .unwrap();
+        let sent = match connection.read_packet().await.unwrap() {
+            Some(ControlPacket::Publish(publish)) => publish,
+            packet => panic!("expected PUBLISH, received {packet:?}"),
+        };
+        assert!(!sent.dup());
+        drop(connection);
+
+        let mut connection = accept(&listener, true).await;
+        let resent = match connection.read_packet().await.unwrap() {
+            Some(ControlPacket::Publish(publish)) => publish,
+            packet => panic!("expected PUBLISH, received {packet:?}"),
+        };
+        assert!(resent.dup());
+        assert_eq!(sent.packet_id, resent.packet_id);
+        assert_eq!(sent.application_message, resent.application_message);
+
+        let puback = PubAck {
+            packet_id: resent.packet_id.unwrap(),
+            ..Default::default()
+        };
+        connection
+            .write_packet(&ControlPacket::PubAck(puback))
+            .await
+            .unwrap();
+        acknowledgement.await.unwrap();
+
+        assert!(matches!(events.recv().await, Ok(Event::Disconnected(_))));
+        assert!(matches!(
+            events.recv().await,
+            Ok(Event::Reconnecting { attempt: 1, .. })
+        ));
+        assert_eq!(
+            Event::Connected {
+                session_present: true
+            },
+            events.recv().await.unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn should_follow_server_reference() {
+        let first = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let standby = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let reference = Some(vec![Property::ServerReference(Utf8EncodedString(
+            standby.local_addr().unwrap().to_string(),
+        ))]);
+        let options = ConnectOptions::new(&first.local_addr().unwrap().to_string());
+
+        // refused by the CONNACK
+        let refuse = async {
+            let (stream, _) = first.accept().await.unwrap();
+            let mut connection = Connection::new(stream);
+            connection.read_packet().await.unwrap();
+            let connack = ConnAck {
+                connect_reason_code: CONNECTACK::UseAnotherServer as u8,
+                variable_header_properties: reference.clone(),
+                ..Default::default()
+            };
+            connection
+                .write_packet(&ControlPacket::ConnAck(connack))
+                .await
+                .unwrap();
+        };
+        let (connected, _, mut connection) = tokio::join!(
+            Client::connect(options.clone()),
+            refuse,
+            accept(&standby, false)
+        );
+        let (client, _) = connected.unwrap();
+
+        // disconnected, without reconnection enabled
+        let mut events = client.events();
+        let disconnect = Disconnect {
+            reason_code: DISCONNECT::ServerMoved,
+            variable_header_properties: reference.clone(),
+            ..Default::default()
+        };
+        connection
+            .write_packet(&ControlPacket::Disconnect(disconnect))
+            .await
+            .unwrap();
+        let _connection = accept(&standby, true).await;
+        match events.recv().await.unwrap() {
+            Event::Disconnected(reason) => assert!(reason.contains("Redirected"), "{reason}"),
+            event => panic!("expected Disconnected, received {event:?}"),
+        }
+        assert_eq!(
+            Event::Connected {
+                session_present: true
+            },
+            events.recv().await.unwrap()
+        );
+    }
+}
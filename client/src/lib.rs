@@ -1,29 +1,22 @@
-use tokio::net::TcpListener;
+//! Asynchronous MQTT 5 client.
+//!
+//! `Client::connect` spawns a task owning the connection. `Client` is a cheap handle to it,
+//! clones can publish, subscribe and make requests concurrently.
 
-pub fn add(left: usize, right: usize) -> usize {
-    left + right
-}
+mod buffer;
+mod client;
+pub mod connection;
+mod event_loop;
+mod message;
+mod options;
+mod reconnect;
+mod request;
+mod tls;
 
-pub enum ClientError {}
-
-struct Client {
-    addr: String,
-    connection: TcpListener,
-}
-
-impl Client {
-    pub fn connect(addr: String, user_name: String, password: String) -> Result<(), ClientError> {
-        todo!()
-    }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn it_works() {
-        let result = add(2, 2);
-        assert_eq!(result, 4);
-    }
-}
+pub use buffer::BufferOptions;
+pub use client::{Acknowledgement, Client, ClientError};
+pub use deser::packets::reason_codes::{SUBACK, UNSUBACK};
+pub use message::{Message, QoS};
+pub use options::{ConnectOptions, Subscription};
+pub use reconnect::{Backoff, Event};
+pub use tls::{TlsError, TlsOptions};
@@ -0,0 +1,103 @@
+use deser::packets::publish::Publish;
+use deser::primitive_types::{BinaryData, Utf8EncodedString};
+use deser::properties::Property;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum QoS {
+    #[default]
+    AtMostOnce,
+    AtLeastOnce,
+    ExactlyOnce,
+}
+
+impl QoS {
+    pub(crate) fn from_number(qos: u8) -> QoS {
+        match qos {
+            0 => QoS::AtMostOnce,
+            1 => QoS::AtLeastOnce,
+            _ => QoS::ExactlyOnce,
+        }
+    }
+
+    pub(crate) fn number(self) -> u8 {
+        self as u8
+    }
+}
+
+/// Application message, published by the client or received from the broker.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub qos: QoS,
+    pub retain: bool,
+    pub properties: Vec<Property>,
+}
+
+impl Message {
+    /// Message published at QoS 0, not retained.
+    pub fn new(topic: &str, payload: &[u8]) -> Self {
+        Message {
+            topic: topic.to_string(),
+            payload: payload.to_vec(),
+            qos: QoS::AtMostOnce,
+            retain: false,
+            properties: vec![],
+        }
+    }
+
+    pub fn qos(mut self, qos: QoS) -> Self {
+        self.qos = qos;
+        self
+    }
+
+    pub fn retain(mut self, retain: bool) -> Self {
+        self.retain = retain;
+        self
+    }
+
+    pub fn property(mut self, property: Property) -> Self {
+        self.properties.push(property);
+        self
+    }
+
+    /// Topic the publisher expects a response on, when the message is a request.
+    pub fn response_topic(&self) -> Option<&str> {
+        self.properties.iter().find_map(|p| match p {
+            Property::ResponseTopic(Utf8EncodedString(topic)) => Some(topic.as_str()),
+            _ => None,
+        })
+    }
+
+    pub fn correlation_data(&self) -> Option<&[u8]> {
+        self.properties.iter().find_map(|p| match p {
+            Property::CorrelationData(BinaryData(data)) => Some(data.as_slice()),
+            _ => None,
+        })
+    }
+}
+
+impl From<&Message> for Publish {
+    fn from(message: &Message) -> Self {
+        Publish {
+            packet_type_low_nibble: message.qos.number() << 1 | message.retain as u8,
+            topic_name: message.topic.clone(),
+            variable_header_properties: Some(message.properties.clone())
+                .filter(|properties| !properties.is_empty()),
+            application_message: Some(message.payload.clone()),
+            ..Default::default()
+        }
+    }
+}
+
+impl From<Publish> for Message {
+    fn from(publish: Publish) -> Self {
+        Message {
+            qos: QoS::from_number(publish.qos_number()),
+            retain: publish.retain(),
+            topic: publish.topic_name,
+            payload: publish.application_message.unwrap_or_default(),
+            properties: publish.variable_header_properties.unwrap_or_default(),
+        }
+    }
+}
@@ -0,0 +1,284 @@
+use crate::buffer::BufferOptions;
+use crate::message::{Message, QoS};
+use crate::reconnect::Backoff;
+use crate::tls::TlsOptions;
+use deser::packets::connect::Connect;
+use deser::packets::subscribe::{SubscriptionOptions, TopicFilterAndSubscriptionOptions};
+use deser::primitive_types::{Byte, FourByteInteger, TwoByteInteger, Utf8StringPair};
+use deser::properties::Property;
+use std::time::Duration;
+
+/// Where and how to connect.
+#[derive(Debug, Clone)]
+pub struct ConnectOptions {
+    pub(crate) addr: String,
+    pub(crate) client_id: String,
+    pub(crate) clean_start: bool,
+    pub(crate) keep_alive: Duration,
+    pub(crate) session_expiry_interval: u32,
+    pub(crate) receive_maximum: Option<u16>,
+    pub(crate) maximum_packet_size: Option<u32>,
+    pub(crate) user_properties: Vec<(String, String)>,
+    pub(crate) username: Option<String>,
+    pub(crate) password: Option<Vec<u8>>,
+    pub(crate) will: Option<Message>,
+    pub(crate) request_timeout: Duration,
+    pub(crate) reconnect: Option<Backoff>,
+    pub(crate) buffer: BufferOptions,
+    pub(crate) tls: Option<TlsOptions>,
+}
+
+impl ConnectOptions {
+    /// Connects to the broker at `addr`, `host:port`, with an identifier assigned by the broker.
+    pub fn new(addr: &str) -> Self {
+        ConnectOptions {
+            addr: addr.to_string(),
+            client_id: String::new(),
+            clean_start: true,
+            keep_alive: Duration::from_secs(60),
+            session_expiry_interval: 0,
+            receive_maximum: None,
+            maximum_packet_size: None,
+            user_properties: vec![],
+            username: None,
+            password: None,
+            will: None,
+            request_timeout: Duration::from_secs(30),
+            reconnect: None,
+            buffer: BufferOptions::default(),
+            tls: None,
+        }
+    }
+
+    pub fn client_id(mut self, client_id: &str) -> Self {
+        self.client_id = client_id.to_string();
+        self
+    }
+
+    /// Starts a new session instead of resuming the one the broker keeps for the client
+    /// identifier, true by default.
+    pub fn clean_start(mut self, clean_start: bool) -> Self {
+        self.clean_start = clean_start;
+        self
+    }
+
+    /// Longest time without sending a packet, after which the client pings the broker. 60
+    /// seconds by default, zero disables pings. The Server Keep Alive of the broker wins.
+    pub fn keep_alive(mut self, keep_alive: Duration) -> Self {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    /// Seconds the broker keeps the session after the connection closes, `u32::MAX` for ever.
+    /// 0 by default.
+    pub fn session_expiry_interval(mut self, interval: u32) -> Self {
+        self.session_expiry_interval = interval;
+        self
+    }
+
+    /// QoS 1 and 2 messages the client accepts to have in flight.
+    pub fn receive_maximum(mut self, receive_maximum: u16) -> Self {
+        self.receive_maximum = Some(receive_maximum);
+        self
+    }
+
+    pub fn maximum_packet_size(mut self, maximum_packet_size: u32) -> Self {
+        self.maximum_packet_size = Some(maximum_packet_size);
+        self
+    }
+
+    pub fn user_property(mut self, key: &str, value: &str) -> Self {
+        self.user_properties
+            .push((key.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn credentials(mut self, username: &str, password: &[u8]) -> Self {
+        self.username = Some(username.to_string());
+        self.password = Some(password.to_vec());
+        self
+    }
+
+    /// Message the broker publishes when the connection closes without DISCONNECT.
+    pub fn will(mut self, will: Message) -> Self {
+        self.will = Some(will);
+        self
+    }
+
+    /// How long `Client::request` waits for the response, 30 seconds by default.
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = timeout;
+        self
+    }
+
+    /// Reconnects when the connection is lost, resuming the session with `clean_start` unset.
+    /// Without it the client stays disconnected.
+    pub fn reconnect(mut self, backoff: Backoff) -> Self {
+        self.reconnect = Some(backoff);
+        self
+    }
+
+    /// Connects over TLS.
+    pub fn tls(mut self, tls: TlsOptions) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Buffer of the QoS 1 and 2 messages not acknowledged yet, which keeps those published
+    /// while disconnected.
+    pub fn buffer(mut self, buffer: BufferOptions) -> Self {
+        self.buffer = buffer;
+        self
+    }
+
+    pub(crate) fn connect_packet(&self) -> Connect {
+        let mut connect_flags = 0;
+        if self.clean_start {
+            connect_flags |= 0b0000_0010;
+        }
+        if self.username.is_some() {
+            connect_flags |= 0b1000_0000;
+        }
+        if self.password.is_some() {
+            connect_flags |= 0b0100_0000;
+        }
+        if let Some(will) = &self.will {
+            connect_flags |= 0b0000_0100 | will.qos.number() << 3;
+            if will.retain {
+                connect_flags |= 0b0010_0000;
+            }
+        }
+
+        // the Response Information is the basis of the response topic of requests
+        let mut properties = vec![Property::RequestResponseInformation(Byte(1))];
+        if self.session_expiry_interval > 0 {
+            properties.push(Property::SessionExpiryInterval(FourByteInteger(
+                self.session_expiry_interval,
+            )));
+        }
+        if let Some(receive_maximum) = self.receive_maximum {
+            properties.push(Property::ReceiveMaximum(TwoByteInteger(receive_maximum)));
+        }
+        if let Some(maximum_packet_size) = self.maximum_packet_size {
+            properties.push(Property::MaximumPacketSize(FourByteInteger(
+                maximum_packet_size,
+            )));
+        }
+        for (key, value) in &self.user_properties {
+            properties.push(Property::User(Utf8StringPair(key.clone(), value.clone())));
+        }
+
+        Connect {
+            client_id: self.client_id.clone(),
+            keep_alive: self.keep_alive.as_secs().min(u16::MAX as u64) as u16,
+            connect_flags,
+            variable_header_properties: Some(properties),
+            will_properties: self.will.as_ref().map(|will| will.properties.clone()),
+            will_topic: self.will.as_ref().map(|will| will.topic.clone()),
+            will_payload: self.will.as_ref().map(|will| will.payload.clone()),
+            username: self.username.clone(),
+            password: self.password.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Topic filter to subscribe to, with its subscription options.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Subscription {
+    topic_filter: String,
+    options: u8,
+}
+
+impl Subscription {
+    /// Subscribes at QoS 0, the broker sending the retained messages.
+    pub fn new(topic_filter: &str) -> Self {
+        Subscription {
+            topic_filter: topic_filter.to_string(),
+            options: 0,
+        }
+    }
+
+    /// Maximum QoS of the messages the broker sends for this subscription.
+    pub fn qos(mut self, qos: QoS) -> Self {
+        self.options = (self.options & !0b0000_0011) | qos.number();
+        self
+    }
+
+    /// The broker does not send back the messages the client publishes itself.
+    pub fn no_local(mut self, no_local: bool) -> Self {
+        self.set_flag(0b0000_0100, no_local);
+        self
+    }
+
+    /// The broker keeps the retain flag of the messages it forwards.
+    pub fn retain_as_published(mut self, retain_as_published: bool) -> Self {
+        self.set_flag(0b0000_1000, retain_as_published);
+        self
+    }
+
+    /// 0 sends the retained messages at subscribe, 1 only if the subscription is new, 2 never.
+    pub fn retain_handling(mut self, retain_handling: u8) -> Self {
+        self.options = (self.options & !0b0011_0000) | (retain_handling & 0b11) << 4;
+        self
+    }
+
+    fn set_flag(&mut self, flag: u8, value: bool) {
+        if value {
+            self.options |= flag;
+        } else {
+            self.options &= !flag;
+        }
+    }
+
+    pub(crate) fn to_topic_filter(&self) -> TopicFilterAndSubscriptionOptions {
+        TopicFilterAndSubscriptionOptions::new(
+            self.topic_filter.clone(),
+            SubscriptionOptions {
+                raw_value: self.options,
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use crate::message::{Message, QoS};
+    use crate::options::{ConnectOptions, Subscription};
+
+    #[test]
+    pub fn should_set_subscription_option_bits() {
+        let subscription = Subscription::new("a/#")
+            .qos(QoS::ExactlyOnce)
+            .no_local(true)
+            .retain_as_published(true)
+            .retain_handling(2)
+            .qos(QoS::AtLeastOnce)
+            .no_local(false);
+
+        let topic_filter = subscription.to_topic_filter();
+        assert_eq!(1, topic_filter.qos());
+        assert!(!topic_filter.no_local());
+        assert!(topic_filter.retain_as_published());
+        assert_eq!(2, topic_filter.retain_handling());
+    }
+
+    #[test]
+    pub fn should_set_will_flags() {
+        let will = Message::new("status", b"offline")
+            .qos(QoS::AtLeastOnce)
+            .retain(true);
+        let connect = ConnectOptions::new("localhost:1883")
+            .clean_start(false)
+            .credentials("alice", b"secret")
+            .will(will)
+            .connect_packet();
+
+        assert!(connect.will_flag());
+        assert_eq!(1, connect.will_qos_flag());
+        assert!(connect.will_retain_flag());
+        assert!(!connect.clean_start_flag());
+        assert!(connect.username_flag());
+        assert!(connect.password_flag());
+    }
+}
@@ -0,0 +1,87 @@
+//! Reconnection after the connection to the broker is lost.
+
+use rand::Rng;
+use std::time::Duration;
+
+/// Exponential backoff between reconnection attempts: the delay doubles with each failed
+/// attempt up to a maximum, and a random part of it, the jitter, is taken off so that clients
+/// disconnected together do not reconnect together.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Backoff {
+    pub(crate) initial: Duration,
+    pub(crate) maximum: Duration,
+    pub(crate) jitter: f64,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            initial: Duration::from_millis(500),
+            maximum: Duration::from_secs(60),
+            jitter: 0.5,
+        }
+    }
+}
+
+impl Backoff {
+    /// Delay before the first attempt, 500 milliseconds by default.
+    pub fn initial(mut self, initial: Duration) -> Self {
+        self.initial = initial;
+        self
+    }
+
+    /// 60 seconds by default.
+    pub fn maximum(mut self, maximum: Duration) -> Self {
+        self.maximum = maximum;
+        self
+    }
+
+    /// Largest fraction of the delay taken off at random, between 0 and 1, 0.5 by default.
+    pub fn jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Delay before the attempt following `failed` failed attempts.
+    pub fn delay(&self, failed: u32) -> Duration {
+        let delay = self
+            .initial
+            .saturating_mul(1 << failed.min(31))
+            .min(self.maximum);
+        delay.mul_f64(1.0 - self.jitter * rand::thread_rng().gen::<f64>())
+    }
+}
+
+/// Change of the connection state of a client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// Reconnected after the connection was lost.
+    Connected { session_present: bool },
+    /// The connection was lost, for the given reason.
+    Disconnected(String),
+    /// Waiting `delay` before the reconnection attempt number `attempt`, counted from 1.
+    Reconnecting { attempt: u32, delay: Duration },
+}
+
+#[cfg(test)]
+pub mod test {
+    use crate::reconnect::Backoff;
+    use std::time::Duration;
+
+    #[test]
+    pub fn should_double_delay_up_to_maximum_minus_jitter() {
+        let backoff = Backoff::default()
+            .initial(Duration::from_millis(100))
+            .maximum(Duration::from_secs(1))
+            .jitter(0.25);
+
+        for (failed, full) in [(0, 100), (1, 200), (3, 800), (4, 1000), (40, 1000)] {
+            let full = Duration::from_millis(full);
+            for _ in 0..20 {
+                let delay = backoff.delay(failed);
+                assert!(delay <= full, "{delay:?} over {full:?}");
+                assert!(delay >= full.mul_f64(0.75), "{delay:?} under {full:?}");
+            }
+        }
+    }
+}
@@ -0,0 +1,134 @@
+//! Request/response over MQTT 5: a request carries the Response Topic the responder publishes
+//! its response to, and Correlation Data the response copies so the requester can tell which
+//! request it answers.
+//!
+//! The response topic of a client is built from the Response Information the broker returned
+//! in the CONNACK, and subscribed to before the first request.
+
+use crate::client::{Client, ClientError};
+use crate::message::{Message, QoS};
+use deser::packets::reason_codes::SUBACK;
+use deser::primitive_types::{BinaryData, Utf8EncodedString};
+use deser::properties::Property;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tokio::sync::{oneshot, OnceCell};
+
+/// Requests waiting for their response, by correlation data. The correlation data is random,
+/// so another client publishing to the response topic cannot guess it.
+#[derive(Default)]
+pub(crate) struct Requests {
+    waiting: Mutex<HashMap<Vec<u8>, oneshot::Sender<Message>>>,
+    response_topic: OnceCell<String>,
+}
+
+impl Requests {
+    /// Hands a response to the request waiting for it. Returns the message when it answers no
+    /// request, it is then for the application. Only messages received on the response topic
+    /// are responses.
+    pub(crate) fn resolve(&self, message: Message) -> Option<Message> {
+        let waiting = message
+            .correlation_data()
+            .filter(|_| self.response_topic.get() == Some(&message.topic))
+            .and_then(|data| self.waiting.lock().unwrap().remove(data));
+        match waiting {
+            Some(tx) => {
+                let _ = tx.send(message);
+                None
+            }
+            None => Some(message),
+        }
+    }
+}
+
+impl Client {
+    /// Publishes a request and waits for its response, for the request timeout of the connect
+    /// options at most.
+    pub async fn request(&self, topic: &str, payload: &[u8]) -> Result<Message, ClientError> {
+        let requests = &self.shared.requests;
+        let response_topic = requests
+            .response_topic
+            .get_or_try_init(|| self.subscribe_responses())
+            .await?;
+
+        let correlation_data = rand::random::<[u8; 16]>().to_vec();
+        let (tx, rx) = oneshot::channel();
+        requests
+            .waiting
+            .lock()
+            .unwrap()
+            .insert(correlation_data.clone(), tx);
+
+        let message = Message::new(topic, payload)
+            .qos(QoS::AtLeastOnce)
+            .property(Property::ResponseTopic(Utf8EncodedString(
+                response_topic.clone(),
+            )))
+            .property(Property::CorrelationData(BinaryData(
+                correlation_data.clone(),
+            )));
+        let exchange = async {
+            self.publish_message(&message).await?.await?;
+            rx.await.map_err(|_| ClientError::Disconnected)
+        };
+        let result = tokio::time::timeout(self.shared.request_timeout, exchange)
+            .await
+            .unwrap_or(Err(ClientError::Timeout));
+
+        requests.waiting.lock().unwrap().remove(&correlation_data);
+        result
+    }
+
+    /// Publishes the response to a request received on a subscription, with its Correlation
+    /// Data.
+    pub async fn respond(&self, request: &Message, payload: &[u8]) -> Result<(), ClientError> {
+        let response_topic = request.response_topic().ok_or(ClientError::NotARequest)?;
+
+        let mut response = Message::new(response_topic, payload).qos(request.qos);
+        if let Some(data) = request.correlation_data() {
+            response = response.property(Property::CorrelationData(BinaryData(data.to_vec())));
+        }
+        self.publish_message(&response).await?.await
+    }
+
+    /// Subscribes to the response topic of the client. Without Response Information from the
+    /// broker it falls back to `responses/<client id>`.
+    async fn subscribe_responses(&self) -> Result<String, ClientError> {
+        let response_topic = match &self.shared.response_information {
+            Some(info) => format!("{info}/responses"),
+            None => format!("responses/{}", self.shared.client_id),
+        };
+        match self.subscribe(&response_topic, QoS::AtLeastOnce).await? {
+            SUBACK::GrantedQos0 | SUBACK::GrantedQos1 | SUBACK::GrantedQos2 => Ok(response_topic),
+            code => Err(ClientError::SubscriptionRefused(code as u8)),
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    fn response(topic: &str, correlation_data: &[u8]) -> Message {
+        Message::new(topic, b"42").property(Property::CorrelationData(BinaryData(
+            correlation_data.to_vec(),
+        )))
+    }
+
+    #[test]
+    pub fn should_only_resolve_responses_on_the_response_topic() {
+        let requests = Requests::default();
+        requests
+            .response_topic
+            .set(String::from("responses/ID"))
+            .unwrap();
+        let (tx, mut rx) = oneshot::channel();
+        requests.waiting.lock().unwrap().insert(vec![7], tx);
+
+        assert!(requests.resolve(response("sensors/ID", &[7])).is_some());
+        assert!(rx.try_recv().is_err());
+
+        assert!(requests.resolve(response("responses/ID", &[7])).is_none());
+        assert_eq!("responses/ID", rx.try_recv().unwrap().topic);
+    }
+}
@@ -0,0 +1,137 @@
+//! MQTT over TLS.
+//!
+//! The certificate of the broker is verified against a CA bundle read from a PEM file. The
+//! client can present its own certificate to brokers which authenticate clients with them.
+
+use crate::client::ClientError;
+use rustls::crypto::ring::default_provider;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::{ClientConfig, RootCertStore};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+
+#[derive(Error, Debug)]
+pub enum TlsError {
+    #[error("Reading {0}: {1}")]
+    Io(PathBuf, std::io::Error),
+    #[error("No certificate found in {0}")]
+    NoCertificate(PathBuf),
+    #[error("No private key found in {0}")]
+    NoPrivateKey(PathBuf),
+    #[error(transparent)]
+    Rustls(#[from] rustls::Error),
+    #[error("Invalid server name {0}")]
+    ServerName(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsOptions {
+    ca_path: PathBuf,
+    client_certificate: Option<(PathBuf, PathBuf)>,
+    server_name: Option<String>,
+}
+
+impl TlsOptions {
+    /// Verifies the certificate of the broker against the PEM encoded CA bundle.
+    pub fn new(ca_path: impl Into<PathBuf>) -> TlsOptions {
+        TlsOptions {
+            ca_path: ca_path.into(),
+            client_certificate: None,
+            server_name: None,
+        }
+    }
+
+    /// Certificate chain and private key of the client, both PEM encoded.
+    pub fn client_certificate(
+        mut self,
+        cert_path: impl Into<PathBuf>,
+        key_path: impl Into<PathBuf>,
+    ) -> Self {
+        self.client_certificate = Some((cert_path.into(), key_path.into()));
+        self
+    }
+
+    /// Name the certificate of the broker must be valid for, the host of the address by
+    /// default.
+    pub fn server_name(mut self, server_name: &str) -> Self {
+        self.server_name = Some(server_name.to_string());
+        self
+    }
+
+    fn client_config(&self) -> Result<ClientConfig, TlsError> {
+        let mut roots = RootCertStore::empty();
+        for certificate in load_certificates(&self.ca_path)? {
+            roots.add(certificate)?;
+        }
+
+        let builder = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots);
+        Ok(match &self.client_certificate {
+            Some((cert_path, key_path)) => builder.with_client_auth_cert(
+                load_certificates(cert_path)?,
+                load_private_key(key_path)?,
+            )?,
+            None => builder.with_no_client_auth(),
+        })
+    }
+
+    /// Performs the TLS handshake on a connection to `addr`, `host:port`.
+    pub(crate) async fn connect(
+        &self,
+        addr: &str,
+        stream: TcpStream,
+    ) -> Result<TlsStream<TcpStream>, ClientError> {
+        let host = match &self.server_name {
+            Some(server_name) => server_name.as_str(),
+            None => host(addr),
+        };
+        let server_name = ServerName::try_from(host.to_string())
+            .map_err(|_| TlsError::ServerName(host.to_string()))?;
+
+        let connector = TlsConnector::from(Arc::new(self.client_config()?));
+        Ok(connector.connect(server_name, stream).await?)
+    }
+}
+
+/// Host of a `host:port` address, without the brackets of an IPv6 address.
+fn host(addr: &str) -> &str {
+    let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+fn load_certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>, TlsError> {
+    let file = std::fs::File::open(path).map_err(|e| TlsError::Io(path.to_path_buf(), e))?;
+    let certificates = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| TlsError::Io(path.to_path_buf(), e))?;
+
+    if certificates.is_empty() {
+        return Err(TlsError::NoCertificate(path.to_path_buf()));
+    }
+    Ok(certificates)
+}
+
+fn load_private_key(path: &Path) -> Result<PrivateKeyDer<'static>, TlsError> {
+    let file = std::fs::File::open(path).map_err(|e| TlsError::Io(path.to_path_buf(), e))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .map_err(|e| TlsError::Io(path.to_path_buf(), e))?
+        .ok_or_else(|| TlsError::NoPrivateKey(path.to_path_buf()))
+}
+
+#[cfg(test)]
+pub mod test {
+    use crate::tls::host;
+
+    #[test]
+    pub fn should_take_host_of_address() {
+        assert_eq!("broker.example.com", host("broker.example.com:8883"));
+        assert_eq!("::1", host("[::1]:8883"));
+        assert_eq!("localhost", host("localhost"));
+    }
+}
@@ -0,0 +1,483 @@
+#[cfg(test)]
+mod client_test {
+    use client::{
+        Backoff, BufferOptions, Client, ClientError, ConnectOptions, Event, Message, QoS,
+        Subscription, SUBACK, UNSUBACK,
+    };
+    use deser::primitive_types::FourByteInteger;
+    use deser::properties::Property;
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::{broadcast, mpsc};
+    use tokio::task::JoinHandle;
+    use MQTTBroker::auth::password::{AuthFn, Credentials, PasswordCheck};
+    use MQTTBroker::broker::Broker;
+    use MQTTBroker::proxy::ProxyProtocol;
+    use MQTTBroker::tls::{TlsAcceptor, TlsConfig};
+
+    /// Starts a broker on a free port and returns its address.
+    async fn start_broker(broker: Arc<Broker>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(broker.serve(listener, ProxyProtocol::Reject));
+        addr
+    }
+
+    /// Forwards connections to the broker, and cuts them on demand like a flaky network.
+    struct Forwarder {
+        addr: String,
+        connections: Arc<Mutex<Vec<JoinHandle<()>>>>,
+    }
+
+    impl Forwarder {
+        async fn start(broker_addr: String) -> Forwarder {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap().to_string();
+            let connections = Arc::new(Mutex::new(vec![]));
+
+            let handles = connections.clone();
+            tokio::spawn(async move {
+                while let Ok((mut client, _)) = listener.accept().await {
+                    let mut broker = TcpStream::connect(&broker_addr).await.unwrap();
+                    handles.lock().unwrap().push(tokio::spawn(async move {
+                        let _ = tokio::io::copy_bidirectional(&mut client, &mut broker).await;
+                    }));
+                }
+            });
+            Forwarder { addr, connections }
+        }
+
+        fn cut(&self) {
+            for connection in self.connections.lock().unwrap().drain(..) {
+                connection.abort();
+            }
+        }
+    }
+
+    async fn wait_reconnected(events: &mut broadcast::Receiver<Event>) -> bool {
+        loop {
+            match events.recv().await.unwrap() {
+                Event::Connected { session_present } => return session_present,
+                Event::Disconnected(_) | Event::Reconnecting { .. } => {}
+            }
+        }
+    }
+
+    fn reconnecting(addr: &str, client_id: &str) -> ConnectOptions {
+        ConnectOptions::new(addr)
+            .client_id(client_id)
+            .reconnect(Backoff::default().initial(Duration::from_millis(20)))
+    }
+
+    #[tokio::test]
+    async fn should_reconnect_and_resume_persistent_session() {
+        let addr = start_broker(Broker::builder().build()).await;
+        let forwarder = Forwarder::start(addr.clone()).await;
+        let options = reconnecting(&forwarder.addr, "device").session_expiry_interval(3600);
+
+        let (device, mut messages) = Client::connect(options).await.unwrap();
+        let mut events = device.events();
+        device
+            .subscribe("devices/device/commands", QoS::AtLeastOnce)
+            .await
+            .unwrap();
+
+        forwarder.cut();
+        assert!(wait_reconnected(&mut events).await);
+        assert!(device.is_connected());
+
+        let (controller, _) = Client::connect(ConnectOptions::new(&addr)).await.unwrap();
+        controller
+            .publish("devices/device/commands", QoS::AtLeastOnce, b"reboot")
+            .await
+            .unwrap()
+            .await
+            .unwrap();
+        assert_eq!(b"reboot".as_slice(), messages.recv().await.unwrap().payload);
+    }
+
+    #[tokio::test]
+    async fn should_resubscribe_when_session_was_lost() {
+        let addr = start_broker(Broker::builder().build()).await;
+        let forwarder = Forwarder::start(addr.clone()).await;
+
+        // leaves the broker time to drop the session of the cut connection
+        let options = ConnectOptions::new(&forwarder.addr)
+            .client_id("device")
+            .reconnect(Backoff::default().initial(Duration::from_millis(200)));
+        let (device, mut messages) = Client::connect(options).await.unwrap();
+        let mut events = device.events();
+        device
+            .subscribe("devices/device/commands", QoS::AtLeastOnce)
+            .await
+            .unwrap();
+
+        forwarder.cut();
+        assert!(!wait_reconnected(&mut events).await);
+
+        // published until the renewed subscription is in place
+        let (controller, _) = Client::connect(ConnectOptions::new(&addr)).await.unwrap();
+        let received = loop {
+            controller
+                .publish("devices/device/commands", QoS::AtLeastOnce, b"reboot")
+                .await
+                .unwrap()
+                .await
+                .unwrap();
+            let wait = tokio::time::timeout(Duration::from_millis(50), messages.recv()).await;
+            if let Ok(message) = wait {
+                break message.unwrap();
+            }
+        };
+        assert_eq!(b"reboot".as_slice(), received.payload);
+    }
+
+    #[tokio::test]
+    async fn should_deliver_messages_published_while_disconnected() {
+        let addr = start_broker(Broker::builder().build()).await;
+        let forwarder = Forwarder::start(addr.clone()).await;
+        let (subscriber, mut messages) = Client::connect(ConnectOptions::new(&addr)).await.unwrap();
+        subscriber
+            .subscribe("sensors/#", QoS::AtLeastOnce)
+            .await
+            .unwrap();
+
+        let (device, _) = Client::connect(reconnecting(&forwarder.addr, "sensor"))
+            .await
+            .unwrap();
+        let mut events = device.events();
+        forwarder.cut();
+        while !matches!(events.recv().await, Ok(Event::Disconnected(_))) {}
+
+        let acknowledgement = device
+            .publish("sensors/kitchen", QoS::AtLeastOnce, b"21.5")
+            .await
+            .unwrap();
+        acknowledgement.await.unwrap();
+        assert_eq!(b"21.5".as_slice(), messages.recv().await.unwrap().payload);
+    }
+
+    #[tokio::test]
+    async fn should_send_spooled_messages_after_restart() {
+        let addr = start_broker(Broker::builder().build()).await;
+        let forwarder = Forwarder::start(addr.clone()).await;
+        let (subscriber, mut messages) = Client::connect(ConnectOptions::new(&addr)).await.unwrap();
+        subscriber
+            .subscribe("loggers/#", QoS::AtLeastOnce)
+            .await
+            .unwrap();
+
+        let spool = std::env::temp_dir().join(format!("client-spool-{}", std::process::id()));
+        let _ = std::fs::remove_file(&spool);
+        let options = ConnectOptions::new(&forwarder.addr)
+            .client_id("logger")
+            .buffer(BufferOptions::default().spool(&spool))
+            .reconnect(Backoff::default().initial(Duration::from_secs(60)));
+        let (logger, _) = Client::connect(options).await.unwrap();
+        let mut events = logger.events();
+        forwarder.cut();
+        while !matches!(events.recv().await, Ok(Event::Disconnected(_))) {}
+
+        let expiring = Message::new("loggers/1", b"stale")
+            .qos(QoS::AtLeastOnce)
+            .property(Property::MessageExpiryInterval(FourByteInteger(1)));
+        let expired = logger.publish_message(&expiring).await.unwrap();
+        logger
+            .publish("loggers/1", QoS::ExactlyOnce, b"kept")
+            .await
+            .unwrap();
+        logger.disconnect().await.unwrap();
+        assert!(matches!(expired.await, Err(ClientError::Disconnected)));
+        tokio::time::sleep(Duration::from_secs(2)).await;
+
+        let options = ConnectOptions::new(&addr)
+            .client_id("logger")
+            .buffer(BufferOptions::default().spool(&spool));
+        let (_logger, _) = Client::connect(options).await.unwrap();
+        assert_eq!(b"kept".as_slice(), messages.recv().await.unwrap().payload);
+        assert_no_message(&mut messages).await;
+    }
+
+    #[tokio::test]
+    async fn should_wait_for_room_in_full_buffer() {
+        let addr = start_broker(Broker::builder().build()).await;
+        let forwarder = Forwarder::start(addr).await;
+        // reconnects after at least 250 milliseconds
+        let options = ConnectOptions::new(&forwarder.addr)
+            .client_id("logger")
+            .buffer(BufferOptions::default().max_messages(1))
+            .reconnect(Backoff::default());
+        let (logger, _) = Client::connect(options).await.unwrap();
+        let mut events = logger.events();
+        forwarder.cut();
+        while !matches!(events.recv().await, Ok(Event::Disconnected(_))) {}
+
+        let first = logger
+            .publish("loggers/1", QoS::AtLeastOnce, b"1")
+            .await
+            .unwrap();
+        let second = tokio::time::timeout(
+            Duration::from_millis(100),
+            logger.publish("loggers/1", QoS::AtLeastOnce, b"2"),
+        )
+        .await;
+        assert!(second.is_err(), "buffer should be full");
+
+        first.await.unwrap();
+        logger
+            .publish("loggers/1", QoS::AtLeastOnce, b"2")
+            .await
+            .unwrap()
+            .await
+            .unwrap();
+    }
+
+    async fn assert_no_message(messages: &mut mpsc::Receiver<Message>) {
+        let received = tokio::time::timeout(Duration::from_millis(100), messages.recv()).await;
+        assert!(received.is_err(), "unexpected message {received:?}");
+    }
+
+    #[tokio::test]
+    async fn should_publish_and_receive_at_each_qos() {
+        let addr = start_broker(Broker::builder().build()).await;
+        let (subscriber, mut messages) = Client::connect(ConnectOptions::new(&addr)).await.unwrap();
+        let (publisher, _) = Client::connect(ConnectOptions::new(&addr)).await.unwrap();
+
+        let reason_codes = subscriber
+            .subscribe_many(vec![
+                Subscription::new("sensors/#").qos(QoS::ExactlyOnce),
+                Subscription::new("sensors/+/humidity").qos(QoS::AtLeastOnce),
+            ])
+            .await
+            .unwrap();
+        assert_eq!(vec![SUBACK::GrantedQos2, SUBACK::GrantedQos1], reason_codes);
+
+        for qos in [QoS::AtMostOnce, QoS::AtLeastOnce, QoS::ExactlyOnce] {
+            let acknowledgement = publisher
+                .publish("sensors/kitchen/temperature", qos, b"21.5")
+                .await
+                .unwrap();
+            acknowledgement.await.unwrap();
+
+            let message = messages.recv().await.unwrap();
+            assert_eq!("sensors/kitchen/temperature", message.topic);
+            assert_eq!(b"21.5".as_slice(), message.payload);
+            assert_eq!(qos, message.qos);
+        }
+    }
+
+    #[tokio::test]
+    async fn should_stop_receiving_after_unsubscribe() {
+        let addr = start_broker(Broker::builder().build()).await;
+        let (client, mut messages) = Client::connect(ConnectOptions::new(&addr)).await.unwrap();
+        client
+            .subscribe("sensors/#", QoS::AtLeastOnce)
+            .await
+            .unwrap();
+
+        let reason_codes = client
+            .unsubscribe(&["sensors/#", "actuators/#"])
+            .await
+            .unwrap();
+        assert_eq!(
+            vec![UNSUBACK::Success, UNSUBACK::NoSubscriptionExisted],
+            reason_codes
+        );
+
+        let acknowledgement = client
+            .publish("sensors/kitchen/temperature", QoS::AtLeastOnce, b"21.5")
+            .await
+            .unwrap();
+        acknowledgement.await.unwrap();
+        assert_no_message(&mut messages).await;
+    }
+
+    #[tokio::test]
+    async fn should_resume_persistent_session() {
+        let addr = start_broker(Broker::builder().build()).await;
+        let options = ConnectOptions::new(&addr)
+            .client_id("device")
+            .session_expiry_interval(3600)
+            .will(Message::new("devices/device/status", b"offline").retain(true))
+            .user_property("firmware", "1.2");
+
+        let (device, _) = Client::connect(options.clone()).await.unwrap();
+        assert!(!device.session_present());
+        device
+            .subscribe("devices/device/commands", QoS::AtLeastOnce)
+            .await
+            .unwrap();
+        device.disconnect().await.unwrap();
+
+        let (controller, _) = Client::connect(ConnectOptions::new(&addr)).await.unwrap();
+        controller
+            .publish("devices/device/commands", QoS::AtLeastOnce, b"reboot")
+            .await
+            .unwrap()
+            .await
+            .unwrap();
+
+        let (device, mut messages) = Client::connect(options.clean_start(false)).await.unwrap();
+        assert!(device.session_present());
+        assert_eq!(b"reboot".as_slice(), messages.recv().await.unwrap().payload);
+    }
+
+    #[tokio::test]
+    async fn should_be_refused_with_wrong_credentials() {
+        let backend = AuthFn::new(|credentials: Credentials| async move {
+            match credentials.password.as_deref() {
+                Some(b"secret") => PasswordCheck::Allow,
+                _ => PasswordCheck::BadUserNameOrPassword,
+            }
+        });
+        let addr = start_broker(Broker::builder().password_backend(backend).build()).await;
+
+        let options = ConnectOptions::new(&addr).credentials("alice", b"guess");
+        let result = Client::connect(options).await;
+        assert!(matches!(result, Err(ClientError::Refused(0x86))));
+
+        let options = ConnectOptions::new(&addr).credentials("alice", b"secret");
+        assert!(Client::connect(options).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn should_keep_idle_connection_alive_with_pings() {
+        let addr = start_broker(Broker::builder().build()).await;
+        let options = ConnectOptions::new(&addr).keep_alive(Duration::from_secs(1));
+        let (client, mut messages) = Client::connect(options).await.unwrap();
+        client.subscribe("a/b", QoS::AtMostOnce).await.unwrap();
+
+        tokio::time::sleep(Duration::from_millis(2500)).await;
+
+        client
+            .publish("a/b", QoS::AtLeastOnce, b"still here")
+            .await
+            .unwrap()
+            .await
+            .unwrap();
+        assert_eq!(
+            b"still here".as_slice(),
+            messages.recv().await.unwrap().payload
+        );
+    }
+
+    #[tokio::test]
+    async fn should_receive_response_to_request() {
+        let addr = start_broker(Broker::builder().build()).await;
+
+        let (responder, mut requests) =
+            Client::connect(ConnectOptions::new(&addr).client_id("clock"))
+                .await
+                .unwrap();
+        responder
+            .subscribe("services/time", QoS::AtLeastOnce)
+            .await
+            .unwrap();
+        tokio::spawn(async move {
+            while let Some(request) = requests.recv().await {
+                let response = [b"time for ", request.payload.as_slice()].concat();
+                responder.respond(&request, &response).await.unwrap();
+            }
+        });
+
+        let (requester, _) = Client::connect(ConnectOptions::new(&addr).client_id("app"))
+            .await
+            .unwrap();
+        let (first, second) = tokio::join!(
+            requester.request("services/time", b"alice"),
+            requester.request("services/time", b"bob")
+        );
+
+        let first = first.unwrap();
+        assert_eq!(b"time for alice".as_slice(), first.payload);
+        assert_eq!("response/app/responses", first.topic);
+        assert_eq!(b"time for bob".as_slice(), second.unwrap().payload);
+    }
+
+    #[tokio::test]
+    async fn should_time_out_request_without_responder() {
+        let addr = start_broker(Broker::builder().build()).await;
+
+        let options = ConnectOptions::new(&addr).request_timeout(Duration::from_millis(100));
+        let (requester, _) = Client::connect(options).await.unwrap();
+
+        let result = requester.request("services/time", b"alice").await;
+        assert!(matches!(result, Err(ClientError::Timeout)), "{result:?}");
+    }
+
+    /// Writes a CA and a certificate for localhost issued by it, returning the directory.
+    fn write_certificates(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("client-tls-{}-{name}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let ca_key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec![]).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = params.self_signed(&ca_key).unwrap();
+        std::fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+
+        let key = KeyPair::generate().unwrap();
+        let params = CertificateParams::new(vec![String::from("localhost")]).unwrap();
+        let certificate = params.signed_by(&key, &ca, &ca_key).unwrap();
+        std::fs::write(dir.join("broker.pem"), certificate.pem()).unwrap();
+        std::fs::write(dir.join("broker.key"), key.serialize_pem()).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn should_publish_and_subscribe_with_command_line_tools_over_tls() {
+        let dir = write_certificates("cli");
+        let tls = TlsAcceptor::new(TlsConfig::new(
+            dir.join("broker.pem"),
+            dir.join("broker.key"),
+        ));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port().to_string();
+        tokio::spawn(Broker::builder().build().serve_tls(
+            listener,
+            Arc::new(tls.unwrap()),
+            ProxyProtocol::Reject,
+        ));
+        let ca = dir.join("ca.pem");
+        let connect = [
+            "-h",
+            "127.0.0.1",
+            "-p",
+            &port,
+            "--cafile",
+            ca.to_str().unwrap(),
+            "--tls-server-name",
+            "localhost",
+        ];
+
+        let published = tokio::process::Command::new(env!("CARGO_BIN_EXE_mqtt-pub"))
+            .args(connect)
+            .args(["-t", "sensors/kitchen", "-m", "21.5", "-q", "1", "-r"])
+            .args(["--content-type", "text/plain", "--user-property", "unit=C"])
+            .output()
+            .await
+            .unwrap();
+        assert!(published.status.success(), "{published:?}");
+
+        let received = tokio::process::Command::new(env!("CARGO_BIN_EXE_mqtt-sub"))
+            .args(connect)
+            .args(["-t", "alerts/#", "-t", "sensors/#", "-C", "1", "-F", "json"])
+            .output()
+            .await
+            .unwrap();
+        assert!(received.status.success(), "{received:?}");
+        let message: serde_json::Value = serde_json::from_slice(&received.stdout).unwrap();
+        assert_eq!("sensors/kitchen", message["topic"]);
+        assert_eq!("21.5", message["payload"]);
+        assert_eq!("text/plain", message["properties"]["content-type"]);
+        assert_eq!(
+            serde_json::json!([["unit", "C"]]),
+            message["properties"]["user-properties"]
+        );
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,104 @@
+//! Prints an annotated view of the MQTT 5 control packets in captured byte streams.
+//!
+//! ```text
+//! mqtt-inspect capture.bin
+//! tshark -r capture.pcap -Y mqtt -T fields -e tcp.payload | mqtt-inspect --hex
+//! ```
+
+use clap::Parser;
+use deser::inspect::{annotate, frames};
+use std::io::Read;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+#[derive(Parser, Debug)]
+#[command(about = "Decodes and annotates raw MQTT 5 byte streams")]
+struct Args {
+    /// Files holding a stream each, the standard input when none is given.
+    files: Vec<PathBuf>,
+
+    /// Reads hex text, such as TCP payloads exported from a capture, instead of raw bytes.
+    #[arg(long)]
+    hex: bool,
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+
+    let mut malformed = false;
+    let inputs: Vec<Option<&PathBuf>> = if args.files.is_empty() {
+        vec![None]
+    } else {
+        args.files.iter().map(Some).collect()
+    };
+    for input in inputs {
+        let stream = match read(input, args.hex) {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("mqtt-inspect: {e}");
+                return ExitCode::FAILURE;
+            }
+        };
+        if let Some(path) = input {
+            println!("== {}", path.display());
+        }
+        for (index, frame) in frames(&stream).iter().enumerate() {
+            malformed |= frame.packet.is_err();
+            println!("frame {}, {}", index + 1, annotate(frame));
+        }
+    }
+
+    if malformed {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
+
+fn read(path: Option<&PathBuf>, hex: bool) -> Result<Vec<u8>, String> {
+    let mut bytes = vec![];
+    match path {
+        Some(path) => {
+            bytes = std::fs::read(path).map_err(|e| format!("{}: {e}", path.display()))?
+        }
+        None => {
+            std::io::stdin()
+                .read_to_end(&mut bytes)
+                .map_err(|e| e.to_string())?;
+        }
+    }
+    if hex {
+        bytes = parse_hex(&String::from_utf8_lossy(&bytes))?;
+    }
+    Ok(bytes)
+}
+
+/// Bytes of hex text, ignoring whitespace and the colons some tools separate bytes with.
+fn parse_hex(text: &str) -> Result<Vec<u8>, String> {
+    let digits: Vec<char> = text
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != ':')
+        .collect();
+    if !digits.len().is_multiple_of(2) {
+        return Err(String::from("odd number of hex digits"));
+    }
+    digits
+        .chunks(2)
+        .map(|pair| {
+            let pair: String = pair.iter().collect();
+            u8::from_str_radix(&pair, 16).map_err(|_| format!("invalid hex byte {pair}"))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+pub mod test {
+    use crate::parse_hex;
+
+    #[test]
+    pub fn should_parse_hex_dumps() {
+        assert_eq!(Ok(vec![0xc0, 0x00, 0xd0, 0x00]), parse_hex("c000\nd0:00\n"));
+        assert!(parse_hex("c00").is_err());
+        assert!(parse_hex("zz").is_err());
+    }
+}
@@ -0,0 +1,428 @@
+use crate::decode::DecodeError;
+use crate::packets::auth::Auth;
+use crate::packets::connack::ConnAck;
+use crate::packets::connect::Connect;
+use crate::packets::disconnect::Disconnect;
+use crate::packets::pingreq::PingReq;
+use crate::packets::pingresp::PingResp;
+use crate::packets::puback::PubAck;
+use crate::packets::pubcomp::PubComp;
+use crate::packets::publish::Publish;
+use crate::packets::pubrec::PubRec;
+use crate::packets::pubrel::PubRel;
+use crate::packets::reason_codes::ReasonCodeError;
+use crate::packets::suback::SubAck;
+use crate::packets::subscribe::Subscribe;
+use crate::packets::unsuback::UnsubAck;
+use crate::packets::unsubscribe::UnSubscribe;
+use crate::packets::{Decoder, Encoder, PacketTypes};
+use crate::ControlPacket;
+use bytes::BytesMut;
+use std::error::Error;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum CodecError {
+    #[error("The remaining length does not have the MSB clear on the fourth byte.")]
+    MalformedRemainingLength,
+    #[error("Unknown control packet type {0}")]
+    UnknownPacketType(u8),
+    #[error("Packet of {0} bytes exceeds the maximum packet size")]
+    PacketTooLarge(usize),
+    #[error("Failed decoding {0} packet: {1}")]
+    Decode(String, Box<dyn Error + Send + Sync>),
+    #[error("Failed encoding {0} packet: {1}")]
+    Encode(String, String),
+}
+
+impl CodecError {
+    /// Name of the `DecodeError` variant, or of this error when it is not a `DecodeError`.
+    pub fn kind(&self) -> &'static str {
+        if let CodecError::Decode(_, e) = self {
+            if e.is::<ReasonCodeError>() {
+                return "InvalidReasonCode";
+            }
+        }
+        match self {
+            CodecError::MalformedRemainingLength => "MalformedRemainingLength",
+            CodecError::UnknownPacketType(_) => "UnknownPacketType",
+            CodecError::PacketTooLarge(_) => "PacketTooLarge",
+            CodecError::Decode(_, e) => match e.downcast_ref::<DecodeError>() {
+                Some(DecodeError::NotEnoughBytes(_)) => "NotEnoughBytes",
+                Some(DecodeError::NotValidVarInt) => "NotValidVarInt",
+                Some(DecodeError::MoreBytesRequired(..)) => "MoreBytesRequired",
+                Some(DecodeError::UTF8Errors(_)) => "UTF8Errors",
+                Some(DecodeError::UnknownProperty(_)) => "UnknownProperty",
+                None => "Decode",
+            },
+            CodecError::Encode(..) => "Encode",
+        }
+    }
+}
+
+/// Returns the number of bytes taken by the fixed header and the value of the remaining length,
+/// or None if the buffer does not yet hold the whole fixed header.
+fn fixed_header_length(buffer: &[u8]) -> Result<Option<(usize, usize)>, CodecError> {
+    let mut remaining_length = 0usize;
+    let mut multiplier = 1usize;
+
+    for pos in 0..4 {
+        let encoded_byte = match buffer.get(pos + 1) {
+            Some(b) => *b,
+            None => return Ok(None),
+        };
+        remaining_length += (encoded_byte & 127) as usize * multiplier;
+        multiplier *= 128;
+
+        if encoded_byte & 128 == 0 {
+            return Ok(Some((pos + 2, remaining_length)));
+        }
+    }
+
+    Err(CodecError::MalformedRemainingLength)
+}
+
+/// Splits the next complete control packet off the front of the buffer.
+///
+/// Returns None when more bytes are required. Bytes belonging to the following packets are
+/// left in the buffer. Packets larger than `maximum_packet_size` are refused as soon as their
+/// fixed header is read, before their bytes are buffered.
+pub fn next_frame(
+    buffer: &mut BytesMut,
+    maximum_packet_size: usize,
+) -> Result<Option<BytesMut>, CodecError> {
+    let (header_length, remaining_length) = match fixed_header_length(buffer)? {
+        Some(lengths) => lengths,
+        None => return Ok(None),
+    };
+
+    let frame_length = header_length + remaining_length;
+    if frame_length > maximum_packet_size {
+        return Err(CodecError::PacketTooLarge(frame_length));
+    }
+    if buffer.len() < frame_length {
+        return Ok(None);
+    }
+
+    Ok(Some(buffer.split_to(frame_length)))
+}
+
+/// Decodes a single frame, as returned by next_frame, into a control packet.
+pub fn decode_packet(mut frame: BytesMut) -> Result<ControlPacket, CodecError> {
+    decode_frame(&mut frame)
+}
+
+/// Decodes a frame in place, leaving in it the bytes the decoder did not consume.
+pub(crate) fn decode_frame(bytes: &mut BytesMut) -> Result<ControlPacket, CodecError> {
+    let packet_type = bytes.first().map(|b| b >> 4).unwrap_or(0);
+
+    let decoded = match packet_type {
+        1 => Connect::decode(bytes).map(ControlPacket::Connect),
+        2 => ConnAck::decode(bytes).map(ControlPacket::ConnAck),
+        3 => Publish::decode(bytes).map(ControlPacket::Publish),
+        4 => PubAck::decode(bytes).map(ControlPacket::PubAck),
+        5 => PubRec::decode(bytes).map(ControlPacket::PubRec),
+        6 => PubRel::decode(bytes).map(ControlPacket::PubRel),
+        7 => PubComp::decode(bytes).map(ControlPacket::PubComp),
+        8 => Subscribe::decode(bytes).map(ControlPacket::Subscribe),
+        9 => SubAck::decode(bytes).map(ControlPacket::SubAck),
+        10 => UnSubscribe::decode(bytes).map(ControlPacket::Unsubscribe),
+        11 => UnsubAck::decode(bytes).map(ControlPacket::UnsubAck),
+        12 => PingReq::decode(bytes).map(ControlPacket::PingReq),
+        13 => PingResp::decode(bytes).map(ControlPacket::PingResp),
+        14 => Disconnect::decode(bytes).map(ControlPacket::Disconnect),
+        15 => Auth::decode(bytes).map(ControlPacket::Auth),
+        n => return Err(CodecError::UnknownPacketType(n)),
+    };
+
+    decoded.map_err(|e| CodecError::Decode(packet_type_name(packet_type), e))
+}
+
+/// Encodes a control packet into the bytes sent over the wire.
+pub fn encode_packet(control_packet: &ControlPacket) -> Result<BytesMut, CodecError> {
+    let encoded = match control_packet {
+        ControlPacket::Connect(p) => match p.check_lengths() {
+            Ok(()) => Connect::encode(p.packet_type, p.packet_type_low_nibble, p),
+            Err(e) => Err(e.into()),
+        },
+        ControlPacket::ConnAck(p) => ConnAck::encode(p.packet_type, p.packet_type_low_nibble, p),
+        ControlPacket::Publish(p) => Publish::encode(p.packet_type, p.packet_type_low_nibble, p),
+        ControlPacket::PubAck(p) => PubAck::encode(p.packet_type, p.packet_type_low_nibble, p),
+        ControlPacket::PubRec(p) => PubRec::encode(p.packet_type, p.packet_type_low_nibble, p),
+        ControlPacket::PubRel(p) => PubRel::encode(p.packet_type, p.packet_type_low_nibble, p),
+        ControlPacket::PubComp(p) => PubComp::encode(p.packet_type, p.packet_type_low_nibble, p),
+        ControlPacket::Subscribe(p) => {
+            Subscribe::encode(p.packet_type, p.packet_type_low_nibble, p)
+        }
+        ControlPacket::SubAck(p) => SubAck::encode(p.packet_type, p.packet_type_low_nibble, p),
+        ControlPacket::Unsubscribe(p) => {
+            UnSubscribe::encode(p.packet_type, p.packet_type_low_nibble, p)
+        }
+        ControlPacket::UnsubAck(p) => UnsubAck::encode(p.packet_type, p.packet_type_low_nibble, p),
+        ControlPacket::PingReq(p) => PingReq::encode(p.packet_type, p.packet_type_low_nibble, p),
+        ControlPacket::PingResp(p) => PingResp::encode(p.packet_type, p.packet_type_low_nibble, p),
+        ControlPacket::Disconnect(p) => {
+            Disconnect::encode(p.packet_type, p.packet_type_low_nibble, p)
+        }
+        ControlPacket::Auth(p) => Auth::encode(p.packet_type, p.packet_type_low_nibble, p),
+    };
+
+    encoded.map_err(|e| CodecError::Encode(control_packet.name(), e.to_string()))
+}
+
+pub(crate) fn packet_type_name(packet_type: u8) -> String {
+    let name = match packet_type {
+        1 => "CONNECT",
+        2 => "CONNACK",
+        3 => "PUBLISH",
+        4 => "PUBACK",
+        5 => "PUBREC",
+        6 => "PUBREL",
+        7 => "PUBCOMP",
+        8 => "SUBSCRIBE",
+        9 => "SUBACK",
+        10 => "UNSUBSCRIBE",
+        11 => "UNSUBACK",
+        12 => "PINGREQ",
+        13 => "PINGRESP",
+        14 => "DISCONNECT",
+        15 => "AUTH",
+        _ => "UNKNOWN",
+    };
+
+    String::from(name)
+}
+
+impl ControlPacket {
+    pub fn packet_type(&self) -> PacketTypes {
+        match self {
+            ControlPacket::Connect(_) => PacketTypes::Connect,
+            ControlPacket::ConnAck(_) => PacketTypes::Connack,
+            ControlPacket::Publish(_) => PacketTypes::Publish,
+            ControlPacket::PubAck(_) => PacketTypes::Puback,
+            ControlPacket::PubRec(_) => PacketTypes::Pubrec,
+            ControlPacket::PubRel(_) => PacketTypes::Pubrel,
+            ControlPacket::PubComp(_) => PacketTypes::Pubcomp,
+            ControlPacket::Subscribe(_) => PacketTypes::Subscribe,
+            ControlPacket::SubAck(_) => PacketTypes::Suback,
+            ControlPacket::Unsubscribe(_) => PacketTypes::Unsubscribe,
+            ControlPacket::UnsubAck(_) => PacketTypes::Unsuback,
+            ControlPacket::PingReq(_) => PacketTypes::Pingreq,
+            ControlPacket::PingResp(_) => PacketTypes::Pingresp,
+            ControlPacket::Disconnect(_) => PacketTypes::Disconnect,
+            ControlPacket::Auth(_) => PacketTypes::Auth,
+        }
+    }
+
+    /// Name of the packet type as used in the MQTT specification.
+    pub fn name(&self) -> String {
+        packet_type_name(self.packet_type() as u8)
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use crate::codec::{decode_packet, encode_packet, next_frame};
+    use crate::packets::auth::Auth;
+    use crate::packets::connect::Connect;
+    use crate::packets::disconnect::Disconnect;
+    use crate::packets::pingreq::PingReq;
+    use crate::packets::publish::Publish;
+    use crate::packets::reason_codes::AUTH;
+    use crate::packets::subscribe::{
+        Subscribe, SubscriptionOptions, TopicFilterAndSubscriptionOptions,
+    };
+    use crate::packets::unsubscribe::UnSubscribe;
+    use crate::primitive_types::{BinaryData, Utf8EncodedString};
+    use crate::properties::Property;
+    use crate::ControlPacket;
+    use bytes::BytesMut;
+
+    #[test]
+    pub fn should_split_frames_that_share_and_span_reads() {
+        let auth = ControlPacket::Auth(Auth {
+            reason_code: AUTH::ContinueAuthentication,
+            variable_header_properties: Some(vec![Property::AuthenticationMethod(
+                Utf8EncodedString(String::from("SCRAM-SHA-256")),
+            )]),
+            ..Default::default()
+        });
+        let ping = ControlPacket::PingReq(PingReq::default());
+
+        let mut wire = encode_packet(&auth).unwrap();
+        wire.extend_from_slice(&encode_packet(&ping).unwrap());
+
+        // first read only holds part of the AUTH packet
+        let mut buffer = BytesMut::from(&wire[..3]);
+        assert!(next_frame(&mut buffer, usize::MAX).unwrap().is_none());
+
+        buffer.extend_from_slice(&wire[3..]);
+        let first = next_frame(&mut buffer, usize::MAX).unwrap().unwrap();
+        let second = next_frame(&mut buffer, usize::MAX).unwrap().unwrap();
+
+        assert!(buffer.is_empty());
+        assert_eq!(auth, decode_packet(first).unwrap());
+        assert_eq!(ping, decode_packet(second).unwrap());
+    }
+
+    #[test]
+    pub fn should_decode_request_response_properties() {
+        let request = ControlPacket::Publish(Publish {
+            topic_name: String::from("services/time"),
+            variable_header_properties: Some(vec![
+                Property::ResponseTopic(Utf8EncodedString(String::from("responses/ID"))),
+                Property::CorrelationData(BinaryData(vec![0, 1])),
+            ]),
+            ..Default::default()
+        });
+
+        let frame = encode_packet(&request).unwrap();
+        assert_eq!(request, decode_packet(frame).unwrap());
+    }
+
+    #[test]
+    pub fn should_refuse_to_encode_a_password_longer_than_65535_bytes() {
+        let connect = |length| {
+            ControlPacket::Connect(Connect {
+                client_id: String::from("ID"),
+                connect_flags: 0b0100_0000,
+                password: Some(vec![1; length]),
+                ..Default::default()
+            })
+        };
+
+        let longest = connect(u16::MAX as usize);
+        let frame = encode_packet(&longest).unwrap();
+        assert_eq!(longest, decode_packet(frame).unwrap());
+        assert_eq!(
+            "Failed encoding CONNECT packet: password is longer than 65,535 bytes",
+            encode_packet(&connect(u16::MAX as usize + 1))
+                .unwrap_err()
+                .to_string()
+        );
+    }
+
+    #[test]
+    pub fn should_name_kind_of_decoding_error() {
+        // DISCONNECT with reason code 0x03, which does not exist
+        let frame = BytesMut::from(&[0xe0, 0x01, 0x03][..]);
+        assert_eq!(
+            "InvalidReasonCode",
+            decode_packet(frame).unwrap_err().kind()
+        );
+
+        let mut buffer = BytesMut::from(&[0x30, 0xff, 0xff, 0xff, 0xff][..]);
+        assert_eq!(
+            "MalformedRemainingLength",
+            next_frame(&mut buffer, usize::MAX).unwrap_err().kind()
+        );
+    }
+
+    #[test]
+    pub fn should_refuse_packets_above_the_maximum_size() {
+        let publish = ControlPacket::Publish(Publish {
+            topic_name: String::from("sensors/1"),
+            application_message: Some(vec![0; 100]),
+            ..Default::default()
+        });
+        let wire = encode_packet(&publish).unwrap();
+
+        // the fixed header alone is enough to refuse the packet
+        let mut buffer = BytesMut::from(&wire[..2]);
+        let error = next_frame(&mut buffer, wire.len() - 1).unwrap_err();
+        assert_eq!("PacketTooLarge", error.kind());
+
+        let mut buffer = BytesMut::from(&wire[..]);
+        assert!(next_frame(&mut buffer, wire.len()).unwrap().is_some());
+    }
+
+    /// Fixed header and body as a single frame.
+    fn frame(first_byte: u8, body: &[u8]) -> BytesMut {
+        let mut frame = BytesMut::from(&[first_byte][..]);
+        let mut length = body.len();
+        loop {
+            let mut byte = (length % 128) as u8;
+            length /= 128;
+            if length > 0 {
+                byte |= 128;
+            }
+            frame.extend_from_slice(&[byte]);
+            if length == 0 {
+                break;
+            }
+        }
+        frame.extend_from_slice(body);
+        frame
+    }
+
+    #[test]
+    pub fn should_not_panic_on_truncated_or_corrupted_packets() {
+        let packets = vec![
+            ControlPacket::Connect(Connect {
+                // user name, password and will flags
+                connect_flags: 0b1100_0100,
+                variable_header_properties: Some(vec![Property::AuthenticationMethod(
+                    Utf8EncodedString(String::from("SCRAM-SHA-256")),
+                )]),
+                client_id: String::from("ID"),
+                will_properties: Some(vec![]),
+                will_topic: Some(String::from("will")),
+                will_payload: Some(vec![1, 2]),
+                username: Some(String::from("user")),
+                password: Some(b"secret".to_vec()),
+                ..Default::default()
+            }),
+            ControlPacket::Publish(Publish {
+                topic_name: String::from("services/time"),
+                variable_header_properties: Some(vec![Property::CorrelationData(BinaryData(
+                    vec![0, 1],
+                ))]),
+                application_message: Some(vec![1, 2, 3]),
+                ..Default::default()
+            }),
+            ControlPacket::Subscribe(Subscribe {
+                packet_id: 1,
+                topic_filters: vec![TopicFilterAndSubscriptionOptions::new(
+                    String::from("sensors/#"),
+                    SubscriptionOptions { raw_value: 1 },
+                )],
+                ..Default::default()
+            }),
+            ControlPacket::Unsubscribe(UnSubscribe {
+                packet_id: 1,
+                topic_filters: vec![String::from("sensors/#")],
+                ..Default::default()
+            }),
+            ControlPacket::Disconnect(Disconnect {
+                variable_header_properties: Some(vec![Property::ReasonString(Utf8EncodedString(
+                    String::from("bye"),
+                ))]),
+                ..Default::default()
+            }),
+        ];
+
+        for packet in packets {
+            let wire = encode_packet(&packet).unwrap();
+            // the remaining length of these packets fits in one byte
+            assert!(wire[1] < 128);
+            let body = &wire[2..];
+
+            // every packet type, so each decoder sees every truncation of every body
+            for packet_type in 1..=15u8 {
+                for flags in [0, 0b0010] {
+                    let first_byte = packet_type << 4 | flags;
+                    for end in 0..body.len() {
+                        let _ = decode_packet(frame(first_byte, &body[..end]));
+                    }
+                    for position in 0..body.len() {
+                        for value in [0x00, 0x7f, 0x80, 0xff] {
+                            let mut corrupted = body.to_vec();
+                            corrupted[position] = value;
+                            let _ = decode_packet(frame(first_byte, &corrupted));
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
@@ -120,6 +120,12 @@ lazy_static! {
         h
     };
 }
+
+/// Name of a property in the MQTT specification.
+pub fn property_name(identifier: u8) -> Option<&'static str> {
+    PROPERTYNAME.get(&identifier).map(String::as_str)
+}
+
 #[derive(Eq, PartialEq)]
 pub enum PayLoad {
     Required,
@@ -314,6 +320,14 @@ pub enum DecodeError {
     UnknownProperty(u8),
 }
 
+pub fn byte(name: String, b: &mut BytesMut) -> Result<Byte, DecodeError> {
+    if b.is_empty() {
+        Err(DecodeError::NotEnoughBytes(name))
+    } else {
+        Ok(Byte(b.get_u8()))
+    }
+}
+
 pub fn two_byte_integer(name: String, b: &mut BytesMut) -> Result<TwoByteInteger, DecodeError> {
     if b.len() < 2 {
         Err(DecodeError::MoreBytesRequired(2, b.len() as u16, name))
@@ -334,13 +348,12 @@ pub fn utf8_string(name: String, b: &mut BytesMut) -> Result<String, DecodeError
     if b.len() < 2 {
         Err(DecodeError::NotEnoughBytes(name))
     } else {
-        let mut i = b.iter();
-        let string_length = *(i.next().unwrap()) as u16 * 256 + *(i.next().unwrap()) as u16;
+        let string_length = u16::from_be_bytes([b[0], b[1]]);
         trace!("String {} length is {} ****", name, string_length);
-        if (b.len() as u16) < (string_length + 2) {
+        if b.len() - 2 < string_length as usize {
             Err(DecodeError::MoreBytesRequired(
                 string_length,
-                b.len() as u16 - 2,
+                (b.len() - 2).min(u16::MAX as usize) as u16,
                 name,
             ))
         } else {
@@ -358,12 +371,11 @@ pub fn binary(name: String, b: &mut BytesMut) -> Result<BinaryData, DecodeError>
     if b.len() < 2 {
         Err(DecodeError::NotEnoughBytes(name))
     } else {
-        let mut i = b.iter();
-        let string_length: u16 = *(i.next().unwrap()) as u16 * 256 + *(i.next().unwrap()) as u16;
-        if (b.len() as u16) < (string_length + 2) {
+        let string_length = u16::from_be_bytes([b[0], b[1]]);
+        if b.len() - 2 < string_length as usize {
             Err(DecodeError::MoreBytesRequired(
                 string_length,
-                b.len() as u16 - 2,
+                (b.len() - 2).min(u16::MAX as usize) as u16,
                 name,
             ))
         } else {
@@ -423,7 +435,9 @@ pub fn varint(b: &mut BytesMut) -> Result<VariableByteInteger, DecodeError> {
     let mut bytes = b.iter();
 
     loop {
-        encoded_byte = *bytes.next().unwrap();
+        encoded_byte = *bytes
+            .next()
+            .ok_or_else(|| DecodeError::NotEnoughBytes(String::from("variable byte integer")))?;
         value += (encoded_byte & 127) as u32 * multiplier;
         multiplier *= 128;
 
@@ -445,7 +459,15 @@ pub fn property(b: &mut BytesMut) -> Result<Vec<Property>, DecodeError> {
     trace!("pre varint length is {}", b.len());
     let length = varint(b)?;
     trace!("post varint length is {}", b.len());
-    let mut sub_b = b.split_to(*length.as_ref() as usize);
+    let length_value = *length.as_ref() as usize;
+    if b.len() < length_value {
+        return Err(DecodeError::MoreBytesRequired(
+            length_value.min(u16::MAX as usize) as u16,
+            b.len().min(u16::MAX as usize) as u16,
+            String::from("properties"),
+        ));
+    }
+    let mut sub_b = b.split_to(length_value);
     trace!("post sub_b is {:?}", sub_b);
 
     let mut p_vec: Vec<Property> = vec![];
@@ -453,16 +475,21 @@ pub fn property(b: &mut BytesMut) -> Result<Vec<Property>, DecodeError> {
 
     while !sub_b.is_empty() && length.0 > 0 {
         let property_identifier = sub_b.get_u8();
+        let name = || {
+            property_name(property_identifier)
+                .unwrap_or("property")
+                .to_string()
+        };
         trace!("read property is {property_identifier}");
 
         let p = match property_identifier {
             prop if 0x01 == prop => {
-                let val = Byte(sub_b.get_u8());
+                let val = byte(name(), &mut sub_b)?;
                 Property::PayloadFormatIndicator(val)
             }
 
             prop if PropertyIdentifierConstant::MessageExpiryInterval as u8 == prop => {
-                Property::MessageExpiryInterval(FourByteInteger(sub_b.get_u32()))
+                Property::MessageExpiryInterval(four_byte_integer(name(), &mut sub_b)?)
             }
 
             prop if PropertyIdentifierConstant::ContentType as u8 == prop => {
@@ -475,7 +502,7 @@ pub fn property(b: &mut BytesMut) -> Result<Vec<Property>, DecodeError> {
             prop if PropertyIdentifierConstant::ResponseTopic as u8 == prop => {
                 let str = utf8_string((*PROPERTYNAME.get(&prop).unwrap()).clone(), &mut sub_b)?;
 
-                Property::ContentType(Utf8EncodedString(str))
+                Property::ResponseTopic(Utf8EncodedString(str))
             }
 
             prop if PropertyIdentifierConstant::CorrelationData as u8 == prop => {
@@ -490,7 +517,7 @@ pub fn property(b: &mut BytesMut) -> Result<Vec<Property>, DecodeError> {
             }
 
             prop if PropertyIdentifierConstant::SessionExpiryInterval as u8 == prop => {
-                Property::SessionExpiryInterval(FourByteInteger(sub_b.get_u32()))
+                Property::SessionExpiryInterval(four_byte_integer(name(), &mut sub_b)?)
             }
 
             prop if PropertyIdentifierConstant::AssignedClientIdentifier as u8 == prop => {
@@ -500,7 +527,7 @@ pub fn property(b: &mut BytesMut) -> Result<Vec<Property>, DecodeError> {
             }
 
             prop if PropertyIdentifierConstant::ServerKeepAlive as u8 == prop => {
-                Property::ServerKeepAlive(TwoByteInteger(sub_b.get_u16()))
+                Property::ServerKeepAlive(two_byte_integer(name(), &mut sub_b)?)
             }
 
             prop if PropertyIdentifierConstant::AuthenticationMethod as u8 == prop => {
@@ -515,15 +542,15 @@ pub fn property(b: &mut BytesMut) -> Result<Vec<Property>, DecodeError> {
             }
 
             prop if PropertyIdentifierConstant::RequestProblemInformation as u8 == prop => {
-                Property::RequestProblemInformation(Byte(sub_b.get_u8()))
+                Property::RequestProblemInformation(byte(name(), &mut sub_b)?)
             }
 
             prop if PropertyIdentifierConstant::WillDelayInterval as u8 == prop => {
-                Property::WillDelayInterval(FourByteInteger(sub_b.get_u32()))
+                Property::WillDelayInterval(four_byte_integer(name(), &mut sub_b)?)
             }
 
             prop if PropertyIdentifierConstant::RequestResponseInformation as u8 == prop => {
-                Property::RequestResponseInformation(Byte(sub_b.get_u8()))
+                Property::RequestResponseInformation(byte(name(), &mut sub_b)?)
             }
 
             prop if PropertyIdentifierConstant::ResponseInformation as u8 == prop => {
@@ -542,22 +569,22 @@ pub fn property(b: &mut BytesMut) -> Result<Vec<Property>, DecodeError> {
             }
 
             prop if PropertyIdentifierConstant::ReceiveMaximum as u8 == prop => {
-                Property::ReceiveMaximum(TwoByteInteger(sub_b.get_u16()))
+                Property::ReceiveMaximum(two_byte_integer(name(), &mut sub_b)?)
             }
             prop if PropertyIdentifierConstant::TopicAliasMaximum as u8 == prop => {
-                Property::TopicAliasMaximum(TwoByteInteger(sub_b.get_u16()))
+                Property::TopicAliasMaximum(two_byte_integer(name(), &mut sub_b)?)
             }
 
             prop if PropertyIdentifierConstant::TopicAlias as u8 == prop => {
-                Property::TopicAlias(TwoByteInteger(sub_b.get_u16()))
+                Property::TopicAlias(two_byte_integer(name(), &mut sub_b)?)
             }
 
             prop if PropertyIdentifierConstant::MaximumQos as u8 == prop => {
-                Property::MaximumQos(Byte(sub_b.get_u8()))
+                Property::MaximumQos(byte(name(), &mut sub_b)?)
             }
 
             prop if PropertyIdentifierConstant::RetainAvailable as u8 == prop => {
-                Property::RetainAvailable(Byte(sub_b.get_u8()))
+                Property::RetainAvailable(byte(name(), &mut sub_b)?)
             }
 
             prop if PropertyIdentifierConstant::User as u8 == prop => {
@@ -568,19 +595,19 @@ pub fn property(b: &mut BytesMut) -> Result<Vec<Property>, DecodeError> {
             }
 
             prop if PropertyIdentifierConstant::MaximumPacketSize as u8 == prop => {
-                Property::MaximumPacketSize(FourByteInteger(sub_b.get_u32()))
+                Property::MaximumPacketSize(four_byte_integer(name(), &mut sub_b)?)
             }
 
             prop if PropertyIdentifierConstant::WildcardSubscriptionAvailable as u8 == prop => {
-                Property::WildcardSubscriptionAvailable(Byte(sub_b.get_u8()))
+                Property::WildcardSubscriptionAvailable(byte(name(), &mut sub_b)?)
             }
 
             prop if PropertyIdentifierConstant::SubscriptionIdentifierAvailable as u8 == prop => {
-                Property::SubscriptionIdentifierAvailable(Byte(sub_b.get_u8()))
+                Property::SubscriptionIdentifierAvailable(byte(name(), &mut sub_b)?)
             }
 
             prop if PropertyIdentifierConstant::SharedSubscriptionAvailable as u8 == prop => {
-                Property::SharedSubscriptionAvailable(Byte(sub_b.get_u8()))
+                Property::SharedSubscriptionAvailable(byte(name(), &mut sub_b)?)
             }
             _ => return Err(DecodeError::UnknownProperty(property_identifier)),
         };
@@ -591,19 +618,15 @@ pub fn property(b: &mut BytesMut) -> Result<Vec<Property>, DecodeError> {
     Ok(p_vec)
 }
 
-pub fn decode_property(bytes: &mut BytesMut) -> Option<Vec<Property>> {
-    let p = property(bytes);
-    let p = match p {
-        Ok(v) if !v.is_empty() => Some(v),
-        _ => None,
-    };
+/// Properties of a packet, None when there are none.
+pub fn decode_property(bytes: &mut BytesMut) -> Result<Option<Vec<Property>>, DecodeError> {
+    // the property length is omitted when a packet ends after its reason code
+    if bytes.is_empty() {
+        return Ok(None);
+    }
 
-    // if !p.is_empty() {
-    //     Some(p)
-    // } else {
-    //     None
-    // }
-    p
+    let properties = property(bytes)?;
+    Ok(Some(properties).filter(|properties| !properties.is_empty()))
 }
 
 #[cfg(test)]
@@ -7,6 +7,8 @@ use tracing::trace;
 pub enum EncodeError {
     #[error("Number is too large, greater than 268,435,455, to convert to a variable integer")]
     NumberTooLarge,
+    #[error("{0} is longer than 65,535 bytes")]
+    TooLong(&'static str),
 }
 
 fn encode_two_byte_integer(name: &str, i: TwoByteInteger, b: &mut BytesMut) {
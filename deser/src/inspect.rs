@@ -0,0 +1,498 @@
+//! Annotated view of raw MQTT byte streams, to see exactly what went over the wire.
+//!
+//! The stream is split into frames with the codec and each frame is decoded into a
+//! `ControlPacket`. Frames which cannot be split or decoded are flagged with the offset, in the
+//! stream, of the byte at which decoding stopped. Decoded packets are encoded again and
+//! compared with their frame, which points at bytes the decoders skip or which are not
+//! canonically encoded, such as overlong variable byte integers.
+
+use crate::codec::{decode_frame, encode_packet, next_frame, packet_type_name};
+use crate::decode::property_name;
+use crate::packets::reason_codes::{DecodeReasonCode, CONNECTACK};
+use crate::primitive_types::{
+    BinaryData, Byte, FourByteInteger, TwoByteInteger, Utf8EncodedString, Utf8StringPair,
+    VariableByteInteger,
+};
+use crate::properties::{Property, PropertyIdentifier};
+use crate::ControlPacket;
+use bytes::BytesMut;
+use nu_pretty_hex::{hex_write, HexConfig};
+use std::fmt::Debug;
+
+/// Bytes of the stream taken by a control packet.
+#[derive(Debug)]
+pub struct Frame {
+    /// Offset of the first byte in the stream.
+    pub offset: usize,
+    pub bytes: Vec<u8>,
+    pub packet: Result<ControlPacket, Malformed>,
+    /// Offset in the stream of the first byte which differs when the packet is encoded again.
+    pub differs_at: Option<usize>,
+}
+
+/// Why a frame could not be decoded, and the offset in the stream where decoding stopped.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Malformed {
+    pub offset: usize,
+    pub reason: String,
+}
+
+/// Splits the stream into frames. When the stream ends in the middle of a packet or the fixed
+/// header of a packet is invalid, the remaining bytes end up in a last, malformed, frame.
+pub fn frames(stream: &[u8]) -> Vec<Frame> {
+    let mut frames = vec![];
+    let mut buffer = BytesMut::from(stream);
+    let mut offset = 0;
+
+    while !buffer.is_empty() {
+        let malformed = match next_frame(&mut buffer, usize::MAX) {
+            Ok(Some(bytes)) => {
+                let length = bytes.len();
+                frames.push(decode(offset, bytes));
+                offset += length;
+                continue;
+            }
+            Ok(None) => Malformed {
+                offset: stream.len(),
+                reason: format!("stream ends {} bytes into the packet", buffer.len()),
+            },
+            // the fourth byte of the remaining length has its continuation bit set
+            Err(e) => Malformed {
+                offset: offset + 4,
+                reason: e.to_string(),
+            },
+        };
+        frames.push(Frame {
+            offset,
+            bytes: buffer.to_vec(),
+            packet: Err(malformed),
+            differs_at: None,
+        });
+        break;
+    }
+    frames
+}
+
+fn decode(offset: usize, frame: BytesMut) -> Frame {
+    let mut bytes = frame.clone();
+    let decoded = decode_frame(&mut bytes);
+    let stopped_at = offset + frame.len() - bytes.len();
+
+    let packet = match decoded {
+        Ok(packet) => Ok(packet),
+        Err(e) => Err(Malformed {
+            offset: stopped_at,
+            reason: e.to_string(),
+        }),
+    };
+
+    let differs_at = packet.as_ref().ok().and_then(|packet| {
+        let encoded = encode_packet(packet).ok()?;
+        let differs = encoded
+            .iter()
+            .zip(frame.iter())
+            .position(|(encoded, received)| encoded != received)
+            .or((encoded.len() != frame.len()).then(|| encoded.len().min(frame.len())));
+        differs.map(|position| offset + position)
+    });
+
+    Frame {
+        offset,
+        bytes: frame.to_vec(),
+        packet,
+        differs_at,
+    }
+}
+
+/// Describes a frame: its fixed header, the fields and properties of its packet, and a hex
+/// dump of payloads. Malformed frames are dumped whole.
+pub fn annotate(frame: &Frame) -> String {
+    let mut out = Annotation::default();
+    out.line(
+        0,
+        format!("offset {}, {} bytes", frame.offset, frame.bytes.len()),
+    );
+    fixed_header(&mut out, &frame.bytes);
+
+    match &frame.packet {
+        Ok(packet) => {
+            packet_fields(&mut out, packet);
+            if let Some(offset) = frame.differs_at {
+                out.line(
+                    1,
+                    format!(
+                        "WARNING: encoding the packet again differs from offset {offset} on, \
+                         bytes there were skipped or are not canonically encoded"
+                    ),
+                );
+                out.hex(1, &frame.bytes, frame.offset);
+            }
+        }
+        Err(malformed) => {
+            out.line(
+                1,
+                format!(
+                    "MALFORMED at offset {}: {}",
+                    malformed.offset, malformed.reason
+                ),
+            );
+            out.hex(1, &frame.bytes, frame.offset);
+        }
+    }
+    out.0
+}
+
+#[derive(Default)]
+struct Annotation(String);
+
+impl Annotation {
+    fn line(&mut self, depth: usize, text: impl AsRef<str>) {
+        self.0.push_str(&"  ".repeat(depth));
+        self.0.push_str(text.as_ref());
+        self.0.push('\n');
+    }
+
+    /// Hex dump with the offsets in the stream as addresses.
+    fn hex(&mut self, depth: usize, bytes: &[u8], offset: usize) {
+        let config = HexConfig {
+            title: false,
+            address_offset: offset,
+            ..HexConfig::default()
+        };
+        let mut dump = String::new();
+        let _ = hex_write(&mut dump, &bytes, config, Some(false));
+        for line in dump.lines() {
+            self.line(depth, line);
+        }
+    }
+
+    fn properties(&mut self, depth: usize, title: &str, properties: &Option<Vec<Property>>) {
+        let Some(properties) = properties else {
+            return;
+        };
+        self.line(depth, format!("{title}:"));
+        for property in properties {
+            let identifier = PropertyIdentifier::from(property).to_u8();
+            let name = property_name(identifier).unwrap_or("Unknown Property");
+            self.line(
+                depth + 1,
+                format!("{identifier:#04x} {name}: {}", property_value(property)),
+            );
+        }
+    }
+
+    fn reason_code(&mut self, depth: usize, value: u8, reason_code: &dyn Debug) {
+        self.line(depth, format!("reason code: {value:#04x} {reason_code:?}"));
+    }
+
+    fn packet_id(&mut self, packet_id: u16) {
+        self.line(1, format!("packet identifier: {packet_id}"));
+    }
+}
+
+fn fixed_header(out: &mut Annotation, bytes: &[u8]) {
+    let Some(&first) = bytes.first() else {
+        return;
+    };
+    let packet_type = first >> 4;
+    let flags = first & 0x0f;
+    let flags = match packet_type {
+        3 => format!(
+            "dup {}, qos {}, retain {}",
+            flags >> 3,
+            (flags >> 1) & 0b11,
+            flags & 1
+        ),
+        // PUBREL, SUBSCRIBE and UNSUBSCRIBE
+        6 | 8 | 10 if flags == 0b0010 => String::from("flags 0b0010"),
+        6 | 8 | 10 => format!("flags {flags:#06b}, reserved flags must be 0b0010"),
+        _ if flags == 0 => String::from("flags 0b0000"),
+        _ => format!("flags {flags:#06b}, reserved flags must be 0b0000"),
+    };
+
+    let mut remaining_length = 0usize;
+    for (position, byte) in bytes.iter().skip(1).take(4).enumerate() {
+        remaining_length += ((byte & 127) as usize) << (7 * position);
+        if byte & 128 == 0 {
+            break;
+        }
+    }
+    out.line(
+        1,
+        format!(
+            "fixed header: {first:#04x} {}, {flags}, remaining length {remaining_length}",
+            packet_type_name(packet_type)
+        ),
+    );
+}
+
+fn packet_fields(out: &mut Annotation, packet: &ControlPacket) {
+    match packet {
+        ControlPacket::Connect(connect) => {
+            out.line(
+                1,
+                format!(
+                    "protocol: {} version {}",
+                    connect.protocol_name, connect.protocol_version
+                ),
+            );
+            out.line(
+                1,
+                format!(
+                    "connect flags: {:#010b}, clean start {}, will {}, will qos {}, \
+                     will retain {}, password {}, username {}",
+                    connect.connect_flags,
+                    connect.clean_start_flag() as u8,
+                    connect.will_flag() as u8,
+                    connect.will_qos_flag(),
+                    connect.will_retain_flag() as u8,
+                    connect.password_flag() as u8,
+                    connect.username_flag() as u8,
+                ),
+            );
+            out.line(1, format!("keep alive: {}", connect.keep_alive));
+            out.properties(1, "properties", &connect.variable_header_properties);
+            out.line(1, format!("client identifier: {:?}", connect.client_id));
+            out.properties(1, "will properties", &connect.will_properties);
+            if let Some(will_topic) = &connect.will_topic {
+                out.line(1, format!("will topic: {will_topic}"));
+            }
+            if let Some(will_payload) = &connect.will_payload {
+                out.line(1, format!("will payload, {} bytes:", will_payload.len()));
+                out.hex(2, will_payload, 0);
+            }
+            if let Some(username) = &connect.username {
+                out.line(1, format!("username: {username}"));
+            }
+            if let Some(password) = &connect.password {
+                out.line(1, format!("password: {} bytes", password.len()));
+            }
+        }
+        ControlPacket::ConnAck(connack) => {
+            out.line(
+                1,
+                format!("session present: {}", connack.connect_ack_flags & 1),
+            );
+            let code = connack.connect_reason_code;
+            match CONNECTACK::decode(code) {
+                Ok(reason_code) => out.reason_code(1, code, &reason_code),
+                Err(e) => out.line(1, format!("reason code: {code:#04x}, {e}")),
+            }
+            out.properties(1, "properties", &connack.variable_header_properties);
+        }
+        ControlPacket::Publish(publish) => {
+            out.line(1, format!("topic name: {}", publish.topic_name));
+            if let Some(packet_id) = publish.packet_id {
+                out.packet_id(packet_id);
+            }
+            out.properties(1, "properties", &publish.variable_header_properties);
+            let payload = publish.application_message.as_deref().unwrap_or_default();
+            out.line(1, format!("payload, {} bytes:", payload.len()));
+            out.hex(2, payload, 0);
+        }
+        ControlPacket::PubAck(puback) => {
+            out.packet_id(puback.packet_id);
+            out.reason_code(1, puback.reason_code.clone() as u8, &puback.reason_code);
+            out.properties(1, "properties", &puback.variable_header_properties);
+        }
+        ControlPacket::PubRec(pubrec) => {
+            out.packet_id(pubrec.packet_id);
+            out.reason_code(1, pubrec.reason_code.clone() as u8, &pubrec.reason_code);
+            out.properties(1, "properties", &pubrec.variable_header_properties);
+        }
+        ControlPacket::PubRel(pubrel) => {
+            out.packet_id(pubrel.packet_id);
+            out.reason_code(1, pubrel.reason_code.clone() as u8, &pubrel.reason_code);
+            out.properties(1, "properties", &pubrel.variable_header_properties);
+        }
+        ControlPacket::PubComp(pubcomp) => {
+            out.packet_id(pubcomp.packet_id);
+            out.reason_code(1, pubcomp.reason_code.clone() as u8, &pubcomp.reason_code);
+            out.properties(1, "properties", &pubcomp.variable_header_properties);
+        }
+        ControlPacket::Subscribe(subscribe) => {
+            out.packet_id(subscribe.packet_id);
+            out.properties(1, "properties", &subscribe.variable_header_properties);
+            out.line(1, "topic filters:");
+            for filter in &subscribe.topic_filters {
+                out.line(
+                    2,
+                    format!(
+                        "{}: options {:#010b}, qos {}, no local {}, retain as published {}, \
+                         retain handling {}",
+                        filter.topic_filter,
+                        filter.subscription_options.raw_value,
+                        filter.qos(),
+                        filter.no_local() as u8,
+                        filter.retain_as_published() as u8,
+                        filter.retain_handling()
+                    ),
+                );
+            }
+        }
+        ControlPacket::SubAck(suback) => {
+            out.packet_id(suback.packet_id);
+            out.properties(1, "properties", &suback.variable_header_properties);
+            for reason_code in &suback.reason_codes {
+                out.reason_code(1, reason_code.clone() as u8, reason_code);
+            }
+        }
+        ControlPacket::Unsubscribe(unsubscribe) => {
+            out.packet_id(unsubscribe.packet_id);
+            out.properties(1, "properties", &unsubscribe.variable_header_properties);
+            out.line(1, "topic filters:");
+            for filter in &unsubscribe.topic_filters {
+                out.line(2, filter);
+            }
+        }
+        ControlPacket::UnsubAck(unsuback) => {
+            out.packet_id(unsuback.packet_id);
+            out.properties(1, "properties", &unsuback.variable_header_properties);
+            for reason_code in &unsuback.topic_filters {
+                out.reason_code(1, reason_code.clone() as u8, reason_code);
+            }
+        }
+        ControlPacket::PingReq(_) | ControlPacket::PingResp(_) => {}
+        ControlPacket::Disconnect(disconnect) => {
+            out.reason_code(
+                1,
+                disconnect.reason_code.clone() as u8,
+                &disconnect.reason_code,
+            );
+            out.properties(1, "properties", &disconnect.variable_header_properties);
+        }
+        ControlPacket::Auth(auth) => {
+            out.reason_code(1, auth.reason_code.clone() as u8, &auth.reason_code);
+            out.properties(1, "properties", &auth.variable_header_properties);
+        }
+    }
+}
+
+fn property_value(property: &Property) -> String {
+    match property {
+        Property::PayloadFormatIndicator(Byte(value))
+        | Property::RequestProblemInformation(Byte(value))
+        | Property::RequestResponseInformation(Byte(value))
+        | Property::MaximumQos(Byte(value))
+        | Property::RetainAvailable(Byte(value))
+        | Property::WildcardSubscriptionAvailable(Byte(value))
+        | Property::SubscriptionIdentifierAvailable(Byte(value))
+        | Property::SharedSubscriptionAvailable(Byte(value)) => value.to_string(),
+        Property::ServerKeepAlive(TwoByteInteger(value))
+        | Property::ReceiveMaximum(TwoByteInteger(value))
+        | Property::TopicAliasMaximum(TwoByteInteger(value))
+        | Property::TopicAlias(TwoByteInteger(value)) => value.to_string(),
+        Property::MessageExpiryInterval(FourByteInteger(value))
+        | Property::SessionExpiryInterval(FourByteInteger(value))
+        | Property::WillDelayInterval(FourByteInteger(value))
+        | Property::MaximumPacketSize(FourByteInteger(value)) => value.to_string(),
+        Property::SubscriptionIdentifier(VariableByteInteger(value)) => value.to_string(),
+        Property::ContentType(Utf8EncodedString(value))
+        | Property::ResponseTopic(Utf8EncodedString(value))
+        | Property::AssignedClientIdentifier(Utf8EncodedString(value))
+        | Property::AuthenticationMethod(Utf8EncodedString(value))
+        | Property::ResponseInformation(Utf8EncodedString(value))
+        | Property::ServerReference(Utf8EncodedString(value))
+        | Property::ReasonString(Utf8EncodedString(value)) => format!("{value:?}"),
+        Property::CorrelationData(BinaryData(value))
+        | Property::AuthenticationData(BinaryData(value)) => nu_pretty_hex::simple_hex(value),
+        Property::User(Utf8StringPair(key, value)) => format!("{key:?} = {value:?}"),
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use crate::codec::encode_packet;
+    use crate::inspect::{annotate, frames, Malformed};
+    use crate::packets::pingreq::PingReq;
+    use crate::packets::publish::Publish;
+    use crate::primitive_types::{FourByteInteger, Utf8StringPair};
+    use crate::properties::Property;
+    use crate::ControlPacket;
+
+    fn publish() -> ControlPacket {
+        ControlPacket::Publish(Publish {
+            packet_type_low_nibble: 0b0010,
+            packet_id: Some(7),
+            topic_name: String::from("sensors/kitchen"),
+            variable_header_properties: Some(vec![
+                Property::MessageExpiryInterval(FourByteInteger(60)),
+                Property::User(Utf8StringPair(String::from("unit"), String::from("C"))),
+            ]),
+            application_message: Some(b"21.5".to_vec()),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    pub fn should_annotate_properties_with_their_names_and_payload_as_hex() {
+        let bytes = encode_packet(&publish()).unwrap();
+        let frames = frames(&bytes);
+        assert_eq!(1, frames.len());
+        assert_eq!(None, frames[0].differs_at);
+
+        let text = annotate(&frames[0]);
+        assert!(
+            text.contains("0x32 PUBLISH, dup 0, qos 1, retain 0"),
+            "{text}"
+        );
+        assert!(text.contains("0x02 Message Expiry Interval: 60"), "{text}");
+        assert!(
+            text.contains("0x26 User Property: \"unit\" = \"C\""),
+            "{text}"
+        );
+        assert!(text.contains("32 31 2e 35"), "{text}");
+    }
+
+    #[test]
+    pub fn should_split_stream_into_frames() {
+        let mut stream = encode_packet(&ControlPacket::PingReq(PingReq::default()))
+            .unwrap()
+            .to_vec();
+        let ping_len = stream.len();
+        stream.extend_from_slice(&encode_packet(&publish()).unwrap());
+
+        let frames = frames(&stream);
+        assert_eq!(2, frames.len());
+        assert_eq!(0, frames[0].offset);
+        assert_eq!(ping_len, frames[1].offset);
+        assert!(frames.iter().all(|frame| frame.packet.is_ok()));
+    }
+
+    #[test]
+    pub fn should_flag_truncated_packet_at_end_of_stream() {
+        let mut stream = encode_packet(&publish()).unwrap().to_vec();
+        stream.truncate(10);
+
+        let frames = frames(&stream);
+        assert_eq!(1, frames.len());
+        assert_eq!(10, frames[0].packet.as_ref().unwrap_err().offset);
+    }
+
+    #[test]
+    pub fn should_flag_offset_where_decoding_stopped() {
+        // PINGREQ, then a PUBLISH whose topic name claims 5 bytes when only 1 follows
+        let stream = [0xc0, 0x00, 0x30, 0x03, 0x00, 0x05, b'a'];
+        let frames = frames(&stream);
+        assert_eq!(2, frames.len());
+        assert_eq!(4, frames[1].packet.as_ref().unwrap_err().offset);
+        assert!(annotate(&frames[1]).contains("MALFORMED at offset 4"));
+
+        let frames = self::frames(&[0x30, 0xff, 0xff, 0xff, 0xff, 0x01]);
+        assert!(matches!(frames[0].packet, Err(Malformed { offset: 4, .. })));
+    }
+
+    #[test]
+    pub fn should_point_at_bytes_that_do_not_encode_back() {
+        // PUBLISH whose property length of 0 takes two bytes instead of one
+        let stream = [0x30, 0x07, 0x00, 0x01, b'a', 0x80, 0x00, b'x', b'y'];
+        let frames = frames(&stream);
+        assert!(frames[0].packet.is_ok());
+        assert!(frames[0].differs_at.is_some());
+        assert!(annotate(&frames[0]).contains("WARNING"));
+
+        // an unknown property identifier makes the packet malformed
+        let stream = [0x30, 0x08, 0x00, 0x01, b'a', 0x02, 0x7f, 0x00, b'x', b'y'];
+        assert!(self::frames(&stream)[0].packet.is_err());
+    }
+}
@@ -15,8 +15,10 @@ use crate::packets::subscribe::Subscribe;
 use crate::packets::unsuback::UnsubAck;
 use std::error::Error;
 
+pub mod codec;
 pub mod decode;
 pub mod encode;
+pub mod inspect;
 pub mod net;
 pub mod packets;
 pub mod primitive_types;
@@ -322,7 +324,7 @@ mod tests {
         // variable header fields - end
 
         // payload fields - start
-        original_packet = original_packet.password(Some("hello".to_string()));
+        original_packet = original_packet.password(Some(b"hello".to_vec()));
         original_packet = original_packet.client_id("ID".to_string());
         original_packet = original_packet.set_keep_alive(1000);
         let res = original_packet.will_message(&vec![], "topic".to_string(), vec![1, 2, 3, 4]);
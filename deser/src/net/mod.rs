@@ -53,7 +53,7 @@ mod test {
         // variable header fields - end
 
         // payload fields - start
-        original_packet = original_packet.password(Some("hello".to_string()));
+        original_packet = original_packet.password(Some(b"hello".to_vec()));
         original_packet = original_packet.client_id("ID".to_string());
         original_packet = original_packet.set_keep_alive(1000);
         let res = original_packet.will_message(&vec![], "topic".to_string(), vec![1, 2, 3, 4]);
@@ -1,8 +1,8 @@
-use crate::decode::{decode_property, varint};
+use crate::decode::{byte, decode_property, varint};
 use crate::packets::auth::Auth;
 use crate::packets::reason_codes::{DecodeReasonCode, AUTH};
 use crate::packets::{encode_properties, Decoder, Encoder, GeneratePacketParts};
-use bytes::{Buf, BufMut, BytesMut};
+use bytes::{BufMut, BytesMut};
 use std::error::Error;
 
 impl GeneratePacketParts for Auth {
@@ -28,17 +28,22 @@ impl Encoder<Auth> for Auth {}
 impl Decoder<Auth> for Auth {
     fn decode(bytes: &mut BytesMut) -> Result<Auth, Box<dyn Error + Send + Sync>> {
         // fixed header
-        let packet_with_flags = bytes.get_u8();
+        let packet_with_flags = byte(String::from("fixed header"), bytes)?.0;
         let packet_type = packet_with_flags >> 4;
         let packet_type_low_nibble = packet_with_flags & 0x0f;
 
-        let packet_size = varint(bytes).unwrap();
+        let packet_size = varint(bytes)?;
         // reason code
-        let reason_code = AUTH::decode(bytes.get_u8())?;
+        // a remaining length of 0 means Success without properties
+        let reason_code = if bytes.is_empty() {
+            AUTH::Success
+        } else {
+            AUTH::decode(byte(String::from("reason code"), bytes)?.0)?
+        };
 
         // variable header properties
 
-        let variable_header_properties = decode_property(bytes);
+        let variable_header_properties = decode_property(bytes)?;
 
         Ok(Auth {
             packet_type,
@@ -1,7 +1,7 @@
-use crate::decode::{decode_property, varint};
+use crate::decode::{byte, decode_property, varint};
 use crate::packets::connack::ConnAck;
 use crate::packets::{encode_properties, Decoder, Encoder, GeneratePacketParts};
-use bytes::{Buf, BufMut, BytesMut};
+use bytes::{BufMut, BytesMut};
 use std::error::Error;
 
 impl GeneratePacketParts for ConnAck {
@@ -22,14 +22,14 @@ impl Encoder<ConnAck> for ConnAck {}
 
 impl Decoder<ConnAck> for ConnAck {
     fn decode(bytes: &mut BytesMut) -> Result<ConnAck, Box<dyn Error + Send + Sync>> {
-        let packet_type_with_flags = bytes.get_u8();
+        let packet_type_with_flags = byte(String::from("fixed header"), bytes)?.0;
         let packet_type = packet_type_with_flags >> 4;
         let packet_type_flags = packet_type_with_flags & 0x0f;
-        let packet_size = varint(bytes).unwrap();
-        let connect_ack_flags = bytes.get_u8();
-        let connect_reason_code = bytes.get_u8();
+        let packet_size = varint(bytes)?;
+        let connect_ack_flags = byte(String::from("connect acknowledge flags"), bytes)?.0;
+        let connect_reason_code = byte(String::from("reason code"), bytes)?.0;
 
-        let variable_header_properties = decode_property(bytes);
+        let variable_header_properties = decode_property(bytes)?;
 
         Ok(ConnAck {
             packet_type,
@@ -120,7 +120,7 @@ impl ConnectBuilder {
         self
     }
 
-    pub fn password(mut self, password: Option<String>) -> Self {
+    pub fn password(mut self, password: Option<Vec<u8>>) -> Self {
         if password.is_some() && !password.as_ref().unwrap().is_empty() {
             self.packet.password = password;
 
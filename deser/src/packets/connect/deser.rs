@@ -1,5 +1,7 @@
-use crate::decode::{binary, decode_property, property, utf8_string, varint};
-use crate::encode::{utf8_encoded_string, variable_byte_integer};
+use crate::decode::{
+    binary, byte, decode_property, property, two_byte_integer, utf8_string, varint,
+};
+use crate::encode::{utf8_encoded_string, variable_byte_integer, EncodeError};
 use crate::packets::connect::builder::ConnectBuilder;
 use crate::packets::connect::Connect;
 use crate::packets::{
@@ -8,7 +10,7 @@ use crate::packets::{
 };
 use crate::primitive_types::VariableByteInteger;
 use crate::properties::Property;
-use bytes::{Buf, BufMut, BytesMut};
+use bytes::{BufMut, BytesMut};
 use nu_pretty_hex::*;
 use std::error::Error;
 use tracing::trace;
@@ -85,8 +87,10 @@ impl GeneratePacketParts for Connect {
 
         //password
         if self.password_flag() {
-            //payload.put(self.password.as_ref().unwrap().as_bytes());
-            utf8_encoded_string("password", self.password.as_ref().unwrap(), &mut payload);
+            // Binary Data, passwords need not be valid UTF-8
+            let password = self.password.as_ref().unwrap();
+            payload.put_u16(password.len() as u16);
+            payload.put(password.as_slice());
         }
 
         // end of payload <<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<
@@ -96,6 +100,26 @@ impl GeneratePacketParts for Connect {
     }
 }
 
+impl Connect {
+    /// Payload fields are prefixed with a two byte length, which would wrap for longer ones.
+    pub(crate) fn check_lengths(&self) -> Result<(), EncodeError> {
+        let lengths = [
+            ("client identifier", Some(self.client_id.len())),
+            ("will topic", self.will_topic.as_ref().map(String::len)),
+            ("will payload", self.will_payload.as_ref().map(Vec::len)),
+            ("user name", self.username.as_ref().map(String::len)),
+            ("password", self.password.as_ref().map(Vec::len)),
+        ];
+        match lengths
+            .into_iter()
+            .find(|(_, length)| length.is_some_and(|length| length > u16::MAX as usize))
+        {
+            Some((field, _)) => Err(EncodeError::TooLong(field)),
+            None => Ok(()),
+        }
+    }
+}
+
 impl Encoder<Connect> for Connect {}
 
 impl Decoder<Connect> for Connect {
@@ -106,44 +130,44 @@ impl Decoder<Connect> for Connect {
 
         // decode Fixed Header
 
-        let packet_type_with_flags = bytes.get_u8();
+        let packet_type_with_flags = byte(String::from("fixed header"), bytes)?.0;
         trace!("packet type with flags {:X}", packet_type_with_flags);
         let packet_type = packet_type_with_flags >> 4;
         trace!("packet_type {}", packet_type);
         let packet_type_flags = packet_type_with_flags & 0x0f;
         trace!("packet_type_flags {}", packet_type_flags);
 
-        let packet_size = varint(bytes).unwrap();
+        let packet_size = varint(bytes)?;
 
         trace!("size of packet {}", packet_size.0);
 
         // decode Variable Header
 
-        let protocol_name = utf8_string(String::from("protocol name"), bytes).unwrap();
+        let protocol_name = utf8_string(String::from("protocol name"), bytes)?;
 
         trace!("protocol_name is {}", protocol_name);
 
         // trace!("bytes after protocol are {bytes:?}");
 
-        let protocol_version = bytes.get_u8();
+        let protocol_version = byte(String::from("protocol version"), bytes)?.0;
 
         trace!("protocol version {:X}", protocol_version);
 
         // trace!("bytes after protocol version are {bytes:?}");
 
-        let connect_flags = bytes.get_u8();
+        let connect_flags = byte(String::from("connect flags"), bytes)?.0;
 
         trace!("connect flags are {:X}", connect_flags);
 
         // trace!("bytes after connect flags are {bytes:?}");
 
-        let keep_alive = bytes.get_u16();
+        let keep_alive = two_byte_integer(String::from("keep alive"), bytes)?.0;
 
         trace!("keep alive {:X}", keep_alive);
 
         trace!("bytes before reading properties {bytes:?}");
 
-        let variable_header_properties = decode_property(bytes);
+        let variable_header_properties = decode_property(bytes)?;
 
         trace!("bytes after reading properties {bytes:?}");
 
@@ -158,7 +182,7 @@ impl Decoder<Connect> for Connect {
         // user property can be duplicated
         // other properties can't be duplicated
 
-        let client_id = utf8_string(String::from("client identifier"), bytes).unwrap();
+        let client_id = utf8_string(String::from("client identifier"), bytes)?;
 
         trace!("client_id = {}", client_id);
 
@@ -175,7 +199,7 @@ impl Decoder<Connect> for Connect {
 
         let will_properties: Option<Vec<Property>> = if is_will_flag {
             // Will flag is set
-            let prop = Some(property(bytes).unwrap());
+            let prop = Some(property(bytes)?);
             trace!("will properties are {:?}", prop);
             prop
         } else {
@@ -187,7 +211,7 @@ impl Decoder<Connect> for Connect {
 
         let will_topic: Option<String> = if is_will_flag {
             trace!("decoding will topic");
-            let topic = Some(utf8_string(String::from("will_topic"), bytes).unwrap());
+            let topic = Some(utf8_string(String::from("will_topic"), bytes)?);
             trace!("will topic is {:?}", topic.clone().unwrap());
             topic
         } else {
@@ -197,12 +221,7 @@ impl Decoder<Connect> for Connect {
         trace!("bytes left after will_topic {}", bytes.len());
 
         let will_payload: Option<Vec<u8>> = if is_will_flag {
-            let payload = Some(
-                binary(String::from("payload"), bytes)
-                    .unwrap()
-                    .as_ref()
-                    .clone(),
-            );
+            let payload = Some(binary(String::from("payload"), bytes)?.as_ref().clone());
 
             if payload.is_some() {
                 trace!("will payload is {:?}", payload.clone().unwrap())
@@ -216,7 +235,7 @@ impl Decoder<Connect> for Connect {
         let is_username_flag = connect_flags & connect_flags::USER_NAME_FLAG > 0;
 
         let username = if is_username_flag {
-            let name = Some(utf8_string(String::from("username"), bytes).unwrap());
+            let name = Some(utf8_string(String::from("username"), bytes)?);
             trace!("username is {:?}", name);
             name
         } else {
@@ -228,7 +247,7 @@ impl Decoder<Connect> for Connect {
         trace!("password flag is {is_password_flag}");
         trace!("bytes are {bytes:?}");
         let password = if is_password_flag {
-            let passwd = Some(utf8_string(String::from("password"), bytes).unwrap());
+            let passwd = Some(binary(String::from("password"), bytes)?.as_ref().clone());
             trace!("password is {:?}", passwd);
             passwd
         } else {
@@ -28,7 +28,7 @@ pub struct Connect {
     pub will_topic: Option<String>,
     pub will_payload: Option<Vec<u8>>,
     pub username: Option<String>,
-    pub password: Option<String>,
+    pub password: Option<Vec<u8>>,
 }
 
 // impl block for reading properties
@@ -166,7 +166,7 @@ pub mod test {
         // variable header fields - end
 
         // payload fields - start
-        original_packet = original_packet.password(Some("hello".to_string()));
+        original_packet = original_packet.password(Some(b"hello".to_vec()));
         original_packet = original_packet.client_id("ID".to_string());
         original_packet = original_packet.set_keep_alive(1000);
         let res = original_packet.will_message(&vec![], "topic".to_string(), vec![1, 2, 3, 4]);
@@ -189,4 +189,26 @@ pub mod test {
 
         assert_eq!(built_packet, deserialed_packet);
     }
+
+    #[test]
+    fn should_encode_decode_non_utf8_password() {
+        let password = vec![0xff, 0x00, 0xc3, 0x28, 0x80];
+        let built_packet = ConnectBuilder::new()
+            .client_id("ID".to_string())
+            .username(Some("alice".to_string()))
+            .password(Some(password.clone()))
+            .build()
+            .unwrap();
+
+        let mut serialized_packet = Connect::encode(
+            built_packet.packet_type,
+            built_packet.packet_type_low_nibble,
+            &built_packet,
+        )
+        .unwrap();
+        let deserialized_packet = Connect::decode(&mut serialized_packet).unwrap();
+
+        assert_eq!(Some(password), deserialized_packet.password);
+        assert_eq!(built_packet, deserialized_packet);
+    }
 }
@@ -1,8 +1,8 @@
-use crate::decode::{decode_property, varint};
+use crate::decode::{byte, decode_property, varint};
 use crate::packets::disconnect::Disconnect;
 use crate::packets::reason_codes::{DecodeReasonCode, DISCONNECT};
 use crate::packets::{encode_properties, Decoder, Encoder, GeneratePacketParts};
-use bytes::{Buf, BufMut, BytesMut};
+use bytes::{BufMut, BytesMut};
 use std::error::Error;
 
 impl GeneratePacketParts for Disconnect {
@@ -28,18 +28,23 @@ impl Encoder<Disconnect> for Disconnect {}
 impl Decoder<Disconnect> for Disconnect {
     fn decode(bytes: &mut BytesMut) -> Result<Disconnect, Box<dyn Error + Send + Sync>> {
         // fixed header
-        let packet_with_flags = bytes.get_u8();
+        let packet_with_flags = byte(String::from("fixed header"), bytes)?.0;
         let packet_type = packet_with_flags >> 4;
         let packet_type_low_nibble = packet_with_flags & 0x0f;
 
         // remaining length
-        let _packet_size = varint(bytes).unwrap();
+        let _packet_size = varint(bytes)?;
 
         // reason_code
-        let reason_code = DISCONNECT::decode(bytes.get_u8())?;
+        // a remaining length of 0 means Normal disconnection without properties
+        let reason_code = if bytes.is_empty() {
+            DISCONNECT::NormalDisconnection
+        } else {
+            DISCONNECT::decode(byte(String::from("reason code"), bytes)?.0)?
+        };
 
         // variable_header_properties
-        let variable_header_properties = decode_property(bytes);
+        let variable_header_properties = decode_property(bytes)?;
 
         // no_payload
 
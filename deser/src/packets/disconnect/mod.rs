@@ -34,7 +34,7 @@ impl Default for Disconnect {
 pub mod test {
     use crate::packets::disconnect::builder::DisconnectBuilder;
     use crate::packets::disconnect::Disconnect;
-    use crate::packets::reason_codes::DISCONNECT;
+    use crate::packets::reason_codes::{DecodeReasonCode, DISCONNECT};
     use crate::packets::{BuilderLifecycle, Decoder, Encoder, Properties};
     use crate::primitive_types::FourByteInteger;
     use crate::properties::Property;
@@ -59,4 +59,20 @@ pub mod test {
 
         assert_eq!(build_packet, deserialized_packet);
     }
+
+    #[test]
+    pub fn should_decode_reason_codes_to_their_own_value() {
+        for reason_code in [
+            DISCONNECT::KeepAliveTimeout,
+            DISCONNECT::SessionTakenOver,
+            DISCONNECT::TopicFilterInvalid,
+            DISCONNECT::TopicNameInvalid,
+            DISCONNECT::PacketTooLarge,
+        ] {
+            assert_eq!(
+                reason_code,
+                DISCONNECT::decode(reason_code.clone() as u8).unwrap()
+            );
+        }
+    }
 }
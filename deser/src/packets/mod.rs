@@ -930,9 +930,10 @@ pub mod reason_codes {
                 0x87 => DISCONNECT::NotAuthorized,
                 0x89 => DISCONNECT::ServerBusy,
                 0x8b => DISCONNECT::ServerShuttingDown,
-                0x8e => DISCONNECT::KeepAliveTimeout,
-                0x8f => DISCONNECT::SessionTakenOver,
-                0x90 => DISCONNECT::TopicFilterInvalid,
+                0x8d => DISCONNECT::KeepAliveTimeout,
+                0x8e => DISCONNECT::SessionTakenOver,
+                0x8f => DISCONNECT::TopicFilterInvalid,
+                0x90 => DISCONNECT::TopicNameInvalid,
                 0x93 => DISCONNECT::ReceiveMaximumExceed,
                 0x94 => DISCONNECT::TopicAliasInvalid,
                 0x95 => DISCONNECT::PacketTooLarge,
@@ -985,7 +986,7 @@ pub mod reason_codes {
     #[derive(Debug, PartialEq, Eq, Clone)]
     #[repr(u8)]
     pub enum UNSUBACK {
-        Success = 0x99,
+        Success = 0x00,
         NoSubscriptionExisted = 0x11,
         UnspecifiedError = 0x80,
         ImplementationSpecificError = 0x83,
@@ -997,11 +998,12 @@ pub mod reason_codes {
     impl DecodeReasonCode<UNSUBACK, ReasonCodeError> for UNSUBACK {
         fn decode(reason_code: u8) -> Result<UNSUBACK, ReasonCodeError> {
             let ret = match reason_code {
-                0x99 => UNSUBACK::Success,
+                0x00 => UNSUBACK::Success,
                 0x11 => UNSUBACK::NoSubscriptionExisted,
                 0x80 => UNSUBACK::UnspecifiedError,
                 0x83 => UNSUBACK::ImplementationSpecificError,
                 0x87 => UNSUBACK::NotAuthorized,
+                0x8f => UNSUBACK::TopicFilterInvalid,
                 0x91 => UNSUBACK::PacketIdentifierInUse,
                 n => {
                     return Err(ReasonCodeError::InvalidReasonCode(
@@ -42,6 +42,7 @@ pub mod test {
     use crate::packets::{BuilderLifecycle, Decoder, Encoder, Properties};
     use crate::primitive_types::Byte;
     use crate::properties::Property;
+    use bytes::BytesMut;
 
     #[test]
     pub fn should_encode_decode_packet() {
@@ -63,4 +64,18 @@ pub mod test {
 
         assert_eq!(built_packet, deserialized_packet);
     }
+
+    #[test]
+    pub fn should_decode_packet_without_reason_code() {
+        let mut serialized_packet = BytesMut::from(&[0x40u8, 0x02, 0x00, 0x05][..]);
+
+        let deserialized_packet = PubAck::decode(&mut serialized_packet).unwrap();
+        assert_eq!(
+            PubAck {
+                packet_id: 5,
+                ..Default::default()
+            },
+            deserialized_packet
+        );
+    }
 }
@@ -1,8 +1,8 @@
-use crate::decode::{decode_property, varint};
+use crate::decode::{byte, decode_property, two_byte_integer, varint};
 use crate::packets::pubcomp::PubComp;
 use crate::packets::reason_codes::{DecodeReasonCode, PUBCOMP};
 use crate::packets::{encode_properties, Decoder, Encoder, GeneratePacketParts};
-use bytes::{Buf, BufMut, BytesMut};
+use bytes::{BufMut, BytesMut};
 use std::error::Error;
 
 impl GeneratePacketParts for PubComp {
@@ -29,19 +29,24 @@ impl Encoder<PubComp> for PubComp {}
 impl Decoder<PubComp> for PubComp {
     fn decode(bytes: &mut BytesMut) -> Result<PubComp, Box<dyn Error + Send + Sync>> {
         // fixed header
-        let packet_with_flags = bytes.get_u8();
+        let packet_with_flags = byte(String::from("fixed header"), bytes)?.0;
         let packet_type = packet_with_flags >> 4;
         let packet_type_low_nibble = packet_with_flags & 0x0f;
         // remaining length
-        let _packet_size = varint(bytes).unwrap();
+        let _packet_size = varint(bytes)?;
 
         // packet identifier
-        let packet_id = bytes.get_u16();
+        let packet_id = two_byte_integer(String::from("packet identifier"), bytes)?.0;
         // reason code
-        let reason_code = PUBCOMP::decode(bytes.get_u8())?;
+        // a remaining length of 2 means Success without properties
+        let reason_code = if bytes.is_empty() {
+            PUBCOMP::Success
+        } else {
+            PUBCOMP::decode(byte(String::from("reason code"), bytes)?.0)?
+        };
 
         // variable header properties
-        let variable_header_properties = decode_property(bytes);
+        let variable_header_properties = decode_property(bytes)?;
 
         Ok(PubComp {
             packet_type,
@@ -1,9 +1,10 @@
-use crate::decode::{decode_property, utf8_string, varint};
+use crate::decode::{byte, decode_property, two_byte_integer, utf8_string, varint};
 use crate::encode::utf8_encoded_string;
 use crate::packets::publish::Publish;
 use crate::packets::{encode_properties, Decoder, Encoder, GeneratePacketParts};
-use bytes::{Buf, BufMut, BytesMut};
+use bytes::{BufMut, BytesMut};
 use std::error::Error;
+use tracing::trace;
 
 impl GeneratePacketParts for Publish {
     fn generate_variable_header(&self) -> BytesMut {
@@ -29,7 +30,7 @@ impl GeneratePacketParts for Publish {
             payload.put(local_appmessage.unwrap().as_slice());
         }
 
-        println!("encode payload is {payload:?}",);
+        trace!("encode payload is {payload:?}");
 
         payload
     }
@@ -39,23 +40,23 @@ impl Encoder<Publish> for Publish {}
 
 impl Decoder<Publish> for Publish {
     fn decode(bytes: &mut BytesMut) -> Result<Publish, Box<dyn Error + Send + Sync>> {
-        let packet_type_with_flags = bytes.get_u8();
+        let packet_type_with_flags = byte(String::from("fixed header"), bytes)?.0;
         let packet_type = packet_type_with_flags >> 4;
         let packet_type_low_nibble = packet_type_with_flags & 0x0f;
-        let packet_size = varint(bytes).unwrap();
-        let topic_name = utf8_string(String::from("topic_name"), bytes).unwrap();
+        let packet_size = varint(bytes)?;
+        let topic_name = utf8_string(String::from("topic_name"), bytes)?;
         // 0b000_0110 mask for Qos Level.
-        let qos = packet_type_low_nibble & 0b0000_0110 >> 1;
-        println!("qos is {qos}");
+        let qos = (packet_type_low_nibble & 0b0000_0110) >> 1;
+        trace!("qos is {qos}");
         let packet_id = if (1..=2).contains(&qos) {
-            println!("got packet_id");
-            Some(bytes.get_u16())
+            trace!("got packet_id");
+            Some(two_byte_integer(String::from("packet identifier"), bytes)?.0)
         } else {
-            println!("packet _id isnot present");
+            trace!("packet_id is not present");
             None
         };
 
-        let variable_header_properties = decode_property(bytes);
+        let variable_header_properties = decode_property(bytes)?;
 
         let application_message = if !bytes.is_empty() {
             Some(bytes.to_vec())
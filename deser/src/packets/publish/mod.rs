@@ -42,13 +42,9 @@ impl Publish {
         }
     }
 
+    /// QoS bits of the fixed header, readable before a packet identifier is assigned.
     pub fn qos_number(&self) -> u8 {
-        match self.qos() {
-            Qos::Q0 => 0,
-            Qos::Q1(_) => 1,
-            Qos::Q2(_) => 2,
-            Qos::Reserved => 3,
-        }
+        (self.packet_type_low_nibble >> 1) & 3
     }
 
     pub fn packet_id(self) -> Option<u16> {
@@ -130,4 +126,21 @@ pub mod test {
 
         assert_eq!(built_packet, deserialized_packet);
     }
+
+    #[test]
+    pub fn should_decode_packet_id_from_qos_bits() {
+        for (low_nibble, packet_id) in [(0b0001, None), (0b0010, Some(7)), (0b0100, Some(8))] {
+            let packet = Publish {
+                packet_type_low_nibble: low_nibble,
+                topic_name: String::from("a/b"),
+                packet_id,
+                ..Default::default()
+            };
+            let mut serialized_packet =
+                Publish::encode(packet.packet_type, packet.packet_type_low_nibble, &packet)
+                    .unwrap();
+
+            assert_eq!(packet, Publish::decode(&mut serialized_packet).unwrap());
+        }
+    }
 }
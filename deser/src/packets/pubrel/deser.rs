@@ -1,8 +1,8 @@
-use crate::decode::{decode_property, varint};
+use crate::decode::{byte, decode_property, two_byte_integer, varint};
 use crate::packets::pubrel::PubRel;
 use crate::packets::reason_codes::{DecodeReasonCode, PUBREL};
 use crate::packets::{encode_properties, Decoder, Encoder, GeneratePacketParts};
-use bytes::{Buf, BufMut, BytesMut};
+use bytes::{BufMut, BytesMut};
 use std::error::Error;
 
 impl GeneratePacketParts for PubRel {
@@ -30,20 +30,25 @@ impl Encoder<PubRel> for PubRel {}
 impl Decoder<PubRel> for PubRel {
     fn decode(bytes: &mut BytesMut) -> Result<PubRel, Box<dyn Error + Send + Sync>> {
         // fixed header
-        let packet_with_flags = bytes.get_u8();
+        let packet_with_flags = byte(String::from("fixed header"), bytes)?.0;
         let packet_type = packet_with_flags >> 4;
         let packet_type_low_nibble = packet_with_flags & 0x0f;
         // remaining length
-        let _packet_size = varint(bytes).unwrap();
+        let _packet_size = varint(bytes)?;
 
         // packet_id
-        let packet_id = bytes.get_u16();
+        let packet_id = two_byte_integer(String::from("packet identifier"), bytes)?.0;
 
         // reason_code
-        let reason_code = PUBREL::decode(bytes.get_u8())?;
+        // a remaining length of 2 means Success without properties
+        let reason_code = if bytes.is_empty() {
+            PUBREL::Success
+        } else {
+            PUBREL::decode(byte(String::from("reason code"), bytes)?.0)?
+        };
 
         // variable_header_properties
-        let variable_header_properties = decode_property(bytes);
+        let variable_header_properties = decode_property(bytes)?;
 
         // no_payload
 
@@ -26,7 +26,8 @@ impl Default for PubRel {
     fn default() -> Self {
         PubRel {
             packet_type: PacketTypes::Pubrel as u8,
-            packet_type_low_nibble: 0,
+            // reserved bits of the fixed header must be 0b0010
+            packet_type_low_nibble: 0b0010,
             packet_id: 0,
             reason_code: PUBREL::Success,
             variable_header_properties: None,
@@ -42,6 +43,7 @@ pub mod test {
     use crate::packets::{BuilderLifecycle, Decoder, Encoder, Properties};
     use crate::primitive_types::{Utf8EncodedString, Utf8StringPair};
     use crate::properties::Property;
+    use bytes::BytesMut;
 
     #[test]
     pub fn should_encode_decode_packet() {
@@ -66,4 +68,18 @@ pub mod test {
 
         assert_eq!(build_packet, deserialized_packet)
     }
+
+    #[test]
+    pub fn should_decode_packet_without_reason_code() {
+        let mut serialized_packet = BytesMut::from(&[0x62u8, 0x02, 0x00, 0x05][..]);
+
+        let deserialized_packet = PubRel::decode(&mut serialized_packet).unwrap();
+        assert_eq!(
+            PubRel {
+                packet_id: 5,
+                ..Default::default()
+            },
+            deserialized_packet
+        );
+    }
 }
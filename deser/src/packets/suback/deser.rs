@@ -1,8 +1,8 @@
-use crate::decode::{decode_property, varint};
+use crate::decode::{byte, decode_property, two_byte_integer, varint};
 use crate::packets::reason_codes::{DecodeReasonCode, SUBACK};
 use crate::packets::suback::SubAck;
 use crate::packets::{encode_properties, Decoder, Encoder, GeneratePacketParts};
-use bytes::{Buf, BufMut, BytesMut};
+use bytes::{BufMut, BytesMut};
 use std::error::Error;
 
 impl GeneratePacketParts for SubAck {
@@ -30,18 +30,18 @@ impl Encoder<SubAck> for SubAck {}
 
 impl Decoder<SubAck> for SubAck {
     fn decode(bytes: &mut BytesMut) -> Result<SubAck, Box<dyn Error + Send + Sync>> {
-        let packet_type_with_flags = bytes.get_u8();
+        let packet_type_with_flags = byte(String::from("fixed header"), bytes)?.0;
         let packet_type = packet_type_with_flags >> 4;
         let packet_type_low_nibble = packet_type_with_flags & 0x0f;
         //remaining length
-        let _remaining_length = varint(bytes).unwrap();
+        let _remaining_length = varint(bytes)?;
 
         // packet_id
 
-        let packet_id = bytes.get_u16();
+        let packet_id = two_byte_integer(String::from("packet identifier"), bytes)?.0;
 
         //variable header properties
-        let variable_header_properties = decode_property(bytes);
+        let variable_header_properties = decode_property(bytes)?;
         let mut reason_codes: Vec<SUBACK> = vec![];
         for r in bytes.to_vec() {
             reason_codes.push(SUBACK::decode(r)?);
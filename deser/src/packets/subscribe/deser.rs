@@ -1,10 +1,10 @@
-use crate::decode::{decode_property, utf8_string, varint};
+use crate::decode::{byte, decode_property, two_byte_integer, utf8_string, varint};
 use crate::encode::utf8_encoded_string;
 use crate::packets::subscribe::{
     Subscribe, SubscriptionOptions, TopicFilterAndSubscriptionOptions,
 };
 use crate::packets::{encode_properties, Decoder, Encoder, GeneratePacketParts};
-use bytes::{Buf, BufMut, BytesMut};
+use bytes::{BufMut, BytesMut};
 use std::error::Error;
 
 impl GeneratePacketParts for Subscribe {
@@ -33,27 +33,27 @@ impl Encoder<Subscribe> for Subscribe {}
 impl Decoder<Subscribe> for Subscribe {
     fn decode(bytes: &mut BytesMut) -> Result<Subscribe, Box<dyn Error + Send + Sync>> {
         // fixed header
-        let packet_with_flags = bytes.get_u8();
+        let packet_with_flags = byte(String::from("fixed header"), bytes)?.0;
         let packet_type = packet_with_flags >> 4;
         let packet_type_low_nibble = packet_with_flags & 0x0f;
         // remaining length
-        let _packet_size = varint(bytes).unwrap();
+        let _packet_size = varint(bytes)?;
 
         // packet_Identifier
-        let packet_id = bytes.get_u16();
+        let packet_id = two_byte_integer(String::from("packet identifier"), bytes)?.0;
 
         // variable header properties
-        let variable_header_properties = decode_property(bytes);
+        let variable_header_properties = decode_property(bytes)?;
         // topic filters
         let mut topic_filters: Vec<TopicFilterAndSubscriptionOptions> = vec![];
         while !bytes.is_empty() {
-            let topic_filter = utf8_string(String::from("Topic Filter"), bytes);
-            let subscription_options_raw = bytes.get_u8();
+            let topic_filter = utf8_string(String::from("Topic Filter"), bytes)?;
+            let subscription_options_raw = byte(String::from("subscription options"), bytes)?.0;
             let subscription_options = SubscriptionOptions {
                 raw_value: subscription_options_raw,
             };
             topic_filters.push(TopicFilterAndSubscriptionOptions::new(
-                topic_filter.unwrap(),
+                topic_filter,
                 subscription_options,
             ));
         }
@@ -68,7 +68,8 @@ impl Default for Subscribe {
     fn default() -> Self {
         Subscribe {
             packet_type: PacketTypes::Subscribe as u8,
-            packet_type_low_nibble: 0,
+            // reserved bits of the fixed header must be 0b0010
+            packet_type_low_nibble: 0b0010,
             packet_id: 0,
             variable_header_properties: None,
             topic_filters: vec![],
@@ -99,10 +100,12 @@ pub struct SubscriptionOptions {
 
 #[cfg(test)]
 pub mod test {
+    use crate::packets::pubrel::PubRel;
     use crate::packets::subscribe::builder::{SubscribeBuilder, SubscriptionOptionsBuilder};
     use crate::packets::subscribe::{
         RetainHandlingOptions, Subscribe, TopicFilterAndSubscriptionOptions, QOS,
     };
+    use crate::packets::unsubscribe::UnSubscribe;
     use crate::packets::{BuilderLifecycle, Decoder, Encoder};
     use crate::primitive_types::VariableByteInteger;
     use crate::properties::Property;
@@ -138,4 +141,11 @@ pub mod test {
         let deserialized_packet = Subscribe::decode(&mut serialized_packet).unwrap();
         assert_eq!(built_packet, deserialized_packet);
     }
+
+    #[test]
+    pub fn should_default_to_reserved_fixed_header_flags() {
+        assert_eq!(0b0010, Subscribe::default().packet_type_low_nibble);
+        assert_eq!(0b0010, UnSubscribe::default().packet_type_low_nibble);
+        assert_eq!(0b0010, PubRel::default().packet_type_low_nibble);
+    }
 }
@@ -1,8 +1,8 @@
-use crate::decode::{decode_property, varint};
+use crate::decode::{byte, decode_property, two_byte_integer, varint};
 use crate::packets::reason_codes::{DecodeReasonCode, UNSUBACK};
 use crate::packets::unsuback::UnsubAck;
 use crate::packets::{encode_properties, Decoder, Encoder, GeneratePacketParts};
-use bytes::{Buf, BufMut, BytesMut};
+use bytes::{BufMut, BytesMut};
 use std::error::Error;
 
 impl GeneratePacketParts for UnsubAck {
@@ -31,17 +31,17 @@ impl Encoder<UnsubAck> for UnsubAck {}
 
 impl Decoder<UnsubAck> for UnsubAck {
     fn decode(bytes: &mut BytesMut) -> Result<UnsubAck, Box<dyn Error + Send + Sync>> {
-        let packet_type_with_flags = bytes.get_u8();
+        let packet_type_with_flags = byte(String::from("fixed header"), bytes)?.0;
         let packet_type = packet_type_with_flags >> 4;
         let packet_type_low_nibble = packet_type_with_flags & 0x0f;
         //remaning length
-        let remaining_length = varint(bytes).unwrap();
+        let remaining_length = varint(bytes)?;
 
         // packet_id
-        let packet_id = bytes.get_u16();
+        let packet_id = two_byte_integer(String::from("packet identifier"), bytes)?.0;
 
         // variable_header_properties
-        let variable_header_properties = decode_property(bytes);
+        let variable_header_properties = decode_property(bytes)?;
 
         // payload
         let mut topic_filters: Vec<UNSUBACK> = vec![];
@@ -34,7 +34,7 @@ impl Default for UnsubAck {
 
 #[cfg(test)]
 pub mod test {
-    use crate::packets::reason_codes::UNSUBACK;
+    use crate::packets::reason_codes::{DecodeReasonCode, UNSUBACK};
     use crate::packets::unsuback::builder::UnSubAckBuilder;
     use crate::packets::unsuback::UnsubAck;
     use crate::packets::{BuilderLifecycle, Decoder, Encoder, Properties};
@@ -65,4 +65,21 @@ pub mod test {
 
         assert_eq!(built_packet, deserialized_packet);
     }
+
+    #[test]
+    pub fn should_encode_success_as_zero() {
+        let packet = UnsubAck {
+            packet_id: 1,
+            topic_filters: vec![UNSUBACK::Success],
+            ..Default::default()
+        };
+        let serialized_packet =
+            UnsubAck::encode(packet.packet_type, packet.packet_type_low_nibble, &packet).unwrap();
+
+        assert_eq!(Some(&0x00), serialized_packet.last());
+        assert_eq!(
+            UNSUBACK::TopicFilterInvalid,
+            UNSUBACK::decode(0x8f).unwrap()
+        );
+    }
 }
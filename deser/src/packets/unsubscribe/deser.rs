@@ -1,8 +1,8 @@
-use crate::decode::{decode_property, utf8_string, varint};
+use crate::decode::{byte, decode_property, two_byte_integer, utf8_string, varint};
 use crate::encode::utf8_encoded_string;
 use crate::packets::unsubscribe::UnSubscribe;
 use crate::packets::{encode_properties, Decoder, Encoder, GeneratePacketParts};
-use bytes::{Buf, BufMut, BytesMut};
+use bytes::{BufMut, BytesMut};
 use std::error::Error;
 
 impl GeneratePacketParts for UnSubscribe {
@@ -30,23 +30,23 @@ impl Encoder<UnSubscribe> for UnSubscribe {}
 
 impl Decoder<UnSubscribe> for UnSubscribe {
     fn decode(bytes: &mut BytesMut) -> Result<UnSubscribe, Box<dyn Error + Send + Sync>> {
-        let packet_type_with_flags = bytes.get_u8();
+        let packet_type_with_flags = byte(String::from("fixed header"), bytes)?.0;
         let packet_type = packet_type_with_flags >> 4;
         let packet_type_low_nibble = packet_type_with_flags & 0x0f;
 
         // remaining length
-        let _remaining_length = varint(bytes).unwrap();
+        let _remaining_length = varint(bytes)?;
 
         // packet_id
-        let packet_id = bytes.get_u16();
+        let packet_id = two_byte_integer(String::from("packet identifier"), bytes)?.0;
 
         // variable_header_properties
-        let variable_header_properties = decode_property(bytes);
+        let variable_header_properties = decode_property(bytes)?;
 
         let mut topic_filters: Vec<String> = vec![];
 
         while !bytes.is_empty() {
-            topic_filters.push(utf8_string(String::from("Topic Filter"), bytes).unwrap());
+            topic_filters.push(utf8_string(String::from("Topic Filter"), bytes)?);
         }
 
         Ok(UnSubscribe {
@@ -23,7 +23,8 @@ impl Default for UnSubscribe {
     fn default() -> Self {
         UnSubscribe {
             packet_type: PacketTypes::Unsubscribe as u8,
-            packet_type_low_nibble: 0,
+            // reserved bits of the fixed header must be 0b0010
+            packet_type_low_nibble: 0b0010,
             packet_id: 0,
             variable_header_properties: None,
             topic_filters: vec![],